@@ -2,34 +2,1292 @@
 #define RSIP_WRAPPER_H
 
 #include <stdbool.h>
+#include <stddef.h>
 #include <stdint.h>
 
 #ifdef __cplusplus
 extern "C" {
 #endif
 
+// Opaque handle to an independent SIP stack (socket, listener thread, callback).
+typedef struct RsipContext RsipContext;
+
+// Opaque handle to a parsed SIP message (see rsip_message_parse).
+typedef struct RsipMessage RsipMessage;
+
+// Error codes returned by the *_ex functions. Values are stable.
+typedef enum {
+    RSIP_OK = 0,
+    RSIP_ERR_BIND_FAILED = -1,
+    RSIP_ERR_ADDR_IN_USE = -2,
+    RSIP_ERR_PERMISSION_DENIED = -3,
+    RSIP_ERR_INVALID_ARGUMENT = -4,
+    RSIP_ERR_ALREADY_RUNNING = -5,
+    RSIP_ERR_NOT_RUNNING = -6,
+    RSIP_ERR_SEND_FAILED = -7,
+    RSIP_ERR_IO = -8,
+} RsipError;
+
+// Static, human-readable description of an error code. Never NULL; do not free.
+const char* rsip_strerror(int32_t code);
+
 // Initialize internal structures. Call before other APIs.
+// The context-less functions below operate on a process-wide default context.
+// Lifecycle: the context is idle until a listener starts, then running until
+// rsip_shutdown (or rsip_drain), after which it is idle again and can be restarted.
+// rsip_init may be called in any state and any number of times: if anything runs it
+// is shut down first, exactly as by rsip_shutdown (listener ports are free again when
+// it returns), and all callbacks are removed. Settings such as the bind address are
+// kept. A failed start leaves the context as it was.
 bool rsip_init(void);
+int32_t rsip_init_ex(void);
 
 // Set a callback to receive events from the Rust side. The callback is called
 // synchronously from the Rust listener thread. The strings are valid only for
 // the duration of the callback and will be freed after the call returns.
+// A callback must not unwind into the library (C++ exceptions, longjmp, or a panic in
+// a Rust callback, which aborts the process at the extern "C" boundary). A panic in the
+// library's own code while dispatching to a callback is caught instead of killing the
+// listener, and reported as an "error" event "callback_panic:<message>"; a panic while
+// a lock was held does not poison it for later calls.
 void rsip_set_event_callback(void (*cb)(const char* event, const char* payload));
 void rsip_clear_event_callback(void);
 
-// Start a UDP listener on the given port. Received datagrams trigger the
+// Like the callback above but also receives the peer address of network events
+// (sip_rx, sip_rx_parsed, ...). For events without a peer, src_ip is "" and
+// src_port is 0. Independent of rsip_set_event_callback; both fire if both are set.
+typedef void (*rsip_event_callback_ex)(const char* event, const char* payload,
+                                       const char* src_ip, uint16_t src_port);
+void rsip_set_event_callback_ex(rsip_event_callback_ex cb);
+void rsip_clear_event_callback_ex(void);
+
+// Like rsip_event_callback_ex, but event data arrives as len bytes rather than a C
+// string. For "sip_rx" data is the message exactly as received, including NULs and
+// bytes that are not valid UTF-8 (the string callbacks see a lossy UTF-8 copy); use it
+// for binary bodies or byte-exact signature checks (e.g. SIP Identity). For other
+// events data is the payload text, not NUL-terminated. Has its own default slot.
+typedef void (*rsip_event_callback_bytes)(const char* event, const uint8_t* data, size_t len,
+                                          const char* src_ip, uint16_t src_port);
+void rsip_set_event_callback_bytes(rsip_event_callback_bytes cb);
+void rsip_clear_event_callback_bytes(void);
+
+// For high event rates: each event arrives as one RsipEvent, with no allocation per
+// event (the string callbacks cost two C strings each). kind is one of the
+// RSIP_EVENT_* codes below, 0 for an event without one; rsip_event_name maps a code
+// back to its name (static, do not free; NULL for 0 or unknown codes). data and len
+// are as for rsip_event_callback_bytes. src_ip is the peer's address in network byte
+// order, IPv4 as v4-mapped IPv6 (::ffff:a.b.c.d); it and src_port are zero for events
+// without a peer. seq numbers the events of a context from 1 in the order they are
+// emitted, across all event names; a gap means events were lost: dropped by a full
+// poll queue, or a message dropped by a full worker queue (which uses up a number
+// without emitting anything). A listener filtered to some events naturally sees gaps
+// for the others. The struct is only valid during the call. Has its own default slot.
+typedef struct {
+    uint32_t kind;
+    const uint8_t* data;
+    size_t len;
+    uint8_t src_ip[16];
+    uint16_t src_port;
+    uint64_t seq;
+} RsipEvent;
+typedef void (*rsip_event_callback_struct)(const RsipEvent* event);
+void rsip_set_event_callback_struct(rsip_event_callback_struct cb);
+void rsip_clear_event_callback_struct(void);
+const char* rsip_event_name(uint32_t kind);
+// The seq of the last event emitted, 0 before the first; compare with the last one
+// received to tell whether later events are still on their way or were lost.
+uint64_t rsip_last_event_seq(void);
+// Event codes are stable; new events are appended.
+#define RSIP_EVENT_OTHER 0
+#define RSIP_EVENT_SIP_RX 1
+#define RSIP_EVENT_SIP_RX_PARSED 2
+#define RSIP_EVENT_SIP_RX_MALFORMED 3
+#define RSIP_EVENT_SIP_RX_INVALID 4
+#define RSIP_EVENT_SIP_RX_TRUNCATED 5
+#define RSIP_EVENT_SDP_PARSED 6
+#define RSIP_EVENT_ERROR 7
+#define RSIP_EVENT_FILTERED 8
+#define RSIP_EVENT_RATE_LIMITED 9
+#define RSIP_EVENT_LISTENER_STARTED 10
+#define RSIP_EVENT_LISTENER_STOPPED 11
+#define RSIP_EVENT_TICK 12
+#define RSIP_EVENT_SENT_OK 13
+#define RSIP_EVENT_SEND_FAILED 14
+#define RSIP_EVENT_DEST_UNREACHABLE 15
+#define RSIP_EVENT_REGISTERED 16
+#define RSIP_EVENT_REGISTER_REFRESH 17
+#define RSIP_EVENT_REGISTER_FAILED 18
+#define RSIP_EVENT_PING_OK 19
+#define RSIP_EVENT_PING_TIMEOUT 20
+#define RSIP_EVENT_TXN_PROVISIONAL 21
+#define RSIP_EVENT_TXN_FINAL 22
+#define RSIP_EVENT_TXN_TIMEOUT 23
+#define RSIP_EVENT_DIALOG_CREATED 24
+#define RSIP_EVENT_DIALOG_TERMINATED 25
+#define RSIP_EVENT_TLS_ERROR 26
+#define RSIP_EVENT_WS_ERROR 27
+#define RSIP_EVENT_SIP_RX_RETRANSMISSION 28
+#define RSIP_EVENT_TOO_LARGE 29
+#define RSIP_EVENT_KEEPALIVE_PING 30
+#define RSIP_EVENT_MARTIAN_DROPPED 31
+#define RSIP_EVENT_TRYING_SENT 32
+#define RSIP_EVENT_TOO_MANY_HEADERS 33
+
+// Pulling events instead of receiving callbacks. rsip_set_event_mode selects delivery:
+// to the callbacks (RSIP_EVENT_MODE_CALLBACK, the default), into a queue for
+// rsip_poll_event with no callbacks invoked (RSIP_EVENT_MODE_POLL), or both. queue_size
+// bounds the queue (0 for 1024); events arriving while it is full are dropped.
+// Switching back to callbacks discards queued events. Returns false for an unknown mode.
+// rsip_poll_event waits up to timeout_ms (negative: indefinitely, 0: not at all) for
+// the next event and fills out_event as for rsip_event_callback_struct. Returns 1 with
+// an event, 0 on timeout, or RSIP_ERR_INVALID_ARGUMENT if out_event is NULL or events
+// are not queued (also when the mode is switched back, or rsip_shutdown runs, while it
+// waits). out_event->data stays valid until the next rsip_poll_event call.
+#define RSIP_EVENT_MODE_CALLBACK 0
+#define RSIP_EVENT_MODE_POLL 1
+#define RSIP_EVENT_MODE_BOTH 2
+bool rsip_set_event_mode(uint32_t mode, size_t queue_size);
+int32_t rsip_poll_event(int32_t timeout_ms, RsipEvent* out_event);
+
+// Batched delivery, one call for many events: once rsip_set_batch_mode has given a
+// max_batch and a batch callback is set, each event is copied into a buffer that a
+// separate thread delivers as an array of up to max_batch events, oldest first, or
+// sooner once the oldest has waited max_delay_ms. This comes on top of the other
+// callbacks (not in RSIP_EVENT_MODE_POLL, which runs none). The array and each event's
+// data are only valid during the call. Events arriving while 16 full batches are
+// already waiting are dropped, leaving a gap in seq. max_batch 0 turns batching off,
+// delivering what is buffered first; rsip_shutdown does the same and clears the
+// callback.
+typedef void (*rsip_event_callback_batch)(const RsipEvent* events, size_t count);
+void rsip_set_batch_mode(size_t max_batch, uint64_t max_delay_ms);
+void rsip_set_event_callback_batch(rsip_event_callback_batch cb);
+void rsip_clear_event_callback_batch(void);
+
+// Register an additional callback for a comma-separated list of event names
+// (e.g. "sip_rx,error"; NULL, "" or "*" subscribes to everything). Returns a
+// subscription id (never 0). rsip_set_event_callback above is equivalent to a single
+// catch-all listener that each call replaces. rsip_shutdown removes all listeners.
+uint64_t rsip_add_event_listener(const char* events_csv,
+                                 void (*cb)(const char* event, const char* payload));
+uint64_t rsip_add_event_listener_ex(const char* events_csv, rsip_event_callback_ex cb);
+uint64_t rsip_add_event_listener_bytes(const char* events_csv, rsip_event_callback_bytes cb);
+uint64_t rsip_add_event_listener_struct(const char* events_csv, rsip_event_callback_struct cb);
+bool rsip_remove_event_listener(uint64_t id);
+
+// Start a UDP listener on the given port. Several can run at once (e.g. 5060 and 5080):
+// starting again adds another listener, see rsip_add_udp_listener. Received datagrams
+// trigger the
 // registered callback with event="sip_rx" and payload being the raw SIP text.
+// Each datagram is then parsed and followed by either:
+//   "sip_rx_parsed"    JSON {kind, method, uri, status, call_id, cseq:{seq,method},
+//                      from_tag, to_tag, via_branch, src}; absent fields are null.
+//   "sip_rx_malformed" JSON {error, raw, src} with the parser error and the raw text.
+// "src" is the sender's "ip:port"; use rsip_set_event_callback_ex to get it for the
+// raw "sip_rx" event too.
+// A message with Content-Type application/sdp is additionally reported as
+//   "sdp_parsed"       JSON {call_id, cseq, src, sdp} where sdp is as rsip_parse_sdp.
+// A message that parses but breaks RFC 3261 (see rsip_validate_message) is also
+// reported as
+//   "sip_rx_invalid"   JSON {call_id, src, violations}.
+// A datagram that fills the whole receive buffer was most likely cut short by the
+// kernel; it is additionally reported as "sip_rx_truncated" JSON {src, len}.
+// The receive thread emits "listener_started" JSON {transport: "udp", addr} once it
+// is about to read, and "listener_stopped" with the same payload right before it exits
+// (on shutdown, before the callbacks are removed).
+// Every JSON payload above also carries "listener", the id of the listener the datagram
+// arrived on (as returned by rsip_add_udp_listener; listeners started with
+// rsip_start_udp_listener get one too, counting from 1).
 bool rsip_start_udp_listener(uint16_t port);
+int32_t rsip_start_udp_listener_ex(uint16_t port);
+
+// Start the UDP listener bound to a specific local address (e.g. one NIC on a
+// multi-homed host). On failure an "error" event is emitted whose payload starts
+// with "invalid_addr:" for a malformed address or "bind_err:" if the bind failed.
+bool rsip_start_udp_listener_on(const char* ip, uint16_t port);
+int32_t rsip_start_udp_listener_on_ex(const char* ip, uint16_t port);
+
+// Add a UDP listener on port of the bind address, next to any already running, and
+// return its id (never 0), or 0 if the bind fails. rsip_remove_listener stops that
+// listener and waits for its thread (unless called from one of its callbacks); it
+// returns false for an unknown id. Removing the last listener stops the stack running,
+// as far as rsip_is_running is concerned. Sends from the listener go out of the first
+// listener added that is still running, and rsip_listener_port reports its port.
+uint64_t rsip_add_udp_listener(uint16_t port);
+bool rsip_remove_listener(uint64_t id);
+
+// Restart the receive thread of every UDP listener on the socket it already has bound,
+// so the port and listener id stay the same and datagrams arriving meanwhile wait in
+// the socket buffer. Each new thread's "listener_started" may come before the old
+// thread's "listener_stopped". Returns false if no UDP listener is running.
+bool rsip_restart_listener(void);
+
+// Handle len bytes of data as if the UDP listener had received them from
+// src_ip:src_port: the IP filter, rate limits, worker pool, parsing, events and
+// handlers all run as usual, but no socket is needed or touched, so the martian filter
+// (rsip_set_drop_martians) and packet capture, which belong to a listener socket, are
+// skipped. Meant for tests and fuzzing; without worker threads it is synchronous, so
+// every event has fired when it returns. Empty input is ignored. Returns false if
+// src_ip is NULL or not an IP address, or data is NULL with a non-zero len.
+bool rsip_feed_bytes(const uint8_t* data, size_t len, const char* src_ip, uint16_t src_port);
+
+// Custom transport (shared memory, QUIC, ...): once registered, everything the stack
+// would send from the UDP listener (rsip_send_udp_from_listener, registrations, client
+// transactions, automatic 513 responses) is handed to send_cb instead, whether or not a
+// listener runs, and counts as sent. dest is "host:port" (IPv6 in brackets); data is
+// only valid during the call. Feed what the transport receives to rsip_feed_bytes.
+// NULL goes back to the UDP listener.
+typedef void (*rsip_transport_send_callback)(const char* dest, const uint8_t* data,
+                                             size_t len);
+void rsip_register_transport(rsip_transport_send_callback send_cb);
+
+// Raw capture for debugging (e.g. writing a .pcap): every UDP datagram the listeners
+// receive, before filtering, rate limiting or parsing, and every one the stack sends
+// (rsip_send_udp*, rsip_send_async, rsip_send_and_wait, registrations, transactions,
+// pings) is handed to cb, independently of events. direction is RSIP_CAPTURE_IN or
+// RSIP_CAPTURE_OUT; timestamp_ns is nanoseconds since the Unix epoch (the kernel's
+// receive time with rsip_set_rx_timestamps). Addresses are as in RsipEvent, in network
+// byte order with IPv4 v4-mapped; the local address is the socket's bound one (e.g.
+// 0.0.0.0 for a listener on all interfaces). data and the struct are only valid
+// during the call, which runs on the receiving or sending thread. NULL turns
+// capturing off (the default). Datagrams handed to a registered transport and stream
+// traffic are not captured.
+#define RSIP_CAPTURE_IN 0
+#define RSIP_CAPTURE_OUT 1
+typedef struct {
+    uint32_t direction;
+    uint64_t timestamp_ns;
+    uint8_t src_ip[16];
+    uint16_t src_port;
+    uint8_t dst_ip[16];
+    uint16_t dst_port;
+    const uint8_t* data;
+    size_t len;
+} RsipCapture;
+typedef void (*rsip_capture_callback)(const RsipCapture* capture);
+void rsip_set_capture_callback(rsip_capture_callback cb);
+
+// Address rsip_start_udp_listener binds to: "0.0.0.0" by default, "::" for IPv6.
+// With dual stack enabled, a listener on an unspecified address ("0.0.0.0" or "::")
+// is one IPv6 socket that also accepts IPv4; IPv4 peers are still reported and
+// addressed as plain IPv4. Both must be set before the listener starts.
+bool rsip_set_bind_address(const char* ip);
+bool rsip_set_dual_stack(bool enabled);
+
+// Socket reuse for the UDP listener, applied before bind; both must be set before the
+// listener starts and are off by default.
+// SO_REUSEADDR: on Linux and the BSDs another UDP socket may bind the same address only
+// if every socket on it sets the option. On Windows it lets any later socket take over
+// the port, so leave it off there unless that is intended.
+// SO_REUSEPORT: several processes (of the same user, on Linux) can listen on one port.
+// Linux 3.9+ spreads incoming datagrams across them by source address; macOS and the
+// BSDs deliver each unicast datagram to only one socket, with no load sharing. Returns
+// false on platforms without it (Windows, Solaris, illumos).
+bool rsip_set_reuse_addr(bool enabled);
+bool rsip_set_reuse_port(bool enabled);
+
+// Mark SIP signaling with DSCP value (0..=63, e.g. 24 for CS3 or 46 for EF): sets
+// IP_TOS, and IPV6_TCLASS on IPv6 sockets, on the running UDP listeners, on listeners
+// started later and on the sockets of one-shot sends (rsip_send_udp, rsip_send_async,
+// rsip_send_and_wait). May be called at any time. Returns false for a value above 63,
+// on platforms that cannot mark packets (e.g. Windows), or if a running listener
+// refused the option.
+bool rsip_set_dscp(uint8_t value);
+
+// Enable (or disable) kernel receive timestamps (SO_TIMESTAMPNS) on the running UDP
+// listeners and on listeners started later. The JSON payloads of messages they receive
+// then carry "rx_ts_ns", the arrival time in nanoseconds since the Unix epoch, taken
+// by the kernel rather than when the listener thread got to the datagram. Returns
+// false on platforms without the option (only Linux has it), where messages carry no
+// timestamp, or if a running listener refused the option.
+bool rsip_set_rx_timestamps(bool enabled);
+
+// Defaults for messages the stack builds (rsip_build_request, rsip_build_response,
+// registrations, OPTIONS pings, 513 rejections). rsip_set_default_max_forwards sets
+// the Max-Forwards of requests (70 by default); false for 0. rsip_set_user_agent adds
+// a User-Agent header to requests and a Server header to responses with the same
+// value, for telling this stack's traffic apart in captures; NULL removes it (the
+// default), false for an empty value or one containing CR or LF. Take effect for the
+// next message built.
+bool rsip_set_default_max_forwards(uint8_t max_forwards);
+bool rsip_set_user_agent(const char* user_agent);
+
+// Client side of RFC 3581: add an empty "rport" to the Via of requests the stack builds
+// (rsip_build_request, registrations, OPTIONS pings), asking servers to send responses
+// to the source IP and port they saw rather than the Via sent-by. Together with sending
+// from the listener socket (rsip_send_from_listener), clients behind NAT then receive
+// their responses. Off by default; applies to the next request built.
+void rsip_set_use_rport(bool enabled);
+
+// Write header names in their RFC 3261 compact form in every message the stack builds
+// or serializes (rsip_build_*, rsip_message_to_bytes, registrations, OPTIONS pings,
+// ACKs, 100 and 513 responses): Via as v, From f, To t, Call-ID i, Contact m,
+// Content-Type c, Content-Length l, Subject s, Supported k, Content-Encoding e. Other
+// headers (CSeq, Max-Forwards, ...) have no compact form and keep their names. Saves a
+// few dozen bytes per message, which can keep a large one under the path MTU. Compact
+// names are understood by every RFC 3261 implementation but harder to read in
+// captures. Off by default; applies to the next message built.
+void rsip_set_compact_headers(bool enabled);
+
+// Advertise host:port as the Via sent-by of generated requests (registrations, OPTIONS
+// pings, rsip_build_request) instead of the local socket address, so responses reach
+// a host behind NAT or a load balancer. host is an IPv4 or IPv6 address (brackets
+// optional) or a domain name; port 0 keeps the listener port (or via_port). NULL host
+// restores the default. Responses are unaffected: they copy the request's Vias.
+// Returns false for an invalid host. Takes effect for registrations and pings started
+// afterwards.
+bool rsip_set_via_sentby(const char* host, uint16_t port);
+
+// Start a SIP-over-TCP listener on port, bound like the UDP listener. Each connection
+// is buffered until a whole message (headers plus Content-Length bytes of body) has
+// arrived, so messages split across segments or sharing one are reported exactly as
+// over UDP. A connection whose framing is broken (oversized headers, invalid
+// Content-Length) is closed after an "error" event starting with "framing_err:".
+bool rsip_start_tcp_listener(uint16_t port);
+int32_t rsip_start_tcp_listener_ex(uint16_t port);
+
+// A CRLFCRLF keep-alive ping (RFC 5626) on a TCP or TLS connection is not a message:
+// it is reported as "keepalive_ping" JSON {src, pong} and, unless disabled here,
+// answered with a single CRLF pong (pong: true). Lone CRLFs between messages, such as
+// pongs, are skipped.
+void rsip_set_keepalive_pong(bool enabled);
+
+// Tolerate sloppy peers on TCP and TLS connections accepted from now on: the header
+// section may also end with a blank line terminated by a bare LF ("\n\n"). Such
+// messages parse like any other, as bare-LF line endings are always normalized.
+// Off by default.
+void rsip_set_lenient_line_endings(bool enabled);
+
+// Start a SIP-over-TLS (SIPS) listener on port, bound like the UDP listener, serving
+// the PEM certificate chain and private key at the given paths. Messages are reported
+// with the same events as UDP. A failed handshake is reported as "tls_error" JSON
+// {src, reason}; an unreadable certificate or key as an "error" event starting with
+// "tls_cert:". Returns an RsipError code.
+int32_t rsip_start_tls_listener(uint16_t port, const char* cert_path, const char* key_path);
 
-// Send a raw UDP datagram to dest_ip:dest_port with data being a C string.
+// Same as rsip_start_tls_listener with the certificate chain and private key passed as
+// PEM text, so they never have to be written to disk. A key that does not belong to
+// the leaf certificate fails with the "error" event
+// "tls_cert:private key does not match the leaf certificate".
+int32_t rsip_start_tls_listener_mem(uint16_t port, const char* cert_pem, const char* key_pem);
+
+// Lowest TLS version the next TLS listener accepts (default RSIP_TLS_VERSION_1_2).
+#define RSIP_TLS_VERSION_1_2 0x0303
+#define RSIP_TLS_VERSION_1_3 0x0304
+bool rsip_set_tls_min_version(uint16_t version);
+
+// Serve a different certificate to clients that ask for server_name via SNI. Clients
+// asking for other names, or none, get the listener's certificate. Must be called
+// before rsip_start_tls_listener. Returns an RsipError code.
+int32_t rsip_add_tls_sni_cert(const char* server_name, const char* cert_path,
+                              const char* key_path);
+
+// Start a SIP-over-WebSocket (RFC 7118, ws://) listener on port, bound like the UDP
+// listener, accepting upgrades to path (e.g. "/sip") that offer the "sip" subprotocol.
+// Each text or binary message is one SIP message, reported with the same events as
+// UDP. A rejected upgrade or a protocol violation is reported as "ws_error" JSON
+// {src, reason}. For wss://, terminate TLS in front of this listener.
+bool rsip_start_ws_listener(uint16_t port, const char* path);
+int32_t rsip_start_ws_listener_ex(uint16_t port, const char* path);
+
+// Send data as one text frame to the WebSocket client connected from dest_ip:dest_port
+// (the "src" reported with its messages). Fails if no such client is connected.
+bool rsip_send_ws(const char* dest_ip, uint16_t dest_port, const char* data);
+int32_t rsip_send_ws_ex(const char* dest_ip, uint16_t dest_port, const char* data);
+
+// Send data to dest_ip:dest_port over TCP. The first send to an address connects (up
+// to 5 s, failing with RSIP_ERR_SEND_FAILED and an "error" event "connect_err:..." if
+// that fails); later sends reuse the open connection, so responses and in-dialog
+// requests travel on it. Whatever the peer sends back is reported like any received
+// message, and its keep-alive pings are answered. A connection the peer closed is
+// dropped, and a write that fails on a reused one is retried once on a new connection.
+bool rsip_send_tcp(const char* dest_ip, uint16_t dest_port, const char* data);
+int32_t rsip_send_tcp_ex(const char* dest_ip, uint16_t dest_port, const char* data);
+
+// Close pooled TCP connections (see rsip_send_tcp) after ms milliseconds without
+// traffic in either direction, open ones included; 0 keeps them open until the peer
+// closes them or rsip_shutdown. Default 60000.
+void rsip_set_tcp_idle_timeout_ms(uint32_t ms);
+
+// Set the receive buffer size (and SO_RCVBUF, best effort) for the next listener.
+// Must be called before the listener starts; valid range is 576..=1048576 bytes
+// (default 65535). Returns false if out of range or a listener is running.
+bool rsip_set_recv_buffer_size(size_t bytes);
+
+// Make the next UDP listener emit a "tick" event (payload JSON {transport, addr}, as for
+// "listener_started") every ms milliseconds from its own thread, whether or not
+// messages arrive, so the host can run timers and expiry without a thread of its own.
+// Ticks can be late by the time a callback takes. 0 disables them (the default). Must
+// be called before the listener starts; returns false if a listener is running.
+bool rsip_set_recv_timeout_ms(uint32_t ms);
+
+// Copy the listener's bound "ip:port" (NUL-terminated) into buf. Returns false if
+// no listener is running or buf_len is too small.
+bool rsip_listener_local_addr(char* buf, size_t buf_len);
+
+// Whether a UDP listener is running, and the port the first one is bound to (the actual
+// port when started on 0), or 0 when none is. Safe to call from any thread, including
+// during rsip_shutdown: they report either the running listener or none.
+bool rsip_is_running(void);
+uint16_t rsip_listener_port(void);
+
+// Send a raw UDP datagram to dest_ip:dest_port with data being a C string. dest_ip may
+// be an IPv4 or IPv6 literal (bare "::1" or bracketed "[::1]") or a host name.
 bool rsip_send_udp(const char* dest_ip, uint16_t dest_port, const char* data);
+int32_t rsip_send_udp_ex(const char* dest_ip, uint16_t dest_port, const char* data);
+
+// Same as rsip_send_udp, but sends exactly len bytes of data, which may contain NULs
+// (binary bodies, MIME payloads). data may be NULL only when len is 0.
+bool rsip_send_udp_bytes(const char* dest_ip, uint16_t dest_port, const uint8_t* data,
+                         size_t len);
+int32_t rsip_send_udp_bytes_ex(const char* dest_ip, uint16_t dest_port, const uint8_t* data,
+                               size_t len);
+
+// Queue data for dest_ip:dest_port and return at once with a message id (0 if an
+// argument is NULL). A background sender thread, started on first use, sends messages
+// in order from an ephemeral socket like rsip_send_udp and reports each one:
+//   "sent_ok"     JSON {id, dest, len}
+//   "send_failed" JSON {id, dest, error, os_error}; os_error is the OS error code
+//                 (errno / WSA code), or null if the failure was not an OS error (e.g.
+//                 the name did not resolve)
+// rsip_shutdown sends what is still queued, and reports it, before returning.
+uint64_t rsip_send_async(const char* dest_ip, uint16_t dest_port, const char* data);
+
+// Same as rsip_send_udp, but from local port src_port, bound for this datagram only,
+// so symmetric signaling works without a listener (e.g. a port the caller keeps a NAT
+// pinhole open on). Fails with RSIP_ERR_ADDR_IN_USE if the port is taken, including
+// by one of our own listeners (use rsip_send_udp_from_listener for those), or
+// RSIP_ERR_PERMISSION_DENIED for a privileged port.
+bool rsip_send_udp_from_port(uint16_t src_port, const char* dest_ip, uint16_t dest_port,
+                             const char* data);
+int32_t rsip_send_udp_from_port_ex(uint16_t src_port, const char* dest_ip, uint16_t dest_port,
+                                   const char* data);
+
+// Send from the running listener's socket so the source port equals the listen port
+// (symmetric signaling, RFC 3581). Fails with RSIP_ERR_NOT_RUNNING if no listener.
+// When the ICMP error for such a datagram comes back (port, host or network
+// unreachable: nothing listens there), the listener emits "dest_unreachable" JSON
+// {dest, error}, so the peer can be marked down. On Linux dest is the address the
+// failed datagram went to; where the OS does not say, it is the listener's most recent
+// destination. One-shot sends (rsip_send_udp, rsip_send_async) never see these errors.
+bool rsip_send_udp_from_listener(const char* dest_ip, uint16_t dest_port, const char* data);
+int32_t rsip_send_udp_from_listener_ex(const char* dest_ip, uint16_t dest_port, const char* data);
+
+// Convenience for simple clients, not a transaction layer: send `request` from a
+// one-shot socket and block until the final response with the same Via branch and
+// CSeq arrives (1xx responses are skipped). No retransmissions are made. Returns the
+// raw response (caller-owned; free with rsip_free_string), or NULL on timeout, send
+// failure, or if `request` is not a request with a Via branch and CSeq.
+char* rsip_send_and_wait(const char* dest_ip, uint16_t dest_port, const char* request,
+                         uint32_t timeout_ms);
+
+// Traffic counters as caller-owned JSON {packets_received, bytes_received,
+// parse_failures, truncated, send_ok, send_failed, queue_dropped, rate_limited,
+// filtered}.
+// packets_received counts messages entering the receive pipeline on any transport
+// (datagrams, or framed stream messages); queue_dropped counts messages discarded by a
+// full worker queue, rate_limited those dropped by rsip_set_rate_limit, and filtered
+// those rejected by the source IP filter. Counters are updated independently, so a
+// snapshot taken under load is not an exact cut. rsip_reset_stats zeroes them all.
+char* rsip_get_stats(void);
+void rsip_reset_stats(void);
+
+// Worker pool: with n > 0 threads (at most 64; default 0), listeners only read and
+// enqueue, and the workers run the receive pipeline and every callback, so a slow
+// callback no longer stalls reception. Messages from one source stay in order. Each
+// worker queues up to 1024 messages; when a queue is full, RSIP_BACKPRESSURE_DROP
+// (default) discards the message and counts it in queue_dropped, while
+// RSIP_BACKPRESSURE_BLOCK makes the listener wait. Both must be set before the first
+// listener starts; they return false while one runs or for invalid values.
+#define RSIP_BACKPRESSURE_DROP 0
+#define RSIP_BACKPRESSURE_BLOCK 1
+bool rsip_set_worker_threads(uint32_t threads);
+bool rsip_set_backpressure_mode(int32_t mode);
+
+// Where callbacks run, as a shorthand for the worker count: RSIP_DISPATCH_INLINE (the
+// default) on the thread that read the message, for the lowest latency and a
+// single-threaded host; RSIP_DISPATCH_QUEUED on worker threads behind a queue, so
+// callbacks never block reception (one worker, or the count set with
+// rsip_set_worker_threads; callbacks then run on several threads). Set before the first
+// listener starts; returns false while one runs or for an unknown mode.
+#define RSIP_DISPATCH_INLINE 0
+#define RSIP_DISPATCH_QUEUED 1
+bool rsip_set_dispatch_mode(int32_t mode);
+
+// Flood protection, checked on the listener thread before a message is queued or
+// parsed: messages over max_msg_bytes are dropped, and each source IP may send at most
+// max_pps messages per second (bursts up to max_pps). 0 disables either limit. Drops
+// emit "rate_limited" {src, reason: "size"|"rate", len}; for "rate" only the first
+// drop of each throttled run is reported, but every drop is counted in rate_limited.
+// Per-source state is dropped after a second of inactivity. Can be changed at any time.
+void rsip_set_rate_limit(uint32_t max_pps, size_t max_msg_bytes);
+
+// Parse cache: keep the parsed form of the last `entries` distinct received messages
+// (least recently used first out), so byte-identical retransmissions are not parsed
+// again; 0 (the default) disables it. Changing the size empties the cache. While it is
+// enabled, a message whose exact bytes were already received within the last 32
+// seconds (64*T1) is also reported, right before its "sip_rx_parsed", as
+//   "sip_rx_retransmission" JSON {call_id, cseq:{seq,method}, src, since_ms, listener}
+// where since_ms is the time since those bytes were last received. Events and handlers
+// still run for it as for any other message.
+void rsip_set_parse_cache_size(size_t entries);
+
+// Drop received messages larger than max_bytes (0, the default, accepts any size)
+// before the raw callback, "sip_rx" or any handler sees them; each is reported as
+//   "too_large" JSON {src, len, max, method, call_id, responded, listener}
+// where method is null unless the message parses as a request, and call_id null if it
+// does not parse. With respond set, requests are answered with 513 Message Too Large
+// (RFC 3261) from the UDP listener they arrived on, or the first listener; responded
+// tells whether that answer went out (never for an ACK or a response). Can be changed
+// at any time.
+void rsip_set_max_request_bytes(size_t max_bytes, bool respond);
+
+// Drop received messages with more than max_headers header lines (0, the default,
+// accepts any number) before they are parsed, so a crafted message with thousands of
+// headers costs a scan of its bytes rather than thousands of allocations. Folded
+// continuation lines do not count. Each one dropped is reported as
+//   "too_many_headers" JSON {src, len, max, listener}
+// and nothing else: no "sip_rx", raw callback, handler or response. Applies to every
+// transport; can be changed at any time.
+void rsip_set_max_headers(size_t max_headers);
+
+// Answer every received INVITE with 100 Trying as soon as it is parsed, before events
+// and handlers run, so the client stops retransmitting it (RFC 3261 section 17.2.1)
+// however long the host takes. The response is what rsip_build_response(invite, 100,
+// "Trying", NULL) returns, with the Via's received/rport filled in for the source,
+// and goes to rsip_response_destination of that from the listener the INVITE arrived
+// on. Retransmitted INVITEs are answered again. Each one sent is reported as
+//   "trying_sent" JSON {call_id, src, dest, listener}
+// Off by default; can be changed at any time.
+void rsip_auto_trying(bool enabled);
+
+// Source IP filter, checked for every received message on any transport before rate
+// limiting and parsing. In allowlist mode only sources inside a listed range are
+// accepted (an empty list accepts nothing); in denylist mode listed sources are
+// rejected. Rejected messages emit "filtered" {src, len}. Ranges are CIDRs such as
+// "192.0.2.0/24" or "2001:db8::/32", or bare addresses; IPv4-mapped IPv6 sources match
+// IPv4 ranges. rsip_ip_filter_add returns false for an unparsable range;
+// rsip_ip_filter_clear empties the list but keeps the mode. Changes apply immediately.
+#define RSIP_IP_FILTER_OFF 0
+#define RSIP_IP_FILTER_ALLOW 1
+#define RSIP_IP_FILTER_DENY 2
+bool rsip_set_ip_filter_mode(int32_t mode);
+bool rsip_ip_filter_add(const char* cidr);
+void rsip_ip_filter_clear(void);
+
+// Hardening for internet-facing UDP listeners: drop datagrams from sources no genuine
+// packet can come from, before the IP filter and rate limiting. Always martian:
+// 0.0.0.0/8, the documentation ranges (192.0.2.0/24, 198.51.100.0/24, 203.0.113.0/24,
+// 2001:db8::/32), 198.18.0.0/15, multicast, 240.0.0.0/4 (broadcast included) and ::.
+// On a listener bound to a specific public address, loopback, private (RFC 1918,
+// fc00::/7), shared 100.64.0.0/10 and link-local sources are dropped as spoofed too; a
+// listener bound to 0.0.0.0 or :: accepts them since it cannot tell the interface.
+// Each drop counts as "filtered" in the stats and is reported as "martian_dropped"
+// JSON {src, len, listener}. Off by default; changes apply immediately.
+void rsip_set_drop_martians(bool enabled);
+
+// Operational logging, separate from SIP events: the stack reports what it is doing
+// (listeners starting, bind/recv/send failures, dropped connections, and per-message
+// detail at DEBUG) to this callback. Only lines at the configured level or more severe
+// are formatted and delivered; the default is RSIP_LOG_INFO. NULL disables logging.
+// The message is only valid during the call.
+#define RSIP_LOG_ERROR 1
+#define RSIP_LOG_WARN 2
+#define RSIP_LOG_INFO 3
+#define RSIP_LOG_DEBUG 4
+typedef void (*rsip_log_callback)(int32_t level, const char* msg);
+void rsip_set_log_callback(rsip_log_callback cb);
+
+// One-shot timers for SIP state machines (Timers A to K), run by a single timer thread
+// started on first use. rsip_set_timer arms a timer firing delay_ms from now and
+// returns its id (never 0); when it expires the timer callback is called on the timer
+// thread with that id and user_data, untouched. Keep it short: later timers wait for it.
+// It may arm or cancel timers itself. rsip_cancel_timer disarms a timer and returns
+// false if it is unknown or has already fired. Setting the callback replaces any
+// previous one; NULL lets timers expire silently. rsip_shutdown disarms every timer.
+typedef void (*rsip_timer_callback)(uint64_t id, void* user_data);
+void rsip_set_timer_callback(rsip_timer_callback cb);
+uint64_t rsip_set_timer(uint64_t delay_ms, void* user_data);
+bool rsip_cancel_timer(uint64_t id);
+bool rsip_set_log_level(int32_t level);
 
-// Shutdown listener and clean up.
+// Zero-copy receive: the callback gets every received message as spans into the
+// receive buffer instead of strings and JSON. It runs on the thread handling the
+// message (a worker, if configured) before any event. Offsets are relative to data;
+// header values are trimmed, and a folded value spans its line breaks. If the
+// message is not well formed (no empty line ending the headers, or a header line
+// without a colon), headers holds the lines scanned before the problem and body is
+// empty.
+//
+// LIFETIME: msg, data, headers and src_ip are only valid during the call; they are
+// reused for the next message. Copy whatever must outlive the callback.
+//
+// While a raw callback is set and no event callback is registered, messages are not
+// parsed at all (so no parse_failures are counted), unless a message handler is set
+// or the message may concern a dialog (a 2xx response, or a request while dialogs
+// exist). NULL removes the callback.
+typedef struct {
+    uint32_t offset;
+    uint32_t len;
+} RsipSpan;
+typedef struct {
+    RsipSpan name;
+    RsipSpan value;
+} RsipHeaderSpan;
+typedef struct {
+    const uint8_t* data;
+    size_t len;
+    RsipSpan start_line;
+    const RsipHeaderSpan* headers;
+    size_t header_count;
+    RsipSpan body;
+    bool well_formed;
+    char src_ip[46];
+    uint16_t src_port;
+} RsipRawMessage;
+typedef void (*rsip_raw_callback)(const RsipRawMessage* msg);
+void rsip_set_raw_callback(rsip_raw_callback cb);
+
+// Route each parsed message to a handler instead of switching on event names. A request
+// goes to the handler registered for its method (matched case-sensitively, e.g.
+// "INVITE"), a response to the response handler, and anything without a handler of its
+// own to the catch-all registered with method "*". Handlers get the message text, its
+// "sip_rx_parsed" summary JSON and the peer address, only valid during the call, and
+// run after the message's events. Unparsable messages are not routed. Registering
+// replaces the previous handler; NULL removes it. rsip_shutdown removes all handlers.
+// rsip_on_method returns false for a NULL or empty method.
+typedef void (*rsip_message_handler)(const char* message, const char* summary,
+                                     const char* src_ip, uint16_t src_port);
+bool rsip_on_method(const char* method, rsip_message_handler cb);
+void rsip_on_response(rsip_message_handler cb);
+
+// Route message bodies by Content-Type. content_type is "type/subtype", "type/*" or
+// "*/*" (case-insensitive); a body goes to the most specific match, a body without
+// Content-Type counts as "text/plain". Multipart bodies are split and each part is
+// delivered on its own with the part's Content-Type (nested multiparts too); the
+// container itself is not delivered. Handlers get the media type (lowercase, without
+// parameters), the full Content-Type, the body bytes and the message's "sip_rx_parsed"
+// summary JSON, only valid during the call, and run after the message handler.
+// Registering replaces the previous handler; NULL removes it. Returns false for a NULL
+// or malformed content type.
+typedef void (*rsip_body_handler)(const char* media_type, const char* content_type,
+                                  const uint8_t* body, size_t len, const char* summary);
+bool rsip_on_body(const char* content_type, rsip_body_handler cb);
+
+// Shutdown listener and clean up. Returns promptly (well under 100ms) even when the
+// listener is idle. Stops every listener, registration, ping and client transaction,
+// forgets dialogs, and removes all callbacks (event mode back to callbacks). Safe to
+// call when nothing was started, after a failed start and repeatedly.
 void rsip_shutdown(void);
 
-// Return an informational static string (leaked pointer) for testing linkage.
+// Graceful shutdown for rolling restarts: stop reading new messages on every listener,
+// wait up to timeout_ms for callbacks that are already running to return, then shut
+// down as rsip_shutdown. Returns false if callbacks were still running at the deadline;
+// they are left to finish on their own threads and no further events are delivered.
+bool rsip_drain(uint32_t timeout_ms);
+
+// String ownership: functions returning `const char*` hand out static strings that
+// must not be freed. Functions returning `char*` hand out heap strings owned by the
+// caller, which must be released with rsip_free_string (exactly once).
+void rsip_free_string(char* s);
+// Byte buffers (uint8_t* with a length written to an out parameter) are released with
+// rsip_free_bytes, passing that length back. NULL is a no-op.
+void rsip_free_bytes(uint8_t* bytes, size_t len);
+
+// Build a syntactically valid request (CRLF line endings, Via with a fresh
+// z9hG4bK branch, Max-Forwards (see rsip_set_default_max_forwards), User-Agent if set
+// with rsip_set_user_agent, Content-Length: 0). `from`/`to` accept a bare URI
+// or a name-addr; a From tag is generated if missing. Returns a caller-owned string,
+// or NULL if an argument is NULL or does not parse. A sent-by set with
+// rsip_set_via_sentby replaces via_host and via_port.
+char* rsip_build_request(const char* method, const char* request_uri, const char* from,
+                         const char* to, const char* call_id, uint32_t cseq,
+                         const char* via_host, uint16_t via_port);
+
+// Build the response to a received request, for a minimal UAS: every Via (in order),
+// From, To, Call-ID and CSeq are copied, plus Record-Route for 101-299 responses, with
+// Content-Length: 0. If To has no tag, local_tag is added, or a generated tag when
+// local_tag is NULL (except for 100 Trying), and a Server header with the value of
+// rsip_set_user_agent if one is set. reason may be NULL for the standard phrase. Pair
+// with rsip_apply_rport first for requests from behind NAT. Returns a caller-owned
+// string, or NULL if raw_request is not a parsable request with those headers, is an
+// ACK, or status_code is outside 100-699.
+char* rsip_build_response(const char* raw_request, uint16_t status_code, const char* reason,
+                          const char* local_tag);
+
+// Build the CANCEL for a previously sent INVITE (RFC 3261 section 9.1): same
+// Request-URI, Call-ID, From, To (no tag added) and Route headers, only the top Via
+// (so the branch matches the INVITE transaction), and the INVITE's CSeq number with
+// method CANCEL. Send it to where the INVITE went. Returns a caller-owned string, or
+// NULL if original_invite is not a parsable INVITE with those headers.
+char* rsip_build_cancel(const char* original_invite);
+
+// Build the ACK for final_response, a final response to original_invite:
+// - non-2xx: hop-by-hop within the INVITE transaction (RFC 3261 section 17.1.1.3): the
+//   INVITE's Request-URI, top Via (same branch) and Route headers.
+// - 2xx: a new transaction within the dialog (section 13.2.2.4): Request-URI from the
+//   response's Contact, Route headers from its Record-Route in reverse order (a strict
+//   first route becomes the Request-URI instead), a new Via branch, and the INVITE's
+//   Authorization / Proxy-Authorization headers.
+// Both take From, Call-ID and the CSeq number from the INVITE and To (with its tag)
+// from the response. rsip_txn_send_invite already ACKs non-2xx finals itself. Returns a
+// caller-owned string, or NULL if either message does not parse, the response is
+// provisional or not for an INVITE, or a 2xx has no Contact.
+char* rsip_build_ack(const char* original_invite, const char* final_response);
+
+// Build a Contact header value (without "Contact: ") for REGISTER requests and
+// responses: <uri>;expires=N and, for RFC 5626 outbound, +sip.instance and reg-id, e.g.
+// <sip:alice@192.0.2.4>;expires=3600;+sip.instance="<urn:uuid:f81d...>";reg-id=1.
+// uri may be in angle brackets. instance_id is a URN ("urn:uuid:...", optionally
+// already as "<...>") or a bare UUID, which gets the urn:uuid: prefix; NULL or "" leaves
+// it out. reg_id 0 leaves it out; it requires an instance_id. Returns a caller-owned
+// string, or NULL if uri does not parse, instance_id is malformed, or reg_id is set
+// without an instance_id.
+char* rsip_build_contact(const char* uri, uint32_t expires, const char* instance_id,
+                         uint32_t reg_id);
+
+// Reason (RFC 3326) and Warning (RFC 3261 section 20.43) headers, for telling why a
+// call was torn down or a request failed. rsip_build_reason returns a Reason value
+// (without "Reason: ") such as SIP;cause=200;text="Call completed elsewhere" or
+// Q.850;cause=16; protocol is a token, text may be NULL and is quoted and escaped.
+// rsip_build_warning returns a Warning value such as 370 devnull "Choose a bigger pipe";
+// code has three digits and agent is a host[:port] or pseudonym. Both return NULL for
+// an invalid argument or a text containing control characters (CR, LF, ...).
+//
+// rsip_parse_reason and rsip_parse_warning read a header value (with or without its
+// name; several comma-separated values are allowed, commas inside quotes are text)
+// into a JSON array of {protocol, cause, text} (cause and text null if absent;
+// other parameters are skipped) or of {code, agent, text}. NULL if malformed.
+//
+// rsip_add_reason adds a Reason header with the value reason before the Content-Length
+// of raw_message, e.g. a BYE from rsip_build_request, a CANCEL from rsip_build_cancel
+// or a response from rsip_build_response, and returns the message; NULL if it does not
+// parse or reason is not a valid Reason value. All results are caller-owned; free with
+// rsip_free_string.
+char* rsip_build_reason(const char* protocol, uint32_t cause, const char* text);
+char* rsip_build_warning(uint16_t code, const char* agent, const char* text);
+char* rsip_parse_reason(const char* header);
+char* rsip_parse_warning(const char* header);
+char* rsip_add_reason(const char* raw_message, const char* reason);
+
+// P-Asserted-Identity (RFC 3325) and Privacy (RFC 3323), as used on carrier trunks.
+// Both are found by name in the raw header section (case-insensitive, folded lines
+// joined), so they are read even if rsip would not parse the rest of the message.
+// rsip_get_pai returns the identities of every P-Asserted-Identity header as a JSON
+// array of {display, uri} (display null if absent), in order; entries that are not a
+// sip, sips or tel URI are skipped. NULL if raw is NULL or asserts no identity.
+// rsip_build_pai returns a header value (without the name) such as
+// "Alice" <sip:alice@example.com> or <tel:+15550001111>; display may be NULL and is
+// quoted and escaped. NULL if uri is not a sip, sips or tel URI or display contains
+// control characters. Both results are caller-owned; free with rsip_free_string.
+// rsip_get_privacy returns the privacy values requested by the Privacy headers of raw
+// as RSIP_PRIVACY_* bits (unknown values are ignored), 0 if there are none.
+#define RSIP_PRIVACY_HEADER 1
+#define RSIP_PRIVACY_SESSION 2
+#define RSIP_PRIVACY_USER 4
+#define RSIP_PRIVACY_NONE 8
+#define RSIP_PRIVACY_CRITICAL 16
+#define RSIP_PRIVACY_ID 32
+char* rsip_get_pai(const char* raw);
+char* rsip_build_pai(const char* uri, const char* display);
+uint32_t rsip_get_privacy(const char* raw);
+
+// Generate an RFC 3261 branch: "z9hG4bK" followed by 32 random hex chars.
+// Caller-owned; free with rsip_free_string.
+char* rsip_generate_branch(void);
+
+// Generate a From/To tag, or a "<random>@host" Call-ID (no "@host" if host is NULL
+// or empty). Values are unique within the process. Caller-owned; free with
+// rsip_free_string.
+char* rsip_generate_tag(void);
+char* rsip_generate_call_id(const char* host);
+
+// Make generated identifiers (branches, tags, Call-IDs) deterministic for `seed`.
+// Intended for tests; rsip_unseed_random restores the OS-seeded CSPRNG.
+void rsip_seed_random(uint64_t seed);
+void rsip_unseed_random(void);
+
+// Parse a WWW-Authenticate / Proxy-Authenticate value (the header name is optional)
+// into caller-owned JSON {scheme, realm, nonce, opaque, algorithm, qop:[...], stale,
+// domain}. NULL if it is not a Digest challenge or realm/nonce is missing.
+char* rsip_parse_auth_challenge(const char* header);
+
+// Compute the Authorization (or Proxy-Authorization) header value for a Digest
+// challenge (RFC 2617 / RFC 7616), e.g. `Digest username="alice", realm=..., response=...`.
+// qop may be NULL, a single value or the offered list ("auth" is preferred; "auth-int"
+// assumes an empty body). nc is the nonce count. A NULL cnonce is generated when
+// needed. The first form uses MD5; the _alg form accepts "MD5", "MD5-sess", "SHA-256"
+// or "SHA-256-sess" (NULL = MD5) and an optional opaque to echo back. Returns NULL
+// for missing arguments or an unsupported algorithm; free with rsip_free_string.
+char* rsip_compute_digest_response(const char* username, const char* password,
+                                   const char* realm, const char* nonce, const char* method,
+                                   const char* uri, const char* qop, uint32_t nc,
+                                   const char* cnonce);
+char* rsip_compute_digest_response_alg(const char* username, const char* password,
+                                       const char* realm, const char* nonce,
+                                       const char* method, const char* uri, const char* qop,
+                                       uint32_t nc, const char* cnonce, const char* algorithm,
+                                       const char* opaque);
+
+// Register `aor` (e.g. "sip:alice@example.com") at registrar_ip:registrar_port from the
+// running listener's socket, on a background thread. 401/407 challenges are answered
+// with username (NULL = the AOR's user part) and password (NULL = no auth). The
+// binding is refreshed shortly before it expires. Events (payload JSON):
+//   "registered"       {id, aor, expires} after a successful (re-)registration
+//   "register_refresh" {id, aor, expires} after each successful refresh
+//   "register_failed"  {id, aor, status, reason}; status is null on timeout. The
+//                      registration is retried every 30s until unregistered.
+// Returns the registration id, or 0 if arguments are invalid or no listener runs.
+uint64_t rsip_register(const char* registrar_ip, uint16_t registrar_port, const char* aor,
+                       const char* username, const char* password, uint32_t expires_secs);
+
+// Send REGISTER with Expires: 0 (if registered) and stop refreshing. Blocks until the
+// registrar answered or ~2s passed. rsip_shutdown unregisters everything. Returns
+// false for an unknown id.
+bool rsip_unregister(uint64_t id);
+
+// The registrations started with rsip_register and not yet unregistered, as a
+// caller-owned JSON array ordered by id, each
+//   {id, aor, registrar, state, expires, expires_in, status, idle_ms}
+// registrar is the "ip:port" REGISTERs go to. state is "registering" (no answer yet),
+// "registered" or "failed" (retried every 30 s). expires is the expiry in seconds the
+// registrar last granted and expires_in what is left of it, both null unless
+// registered; status is the response code of a failed attempt, null for a timeout or
+// local error; idle_ms the time since the last attempt completed. Reading it does not
+// disturb the registrations. Free with rsip_free_string.
+char* rsip_list_registrations(void);
+
+// Send OPTIONS to dest_ip:dest_port from the running listener's socket every
+// interval_secs, on a background thread, to check that an upstream is alive. Any final
+// response counts, whatever its status. Events (payload JSON):
+//   "ping_ok"      {id, dest, status, rtt_ms} when a round is answered
+//   "ping_timeout" {id, dest, reason} when it is not answered within the interval
+//                  (at most 32s); reason is "timeout" or the send error
+// Returns the ping id, or 0 if arguments are invalid (interval_secs 0) or no listener
+// runs. rsip_shutdown stops every ping.
+uint64_t rsip_start_options_ping(const char* dest_ip, uint16_t dest_port,
+                                 uint32_t interval_secs);
+
+// Stop pinging. Returns false for an unknown id.
+bool rsip_stop_options_ping(uint64_t id);
+
+// Send an INVITE (with a Via branch) to dest_ip:dest_port from the running listener as
+// an RFC 3261 client transaction: it is retransmitted (timer A, starting at 500ms and
+// doubling) until a response arrives or timer B (32s) fires. Events, each carrying the
+// returned transaction id:
+//   "txn_provisional" JSON {id, status, reason, response} for every 1xx.
+//   "txn_final"       JSON {id, status, reason, response} once, for the final response.
+//   "txn_timeout"     JSON {id, reason}: "timer B", or "transport: ..." if sending failed.
+// Non-2xx finals are ACKed automatically; ACKing a 2xx is up to the caller. Returns 0
+// if the request is not an INVITE or no listener runs. rsip_shutdown abandons
+// transactions still in progress.
+uint64_t rsip_txn_send_invite(const char* dest_ip, uint16_t dest_port, const char* request);
+
+// The key matching a response to its client transaction (RFC 3261 section 17.1.3) for
+// a raw request or response: the top Via branch and the CSeq method, as
+// "<branch>:<METHOD>" (e.g. "z9hG4bK74bf9:INVITE"). An ACK gets its INVITE's key; a
+// CANCEL, being a transaction of its own, does not. Equal keys mean the same
+// transaction; the built-in transactions match responses this way. NULL if raw is
+// NULL, does not parse or lacks a Via branch or CSeq. Caller-owned; free with
+// rsip_free_string.
+char* rsip_transaction_key(const char* raw);
+
+// Dialogs (RFC 3261 section 12) are tracked automatically, keyed by Call-ID, local tag
+// and remote tag. A 2xx to an INVITE creates one: as UAC when it is received, as UAS
+// when it is sent with one of this library's send functions. A BYE sent or received
+// ends it. In-dialog requests update the local or remote CSeq. Early (1xx) dialogs are
+// not tracked, and dialogs never ended by a BYE stay until rsip_shutdown. Events:
+//   "dialog_created"    JSON {id, role, call_id, local_tag, remote_tag}; role is
+//                       "uac" or "uas"
+//   "dialog_terminated" the same plus by: "local" or "remote"
+// rsip_dialog_match returns the id of the dialog a received message belongs to (by its
+// Call-ID, To tag as local and From tag as remote for requests), or 0 if none, or if
+// raw is NULL or does not parse.
+uint64_t rsip_dialog_match(const char* raw);
+// rsip_list_dialogs returns the tracked dialogs as a caller-owned JSON array, oldest
+// first, each with the dialog_created fields plus
+//   {local_seq, remote_seq, remote_uri, remote_addr, age_ms, idle_ms}
+// the CSeqs are null until a request was sent or received in that direction;
+// remote_uri is the other party's From or To URI, remote_addr the "ip:port" the
+// dialog's last message came from (null if none was received yet); idle_ms is the
+// time since a message of the dialog was last sent or received. Free with
+// rsip_free_string.
+char* rsip_list_dialogs(void);
+
+// CSeq numbering for hand-written requests on one Call-ID. rsip_session_new returns a
+// session id (0 if call_id is NULL or empty). rsip_session_next_cseq returns the
+// number for the next request with method: one more than the last, starting at 1, but
+// for an ACK the number of the last INVITE and for a CANCEL that of the last request
+// (RFC 3261 sections 8.1.1.5 and 9.1). It returns 0 for an unknown session, a NULL or
+// malformed method, an ACK before any INVITE, a CANCEL before any request, or once
+// 2^31 - 1 has been used. rsip_session_free forgets a session (false if unknown);
+// rsip_shutdown forgets them all.
+uint64_t rsip_session_new(const char* call_id);
+uint32_t rsip_session_next_cseq(uint64_t id, const char* method);
+bool rsip_session_free(uint64_t id);
+
+// Split a message at the empty line ending its headers into caller-owned JSON
+// {headers, body}. headers is the start line and header lines with their CRLF or LF
+// endings, minus the line break before the empty line. When Content-Length (or "l") is
+// shorter than what follows, the body is cut to it, so empty lines inside a body are
+// handled; otherwise the body is the rest of the message. Without an empty line
+// everything is headers and body is "". Returns NULL if raw is NULL.
+char* rsip_split_message(const char* raw);
+
+// raw with RFC 3261 compact header names expanded ("v:" becomes "Via: ", "i:" becomes
+// "Call-ID: ", ...) and folded header values joined onto one line with single spaces,
+// as caller-owned text. Only the rewritten header lines change (and line endings, which
+// become CRLF once anything is rewritten); the start line, the other headers and the
+// body are kept byte for byte. Received messages, rsip_message_parse and
+// rsip_message_kind are normalized this way before parsing; the "sip_rx" event still
+// carries the bytes as received. Returns raw unchanged if it needs no rewriting or its
+// header section is malformed, NULL if raw is NULL.
+char* rsip_normalize_headers(const char* raw);
+
+// Parse an SDP body into caller-owned JSON:
+//   {version, origin:{username, session_id, session_version, net_type, addr_type, address},
+//    session_name, connection, attributes:[{name, value}],
+//    media:[{type, port, port_count, protocol, formats:[...], payload_types:[...],
+//            connection, rtpmap:[{payload_type, encoding, clock_rate, channels}],
+//            attributes:[{name, value}]}]}
+// connection is {net_type, addr_type, address}; a media section without a c= line
+// inherits the session one, and it is null if neither exists. Unknown lines are
+// ignored. Returns NULL if body is NULL or not SDP; free with rsip_free_string.
+char* rsip_parse_sdp(const char* body);
+
+// Parse a raw SIP message into a handle for header lookups. Returns NULL if raw is not
+// a SIP message. Release with rsip_message_free (NULL is a no-op).
+RsipMessage* rsip_message_parse(const char* raw);
+void rsip_message_free(RsipMessage* msg);
+
+// Why the last rsip_message_parse on the calling thread returned NULL, as caller-owned
+// JSON {category, offset, header, message}, or NULL if it succeeded (or none ran).
+// offset is the byte offset of the problem in raw, header the header line's name as
+// written, or null. category is one of "empty", "invalid_utf8",
+// "truncated_start_line", "bad_start_line", "bad_method", "bad_uri", "bad_version",
+// "bad_status_code", "truncated_headers" (no headers, or the last one lacks CRLF),
+// "bad_folding", "missing_colon", "bad_header_name", or "other" with rsip's own message
+// when nothing more specific was found.
+char* rsip_last_parse_error(void);
+
+// Compare two messages (e.g. what we sent and a captured reference a peer accepted)
+// header by header. Headers are matched by name, case-insensitively and with compact
+// forms expanded; a header that appears several times is compared as the list of its
+// values. Returns a caller-owned JSON array of differences, "[]" if there are none:
+//   {kind: "start_line", a, b}
+//   {kind: "only_in_a", header, values} / {kind: "only_in_b", header, values}
+//   {kind: "value_differs", header, a: [values], b: [values]}
+//   {kind: "reordered", a: [names], b: [names]}  (headers both have, in each order)
+//   {kind: "body", a_len, b_len}
+// NULL if either argument is NULL or does not parse.
+char* rsip_message_diff(const char* a, const char* b);
+
+// Classify a raw SIP message for switch-based dispatch without going through JSON.
+// Returns RSIP_KIND_PARSE_ERROR if raw is NULL or does not parse, one of the
+// RSIP_KIND_* method codes for a request, or RSIP_KIND_RESPONSE + status for a
+// response (1200 for 200 OK). The values are stable. 0 is reserved for unknown methods,
+// which the parser currently rejects as parse errors.
+#define RSIP_KIND_PARSE_ERROR (-1)
+#define RSIP_KIND_UNKNOWN 0
+#define RSIP_KIND_INVITE 1
+#define RSIP_KIND_ACK 2
+#define RSIP_KIND_BYE 3
+#define RSIP_KIND_CANCEL 4
+#define RSIP_KIND_OPTIONS 5
+#define RSIP_KIND_REGISTER 6
+#define RSIP_KIND_INFO 7
+#define RSIP_KIND_MESSAGE 8
+#define RSIP_KIND_NOTIFY 9
+#define RSIP_KIND_PRACK 10
+#define RSIP_KIND_PUBLISH 11
+#define RSIP_KIND_REFER 12
+#define RSIP_KIND_SUBSCRIBE 13
+#define RSIP_KIND_UPDATE 14
+#define RSIP_KIND_RESPONSE 1000
+int32_t rsip_message_kind(const char* raw);
+
+// Fast path for branching on responses: read only the status line of raw, writing the
+// status code to code_out and the reason phrase to reason_out (caller-owned, free with
+// rsip_free_string; "" if there is none). Either output may be NULL. Returns false, and
+// writes nothing, if raw is NULL, a request or has a malformed status line; the headers
+// are not checked.
+bool rsip_response_status(const char* raw, uint16_t* code_out, char** reason_out);
+
+// The CSeq of a raw message, for transaction matching: writes its sequence number to
+// num_out and its method to method_out (caller-owned; free with rsip_free_string).
+// Either may be NULL. Returns false, writing nothing, if raw is NULL, does not parse,
+// or its CSeq is missing or not "<number> <method>".
+bool rsip_get_cseq(const char* raw, uint32_t* num_out, char** method_out);
+
+// True if the CSeq method of a request matches its request line (a common cause of
+// rejects when it does not). For a response, true whenever rsip_get_cseq succeeds.
+// False for NULL, unparsable input or an invalid CSeq.
+bool rsip_cseq_method_matches(const char* raw);
+
+// The tag parameter of the first From (or To) header of a raw message, without parsing
+// the rest of it; compact "f"/"t" and folded headers are understood. NULL if raw is
+// NULL, the header is missing or malformed, or it has no tag (a To header of an initial
+// request). Caller-owned; free with rsip_free_string.
+char* rsip_get_from_tag(const char* raw);
+char* rsip_get_to_tag(const char* raw);
+
+// Registration expiry in seconds as it applies to raw (a REGISTER or its response):
+// the "expires" parameter of the first Contact, which takes precedence, else the
+// Expires header. Returns -1 if there is neither, or raw is NULL or does not parse.
+int64_t rsip_get_effective_expires(const char* raw);
+
+// The same per Contact, for messages listing several (in one header or many): a
+// caller-owned JSON array of {contact, expires} in message order, where expires is the
+// Contact's own parameter, else the Expires header, else null. "[]" without Contacts;
+// NULL if raw is NULL or does not parse.
+char* rsip_get_contact_expires(const char* raw);
+
+// Header lookups by name: case-insensitive, and compact forms ("v", "i", ...) match
+// their full names. rsip_message_header returns the value of the first occurrence or
+// NULL; rsip_message_headers returns a JSON array of every occurrence ("[]" if none).
+// A header line listing several values (e.g. "Via: a, b") is one occurrence. Both
+// results are caller-owned; free with rsip_free_string.
+char* rsip_message_header(const RsipMessage* msg, const char* name);
+char* rsip_message_headers(const RsipMessage* msg, const char* name);
+
+// Calls cb once per header of msg, in message order, with its name (the canonical
+// form for standard headers, so "v" comes out as "Via"; others as written) and trimmed
+// value, passing user_data through. Nothing is
+// allocated for the caller; name and value are only valid during the call. Returns
+// false if msg is NULL.
+typedef void (*rsip_header_callback)(const char* name, const char* value, void* user_data);
+bool rsip_message_for_each_header(const RsipMessage* msg, rsip_header_callback cb,
+                                  void* user_data);
+
+// Serialize msg back to wire bytes, ready to send: CRLF line endings, the body as
+// parsed, and a Content-Length matching that body (the first Content-Length or "l"
+// header is rewritten in place and any others removed; one is appended if there was
+// none). Writes the byte count to out_len. The buffer is not NUL-terminated; release
+// it with rsip_free_bytes(bytes, *out_len). NULL if msg or out_len is NULL.
+uint8_t* rsip_message_to_bytes(const RsipMessage* msg, size_t* out_len);
+
+// Parse a sip:, sips: or tel: URI (optionally in <angle brackets>) into caller-owned
+// JSON {scheme, user, host, port, transport, params, headers}. Absent parts are null;
+// params maps lowercased names to values (null for flags like "lr"); headers holds the
+// "?a=b&c=d" part. IPv6 hosts are bracketed. For tel: the number is in user and host
+// is null. Malformed input returns {"error": "..."} describing the problem (missing or
+// unsupported scheme, bad host or port); NULL only if uri is NULL.
+char* rsip_parse_uri(const char* uri);
+
+// Canonical form of a URI for comparison or storage: lowercase scheme, host and
+// parameter names (and transport/user/maddr values), port removed when it is the
+// default (5061 for sips: or transport=tls, else 5060), visual separators removed from
+// tel: numbers. The user part keeps its case. Caller-owned; NULL if it does not parse.
+char* rsip_uri_normalize(const char* uri);
+
+// Compare two URIs for equality by RFC 3261 section 19.1.4, as dialog and registration
+// matching need: 1 if equal, 0 if not, -1 if either is NULL or does not parse. The
+// scheme must match (sip never equals sips); user and password compare
+// case-sensitively, the host and everything else case-insensitively, after unescaping
+// %XX. A port or a user, ttl, method, maddr or transport parameter present in only one
+// URI makes them differ, even when it holds the default ("sip:a@b" is not
+// "sip:a@b:5060"); other parameters only count when both URIs have them. Headers ("?")
+// must all be in both, in any order. tel URIs match on the number without visual
+// separators and on all their parameters.
+int32_t rsip_uri_equals(const char* a, const char* b);
+
+// Locate the servers for a sip: or sips: URI per RFC 3263 and return a caller-owned
+// JSON array of {transport, ip, port} in the order to try them; transport is "udp",
+// "tcp" or "tls". maddr takes the place of the host. A numeric host is used as is, an
+// explicit port skips NAPTR and SRV, and transport=... skips NAPTR. Otherwise NAPTR
+// (SIP+D2U, SIP+D2T, SIPS+D2T), then SRV (_sip._udp, _sip._tcp, _sips._tcp), then
+// A/AAAA on 5060 (5061 for sips). SRV records of equal priority are ordered by weight,
+// highest first. Blocks on DNS (2 s per query and name server, from /etc/resolv.conf).
+// Returns "[]" if nothing resolves, NULL for NULL, unparsable or tel: URIs.
+char* rsip_resolve_target(const char* sip_uri);
+
+// NAT traversal for a UAS: rewrite the top Via of a request received from
+// src_ip:src_port (the "src" of sip_rx_parsed) before building responses from it.
+// An empty "rport" is filled in with src_port (RFC 3581), and "received=src_ip" is
+// set when the Via sent-by host differs from src_ip, or whenever rport was requested.
+// Everything else in the message is left byte-for-byte. Returns the caller-owned
+// message, or NULL if an argument is invalid or raw_request is not a request with a Via.
+char* rsip_apply_rport(const char* raw_request, const char* src_ip, uint16_t src_port);
+
+// Where a UAS sends responses to raw_request, from its top Via (RFC 3261 section
+// 18.2.2, RFC 3581): the "maddr" address if present, else "received", else the sent-by
+// host; on the "rport" port if it has a value, else the sent-by port, else 5060 (5061
+// for TLS/WSS). Run rsip_apply_rport on the request first so received and rport
+// reflect its actual source. Returns a caller-owned "ip:port" (IPv6 bracketed, and a
+// host name when the Via carries no address), or NULL if raw_request is NULL or not a
+// request with a Via header.
+char* rsip_response_destination(const char* raw_request);
+
+// Proxy helpers (RFC 3261 section 16).
+// rsip_decrement_max_forwards returns raw_request (caller-owned) with Max-Forwards
+// decremented, or set to 70 if it had none, ready to forward. It returns NULL when the
+// request must not be forwarded because Max-Forwards is already 0 (answer 483 Too Many
+// Hops), and also when raw_request is NULL, a response, unparsable or has a malformed
+// Max-Forwards; rsip_max_forwards tells these apart: it returns the current value, or -1
+// if there is none or the message is not a valid request.
+// rsip_has_via_branch is true if any Via of raw (including Vias combined on one line)
+// carries exactly branch. Checking for the branch a proxy would compute for a request
+// detects a loop (section 16.3 step 4). The message is re-serialized by the parser.
+char* rsip_decrement_max_forwards(const char* raw_request);
+int32_t rsip_max_forwards(const char* raw_request);
+bool rsip_has_via_branch(const char* raw, const char* branch);
+
+// Route set editing for loose routing (RFC 3261 sections 16.4 and 16.6). Each returns
+// the edited request (caller-owned), or NULL if an argument is NULL or does not parse,
+// raw_request is not a request, or uri is not a sip:/sips: URI (bare or in angle
+// brackets). Route and Record-Route values keep their order and place among the other
+// headers, one value per header line; a new set goes right below the Vias.
+// rsip_push_route pushes uri as the first Route; include ";lr" for a loose router.
+// rsip_pop_top_route removes the first Route value (the one naming this proxy); it
+// returns NULL if there is no Route. After either, if the first Route left has no "lr"
+// (a strict router), the request is rewritten for it: that URI becomes the Request-URI
+// and the old Request-URI the last Route (section 16.6 step 7).
+// rsip_record_route adds uri as the first Record-Route, adding ";lr" if missing.
+char* rsip_push_route(const char* raw_request, const char* uri);
+char* rsip_pop_top_route(const char* raw_request);
+char* rsip_record_route(const char* raw_request, const char* uri);
+
+// Check a raw SIP message against RFC 3261 and return a caller-owned JSON array of
+// violations, "[]" if there are none. Each is {code, header, detail} with code one of
+// "missing_header" (Via, From, To, Call-ID, CSeq, and Max-Forwards for requests),
+// "duplicate_header", "invalid_header", "cseq_method_mismatch" or
+// "content_length_mismatch". Returns NULL if raw is NULL or does not parse at all.
+char* rsip_validate_message(const char* raw);
+
+// Return an informational static string for testing linkage, e.g. "rsip-wrapper-0.1.0".
+// The pointer is valid for the life of the process. Do not free.
 const char* rsip_version(void);
 
+// Write the version's major, minor and patch numbers. NULL pointers are skipped.
+void rsip_version_parts(uint32_t* major, uint32_t* minor, uint32_t* patch);
+
+// Create a new context. Each context owns its own listener and callback, so several
+// SIP stacks (e.g. one per account) can run side by side in one process.
+RsipContext* rsip_context_new(void);
+
+// Shut down the context's listener and release the handle. NULL is a no-op.
+void rsip_context_free(RsipContext* ctx);
+
+// Context-scoped variants of the functions above.
+void rsip_context_set_event_callback(RsipContext* ctx,
+                                     void (*cb)(const char* event, const char* payload));
+void rsip_context_clear_event_callback(RsipContext* ctx);
+uint64_t rsip_context_add_event_listener(RsipContext* ctx, const char* events_csv,
+                                         void (*cb)(const char* event, const char* payload));
+uint64_t rsip_context_add_event_listener_ex(RsipContext* ctx, const char* events_csv,
+                                            rsip_event_callback_ex cb);
+bool rsip_context_remove_event_listener(RsipContext* ctx, uint64_t id);
+uint64_t rsip_context_last_event_seq(RsipContext* ctx);
+void rsip_context_set_event_callback_ex(RsipContext* ctx, rsip_event_callback_ex cb);
+void rsip_context_clear_event_callback_ex(RsipContext* ctx);
+uint64_t rsip_context_add_event_listener_bytes(RsipContext* ctx, const char* events_csv,
+                                               rsip_event_callback_bytes cb);
+uint64_t rsip_context_add_event_listener_struct(RsipContext* ctx, const char* events_csv,
+                                                rsip_event_callback_struct cb);
+void rsip_context_set_event_callback_struct(RsipContext* ctx, rsip_event_callback_struct cb);
+void rsip_context_clear_event_callback_struct(RsipContext* ctx);
+void rsip_context_set_event_callback_bytes(RsipContext* ctx, rsip_event_callback_bytes cb);
+void rsip_context_clear_event_callback_bytes(RsipContext* ctx);
+bool rsip_context_start_udp_listener(RsipContext* ctx, uint16_t port);
+int32_t rsip_context_start_udp_listener_ex(RsipContext* ctx, uint16_t port);
+bool rsip_context_start_udp_listener_on(RsipContext* ctx, const char* ip, uint16_t port);
+int32_t rsip_context_start_udp_listener_on_ex(RsipContext* ctx, const char* ip, uint16_t port);
+uint64_t rsip_context_add_udp_listener(RsipContext* ctx, uint16_t port);
+bool rsip_context_remove_listener(RsipContext* ctx, uint64_t id);
+bool rsip_context_restart_listener(RsipContext* ctx);
+bool rsip_context_listener_local_addr(RsipContext* ctx, char* buf, size_t buf_len);
+bool rsip_context_is_running(RsipContext* ctx);
+uint16_t rsip_context_listener_port(RsipContext* ctx);
+bool rsip_context_set_recv_buffer_size(RsipContext* ctx, size_t bytes);
+bool rsip_context_set_recv_timeout_ms(RsipContext* ctx, uint32_t ms);
+bool rsip_context_set_bind_address(RsipContext* ctx, const char* ip);
+bool rsip_context_set_dual_stack(RsipContext* ctx, bool enabled);
+bool rsip_context_set_default_max_forwards(RsipContext* ctx, uint8_t max_forwards);
+bool rsip_context_set_user_agent(RsipContext* ctx, const char* user_agent);
+void rsip_context_set_use_rport(RsipContext* ctx, bool enabled);
+void rsip_context_set_compact_headers(RsipContext* ctx, bool enabled);
+bool rsip_context_set_reuse_addr(RsipContext* ctx, bool enabled);
+bool rsip_context_set_reuse_port(RsipContext* ctx, bool enabled);
+bool rsip_context_set_dscp(RsipContext* ctx, uint8_t value);
+bool rsip_context_set_rx_timestamps(RsipContext* ctx, bool enabled);
+bool rsip_context_set_via_sentby(RsipContext* ctx, const char* host, uint16_t port);
+bool rsip_context_start_tcp_listener(RsipContext* ctx, uint16_t port);
+int32_t rsip_context_start_tcp_listener_ex(RsipContext* ctx, uint16_t port);
+void rsip_context_set_keepalive_pong(RsipContext* ctx, bool enabled);
+void rsip_context_set_lenient_line_endings(RsipContext* ctx, bool enabled);
+int32_t rsip_context_start_tls_listener(RsipContext* ctx, uint16_t port, const char* cert_path,
+                                        const char* key_path);
+int32_t rsip_context_start_tls_listener_mem(RsipContext* ctx, uint16_t port,
+                                            const char* cert_pem, const char* key_pem);
+bool rsip_context_set_tls_min_version(RsipContext* ctx, uint16_t version);
+int32_t rsip_context_add_tls_sni_cert(RsipContext* ctx, const char* server_name,
+                                      const char* cert_path, const char* key_path);
+bool rsip_context_start_ws_listener(RsipContext* ctx, uint16_t port, const char* path);
+int32_t rsip_context_start_ws_listener_ex(RsipContext* ctx, uint16_t port, const char* path);
+bool rsip_context_send_ws(RsipContext* ctx, const char* dest_ip, uint16_t dest_port,
+                          const char* data);
+int32_t rsip_context_send_ws_ex(RsipContext* ctx, const char* dest_ip, uint16_t dest_port,
+                                const char* data);
+bool rsip_context_send_tcp(RsipContext* ctx, const char* dest_ip, uint16_t dest_port,
+                           const char* data);
+int32_t rsip_context_send_tcp_ex(RsipContext* ctx, const char* dest_ip, uint16_t dest_port,
+                                 const char* data);
+void rsip_context_set_tcp_idle_timeout_ms(RsipContext* ctx, uint32_t ms);
+uint64_t rsip_context_send_async(RsipContext* ctx, const char* dest_ip, uint16_t dest_port,
+                                 const char* data);
+bool rsip_context_feed_bytes(RsipContext* ctx, const uint8_t* data, size_t len,
+                             const char* src_ip, uint16_t src_port);
+void rsip_context_register_transport(RsipContext* ctx, rsip_transport_send_callback send_cb);
+void rsip_context_set_capture_callback(RsipContext* ctx, rsip_capture_callback cb);
+bool rsip_context_set_event_mode(RsipContext* ctx, uint32_t mode, size_t queue_size);
+int32_t rsip_context_poll_event(RsipContext* ctx, int32_t timeout_ms, RsipEvent* out_event);
+void rsip_context_set_batch_mode(RsipContext* ctx, size_t max_batch, uint64_t max_delay_ms);
+void rsip_context_set_event_callback_batch(RsipContext* ctx, rsip_event_callback_batch cb);
+void rsip_context_clear_event_callback_batch(RsipContext* ctx);
+bool rsip_context_send_udp_from_listener(RsipContext* ctx, const char* dest_ip,
+                                         uint16_t dest_port, const char* data);
+int32_t rsip_context_send_udp_from_listener_ex(RsipContext* ctx, const char* dest_ip,
+                                               uint16_t dest_port, const char* data);
+uint64_t rsip_context_register(RsipContext* ctx, const char* registrar_ip,
+                               uint16_t registrar_port, const char* aor, const char* username,
+                               const char* password, uint32_t expires_secs);
+bool rsip_context_unregister(RsipContext* ctx, uint64_t id);
+char* rsip_context_list_registrations(RsipContext* ctx);
+uint64_t rsip_context_start_options_ping(RsipContext* ctx, const char* dest_ip,
+                                         uint16_t dest_port, uint32_t interval_secs);
+bool rsip_context_stop_options_ping(RsipContext* ctx, uint64_t id);
+uint64_t rsip_context_txn_send_invite(RsipContext* ctx, const char* dest_ip, uint16_t dest_port,
+                                      const char* request);
+void rsip_context_shutdown(RsipContext* ctx);
+bool rsip_context_drain(RsipContext* ctx, uint32_t timeout_ms);
+char* rsip_context_get_stats(RsipContext* ctx);
+void rsip_context_reset_stats(RsipContext* ctx);
+bool rsip_context_set_worker_threads(RsipContext* ctx, uint32_t threads);
+bool rsip_context_set_backpressure_mode(RsipContext* ctx, int32_t mode);
+bool rsip_context_set_dispatch_mode(RsipContext* ctx, int32_t mode);
+void rsip_context_set_rate_limit(RsipContext* ctx, uint32_t max_pps, size_t max_msg_bytes);
+void rsip_context_set_parse_cache_size(RsipContext* ctx, size_t entries);
+void rsip_context_set_max_request_bytes(RsipContext* ctx, size_t max_bytes, bool respond);
+void rsip_context_set_max_headers(RsipContext* ctx, size_t max_headers);
+void rsip_context_auto_trying(RsipContext* ctx, bool enabled);
+bool rsip_context_set_ip_filter_mode(RsipContext* ctx, int32_t mode);
+bool rsip_context_ip_filter_add(RsipContext* ctx, const char* cidr);
+void rsip_context_ip_filter_clear(RsipContext* ctx);
+void rsip_context_set_drop_martians(RsipContext* ctx, bool enabled);
+void rsip_context_set_log_callback(RsipContext* ctx, rsip_log_callback cb);
+void rsip_context_set_timer_callback(RsipContext* ctx, rsip_timer_callback cb);
+uint64_t rsip_context_set_timer(RsipContext* ctx, uint64_t delay_ms, void* user_data);
+bool rsip_context_cancel_timer(RsipContext* ctx, uint64_t id);
+bool rsip_context_set_log_level(RsipContext* ctx, int32_t level);
+void rsip_context_set_raw_callback(RsipContext* ctx, rsip_raw_callback cb);
+bool rsip_context_on_method(RsipContext* ctx, const char* method, rsip_message_handler cb);
+void rsip_context_on_response(RsipContext* ctx, rsip_message_handler cb);
+bool rsip_context_on_body(RsipContext* ctx, const char* content_type, rsip_body_handler cb);
+uint64_t rsip_context_dialog_match(RsipContext* ctx, const char* raw);
+char* rsip_context_list_dialogs(RsipContext* ctx);
+uint64_t rsip_context_session_new(RsipContext* ctx, const char* call_id);
+uint32_t rsip_context_session_next_cseq(RsipContext* ctx, uint64_t id, const char* method);
+bool rsip_context_session_free(RsipContext* ctx, uint64_t id);
+
 #ifdef __cplusplus
 }
 #endif
@@ -0,0 +1,370 @@
+//! Digest authentication (RFC 2617 / RFC 7616) for answering `401`/`407` challenges.
+//!
+//! rsip's typed `WwwAuthenticate` only understands a single qop and rsip's own algorithm
+//! spellings, while real servers send e.g. `qop="auth,auth-int"` and `SHA-256`, so the
+//! challenge is read straight from the auth tokenizer here.
+
+use crate::ffi::{into_c_string, str_arg};
+use crate::random;
+use md5::{Digest, Md5};
+use rsip::headers::typed::{tokenizers::AuthTokenizer, Tokenize};
+use serde_json::json;
+use sha2::Sha256;
+use std::os::raw::c_char;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl Algorithm {
+    /// Accepts the RFC 7616 names case-insensitively, plus rsip's `SHA256` spelling.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "md5" => Some(Self::Md5),
+            "md5-sess" => Some(Self::Md5Sess),
+            "sha-256" | "sha256" => Some(Self::Sha256),
+            "sha-256-sess" | "sha256-sess" => Some(Self::Sha256Sess),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "MD5",
+            Self::Md5Sess => "MD5-sess",
+            Self::Sha256 => "SHA-256",
+            Self::Sha256Sess => "SHA-256-sess",
+        }
+    }
+
+    fn is_sess(self) -> bool {
+        matches!(self, Self::Md5Sess | Self::Sha256Sess)
+    }
+
+    fn hash(self, value: &str) -> String {
+        match self {
+            Self::Md5 | Self::Md5Sess => format!("{:x}", Md5::digest(value.as_bytes())),
+            Self::Sha256 | Self::Sha256Sess => format!("{:x}", Sha256::digest(value.as_bytes())),
+        }
+    }
+}
+
+/// A `WWW-Authenticate` / `Proxy-Authenticate` challenge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    pub scheme: String,
+    pub realm: String,
+    pub nonce: String,
+    pub opaque: Option<String>,
+    pub algorithm: Algorithm,
+    /// Offered qop values, empty if the server sent none (RFC 2069 compatibility).
+    pub qop: Vec<String>,
+    pub stale: bool,
+    pub domain: Option<String>,
+}
+
+/// Parses a challenge, with or without the leading `WWW-Authenticate:` header name.
+pub fn parse_challenge(header: &str) -> Result<Challenge, rsip::Error> {
+    let value = match header.split_once(':') {
+        Some((name, value))
+            if name.eq_ignore_ascii_case("www-authenticate")
+                || name.eq_ignore_ascii_case("proxy-authenticate") =>
+        {
+            value
+        }
+        _ => header,
+    };
+    let tokenizer = AuthTokenizer::tokenize(value.trim())?;
+    let param = |name: &str| {
+        tokenizer
+            .params
+            .iter()
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.to_string())
+    };
+
+    let scheme = tokenizer.scheme.value.to_string();
+    if !scheme.eq_ignore_ascii_case("digest") {
+        return Err(rsip::Error::InvalidParam(format!(
+            "unsupported auth scheme {}",
+            scheme
+        )));
+    }
+    let algorithm = match param("algorithm") {
+        Some(name) => Algorithm::parse(&name)
+            .ok_or_else(|| rsip::Error::InvalidParam(format!("unsupported algorithm {}", name)))?,
+        None => Algorithm::Md5,
+    };
+
+    Ok(Challenge {
+        scheme,
+        realm: param("realm").ok_or_else(|| rsip::Error::InvalidParam("missing realm".into()))?,
+        nonce: param("nonce").ok_or_else(|| rsip::Error::InvalidParam("missing nonce".into()))?,
+        opaque: param("opaque"),
+        algorithm,
+        qop: param("qop")
+            .map(|qop| {
+                qop.split(',')
+                    .map(|q| q.trim().to_string())
+                    .filter(|q| !q.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        stale: param("stale").is_some_and(|s| s.eq_ignore_ascii_case("true")),
+        domain: param("domain"),
+    })
+}
+
+/// The inputs of one digest computation. `qop` may be the raw offered list; `auth` is
+/// preferred over `auth-int`, which is computed for an empty body.
+pub struct DigestParams<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+    pub realm: &'a str,
+    pub nonce: &'a str,
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub qop: Option<&'a str>,
+    pub nc: u32,
+    pub cnonce: Option<&'a str>,
+    pub algorithm: Algorithm,
+    pub opaque: Option<&'a str>,
+}
+
+/// Computes the full `Authorization` header value (without the header name).
+pub fn authorization(params: &DigestParams) -> String {
+    let alg = params.algorithm;
+    let qop = params.qop.and_then(|offered| {
+        let offered: Vec<&str> = offered.split(',').map(str::trim).collect();
+        ["auth", "auth-int"]
+            .iter()
+            .copied()
+            .find(|q| offered.iter().any(|o| o.eq_ignore_ascii_case(q)))
+    });
+    // qop and the -sess algorithms both need a client nonce.
+    let cnonce = match params.cnonce {
+        Some(cnonce) if !cnonce.is_empty() => cnonce.to_string(),
+        _ => random::random_hex(8),
+    };
+    let nc = format!("{:08x}", params.nc);
+
+    let mut ha1 = alg.hash(&format!(
+        "{}:{}:{}",
+        params.username, params.realm, params.password
+    ));
+    if alg.is_sess() {
+        ha1 = alg.hash(&format!("{}:{}:{}", ha1, params.nonce, cnonce));
+    }
+    let ha2 = match qop {
+        Some("auth-int") => alg.hash(&format!(
+            "{}:{}:{}",
+            params.method,
+            params.uri,
+            alg.hash("")
+        )),
+        _ => alg.hash(&format!("{}:{}", params.method, params.uri)),
+    };
+    let response = match qop {
+        Some(qop) => alg.hash(&format!(
+            "{}:{}:{}:{}:{}:{}",
+            ha1, params.nonce, nc, cnonce, qop, ha2
+        )),
+        None => alg.hash(&format!("{}:{}:{}", ha1, params.nonce, ha2)),
+    };
+
+    let mut header = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\", algorithm={}",
+        params.username,
+        params.realm,
+        params.nonce,
+        params.uri,
+        response,
+        alg.name()
+    );
+    if let Some(opaque) = params.opaque {
+        header.push_str(&format!(", opaque=\"{}\"", opaque));
+    }
+    if let Some(qop) = qop {
+        header.push_str(&format!(", qop={}, nc={}, cnonce=\"{}\"", qop, nc, cnonce));
+    }
+    header
+}
+
+/// Parses a `WWW-Authenticate`/`Proxy-Authenticate` value (the header name is optional)
+/// into caller-owned JSON `{scheme, realm, nonce, opaque, algorithm, qop:[..], stale,
+/// domain}`. Returns NULL if it is not a Digest challenge or a required field is missing.
+#[no_mangle]
+pub extern "C" fn rsip_parse_auth_challenge(header: *const c_char) -> *mut c_char {
+    let challenge = match str_arg(header).map(parse_challenge) {
+        Some(Ok(challenge)) => challenge,
+        _ => return std::ptr::null_mut(),
+    };
+    into_c_string(
+        json!({
+            "scheme": challenge.scheme,
+            "realm": challenge.realm,
+            "nonce": challenge.nonce,
+            "opaque": challenge.opaque,
+            "algorithm": challenge.algorithm.name(),
+            "qop": challenge.qop,
+            "stale": challenge.stale,
+            "domain": challenge.domain,
+        })
+        .to_string(),
+    )
+}
+
+/// MD5 digest `Authorization` value; see `rsip_compute_digest_response_alg`.
+#[no_mangle]
+pub extern "C" fn rsip_compute_digest_response(
+    username: *const c_char,
+    password: *const c_char,
+    realm: *const c_char,
+    nonce: *const c_char,
+    method: *const c_char,
+    uri: *const c_char,
+    qop: *const c_char,
+    nc: u32,
+    cnonce: *const c_char,
+) -> *mut c_char {
+    rsip_compute_digest_response_alg(
+        username,
+        password,
+        realm,
+        nonce,
+        method,
+        uri,
+        qop,
+        nc,
+        cnonce,
+        std::ptr::null(),
+        std::ptr::null(),
+    )
+}
+
+/// Computes the `Authorization` header value. `qop`, `cnonce`, `algorithm` and `opaque`
+/// are optional (NULL); a missing cnonce is generated when one is needed and a missing
+/// algorithm means MD5. Returns NULL for a missing required argument or an unknown
+/// algorithm.
+#[no_mangle]
+pub extern "C" fn rsip_compute_digest_response_alg(
+    username: *const c_char,
+    password: *const c_char,
+    realm: *const c_char,
+    nonce: *const c_char,
+    method: *const c_char,
+    uri: *const c_char,
+    qop: *const c_char,
+    nc: u32,
+    cnonce: *const c_char,
+    algorithm: *const c_char,
+    opaque: *const c_char,
+) -> *mut c_char {
+    let algorithm = match str_arg(algorithm) {
+        Some(name) => match Algorithm::parse(name) {
+            Some(algorithm) => algorithm,
+            None => return std::ptr::null_mut(),
+        },
+        None => Algorithm::Md5,
+    };
+    let params = (|| {
+        Some(DigestParams {
+            username: str_arg(username)?,
+            password: str_arg(password)?,
+            realm: str_arg(realm)?,
+            nonce: str_arg(nonce)?,
+            method: str_arg(method)?,
+            uri: str_arg(uri)?,
+            qop: str_arg(qop).filter(|q| !q.is_empty()),
+            nc,
+            cnonce: str_arg(cnonce),
+            algorithm,
+            opaque: str_arg(opaque),
+        })
+    })();
+    match params {
+        Some(params) => into_c_string(authorization(&params)),
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 7616 §3.9.1, which uses the same inputs for both algorithms.
+    fn rfc7616(algorithm: Algorithm) -> DigestParams<'static> {
+        DigestParams {
+            username: "Mufasa",
+            password: "Circle of Life",
+            realm: "http-auth@example.org",
+            nonce: "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v",
+            method: "GET",
+            uri: "/dir/index.html",
+            qop: Some("auth, auth-int"),
+            nc: 1,
+            cnonce: Some("f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ"),
+            algorithm,
+            opaque: Some("FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS"),
+        }
+    }
+
+    #[test]
+    fn rfc7616_vectors() {
+        let md5 = authorization(&rfc7616(Algorithm::Md5));
+        assert!(md5.contains("response=\"8ca523f5e9506fed4657c9700eebdbec\""));
+        assert!(md5.contains("algorithm=MD5"));
+        assert!(md5.contains("qop=auth, nc=00000001"));
+
+        let sha = authorization(&rfc7616(Algorithm::Sha256));
+        assert!(sha.contains(
+            "response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1\""
+        ));
+        assert!(sha.contains("algorithm=SHA-256"));
+        assert!(sha.contains("opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\""));
+    }
+
+    #[test]
+    fn rfc2617_vector() {
+        let header = authorization(&DigestParams {
+            username: "Mufasa",
+            password: "Circle Of Life",
+            realm: "testrealm@host.com",
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093",
+            method: "GET",
+            uri: "/dir/index.html",
+            qop: Some("auth"),
+            nc: 1,
+            cnonce: Some("0a4f113b"),
+            algorithm: Algorithm::Md5,
+            opaque: None,
+        });
+        assert!(header.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+    }
+
+    #[test]
+    fn challenge_parsing() {
+        let challenge = parse_challenge(
+            "WWW-Authenticate: Digest realm=\"asterisk\", nonce=\"1a2b3c\", \
+             algorithm=SHA-256, qop=\"auth,auth-int\", opaque=\"xyz\", stale=TRUE",
+        )
+        .unwrap();
+        assert_eq!(challenge.realm, "asterisk");
+        assert_eq!(challenge.nonce, "1a2b3c");
+        assert_eq!(challenge.algorithm, Algorithm::Sha256);
+        assert_eq!(challenge.qop, vec!["auth", "auth-int"]);
+        assert_eq!(challenge.opaque.as_deref(), Some("xyz"));
+        assert!(challenge.stale);
+
+        let plain = parse_challenge("Digest realm=\"sip.example.com\", nonce=\"n\"").unwrap();
+        assert_eq!(plain.algorithm, Algorithm::Md5);
+        assert!(plain.qop.is_empty());
+
+        assert!(parse_challenge("Digest nonce=\"n\"").is_err());
+        assert!(parse_challenge("Digest realm=\"r\", nonce=\"n\", algorithm=SHA-1").is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Batched event delivery, for hosts where each FFI crossing is expensive: events are
+//! copied into a buffer that a per-context thread hands to the batch callback as one
+//! array, once `max_batch` have accumulated or the oldest has waited `max_delay`.
+
+use crate::context::{with_context, RsipContext};
+use crate::events::RsipEvent;
+use crate::lock::{wait, wait_timeout, Lock};
+use crate::poll::Queued;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Full batches the buffer may hold while the callback is busy; events beyond that are
+/// dropped, which the host sees as a gap in `seq`.
+pub const MAX_PENDING_BATCHES: usize = 16;
+
+/// Receives `count` events, oldest first. The array and the events' data are only
+/// valid during the call.
+pub type EventCallbackBatch = extern "C" fn(events: *const RsipEvent, count: usize);
+
+#[derive(Default)]
+struct State {
+    events: VecDeque<Queued>,
+    /// 0 while batching is off.
+    max_batch: usize,
+    max_delay: Duration,
+    /// When the oldest buffered event is due, full batch or not.
+    deadline: Option<Instant>,
+    callback: Option<EventCallbackBatch>,
+    /// Bumped when batching is turned off; the thread then flushes and exits.
+    generation: u64,
+}
+
+impl State {
+    /// When the oldest buffered event has waited `max_delay`; `None` if there is none.
+    fn due(&self) -> Option<Instant> {
+        let oldest = self.events.front()?;
+        oldest.enqueued.checked_add(self.max_delay)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Batcher {
+    /// Whether events are buffered at all, read on every emit without the lock.
+    enabled: AtomicBool,
+    state: Mutex<State>,
+    wake: Condvar,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Batcher {
+    fn update_enabled(&self, state: &State) {
+        self.enabled.store(
+            state.max_batch > 0 && state.callback.is_some(),
+            Ordering::SeqCst,
+        );
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Buffers a copy of the event, waking the thread once a batch is full.
+    pub fn push(&self, event: &str, data: &[u8], src: Option<SocketAddr>, seq: u64) {
+        if !self.enabled() {
+            return;
+        }
+        let mut state = self.state.locked();
+        if state.max_batch == 0
+            || state.callback.is_none()
+            || state.events.len() >= state.max_batch.saturating_mul(MAX_PENDING_BATCHES)
+        {
+            return;
+        }
+        state.events.push_back(Queued::new(event, data, src, seq));
+        if state.events.len() == 1 {
+            state.deadline = state.due();
+        }
+        let full = state.events.len() >= state.max_batch;
+        drop(state);
+        if full {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Delivers batches until the generation moves on, then whatever is left in one.
+    fn run(ctx: &RsipContext, generation: u64) {
+        let batcher = &ctx.events.batch;
+        let mut state = batcher.state.locked();
+        loop {
+            let stopping = state.generation != generation;
+            let full = state.max_batch > 0 && state.events.len() >= state.max_batch;
+            let due = state.deadline.is_some_and(|d| d <= Instant::now());
+            if !state.events.is_empty() && (full || due || stopping) {
+                let count = if stopping {
+                    state.events.len()
+                } else {
+                    state.events.len().min(state.max_batch)
+                };
+                let batch: Vec<Queued> = state.events.drain(..count).collect();
+                state.deadline = state.due();
+                let callback = state.callback;
+                drop(state);
+                if let Some(cb) = callback {
+                    let events: Vec<RsipEvent> = batch.iter().map(Queued::to_event).collect();
+                    ctx.events.run_counted(|| cb(events.as_ptr(), events.len()));
+                }
+                state = batcher.state.locked();
+                continue;
+            }
+            if stopping {
+                return;
+            }
+            state = match state.deadline {
+                Some(deadline) => {
+                    let wait_for = deadline.saturating_duration_since(Instant::now());
+                    wait_timeout(&batcher.wake, state, wait_for)
+                }
+                None => wait(&batcher.wake, state),
+            };
+        }
+    }
+
+    /// Turns batching off, delivering what is buffered, and stops the thread, unless
+    /// called from it (from the batch callback), in which case it ends once the
+    /// callback returns.
+    pub fn stop(&self) {
+        {
+            let mut state = self.state.locked();
+            state.max_batch = 0;
+            state.generation += 1;
+            self.update_enabled(&state);
+        }
+        self.wake.notify_one();
+        let thread = self.thread.locked().take();
+        if let Some(thread) = thread {
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    pub fn set_callback(&self, cb: Option<EventCallbackBatch>) {
+        let mut state = self.state.locked();
+        state.callback = cb;
+        self.update_enabled(&state);
+    }
+}
+
+impl RsipContext {
+    /// Delivers events to the batch callback in arrays of up to `max_batch`, each
+    /// flushed early once its oldest event has waited `max_delay`. 0 turns batching
+    /// off, delivering what is buffered first.
+    pub fn set_batch_mode(self: &Arc<Self>, max_batch: usize, max_delay: Duration) {
+        if max_batch == 0 {
+            return self.events.batch.stop();
+        }
+        let batcher = &self.events.batch;
+        let mut thread = batcher.thread.locked();
+        let mut state = batcher.state.locked();
+        state.max_batch = max_batch;
+        state.max_delay = max_delay;
+        state.deadline = state.due();
+        batcher.update_enabled(&state);
+        if thread.is_none() {
+            let ctx = self.clone();
+            let generation = state.generation;
+            *thread = Some(thread::spawn(move || Batcher::run(&ctx, generation)));
+        }
+        drop(state);
+        batcher.wake.notify_one();
+    }
+
+    /// Sets the callback batches are delivered to, replacing any previous one; `None`
+    /// removes it, and events are no longer buffered.
+    pub fn set_batch_callback(&self, cb: Option<EventCallbackBatch>) {
+        self.events.batch.set_callback(cb);
+    }
+}
+
+/// Delivers events to the batch callback as arrays of up to `max_batch`, a partial
+/// batch once its oldest event has waited `max_delay_ms`. `max_batch` 0 turns batching
+/// off, after delivering what is buffered.
+#[no_mangle]
+pub extern "C" fn rsip_set_batch_mode(max_batch: usize, max_delay_ms: u64) {
+    crate::default_context().set_batch_mode(max_batch, Duration::from_millis(max_delay_ms));
+}
+
+/// Sets the callback receiving event batches, replacing any previous one.
+#[no_mangle]
+pub extern "C" fn rsip_set_event_callback_batch(cb: EventCallbackBatch) {
+    crate::default_context().set_batch_callback(Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_clear_event_callback_batch() {
+    crate::default_context().set_batch_callback(None);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_batch_mode(
+    ctx: *mut RsipContext,
+    max_batch: usize,
+    max_delay_ms: u64,
+) {
+    with_context(ctx, |ctx| {
+        ctx.set_batch_mode(max_batch, Duration::from_millis(max_delay_ms))
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_event_callback_batch(
+    ctx: *mut RsipContext,
+    cb: EventCallbackBatch,
+) {
+    with_context(ctx, |ctx| ctx.set_batch_callback(Some(cb)));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_clear_event_callback_batch(ctx: *mut RsipContext) {
+    with_context(ctx, |ctx| ctx.set_batch_callback(None));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::event_kind;
+
+    /// Kind, seq and data of a delivered event.
+    type Delivered = (u32, u64, Vec<u8>);
+
+    static BATCHES: Mutex<Vec<Vec<Delivered>>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(events: *const RsipEvent, count: usize) {
+        let events = unsafe { std::slice::from_raw_parts(events, count) };
+        let batch = events
+            .iter()
+            .map(|e| {
+                let data = unsafe { std::slice::from_raw_parts(e.data, e.len) };
+                (e.kind, e.seq, data.to_vec())
+            })
+            .collect();
+        BATCHES.lock().unwrap().push(batch);
+    }
+
+    fn batch_sizes() -> Vec<usize> {
+        BATCHES.lock().unwrap().iter().map(Vec::len).collect()
+    }
+
+    #[test]
+    fn delivers_full_batches_and_flushes_at_the_deadline() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.set_batch_callback(Some(record));
+        ctx.set_batch_mode(3, Duration::from_millis(50));
+        for i in 0..7 {
+            ctx.emit("tick", &i.to_string());
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while batch_sizes().len() < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        // two full batches right away, the last event once it has waited 50ms
+        assert_eq!(batch_sizes(), [3, 3, 1]);
+        let batches = BATCHES.lock().unwrap().clone();
+        let seqs: Vec<u64> = batches.iter().flatten().map(|e| e.1).collect();
+        assert_eq!(seqs, (1..=7).collect::<Vec<_>>());
+        assert_eq!(batches[2][0], (event_kind("tick"), 7, b"6".to_vec()));
+
+        // turning batching off delivers what is buffered
+        ctx.set_batch_mode(3, Duration::from_secs(60));
+        ctx.emit("error", "pending");
+        ctx.set_batch_mode(0, Duration::ZERO);
+        assert_eq!(batch_sizes(), [3, 3, 1, 1]);
+        ctx.emit("error", "not batched");
+        ctx.shutdown();
+        assert_eq!(batch_sizes(), [3, 3, 1, 1]);
+    }
+
+    #[test]
+    fn partial_batches_are_due_by_their_oldest_event() {
+        let mut state = State {
+            max_batch: usize::MAX,
+            max_delay: Duration::from_millis(50),
+            ..State::default()
+        };
+        assert_eq!(state.due(), None);
+        let mut old = Queued::new("tick", b"", None, 1);
+        old.enqueued -= Duration::from_secs(1);
+        state.events.push_back(old);
+        state.events.push_back(Queued::new("tick", b"", None, 2));
+        // left over from a drain, the older event is overdue already
+        assert!(state.due().unwrap() < Instant::now());
+        state.events.pop_front();
+        assert!(state.due().unwrap() > Instant::now());
+    }
+}
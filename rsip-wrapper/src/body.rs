@@ -0,0 +1,356 @@
+//! Body handlers keyed by Content-Type: after a message parses, its body goes to the
+//! handler registered for its media type. Multipart bodies (RFC 2046) are split and
+//! each part is delivered on its own, with the part's Content-Type.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::ffi::str_arg;
+use crate::lock::Lock;
+use crate::raw::split_message;
+use rsip::headers::{Header, UntypedHeader};
+use rsip::prelude::*;
+use rsip::SipMessage;
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+/// Receives the media type (lowercase, without parameters) and its full Content-Type
+/// value, the body bytes and the message's `sip_rx_parsed` summary JSON. Everything is
+/// only valid during the call.
+pub type BodyHandler = extern "C" fn(
+    media_type: *const c_char,
+    content_type: *const c_char,
+    body: *const u8,
+    len: usize,
+    summary: *const c_char,
+);
+
+/// Nested multiparts are split this deep at most; deeper ones are delivered whole.
+const MAX_MULTIPART_DEPTH: usize = 4;
+/// RFC 2046 section 5.1: a part without Content-Type is plain US-ASCII text.
+const DEFAULT_PART_TYPE: &str = "text/plain";
+
+/// `type/subtype` of a Content-Type value, lowercase.
+fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Whether a registered pattern (`type/subtype`, `type/*` or `*/*`) covers `media`.
+pub(crate) fn covers(pattern: &str, media: &str) -> bool {
+    if pattern == "*/*" || pattern == media {
+        return true;
+    }
+    match pattern.strip_suffix("/*") {
+        Some(kind) => media.split('/').next() == Some(kind),
+        None => false,
+    }
+}
+
+/// The `boundary` parameter of a Content-Type value, unquoted.
+fn boundary(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        Some(value).filter(|v| !v.is_empty())
+    })
+}
+
+/// The parts of a multipart body: everything between the `--boundary` lines, minus the
+/// line break before each. The preamble and epilogue are dropped.
+pub(crate) fn multipart_parts<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    // Delimiters only count at the start of a line.
+    let mut starts = Vec::new();
+    let mut pos = 0;
+    while pos + delimiter.len() <= body.len() {
+        if body[pos..].starts_with(&delimiter) && (pos == 0 || body[pos - 1] == b'\n') {
+            starts.push(pos);
+            pos += delimiter.len();
+        } else {
+            pos += 1;
+        }
+    }
+
+    let mut parts = Vec::new();
+    for pair in starts.windows(2) {
+        let after = pair[0] + delimiter.len();
+        if body[after..].starts_with(b"--") {
+            break;
+        }
+        let content = match body[after..pair[1]].iter().position(|b| *b == b'\n') {
+            Some(newline) => &body[after + newline + 1..pair[1]],
+            None => continue,
+        };
+        let content = content.strip_suffix(b"\n").unwrap_or(content);
+        parts.push(content.strip_suffix(b"\r").unwrap_or(content));
+    }
+    parts
+}
+
+/// The Content-Type of a body part from its header lines.
+fn part_content_type(headers: &[u8]) -> String {
+    String::from_utf8_lossy(headers)
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            let name = name.trim();
+            (name.eq_ignore_ascii_case("content-type") || name.eq_ignore_ascii_case("c"))
+                .then(|| value.trim().to_string())
+        })
+        .unwrap_or_else(|| DEFAULT_PART_TYPE.to_string())
+}
+
+/// Calls `deliver` with the content type and bytes of `body`, or of each of its parts
+/// if it is multipart.
+pub(crate) fn each_body(
+    content_type: &str,
+    body: &[u8],
+    depth: usize,
+    deliver: &mut dyn FnMut(&str, &[u8]),
+) {
+    let multipart = media_type(content_type).starts_with("multipart/");
+    match boundary(content_type) {
+        Some(boundary) if multipart && depth < MAX_MULTIPART_DEPTH => {
+            for part in multipart_parts(body, boundary) {
+                let (headers, part_body) = split_message(part);
+                each_body(&part_content_type(headers), part_body, depth + 1, deliver);
+            }
+        }
+        _ => deliver(content_type, body),
+    }
+}
+
+impl RsipContext {
+    /// Sets (or with `None` removes) the handler for bodies of `media_type`, which may
+    /// be `type/subtype`, `type/*` or `*/*`. The most specific match wins.
+    pub fn on_body(&self, media_type: &str, cb: Option<BodyHandler>) -> Result<(), RsipError> {
+        let pattern = media_type.trim().to_ascii_lowercase();
+        let valid = match pattern.split_once('/') {
+            Some((kind, sub)) => !kind.is_empty() && !sub.is_empty() && (kind != "*" || sub == "*"),
+            None => false,
+        };
+        if !valid || pattern.contains(char::is_whitespace) {
+            return Err(RsipError::InvalidArgument);
+        }
+        self.events.router.locked().set_body(&pattern, cb);
+        Ok(())
+    }
+
+    /// Hands the body of a parsed message (or each of its parts) to its handler.
+    pub(crate) fn route_body(&self, msg: &SipMessage, summary: &str) {
+        if msg.body().is_empty() || !self.events.router.locked().has_body_handlers() {
+            return;
+        }
+        let content_type = msg
+            .headers()
+            .iter()
+            .find_map(|h| match h {
+                Header::ContentType(ct) => Some(ct.value().trim().to_string()),
+                _ => None,
+            })
+            .unwrap_or_else(|| DEFAULT_PART_TYPE.to_string());
+        let summary = CString::new(summary).unwrap_or_default();
+        each_body(&content_type, msg.body(), 0, &mut |content_type, body| {
+            let media = media_type(content_type);
+            let cb = match self.events.router.locked().body_handler_for(&media) {
+                Some(cb) => cb,
+                None => return,
+            };
+            let media = CString::new(media).unwrap_or_default();
+            let content_type = CString::new(content_type).unwrap_or_default();
+            self.events.run_counted(|| {
+                cb(
+                    media.as_ptr(),
+                    content_type.as_ptr(),
+                    body.as_ptr(),
+                    body.len(),
+                    summary.as_ptr(),
+                )
+            });
+        });
+    }
+}
+
+/// Delivers received bodies of `content_type` ("application/sdp", "application/*" or
+/// "*/*") to `cb`, replacing any previous handler; NULL removes it. Returns false for a
+/// NULL or malformed content type.
+#[no_mangle]
+pub extern "C" fn rsip_on_body(content_type: *const c_char, cb: Option<BodyHandler>) -> bool {
+    match str_arg(content_type) {
+        Some(content_type) => crate::default_context().on_body(content_type, cb).is_ok(),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_on_body(
+    ctx: *mut RsipContext,
+    content_type: *const c_char,
+    cb: Option<BodyHandler>,
+) -> bool {
+    match str_arg(content_type) {
+        Some(content_type) => {
+            with_context(ctx, |ctx| ctx.on_body(content_type, cb).is_ok()).unwrap_or(false)
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    static BODIES: Mutex<Vec<(&str, String, Vec<u8>)>> = Mutex::new(Vec::new());
+
+    fn record(handler: &'static str, content_type: *const c_char, body: *const u8, len: usize) {
+        let content_type = unsafe { CStr::from_ptr(content_type) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = unsafe { std::slice::from_raw_parts(body, len) }.to_vec();
+        BODIES.lock().unwrap().push((handler, content_type, body));
+    }
+
+    extern "C" fn on_sdp(
+        media: *const c_char,
+        content_type: *const c_char,
+        body: *const u8,
+        len: usize,
+        summary: *const c_char,
+    ) {
+        assert_eq!(
+            unsafe { CStr::from_ptr(media) }.to_str().unwrap(),
+            "application/sdp"
+        );
+        let summary: serde_json::Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(summary) }.to_str().unwrap()).unwrap();
+        assert_eq!(summary["call_id"], "body@192.0.2.1");
+        record("sdp", content_type, body, len);
+    }
+
+    extern "C" fn on_application(
+        _: *const c_char,
+        content_type: *const c_char,
+        body: *const u8,
+        len: usize,
+        _: *const c_char,
+    ) {
+        record("application", content_type, body, len);
+    }
+
+    extern "C" fn on_any(
+        _: *const c_char,
+        content_type: *const c_char,
+        body: *const u8,
+        len: usize,
+        _: *const c_char,
+    ) {
+        record("any", content_type, body, len);
+    }
+
+    fn invite(content_type: &str, body: &str) -> String {
+        format!(
+            "INVITE sip:bob@example.com SIP/2.0\r\n\
+             Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKbody\r\n\
+             Max-Forwards: 70\r\n\
+             From: <sip:alice@example.com>;tag=1\r\n\
+             To: <sip:bob@example.com>\r\n\
+             Call-ID: body@192.0.2.1\r\n\
+             CSeq: 1 INVITE\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn splits_multipart_bodies() {
+        let body = "preamble\r\n\
+            --b1\r\n\
+            Content-Type: application/sdp\r\n\r\n\
+            v=0\r\n\
+            --b1\r\n\
+            \r\n\
+            no headers\r\n\
+            --b1--\r\n\
+            epilogue";
+        let parts = multipart_parts(body.as_bytes(), "b1");
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0], &b"Content-Type: application/sdp\r\n\r\nv=0"[..]);
+        assert_eq!(parts[1], &b"\r\nno headers"[..]);
+        assert_eq!(boundary("multipart/mixed; boundary=\"b1\""), Some("b1"));
+        assert_eq!(boundary("multipart/mixed"), None);
+        assert!(covers("application/*", "application/pidf+xml"));
+        assert!(!covers("application/*", "text/plain"));
+    }
+
+    #[test]
+    fn delivers_bodies_by_content_type() {
+        let ctx = RsipContext::new();
+        let src: SocketAddr = "192.0.2.1:5060".parse().unwrap();
+        assert!(ctx.on_body("sdp", Some(on_any)).is_err());
+        assert!(ctx.on_body("*/sdp", Some(on_any)).is_err());
+        ctx.on_body("Application/SDP", Some(on_sdp)).unwrap();
+        ctx.on_body("application/*", Some(on_application)).unwrap();
+
+        let sdp = "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\n";
+        ctx.handle_datagram(invite("application/sdp", sdp).as_bytes(), src);
+        ctx.handle_datagram(invite("application/json", "{\"a\":1}").as_bytes(), src);
+        // no handler for text/plain yet
+        ctx.handle_datagram(invite("text/plain", "hello").as_bytes(), src);
+        ctx.on_body("*/*", Some(on_any)).unwrap();
+        let multipart = "--xyz\r\n\
+            Content-Type: application/sdp\r\n\r\n\
+            v=0\r\n\
+            --xyz\r\n\
+            Content-Type: application/isup;version=itu-t92+\r\n\r\n\
+            \x01\x02\r\n\
+            --xyz\r\n\
+            \r\n\
+            plain\r\n\
+            --xyz--\r\n";
+        ctx.handle_datagram(
+            invite("multipart/mixed;boundary=xyz", multipart).as_bytes(),
+            src,
+        );
+        ctx.on_body("application/*", None).unwrap();
+        ctx.handle_datagram(invite("application/json", "{}").as_bytes(), src);
+
+        let bodies = BODIES.lock().unwrap();
+        let got: Vec<(&str, &str, &[u8])> = bodies
+            .iter()
+            .map(|(h, ct, b)| (*h, ct.as_str(), b.as_slice()))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                ("sdp", "application/sdp", sdp.as_bytes()),
+                ("application", "application/json", &b"{\"a\":1}"[..]),
+                ("sdp", "application/sdp", &b"v=0"[..]),
+                (
+                    "application",
+                    "application/isup;version=itu-t92+",
+                    &b"\x01\x02"[..]
+                ),
+                ("any", "text/plain", &b"plain"[..]),
+                ("any", "application/json", &b"{}"[..]),
+            ]
+        );
+    }
+}
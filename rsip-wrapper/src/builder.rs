@@ -0,0 +1,1077 @@
+//! Builders that turn a handful of C strings into syntactically valid SIP messages.
+
+use crate::ffi::{into_c_string, str_arg};
+use crate::lock::Lock;
+use crate::random;
+use rsip::common::uri::UriWithParams;
+use rsip::headers::{ToTypedHeader, UntypedHeader};
+use rsip::param::{Branch, OtherParam, Tag};
+use rsip::prelude::{HasHeaders, HeadersExt};
+use rsip::{
+    typed, Error, Header, Host, Method, Param, Request, Response, SipMessage, StatusCode,
+    Transport, Uri, Version,
+};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv6Addr};
+use std::os::raw::c_char;
+
+/// The inputs of [`build_request`]. `from` and `to` accept either a bare URI or a
+/// name-addr with parameters (`"Alice" <sip:alice@example.com>;tag=abc`).
+pub(crate) struct RequestParts<'a> {
+    pub method: &'a str,
+    pub request_uri: &'a str,
+    pub from: &'a str,
+    pub to: &'a str,
+    pub call_id: &'a str,
+    pub cseq: u32,
+    pub via_host: &'a str,
+    pub via_port: u16,
+    pub defaults: &'a MessageDefaults,
+}
+
+/// What a context puts in every message it builds; see `RsipContext::message_defaults`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MessageDefaults {
+    pub max_forwards: u8,
+    /// Added as User-Agent to requests and as Server to responses.
+    pub user_agent: Option<String>,
+    /// An empty Via `rport` asks for the response on the source port (RFC 3581).
+    pub rport: bool,
+    /// Serialize header names in their compact form; see [`serialize`].
+    pub compact: bool,
+}
+
+impl Default for MessageDefaults {
+    fn default() -> Self {
+        Self {
+            max_forwards: 70,
+            user_agent: None,
+            rport: false,
+            compact: false,
+        }
+    }
+}
+
+/// `msg` as sent, with the header names RFC 3261 section 7.3.3 abbreviates (Via,
+/// From, To, Call-ID, Contact, Content-Type, Content-Length, ...) in their compact
+/// form if `compact` is set.
+pub(crate) fn serialize(msg: impl Into<SipMessage>, compact: bool) -> String {
+    let mut msg = msg.into();
+    if compact {
+        crate::message::compact_headers(msg.headers_mut());
+    }
+    msg.to_string()
+}
+
+pub(crate) fn build_request(parts: &RequestParts) -> Result<Request, Error> {
+    let method: Method = parts.method.parse()?;
+    let uri = Uri::try_from(parts.request_uri)?;
+    let mut from = rsip::headers::From::new(parts.from).typed()?;
+    let to = rsip::headers::To::new(parts.to).typed()?;
+    if parts.call_id.trim().is_empty() {
+        return Err(Error::MissingHeader(
+            rsip::headers::CallId::default().to_string(),
+        ));
+    }
+
+    // RFC 3261 §8.1.1.3: the UAC must add a From tag.
+    if from.tag().is_none() {
+        from = from.with_tag(Tag::new(random::generate_tag()));
+    }
+
+    let mut via_params = vec![Param::Branch(Branch::new(random::generate_branch()))];
+    if parts.defaults.rport {
+        via_params.push(Param::Other(OtherParam::new("rport"), None));
+    }
+    let via = typed::Via {
+        version: Version::V2,
+        transport: Transport::Udp,
+        uri: (Host::from(parts.via_host), parts.via_port).into(),
+        params: via_params,
+    };
+
+    let mut headers: rsip::Headers = Default::default();
+    headers.push(via.into());
+    headers.push(rsip::headers::MaxForwards::from(u32::from(parts.defaults.max_forwards)).into());
+    headers.push(from.into());
+    headers.push(to.into());
+    headers.push(rsip::headers::CallId::new(parts.call_id).into());
+    headers.push(
+        typed::CSeq {
+            seq: parts.cseq,
+            method,
+        }
+        .into(),
+    );
+    if let Some(user_agent) = &parts.defaults.user_agent {
+        headers.push(rsip::headers::UserAgent::new(user_agent).into());
+    }
+    headers.push(rsip::headers::ContentLength::default().into());
+
+    Ok(Request {
+        method,
+        uri,
+        version: Version::V2,
+        headers,
+        body: Default::default(),
+    })
+}
+
+/// `host` if it can be a Via sent-by: an IP address (IPv6 with or without brackets) or
+/// a domain name of letters, digits and hyphens (RFC 1123). IPv6 comes back without
+/// brackets and a domain without its trailing dot.
+pub(crate) fn sentby_host(host: &str) -> Option<String> {
+    if let Some(inner) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return inner.parse::<Ipv6Addr>().ok().map(|ip| ip.to_string());
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Some(ip.to_string());
+    }
+    let name = host.strip_suffix('.').unwrap_or(host);
+    let label_ok = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    };
+    // An all-numeric top label would be a malformed IPv4 address such as 1.2.3.999.
+    let top_ok = name
+        .rsplit('.')
+        .next()
+        .is_some_and(|top| !top.bytes().all(|b| b.is_ascii_digit()));
+    if name.len() <= 253 && name.split('.').all(label_ok) && top_ok {
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+/// Builds a request from its parts. Returns a caller-owned string (free with
+/// `rsip_free_string`) or NULL if any argument is missing or does not parse. A Via
+/// sent-by configured on the default context replaces `via_host` (and `via_port`
+/// unless configured as 0); so do its other message defaults.
+#[no_mangle]
+pub extern "C" fn rsip_build_request(
+    method: *const c_char,
+    request_uri: *const c_char,
+    from: *const c_char,
+    to: *const c_char,
+    call_id: *const c_char,
+    cseq: u32,
+    via_host: *const c_char,
+    via_port: u16,
+) -> *mut c_char {
+    let defaults = crate::default_context().message_defaults();
+    let mut parts = match (
+        str_arg(method),
+        str_arg(request_uri),
+        str_arg(from),
+        str_arg(to),
+        str_arg(call_id),
+        str_arg(via_host),
+    ) {
+        (Some(method), Some(request_uri), Some(from), Some(to), Some(call_id), Some(via_host)) => {
+            RequestParts {
+                method,
+                request_uri,
+                from,
+                to,
+                call_id,
+                cseq,
+                via_host,
+                via_port,
+                defaults: &defaults,
+            }
+        }
+        _ => return std::ptr::null_mut(),
+    };
+    let sentby = crate::default_context().config.locked().via_sentby.clone();
+    if let Some((host, port)) = &sentby {
+        parts.via_host = host;
+        if *port != 0 {
+            parts.via_port = *port;
+        }
+    }
+
+    match build_request(&parts) {
+        Ok(request) => into_c_string(serialize(request, defaults.compact)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Reason phrase for `code`: rsip's name for well-known codes split into words
+/// (`BusyHere` -> `Busy Here`), otherwise the name of its class.
+fn default_reason(code: u16) -> String {
+    match StatusCode::from(code) {
+        StatusCode::Other(..) => match code / 100 {
+            1 => "Provisional",
+            2 => "Success",
+            3 => "Redirection",
+            4 => "Client Error",
+            5 => "Server Error",
+            _ => "Global Failure",
+        }
+        .to_string(),
+        known => {
+            let name = known.to_string();
+            let name = name.split_once(' ').map_or("", |(_, name)| name);
+            let mut reason = String::new();
+            let mut prev_lower = false;
+            for c in name.chars() {
+                if c.is_ascii_uppercase() && prev_lower {
+                    reason.push(' ');
+                }
+                prev_lower = c.is_ascii_lowercase();
+                reason.push(c);
+            }
+            reason
+        }
+    }
+}
+
+/// Builds the response a UAS sends for `request` (RFC 3261 §8.2.6): Via (all of
+/// them, in order), From, To, Call-ID and CSeq are mirrored, Record-Route too for
+/// 101-299 responses, which may create a dialog. A To without a tag gets `local_tag`,
+/// or a generated one unless the status is 100 (a 100 Trying needs none). `server`
+/// is added as a Server header.
+pub(crate) fn build_response(
+    request: &Request,
+    status: u16,
+    reason: Option<&str>,
+    local_tag: Option<&str>,
+    server: Option<&str>,
+) -> Result<Response, Error> {
+    if !(100..=699).contains(&status) {
+        return Err(Error::Unexpected(format!("invalid status code {}", status)));
+    }
+    if request.method == Method::Ack {
+        return Err(Error::Unexpected("ACK is never answered".into()));
+    }
+    let reason = match reason {
+        Some(reason) if reason.contains(['\r', '\n']) => {
+            return Err(Error::Unexpected("reason phrase spans lines".into()))
+        }
+        Some(reason) => reason.to_string(),
+        None => default_reason(status),
+    };
+
+    let mut to = request.to_header()?.typed()?;
+    if to.tag().is_none() {
+        let tag = match local_tag {
+            Some(tag) => Some(tag.to_string()),
+            None if status > 100 => Some(random::generate_tag()),
+            None => None,
+        };
+        if let Some(tag) = tag {
+            to = to.with_tag(Tag::new(tag));
+        }
+    }
+
+    let mut headers: rsip::Headers = Default::default();
+    for header in request.headers.iter() {
+        match header {
+            Header::Via(_) => headers.push(header.clone()),
+            Header::RecordRoute(_) if (101..300).contains(&status) => headers.push(header.clone()),
+            _ => {}
+        }
+    }
+    if !headers.iter().any(|h| matches!(h, Header::Via(_))) {
+        return Err(Error::MissingHeader("Via".into()));
+    }
+    headers.push(request.from_header()?.clone().into());
+    headers.push(to.into());
+    headers.push(request.call_id_header()?.clone().into());
+    headers.push(request.cseq_header()?.clone().into());
+    if let Some(server) = server {
+        headers.push(rsip::headers::Server::new(server).into());
+    }
+    headers.push(rsip::headers::ContentLength::default().into());
+
+    Ok(Response {
+        status_code: StatusCode::Other(status, reason),
+        version: Version::V2,
+        headers,
+        body: Default::default(),
+    })
+}
+
+/// Builds the response to `raw_request` (see [`build_response`]). `reason` and
+/// `local_tag` may be NULL for the defaults. Returns a caller-owned string, or NULL if
+/// the request does not parse, lacks a mirrored header, is an ACK, or the status is
+/// outside 100-699. The default context's user agent is added as Server.
+#[no_mangle]
+pub extern "C" fn rsip_build_response(
+    raw_request: *const c_char,
+    status_code: u16,
+    reason: *const c_char,
+    local_tag: *const c_char,
+) -> *mut c_char {
+    let request = match str_arg(raw_request).map(SipMessage::try_from) {
+        Some(Ok(SipMessage::Request(request))) => request,
+        _ => return std::ptr::null_mut(),
+    };
+    let defaults = crate::default_context().message_defaults();
+    match build_response(
+        &request,
+        status_code,
+        str_arg(reason),
+        str_arg(local_tag),
+        defaults.user_agent.as_deref(),
+    ) {
+        Ok(response) => into_c_string(serialize(response, defaults.compact)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Builds the ACK for a non-2xx final response (RFC 3261 §17.1.1.3): the INVITE's
+/// Request-URI, top Via, From, Call-ID and Route set, the response's To, and the
+/// INVITE's CSeq number with method ACK.
+pub(crate) fn ack_for_failure(invite: &Request, response: &Response) -> Request {
+    let mut headers: Vec<Header> = Vec::new();
+    if let Some(via) = invite.headers.iter().find(|h| matches!(h, Header::Via(_))) {
+        headers.push(via.clone());
+    }
+    headers.extend(
+        invite
+            .headers
+            .iter()
+            .filter(|h| matches!(h, Header::Route(_) | Header::From(_) | Header::CallId(_)))
+            .cloned(),
+    );
+    if let Ok(to) = response.to_header() {
+        headers.push(to.clone().into());
+    }
+    let seq = invite
+        .cseq_header()
+        .and_then(|cseq| cseq.seq())
+        .unwrap_or_default();
+    headers.push(rsip::headers::CSeq::new(format!("{} ACK", seq)).into());
+    headers.push(rsip::headers::MaxForwards::new("70").into());
+    headers.push(rsip::headers::ContentLength::default().into());
+    Request {
+        method: Method::Ack,
+        uri: invite.uri.clone(),
+        version: invite.version.clone(),
+        headers: headers.into(),
+        body: Vec::new(),
+    }
+}
+
+/// Builds the ACK for a 2xx (RFC 3261 §13.2.2.4), a request of the dialog: sent to the
+/// response's Contact along the route set (its Record-Route, reversed), with a new Via
+/// branch, the response's To and the INVITE's credentials. A strict first route
+/// (no `lr`) becomes the Request-URI, with the Contact appended as the last route.
+fn ack_for_success(invite: &Request, response: &Response) -> Result<Request, Error> {
+    let target = response.contact_header()?.typed()?.uri;
+    let mut routes: Vec<UriWithParams> = Vec::new();
+    for header in response.headers.iter() {
+        if let Header::RecordRoute(record_route) = header {
+            routes.extend(record_route.typed()?.uris().iter().cloned());
+        }
+    }
+    routes.reverse();
+    let strict = routes
+        .first()
+        .is_some_and(|route| !route.uri.params.contains(&Param::Lr));
+    let uri = if strict {
+        let first = routes.remove(0);
+        routes.push(UriWithParams {
+            uri: target,
+            params: vec![],
+        });
+        first.uri
+    } else {
+        target
+    };
+
+    let top = invite.via_header()?.typed()?;
+    let via = typed::Via {
+        version: Version::V2,
+        transport: top.transport,
+        uri: top.uri,
+        params: vec![Param::Branch(Branch::new(random::generate_branch()))],
+    };
+    let mut headers: rsip::Headers = Default::default();
+    headers.push(via.into());
+    for route in routes {
+        headers.push(rsip::headers::Route::new(route.to_string()).into());
+    }
+    headers.push(rsip::headers::MaxForwards::default().into());
+    headers.push(invite.from_header()?.clone().into());
+    headers.push(response.to_header()?.clone().into());
+    headers.push(invite.call_id_header()?.clone().into());
+    headers.push(
+        typed::CSeq {
+            seq: invite.cseq_header()?.seq()?,
+            method: Method::Ack,
+        }
+        .into(),
+    );
+    for header in invite.headers.iter() {
+        if let Header::Authorization(_) | Header::ProxyAuthorization(_) = header {
+            headers.push(header.clone());
+        }
+    }
+    headers.push(rsip::headers::ContentLength::default().into());
+
+    Ok(Request {
+        method: Method::Ack,
+        uri,
+        version: Version::V2,
+        headers,
+        body: Default::default(),
+    })
+}
+
+/// The ACK for `response`, a final response to `invite`: hop-by-hop in the INVITE's
+/// transaction for non-2xx, a new transaction in the dialog for 2xx.
+pub(crate) fn build_ack(invite: &Request, response: &Response) -> Result<Request, Error> {
+    if invite.method != Method::Invite {
+        return Err(Error::Unexpected("only an INVITE is acknowledged".into()));
+    }
+    if response.status_code().code() < 200 {
+        return Err(Error::Unexpected(
+            "provisional responses are not acknowledged".into(),
+        ));
+    }
+    if response.cseq_header()?.method()? != Method::Invite {
+        return Err(Error::Unexpected("not a response to an INVITE".into()));
+    }
+    match response.status_code().code() {
+        200..=299 => ack_for_success(invite, response),
+        _ => Ok(ack_for_failure(invite, response)),
+    }
+}
+
+/// Builds the CANCEL for a sent INVITE (RFC 3261 §9.1): the same Request-URI, Call-ID,
+/// From, To (no tag is added) and Route set, only the top Via, and the INVITE's CSeq
+/// number with method CANCEL.
+pub(crate) fn build_cancel(invite: &Request) -> Result<Request, Error> {
+    if invite.method != Method::Invite {
+        return Err(Error::Unexpected("only an INVITE can be cancelled".into()));
+    }
+    let seq = invite.cseq_header()?.seq()?;
+    let mut headers: rsip::Headers = Default::default();
+    headers.push(invite.via_header()?.clone().into());
+    for header in invite.headers.iter() {
+        if let Header::Route(_) = header {
+            headers.push(header.clone());
+        }
+    }
+    headers.push(rsip::headers::MaxForwards::default().into());
+    headers.push(invite.from_header()?.clone().into());
+    headers.push(invite.to_header()?.clone().into());
+    headers.push(invite.call_id_header()?.clone().into());
+    headers.push(
+        typed::CSeq {
+            seq,
+            method: Method::Cancel,
+        }
+        .into(),
+    );
+    headers.push(rsip::headers::ContentLength::default().into());
+
+    Ok(Request {
+        method: Method::Cancel,
+        uri: invite.uri.clone(),
+        version: Version::V2,
+        headers,
+        body: Default::default(),
+    })
+}
+
+/// Builds the CANCEL for `original_invite` (see [`build_cancel`]). Returns a
+/// caller-owned string, or NULL if it is not a parsable INVITE with Via, From, To,
+/// Call-ID and CSeq.
+#[no_mangle]
+pub extern "C" fn rsip_build_cancel(original_invite: *const c_char) -> *mut c_char {
+    match str_arg(original_invite).map(SipMessage::try_from) {
+        Some(Ok(SipMessage::Request(invite))) => match build_cancel(&invite) {
+            Ok(cancel) => into_c_string(serialize(
+                cancel,
+                crate::default_context().message_defaults().compact,
+            )),
+            Err(_) => std::ptr::null_mut(),
+        },
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Builds the ACK for `final_response` to `original_invite` (see [`build_ack`]).
+/// Returns a caller-owned string, or NULL if either does not parse, the response is
+/// provisional or not for an INVITE, or a needed header (Contact for 2xx) is missing.
+#[no_mangle]
+pub extern "C" fn rsip_build_ack(
+    original_invite: *const c_char,
+    final_response: *const c_char,
+) -> *mut c_char {
+    let invite = match str_arg(original_invite).map(SipMessage::try_from) {
+        Some(Ok(SipMessage::Request(invite))) => invite,
+        _ => return std::ptr::null_mut(),
+    };
+    let response = match str_arg(final_response).map(SipMessage::try_from) {
+        Some(Ok(SipMessage::Response(response))) => response,
+        _ => return std::ptr::null_mut(),
+    };
+    match build_ack(&invite, &response) {
+        Ok(ack) => into_c_string(serialize(
+            ack,
+            crate::default_context().message_defaults().compact,
+        )),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// A Contact header value for `uri` (bare or in angle brackets) with `expires` and,
+/// for RFC 5626 outbound, `+sip.instance` and `reg-id`. `instance_id` may be a full
+/// `urn:...` (optionally already in `<>` and quotes) or a bare UUID, which becomes
+/// `urn:uuid:<id>`. `reg_id` 0 leaves it out; without an instance it must be 0, as
+/// a reg-id is only meaningful with one (RFC 5626 section 4.2).
+pub(crate) fn build_contact(
+    uri: &str,
+    expires: u32,
+    instance_id: Option<&str>,
+    reg_id: u32,
+) -> Option<String> {
+    crate::uri::parse(uri).ok()?;
+    let uri = uri.trim();
+    let uri = uri
+        .strip_prefix('<')
+        .and_then(|u| u.strip_suffix('>'))
+        .map_or(uri, str::trim);
+    let mut contact = format!("<{}>;expires={}", uri, expires);
+
+    match instance_id.map(str::trim).filter(|id| !id.is_empty()) {
+        Some(id) => {
+            let id = id
+                .strip_prefix('"')
+                .and_then(|id| id.strip_suffix('"'))
+                .unwrap_or(id);
+            let id = id
+                .strip_prefix('<')
+                .and_then(|id| id.strip_suffix('>'))
+                .unwrap_or(id);
+            let urn_ok = |c: char| c.is_ascii_graphic() && !matches!(c, '"' | '<' | '>' | '\\');
+            if !id.chars().all(urn_ok) {
+                return None;
+            }
+            if id.contains(':') {
+                contact.push_str(&format!(";+sip.instance=\"<{}>\"", id));
+            } else {
+                contact.push_str(&format!(";+sip.instance=\"<urn:uuid:{}>\"", id));
+            }
+        }
+        None if reg_id != 0 => return None,
+        None => {}
+    }
+    if reg_id != 0 {
+        contact.push_str(&format!(";reg-id={}", reg_id));
+    }
+    Some(contact)
+}
+
+/// Builds a Contact header value (see [`build_contact`]). Returns a caller-owned
+/// string, or NULL if `uri` is NULL or does not parse, `instance_id` is malformed, or
+/// `reg_id` is set without an instance.
+#[no_mangle]
+pub extern "C" fn rsip_build_contact(
+    uri: *const c_char,
+    expires: u32,
+    instance_id: *const c_char,
+    reg_id: u32,
+) -> *mut c_char {
+    str_arg(uri)
+        .and_then(|uri| build_contact(uri, expires, str_arg(instance_id), reg_id))
+        .map_or(std::ptr::null_mut(), into_c_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsip::SipMessage;
+
+    static DEFAULTS: MessageDefaults = MessageDefaults {
+        max_forwards: 70,
+        user_agent: None,
+        rport: false,
+        compact: false,
+    };
+
+    fn parts<'a>() -> RequestParts<'a> {
+        RequestParts {
+            method: "INVITE",
+            request_uri: "sip:bob@biloxi.example.com",
+            from: "Alice <sip:alice@atlanta.example.com>",
+            to: "sip:bob@biloxi.example.com",
+            call_id: "a84b4c76e66710@pc33.atlanta.example.com",
+            cseq: 1,
+            via_host: "pc33.atlanta.example.com",
+            via_port: 5060,
+            defaults: &DEFAULTS,
+        }
+    }
+
+    #[test]
+    fn builds_outbound_contacts() {
+        let uuid = "f81d4fae-7dec-11d0-a765-00a0c91e6bf6";
+        assert_eq!(
+            build_contact(
+                "sip:alice@192.0.2.4:5060;transport=tcp",
+                3600,
+                Some(uuid),
+                1
+            )
+            .unwrap(),
+            "<sip:alice@192.0.2.4:5060;transport=tcp>;expires=3600;\
+             +sip.instance=\"<urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6>\";reg-id=1"
+        );
+        let quoted = format!("\"<urn:uuid:{}>\"", uuid);
+        assert_eq!(
+            build_contact("<sip:alice@192.0.2.4>", 0, Some(&quoted), 2),
+            build_contact(
+                "sip:alice@192.0.2.4",
+                0,
+                Some(&format!("urn:uuid:{}", uuid)),
+                2
+            )
+        );
+        assert_eq!(
+            build_contact("sip:alice@192.0.2.4", 600, None, 0).unwrap(),
+            "<sip:alice@192.0.2.4>;expires=600"
+        );
+        assert_eq!(
+            build_contact("sip:alice@192.0.2.4", 600, Some(""), 0).unwrap(),
+            "<sip:alice@192.0.2.4>;expires=600"
+        );
+        assert_eq!(build_contact("sip:alice@192.0.2.4", 600, None, 1), None);
+        assert_eq!(
+            build_contact("sip:alice@192.0.2.4", 600, Some("a\"b"), 0),
+            None
+        );
+        assert_eq!(build_contact("not a uri", 600, None, 0), None);
+
+        // the result parses as a Contact with its expires
+        let value = build_contact("sip:alice@192.0.2.4", 60, Some(uuid), 1).unwrap();
+        let contact = rsip::headers::Contact::new(value).typed().unwrap();
+        assert_eq!(contact.expires().unwrap().seconds().unwrap(), 60);
+        assert!(rsip_build_contact(std::ptr::null(), 60, std::ptr::null(), 0).is_null());
+    }
+
+    #[test]
+    fn builds_a_request_that_round_trips() {
+        let raw = build_request(&parts()).unwrap().to_string();
+        assert!(raw.starts_with("INVITE sip:bob@biloxi.example.com SIP/2.0\r\n"));
+        assert!(raw.ends_with("\r\n\r\n"));
+
+        let msg = SipMessage::try_from(raw.as_str()).unwrap();
+        let branch = msg.via_header().unwrap().branch().unwrap().to_string();
+        assert!(branch.starts_with("z9hG4bK"));
+        assert!(
+            msg.from_header().unwrap().tag().unwrap().is_some(),
+            "From tag is added"
+        );
+        assert_eq!(msg.cseq_header().unwrap().seq().unwrap(), 1);
+        assert_eq!(msg.cseq_header().unwrap().method().unwrap(), Method::Invite);
+        assert_eq!(msg.max_forwards_header().unwrap().num().unwrap(), 70);
+    }
+
+    #[test]
+    fn applies_max_forwards_and_user_agent() {
+        let defaults = MessageDefaults {
+            max_forwards: 16,
+            user_agent: Some("acme-phone/1.2".into()),
+            rport: false,
+            compact: false,
+        };
+        let mut p = parts();
+        p.defaults = &defaults;
+        let raw = build_request(&p).unwrap().to_string();
+        assert!(raw.contains("Max-Forwards: 16\r\n"), "{}", raw);
+        assert!(
+            raw.ends_with("User-Agent: acme-phone/1.2\r\nContent-Length: 0\r\n\r\n"),
+            "{}",
+            raw
+        );
+
+        let response = match SipMessage::try_from(raw.as_str()).unwrap() {
+            SipMessage::Request(request) => {
+                build_response(&request, 200, None, None, Some("acme-pbx/3.0")).unwrap()
+            }
+            _ => unreachable!(),
+        };
+        let raw = response.to_string();
+        assert!(raw.contains("Server: acme-pbx/3.0\r\n"), "{}", raw);
+        assert!(!raw.contains("User-Agent"));
+
+        let ctx = crate::context::RsipContext::new();
+        assert!(ctx.set_default_max_forwards(0).is_err());
+        assert!(ctx.set_default_max_forwards(20).is_ok());
+        assert!(ctx.set_user_agent(Some("a\r\nVia: x")).is_err());
+        assert!(ctx.set_user_agent(Some(" ")).is_err());
+        assert!(ctx.set_user_agent(Some(" acme ")).is_ok());
+        let defaults = ctx.message_defaults();
+        assert_eq!(defaults.max_forwards, 20);
+        assert_eq!(defaults.user_agent.as_deref(), Some("acme"));
+        assert!(ctx.set_user_agent(None).is_ok());
+        assert_eq!(ctx.message_defaults().user_agent, None);
+    }
+
+    #[test]
+    fn adds_rport_to_the_via_when_asked() {
+        let raw = build_request(&parts()).unwrap().to_string();
+        assert!(!raw.contains("rport"));
+
+        let ctx = crate::context::RsipContext::new();
+        ctx.set_use_rport(true);
+        let defaults = ctx.message_defaults();
+        let mut p = parts();
+        p.defaults = &defaults;
+        let raw = build_request(&p).unwrap().to_string();
+        let msg = SipMessage::try_from(raw.as_str()).unwrap();
+        let via = msg.via_header().unwrap().value().to_string();
+        assert!(via.ends_with(";rport"), "{}", via);
+        assert!(via.contains(";branch=z9hG4bK"));
+        // what a server then fills in is found again
+        let src = "203.0.113.9:40000".parse().unwrap();
+        let answered = crate::nat::apply_rport(&raw, src).unwrap();
+        assert_eq!(
+            crate::nat::response_destination(&answered).as_deref(),
+            Some("203.0.113.9:40000")
+        );
+    }
+
+    #[test]
+    fn compact_output_matches_the_full_one() {
+        let request = build_request(&parts()).unwrap();
+        let full = serialize(request.clone(), false);
+        assert_eq!(full, request.to_string());
+        let compact = serialize(request, true);
+        assert!(compact.len() < full.len());
+        let names = |raw: &str| -> Vec<String> {
+            raw.split("\r\n")
+                .skip(1)
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, _)| name.to_string())
+                .collect()
+        };
+        assert_eq!(
+            names(&compact),
+            ["v", "Max-Forwards", "f", "t", "i", "CSeq", "l"]
+        );
+        assert_eq!(
+            names(&full),
+            [
+                "Via",
+                "Max-Forwards",
+                "From",
+                "To",
+                "Call-ID",
+                "CSeq",
+                "Content-Length"
+            ]
+        );
+        // the same message either way, only the names differ
+        let headers = |raw: &str| -> Vec<(String, String)> {
+            let msg = SipMessage::try_from(raw).unwrap();
+            msg.headers()
+                .iter()
+                .map(crate::message::name_value)
+                .map(|(name, value)| (crate::message::canonical_name(&name).to_string(), value))
+                .collect()
+        };
+        assert_eq!(headers(&full), headers(&compact));
+    }
+
+    #[test]
+    fn keeps_an_existing_from_tag() {
+        let mut p = parts();
+        p.from = "<sip:alice@atlanta.example.com>;tag=1928301774";
+        let req = build_request(&p).unwrap();
+        assert_eq!(
+            req.from_header()
+                .unwrap()
+                .tag()
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "1928301774"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut p = parts();
+        p.request_uri = "";
+        assert!(build_request(&p).is_err());
+
+        let mut p = parts();
+        p.call_id = " ";
+        assert!(build_request(&p).is_err());
+
+        assert!(rsip_build_request(
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+            1,
+            std::ptr::null(),
+            5060
+        )
+        .is_null());
+    }
+
+    #[test]
+    fn validates_sentby_hosts() {
+        assert_eq!(sentby_host("203.0.113.7").as_deref(), Some("203.0.113.7"));
+        assert_eq!(sentby_host("[2001:db8::1]").as_deref(), Some("2001:db8::1"));
+        assert_eq!(sentby_host("2001:db8::1").as_deref(), Some("2001:db8::1"));
+        assert_eq!(
+            sentby_host("sip.example.com.").as_deref(),
+            Some("sip.example.com")
+        );
+        assert_eq!(sentby_host("edge-1").as_deref(), Some("edge-1"));
+        for bad in [
+            "",
+            "1.2.3.999",
+            "-a.example.com",
+            "a..example.com",
+            "a.example.com;branch=x",
+            "[192.0.2.1]",
+            "a b",
+        ]
+        .iter()
+        {
+            assert_eq!(sentby_host(bad), None, "{:?}", bad);
+        }
+        assert_eq!(sentby_host(&"a".repeat(64)), None);
+    }
+
+    const INVITE: &str = "INVITE sip:bob@biloxi.example.com SIP/2.0\r\n\
+        Via: SIP/2.0/UDP proxy.example.com;branch=z9hG4bKp\r\n\
+        Via: SIP/2.0/UDP pc33.atlanta.example.com;branch=z9hG4bKa;received=192.0.2.1\r\n\
+        Record-Route: <sip:proxy.example.com;lr>\r\n\
+        Max-Forwards: 69\r\n\
+        To: Bob <sip:bob@biloxi.example.com>\r\n\
+        From: Alice <sip:alice@atlanta.example.com>;tag=1928301774\r\n\
+        Call-ID: a84b4c76e66710\r\n\
+        CSeq: 314159 INVITE\r\n\
+        Content-Length: 0\r\n\r\n";
+
+    fn respond(status: u16, reason: Option<&str>, tag: Option<&str>) -> Result<Response, Error> {
+        match SipMessage::try_from(INVITE).unwrap() {
+            SipMessage::Request(request) => build_response(&request, status, reason, tag, None),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn mirrors_the_request() {
+        let raw = respond(180, None, Some("local1")).unwrap().to_string();
+        assert!(raw.starts_with("SIP/2.0 180 Ringing\r\n"), "{}", raw);
+        let res = SipMessage::try_from(raw.as_str()).unwrap();
+        let vias: Vec<String> = res
+            .headers()
+            .iter()
+            .filter_map(|h| match h {
+                Header::Via(via) => Some(via.value().to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vias.len(), 2);
+        assert!(vias[0].contains("z9hG4bKp") && vias[1].contains("z9hG4bKa"));
+        assert!(raw.contains("Record-Route: <sip:proxy.example.com;lr>\r\n"));
+        assert!(!raw.contains("Max-Forwards"));
+        assert_eq!(
+            res.to_header().unwrap().tag().unwrap().unwrap().to_string(),
+            "local1"
+        );
+        assert_eq!(
+            res.from_header()
+                .unwrap()
+                .tag()
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "1928301774"
+        );
+        assert_eq!(res.call_id_header().unwrap().value(), "a84b4c76e66710");
+        assert_eq!(res.cseq_header().unwrap().seq().unwrap(), 314159);
+    }
+
+    #[test]
+    fn tags_and_reasons() {
+        let trying = respond(100, None, None).unwrap();
+        assert!(trying.to_header().unwrap().tag().unwrap().is_none());
+        let busy = respond(486, None, None).unwrap();
+        assert!(busy.to_header().unwrap().tag().unwrap().is_some());
+        assert!(busy.to_string().starts_with("SIP/2.0 486 Busy Here\r\n"));
+        assert!(!busy.to_string().contains("Record-Route"));
+        let custom = respond(299, Some("Fine Thanks"), None).unwrap();
+        assert!(custom
+            .to_string()
+            .starts_with("SIP/2.0 299 Fine Thanks\r\n"));
+        assert_eq!(default_reason(499), "Client Error");
+        assert_eq!(default_reason(200), "OK");
+
+        assert!(respond(99, None, None).is_err());
+        assert!(respond(700, None, None).is_err());
+        assert!(respond(200, Some("two\r\nlines"), None).is_err());
+    }
+
+    #[test]
+    fn cancel_mirrors_the_invite() {
+        let invite = INVITE.replace(
+            "Record-Route: <sip:proxy.example.com;lr>\r\n",
+            "Route: <sip:edge.example.com;lr>\r\nRoute: <sip:core.example.com;lr>\r\n",
+        );
+        let invite = match SipMessage::try_from(invite.as_str()).unwrap() {
+            SipMessage::Request(request) => request,
+            _ => unreachable!(),
+        };
+        let raw = build_cancel(&invite).unwrap().to_string();
+        assert!(raw.starts_with("CANCEL sip:bob@biloxi.example.com SIP/2.0\r\n"));
+        let cancel = SipMessage::try_from(raw.as_str()).unwrap();
+        let vias: Vec<_> = cancel
+            .headers()
+            .iter()
+            .filter(|h| matches!(h, Header::Via(_)))
+            .collect();
+        assert_eq!(vias.len(), 1);
+        assert!(raw.contains("Via: SIP/2.0/UDP proxy.example.com;branch=z9hG4bKp\r\n"));
+        assert!(raw
+            .contains("Route: <sip:edge.example.com;lr>\r\nRoute: <sip:core.example.com;lr>\r\n"));
+        assert!(raw.contains("To: Bob <sip:bob@biloxi.example.com>\r\n"));
+        assert!(cancel.to_header().unwrap().tag().unwrap().is_none());
+        assert!(raw.contains("From: Alice <sip:alice@atlanta.example.com>;tag=1928301774\r\n"));
+        assert_eq!(cancel.call_id_header().unwrap().value(), "a84b4c76e66710");
+        assert_eq!(cancel.cseq_header().unwrap().seq().unwrap(), 314159);
+        assert_eq!(
+            cancel.cseq_header().unwrap().method().unwrap(),
+            Method::Cancel
+        );
+
+        let bye = std::ffi::CString::new(INVITE.replace("INVITE", "BYE")).unwrap();
+        assert!(rsip_build_cancel(bye.as_ptr()).is_null());
+        assert!(rsip_build_cancel(std::ptr::null()).is_null());
+        let ffi = std::ffi::CString::new(INVITE).unwrap();
+        let out = rsip_build_cancel(ffi.as_ptr());
+        assert!(!out.is_null());
+        crate::ffi::rsip_free_string(out);
+    }
+
+    fn invite() -> Request {
+        let invite = INVITE.replace(
+            "Record-Route: <sip:proxy.example.com;lr>\r\n",
+            "Route: <sip:edge.example.com;lr>\r\n\
+             Proxy-Authorization: Digest username=\"alice\", realm=\"atlanta\"\r\n",
+        );
+        match SipMessage::try_from(invite.as_str()).unwrap() {
+            SipMessage::Request(request) => request,
+            _ => unreachable!(),
+        }
+    }
+
+    fn final_response(status: &str, extra: &str) -> Response {
+        let raw = format!(
+            "SIP/2.0 {}\r\n\
+             Via: SIP/2.0/UDP proxy.example.com;branch=z9hG4bKp\r\n\
+             {}\
+             To: Bob <sip:bob@biloxi.example.com>;tag=bobtag\r\n\
+             From: Alice <sip:alice@atlanta.example.com>;tag=1928301774\r\n\
+             Call-ID: a84b4c76e66710\r\n\
+             CSeq: 314159 INVITE\r\n\
+             Content-Length: 0\r\n\r\n",
+            status, extra
+        );
+        match SipMessage::try_from(raw.as_str()).unwrap() {
+            SipMessage::Response(response) => response,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn ack_for_non_2xx_stays_in_the_transaction() {
+        let raw = build_ack(&invite(), &final_response("486 Busy Here", ""))
+            .unwrap()
+            .to_string();
+        assert!(raw.starts_with("ACK sip:bob@biloxi.example.com SIP/2.0\r\n"));
+        assert!(raw.contains("Via: SIP/2.0/UDP proxy.example.com;branch=z9hG4bKp\r\n"));
+        assert!(!raw.contains("z9hG4bKa"), "only the top Via");
+        assert!(raw.contains("Route: <sip:edge.example.com;lr>\r\n"));
+        assert!(raw.contains("To: Bob <sip:bob@biloxi.example.com>;tag=bobtag\r\n"));
+        assert!(raw.contains("CSeq: 314159 ACK\r\n"));
+    }
+
+    #[test]
+    fn ack_for_2xx_follows_the_dialog_route() {
+        let ok = final_response(
+            "200 OK",
+            "Record-Route: <sip:p2.example.com;lr>, <sip:p1.example.com;lr>\r\n\
+             Contact: <sip:bob@192.0.2.4:5062>\r\n",
+        );
+        let ack = build_ack(&invite(), &ok).unwrap();
+        let raw = ack.to_string();
+        assert!(
+            raw.starts_with("ACK sip:bob@192.0.2.4:5062 SIP/2.0\r\n"),
+            "{}",
+            raw
+        );
+        let branch = ack.via_header().unwrap().branch().unwrap().to_string();
+        assert!(branch.starts_with("z9hG4bK") && branch != "z9hG4bKp");
+        assert!(raw.contains("Via: SIP/2.0/UDP proxy.example.com;branch="));
+        assert!(
+            raw.contains("Route: <sip:p1.example.com;lr>\r\nRoute: <sip:p2.example.com;lr>\r\n")
+        );
+        assert!(
+            !raw.contains("edge.example.com"),
+            "the INVITE's Route is not the dialog's"
+        );
+        assert!(raw.contains("To: Bob <sip:bob@biloxi.example.com>;tag=bobtag\r\n"));
+        assert!(raw.contains("Proxy-Authorization: Digest"));
+        assert!(raw.contains("CSeq: 314159 ACK\r\n"));
+
+        let strict = final_response(
+            "200 OK",
+            "Record-Route: <sip:strict.example.com>\r\nContact: <sip:bob@192.0.2.4>\r\n",
+        );
+        let raw = build_ack(&invite(), &strict).unwrap().to_string();
+        assert!(
+            raw.starts_with("ACK sip:strict.example.com SIP/2.0\r\n"),
+            "{}",
+            raw
+        );
+        assert!(raw.contains("Route: <sip:bob@192.0.2.4>\r\n"));
+
+        assert!(
+            build_ack(&invite(), &final_response("200 OK", "")).is_err(),
+            "2xx without Contact"
+        );
+        assert!(build_ack(&invite(), &final_response("180 Ringing", "")).is_err());
+        let invite_raw = std::ffi::CString::new(INVITE).unwrap();
+        let busy = std::ffi::CString::new(final_response("486 Busy Here", "").to_string()).unwrap();
+        let out = rsip_build_ack(invite_raw.as_ptr(), busy.as_ptr());
+        assert!(!out.is_null());
+        crate::ffi::rsip_free_string(out);
+        assert!(rsip_build_ack(busy.as_ptr(), invite_raw.as_ptr()).is_null());
+    }
+
+    #[test]
+    fn ffi_rejects_acks_and_responses() {
+        let ack = std::ffi::CString::new(INVITE.replace("INVITE", "ACK")).unwrap();
+        assert!(
+            rsip_build_response(ack.as_ptr(), 200, std::ptr::null(), std::ptr::null()).is_null()
+        );
+        let response = std::ffi::CString::new("SIP/2.0 200 OK\r\n\r\n").unwrap();
+        assert!(
+            rsip_build_response(response.as_ptr(), 200, std::ptr::null(), std::ptr::null())
+                .is_null()
+        );
+        let invite = std::ffi::CString::new(INVITE).unwrap();
+        let out = rsip_build_response(invite.as_ptr(), 200, std::ptr::null(), std::ptr::null());
+        assert!(!out.is_null());
+        crate::ffi::rsip_free_string(out);
+    }
+}
@@ -0,0 +1,169 @@
+//! A raw capture tap: every UDP datagram received or sent, handed to a host callback
+//! before any filtering or parsing, e.g. to write a pcap file. Off unless a callback
+//! is set, which costs one lock per datagram.
+
+use crate::context::{with_context, RsipContext};
+use crate::events::source_octets;
+use crate::lock::Lock;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `RsipCapture::direction` of a received datagram.
+pub const CAPTURE_IN: u32 = 0;
+/// `RsipCapture::direction` of a sent datagram.
+pub const CAPTURE_OUT: u32 = 1;
+
+/// One captured datagram. Addresses are IPv6, IPv4 as v4-mapped, like
+/// `RsipEvent::src_ip`; `data` is only valid during the callback.
+#[repr(C)]
+pub struct RsipCapture {
+    pub direction: u32,
+    /// Nanoseconds since the Unix epoch: the kernel receive timestamp if enabled
+    /// (`set_rx_timestamps`), else when the stack handled the datagram.
+    pub timestamp_ns: u64,
+    pub src_ip: [u8; 16],
+    pub src_port: u16,
+    pub dst_ip: [u8; 16],
+    pub dst_port: u16,
+    pub data: *const u8,
+    pub len: usize,
+}
+
+pub type CaptureCallback = extern "C" fn(capture: *const RsipCapture);
+
+impl RsipContext {
+    /// Hands every UDP datagram received by the listeners or sent by the stack to
+    /// `cb`; `None` turns capturing off.
+    pub fn set_capture_callback(&self, cb: Option<CaptureCallback>) {
+        *self.capture.locked() = cb;
+    }
+
+    /// Reports `data`, received on or sent from `socket` from or to `peer`, to the
+    /// capture callback if there is one.
+    pub(crate) fn capture(
+        &self,
+        direction: u32,
+        socket: &UdpSocket,
+        peer: SocketAddr,
+        data: &[u8],
+        timestamp_ns: Option<u64>,
+    ) {
+        let cb = match *self.capture.locked() {
+            Some(cb) => cb,
+            None => return,
+        };
+        let local = socket.local_addr().ok();
+        let peer = Some(peer);
+        let (src, dst) = if direction == CAPTURE_IN {
+            (peer, local)
+        } else {
+            (local, peer)
+        };
+        let timestamp_ns = timestamp_ns.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_nanos() as u64)
+        });
+        let capture = RsipCapture {
+            direction,
+            timestamp_ns,
+            src_ip: source_octets(src),
+            src_port: src.map_or(0, |s| s.port()),
+            dst_ip: source_octets(dst),
+            dst_port: dst.map_or(0, |d| d.port()),
+            data: data.as_ptr(),
+            len: data.len(),
+        };
+        cb(&capture);
+    }
+
+    /// `socket.send_to`, capturing the datagram once it is sent.
+    pub(crate) fn send_captured(
+        &self,
+        socket: &UdpSocket,
+        data: &[u8],
+        dest: SocketAddr,
+    ) -> io::Result<usize> {
+        let n = socket.send_to(data, dest)?;
+        self.capture(CAPTURE_OUT, socket, dest, data, None);
+        Ok(n)
+    }
+}
+
+/// Hands every UDP datagram to `cb` as an `RsipCapture`: those the listeners receive
+/// (before filtering, rate limiting and parsing) and those sent by any send function,
+/// registration or transaction. NULL turns capturing off, the default.
+#[no_mangle]
+pub extern "C" fn rsip_set_capture_callback(cb: Option<CaptureCallback>) {
+    crate::default_context().set_capture_callback(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_capture_callback(
+    ctx: *mut RsipContext,
+    cb: Option<CaptureCallback>,
+) {
+    with_context(ctx, |ctx| ctx.set_capture_callback(cb));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    type Captured = (u32, SocketAddr, SocketAddr, Vec<u8>);
+
+    static CAPTURED: Mutex<Vec<Captured>> = Mutex::new(Vec::new());
+
+    fn address(ip: [u8; 16], port: u16) -> SocketAddr {
+        let ip = std::net::Ipv6Addr::from(ip).to_canonical();
+        SocketAddr::new(ip, port)
+    }
+
+    extern "C" fn record(capture: *const RsipCapture) {
+        let capture = unsafe { &*capture };
+        assert!(capture.timestamp_ns > 0);
+        CAPTURED.lock().unwrap().push((
+            capture.direction,
+            address(capture.src_ip, capture.src_port),
+            address(capture.dst_ip, capture.dst_port),
+            unsafe { std::slice::from_raw_parts(capture.data, capture.len) }.to_vec(),
+        ));
+    }
+
+    #[test]
+    fn captures_received_and_sent_datagrams() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        ctx.start_udp_listener(0).unwrap();
+        let listener = ctx.local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        ctx.set_capture_callback(Some(record));
+        // captured before parsing, so garbage is too
+        peer.send_to(b"garbage", listener).unwrap();
+        for _ in 0..200 {
+            if !CAPTURED.lock().unwrap().is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        ctx.send_from_listener("127.0.0.1", peer_addr.port(), b"OPTIONS")
+            .unwrap();
+        ctx.set_capture_callback(None);
+        ctx.send_from_listener("127.0.0.1", peer_addr.port(), b"uncaptured")
+            .unwrap();
+        ctx.shutdown();
+
+        assert_eq!(
+            *CAPTURED.lock().unwrap(),
+            [
+                (CAPTURE_IN, peer_addr, listener, b"garbage".to_vec()),
+                (CAPTURE_OUT, listener, peer_addr, b"OPTIONS".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Per-context tunables. Most of them are read when the listener starts, so setters
+//! generally refuse to run while it is active.
+
+use crate::builder::MessageDefaults;
+use crate::tls::TLS_VERSION_1_2;
+use crate::workers::Backpressure;
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Smallest datagram every IPv4 host must be able to receive (RFC 791).
+pub const MIN_RECV_BUFFER_SIZE: usize = 576;
+/// Upper bound for the receive buffer; leaves room for jumbo frames without letting a
+/// typo allocate gigabytes.
+pub const MAX_RECV_BUFFER_SIZE: usize = 1 << 20;
+pub const DEFAULT_RECV_BUFFER_SIZE: usize = 65535;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub recv_buffer_size: usize,
+    /// Address `start_udp_listener(port)` binds to; `0.0.0.0` unless configured.
+    pub bind_ip: IpAddr,
+    /// Bind unspecified addresses as a single IPv6 socket that also accepts IPv4.
+    pub dual_stack: bool,
+    /// `SO_REUSEADDR` on the UDP listener socket.
+    pub reuse_addr: bool,
+    /// `SO_REUSEPORT` on the UDP listener socket (Unix only).
+    pub reuse_port: bool,
+    /// Lowest TLS version (wire value) accepted by the next TLS listener.
+    pub tls_min_version: u16,
+    /// Extra certificates served by SNI name (lowercase).
+    pub tls_sni_certs: HashMap<String, Arc<CertifiedKey>>,
+    /// Worker threads running the receive pipeline; 0 runs it on the listener threads.
+    pub worker_threads: usize,
+    pub backpressure: Backpressure,
+    /// Interval of the UDP listener's `tick` event; `None` (the default) disables it.
+    pub tick_interval: Option<Duration>,
+    /// Via sent-by host and port for generated requests instead of the listener
+    /// address; port 0 keeps the listener port.
+    pub via_sentby: Option<(String, u16)>,
+    /// DSCP marked on UDP listener and sender sockets; `None` leaves the OS default.
+    pub dscp: Option<u8>,
+    /// Messages above this many bytes are dropped (`too_large`); 0 disables the check.
+    pub max_request_bytes: usize,
+    /// Answer rejected requests with 513 Message Too Large.
+    pub respond_too_large: bool,
+    /// Messages with more header lines are dropped unparsed; 0 disables the check.
+    pub max_headers: usize,
+    /// Answer received INVITEs with 100 Trying before handing them to the host.
+    pub auto_trying: bool,
+    /// Kernel receive timestamps on UDP listener sockets (`SO_TIMESTAMPNS`).
+    pub rx_timestamps: bool,
+    /// Stream framing also ends the header section at an empty line ending in a bare LF.
+    pub lenient_line_endings: bool,
+    /// Answer CRLFCRLF keep-alive pings on stream connections with a CRLF pong.
+    pub keepalive_pong: bool,
+    /// Pooled outbound TCP connections without traffic for this long are closed.
+    pub tcp_idle_timeout: Option<Duration>,
+    pub message_defaults: MessageDefaults,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            recv_buffer_size: DEFAULT_RECV_BUFFER_SIZE,
+            bind_ip: Ipv4Addr::UNSPECIFIED.into(),
+            dual_stack: false,
+            reuse_addr: false,
+            reuse_port: false,
+            tls_min_version: TLS_VERSION_1_2,
+            tls_sni_certs: HashMap::new(),
+            worker_threads: 0,
+            backpressure: Backpressure::Drop,
+            tick_interval: None,
+            via_sentby: None,
+            dscp: None,
+            max_request_bytes: 0,
+            respond_too_large: false,
+            max_headers: 0,
+            auto_trying: false,
+            rx_timestamps: false,
+            lenient_line_endings: false,
+            keepalive_pong: true,
+            tcp_idle_timeout: Some(Duration::from_secs(60)),
+            message_defaults: MessageDefaults::default(),
+        }
+    }
+}
@@ -0,0 +1,985 @@
+use crate::builder::{sentby_host, MessageDefaults};
+use crate::capture::{CaptureCallback, CAPTURE_IN};
+use crate::config::{Config, MAX_RECV_BUFFER_SIZE, MIN_RECV_BUFFER_SIZE};
+use crate::dialog::Dialogs;
+use crate::error::{to_code, RsipError};
+use crate::events::{EventBus, Sink};
+use crate::ffi::{str_arg, write_to_buf};
+use crate::ipfilter::IpFilter;
+use crate::lock::Lock;
+use crate::log::{LogCallback, LogLevel};
+use crate::outbound::Outbound;
+use crate::parsecache::ParseCache;
+use crate::ping::Ping;
+use crate::pool::PooledConnection;
+use crate::ratelimit::RateLimiter;
+use crate::register::{Registration, Wakeup};
+use crate::send::{
+    enable_unreachable_errors, is_unreachable, mark_dscp, send_args, DSCP_SUPPORTED, MAX_DSCP,
+};
+use crate::session::Sessions;
+use crate::stats::Stats;
+use crate::stream::{is_timeout, StreamListener};
+use crate::timer::Timers;
+use crate::timestamp::{enable_rx_timestamps, recv_timestamped};
+use crate::transaction::Transaction;
+use crate::transport::TransportSendCallback;
+use crate::workers::WorkerPool;
+use crate::ws::WsWriter;
+use serde_json::json;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How often `drain` re-checks for running callbacks.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// A UDP listener in the registry: its socket and the thread reading it.
+pub(crate) struct ListenerState {
+    pub socket: Arc<UdpSocket>,
+    /// Cleared to stop just this listener; `running` stops them all.
+    listening: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub type EventCallback = extern "C" fn(event: *const c_char, payload: *const c_char);
+
+/// An independent SIP stack: owns its sockets, listener threads, running flag and callback.
+///
+/// The C side only ever sees an opaque `*mut RsipContext` obtained from `rsip_context_new`.
+/// Internally the handle is an `Arc` so the listener thread can keep the context alive
+/// while it is running.
+pub struct RsipContext {
+    pub(crate) events: EventBus,
+    /// UDP listeners by id; see `add_udp_listener_on`.
+    pub(crate) udp_listeners: Mutex<HashMap<u64, ListenerState>>,
+    pub(crate) next_listener_id: AtomicU64,
+    pub(crate) running: AtomicBool,
+    pub(crate) config: Mutex<Config>,
+    /// Outstanding client requests, keyed by `transaction_key`, waiting for their response.
+    pub(crate) waiters: Mutex<HashMap<String, Sender<Wakeup>>>,
+    pub(crate) registrations: Mutex<HashMap<u64, Registration>>,
+    pub(crate) next_registration_id: AtomicU64,
+    pub(crate) pings: Mutex<HashMap<u64, Ping>>,
+    pub(crate) next_ping_id: AtomicU64,
+    pub(crate) transactions: Mutex<HashMap<u64, Transaction>>,
+    pub(crate) next_transaction_id: AtomicU64,
+    pub(crate) tcp_listener: Mutex<Option<StreamListener>>,
+    pub(crate) tls_listener: Mutex<Option<StreamListener>>,
+    pub(crate) ws_listener: Mutex<Option<StreamListener>>,
+    /// Upgraded WebSocket clients by peer address, for `send_ws`.
+    pub(crate) ws_connections: Mutex<HashMap<SocketAddr, WsWriter>>,
+    pub(crate) log_callback: Mutex<Option<LogCallback>>,
+    pub(crate) log_level: AtomicI32,
+    pub(crate) stats: Stats,
+    pub(crate) workers: Mutex<Option<WorkerPool>>,
+    /// Messages enqueued for the workers but not yet picked up.
+    pub(crate) queued: AtomicUsize,
+    pub(crate) rate_limiter: Mutex<Option<RateLimiter>>,
+    pub(crate) parse_cache: Mutex<Option<ParseCache>>,
+    pub(crate) ip_filter: Mutex<IpFilter>,
+    /// The `send_async` queue and thread, started on first use.
+    pub(crate) outbound: Mutex<Option<Outbound>>,
+    pub(crate) next_send_id: AtomicU64,
+    pub(crate) dialogs: Mutex<Dialogs>,
+    /// Where the listener socket last sent to, blamed for ICMP errors the platform
+    /// reports without an address.
+    pub(crate) last_listener_dest: Mutex<Option<SocketAddr>>,
+    pub(crate) sessions: Mutex<Sessions>,
+    pub(crate) timers: Arc<Timers>,
+    /// The host's transport, used instead of the UDP listener when registered.
+    pub(crate) transport: Mutex<Option<TransportSendCallback>>,
+    pub(crate) capture: Mutex<Option<CaptureCallback>>,
+    /// Outbound TCP connections by destination, for `send_tcp`.
+    pub(crate) tcp_pool: Mutex<HashMap<SocketAddr, PooledConnection>>,
+}
+
+impl RsipContext {
+    pub fn new() -> Self {
+        Self {
+            events: EventBus::default(),
+            udp_listeners: Mutex::new(HashMap::new()),
+            next_listener_id: AtomicU64::new(0),
+            running: AtomicBool::new(false),
+            config: Mutex::new(Config::default()),
+            waiters: Mutex::new(HashMap::new()),
+            registrations: Mutex::new(HashMap::new()),
+            next_registration_id: AtomicU64::new(0),
+            pings: Mutex::new(HashMap::new()),
+            next_ping_id: AtomicU64::new(0),
+            transactions: Mutex::new(HashMap::new()),
+            next_transaction_id: AtomicU64::new(0),
+            tcp_listener: Mutex::new(None),
+            tls_listener: Mutex::new(None),
+            ws_listener: Mutex::new(None),
+            ws_connections: Mutex::new(HashMap::new()),
+            log_callback: Mutex::new(None),
+            log_level: AtomicI32::new(LogLevel::Info as i32),
+            stats: Stats::default(),
+            workers: Mutex::new(None),
+            queued: AtomicUsize::new(0),
+            rate_limiter: Mutex::new(None),
+            parse_cache: Mutex::new(None),
+            ip_filter: Mutex::new(IpFilter::default()),
+            outbound: Mutex::new(None),
+            dialogs: Mutex::new(Dialogs::default()),
+            last_listener_dest: Mutex::new(None),
+            sessions: Mutex::new(Sessions::default()),
+            timers: Arc::default(),
+            transport: Mutex::new(None),
+            capture: Mutex::new(None),
+            tcp_pool: Mutex::new(HashMap::new()),
+            next_send_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets the catch-all callback, replacing any previous one.
+    pub fn set_callback(&self, cb: EventCallback) {
+        self.events.set_default(Sink::Basic(cb));
+    }
+
+    pub fn clear_callback(&self) {
+        self.events.clear_default(Sink::Basic(cb_placeholder));
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Sets the application receive buffer (and `SO_RCVBUF`) used by the next listener.
+    pub fn set_recv_buffer_size(&self, bytes: usize) -> Result<(), RsipError> {
+        if self.is_running() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        if !(MIN_RECV_BUFFER_SIZE..=MAX_RECV_BUFFER_SIZE).contains(&bytes) {
+            return Err(RsipError::InvalidArgument);
+        }
+        self.config.locked().recv_buffer_size = bytes;
+        Ok(())
+    }
+
+    /// Makes the next UDP listener emit `tick` every `ms` milliseconds, whether or not
+    /// messages arrive; 0 disables it (the default).
+    pub fn set_recv_timeout(&self, ms: u32) -> Result<(), RsipError> {
+        if self.is_running() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        self.config.locked().tick_interval =
+            Some(Duration::from_millis(u64::from(ms))).filter(|d| !d.is_zero());
+        Ok(())
+    }
+
+    /// Sets the address `start_udp_listener(port)` binds to, e.g. `::` for IPv6.
+    pub fn set_bind_address(&self, ip: &str) -> Result<(), RsipError> {
+        if self.is_running() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        let ip: IpAddr = ip.parse().map_err(|_| RsipError::InvalidArgument)?;
+        self.config.locked().bind_ip = ip;
+        Ok(())
+    }
+
+    /// When enabled, listeners on an unspecified address (`0.0.0.0` or `::`) bind one
+    /// IPv6 socket with `IPV6_V6ONLY` cleared so they accept both families.
+    pub fn set_dual_stack(&self, enabled: bool) -> Result<(), RsipError> {
+        if self.is_running() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        self.config.locked().dual_stack = enabled;
+        Ok(())
+    }
+
+    /// Advertises `host:port` in the Via of requests this context generates
+    /// (registrations, pings) instead of the listener address, e.g. the public address
+    /// of a NAT. Port 0 keeps the listener port; `None` goes back to the listener address.
+    pub fn set_via_sentby(&self, sentby: Option<(&str, u16)>) -> Result<(), RsipError> {
+        let sentby = match sentby {
+            Some((host, port)) => {
+                Some((sentby_host(host).ok_or(RsipError::InvalidArgument)?, port))
+            }
+            None => None,
+        };
+        self.config.locked().via_sentby = sentby;
+        Ok(())
+    }
+
+    /// The Via sent-by host and port for a request leaving from `local`.
+    pub(crate) fn via_sentby(&self, local: SocketAddr) -> (String, u16) {
+        match &self.config.locked().via_sentby {
+            Some((host, 0)) => (host.clone(), local.port()),
+            Some((host, port)) => (host.clone(), *port),
+            None => (local.ip().to_string(), local.port()),
+        }
+    }
+
+    /// Sets the Max-Forwards of requests this context builds (70 by default).
+    /// `InvalidArgument` for 0, which no request could be forwarded with.
+    pub fn set_default_max_forwards(&self, max_forwards: u8) -> Result<(), RsipError> {
+        if max_forwards == 0 {
+            return Err(RsipError::InvalidArgument);
+        }
+        self.config.locked().message_defaults.max_forwards = max_forwards;
+        Ok(())
+    }
+
+    /// Sets the User-Agent of requests and the Server of responses this context builds;
+    /// `None` (the default) leaves them out. `InvalidArgument` for an empty value or
+    /// one spanning lines.
+    pub fn set_user_agent(&self, user_agent: Option<&str>) -> Result<(), RsipError> {
+        let user_agent = match user_agent.map(str::trim) {
+            Some(ua) if ua.is_empty() || ua.contains(['\r', '\n']) => {
+                return Err(RsipError::InvalidArgument)
+            }
+            ua => ua.map(str::to_string),
+        };
+        self.config.locked().message_defaults.user_agent = user_agent;
+        Ok(())
+    }
+
+    /// Makes requests this context builds ask for responses on their source port
+    /// (RFC 3581) with an empty `rport` in their Via. Off by default.
+    pub fn set_use_rport(&self, enabled: bool) {
+        self.config.locked().message_defaults.rport = enabled;
+    }
+
+    /// Writes the header names of messages this context builds and serializes in their
+    /// compact form (`v`, `f`, `i`, ...), saving bytes on constrained links. Off by
+    /// default.
+    pub fn set_compact_headers(&self, enabled: bool) {
+        self.config.locked().message_defaults.compact = enabled;
+    }
+
+    /// What requests and responses this context builds get by default.
+    pub(crate) fn message_defaults(&self) -> MessageDefaults {
+        self.config.locked().message_defaults.clone()
+    }
+
+    /// Sets `SO_REUSEADDR` on the next UDP listener socket, so a restarted listener can
+    /// bind a port another socket still holds.
+    pub fn set_reuse_addr(&self, enabled: bool) -> Result<(), RsipError> {
+        if self.is_running() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        self.config.locked().reuse_addr = enabled;
+        Ok(())
+    }
+
+    /// Sets `SO_REUSEPORT` on the next UDP listener socket, so several processes can
+    /// listen on the same port. Unavailable (`InvalidArgument`) outside Unix.
+    pub fn set_reuse_port(&self, enabled: bool) -> Result<(), RsipError> {
+        if !REUSE_PORT_SUPPORTED {
+            return Err(RsipError::InvalidArgument);
+        }
+        if self.is_running() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        self.config.locked().reuse_port = enabled;
+        Ok(())
+    }
+
+    /// Marks every UDP packet sent from now on, by the listeners (including running
+    /// ones) and by one-shot sends, with `dscp`. `InvalidArgument` above `MAX_DSCP` or
+    /// where the platform cannot mark packets.
+    pub fn set_dscp(&self, dscp: u8) -> Result<(), RsipError> {
+        if dscp > MAX_DSCP || !DSCP_SUPPORTED {
+            return Err(RsipError::InvalidArgument);
+        }
+        self.config.locked().dscp = Some(dscp);
+        for listener in self.udp_listeners.locked().values() {
+            let ipv6 = listener
+                .socket
+                .local_addr()
+                .map_err(|_| RsipError::Io)?
+                .is_ipv6();
+            mark_dscp(&SockRef::from(&*listener.socket), ipv6, dscp).map_err(|_| RsipError::Io)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dscp(&self) -> Option<u8> {
+        self.config.locked().dscp
+    }
+
+    pub(crate) fn emit(&self, event: &str, payload: &str) {
+        self.events.emit(event, payload, None);
+    }
+
+    /// Emits an event tied to a peer, so `_ex` callbacks receive its address.
+    pub(crate) fn emit_from(&self, event: &str, payload: &str, src: SocketAddr) {
+        self.events.emit(event, payload, Some(src));
+    }
+
+    /// Emits an event tied to a peer whose `Bytes` callbacks receive `data` unchanged.
+    pub(crate) fn emit_bytes_from(&self, event: &str, payload: &str, data: &[u8], src: SocketAddr) {
+        self.events.emit_with_bytes(event, payload, data, Some(src));
+    }
+
+    /// Adds a UDP listener on `port` of the configured bind address; see
+    /// `add_udp_listener_on`.
+    pub fn start_udp_listener(self: &Arc<Self>, port: u16) -> Result<(), RsipError> {
+        self.add_udp_listener(port).map(|_| ())
+    }
+
+    pub fn start_udp_listener_on(self: &Arc<Self>, ip: &str, port: u16) -> Result<(), RsipError> {
+        self.add_udp_listener_on(ip, port).map(|_| ())
+    }
+
+    pub fn add_udp_listener(self: &Arc<Self>, port: u16) -> Result<u64, RsipError> {
+        let ip = self.config.locked().bind_ip;
+        self.add_udp_listener_on(&ip.to_string(), port)
+    }
+
+    /// Binds a UDP listener to exactly `ip:port`, next to any already running, and
+    /// returns its id (never 0). A malformed address and a failed bind are reported as
+    /// distinct `error` events (`invalid_addr:` vs `bind_err:`).
+    pub fn add_udp_listener_on(self: &Arc<Self>, ip: &str, port: u16) -> Result<u64, RsipError> {
+        let ip: IpAddr = match ip.parse() {
+            Ok(ip) => ip,
+            Err(_) => {
+                self.log(
+                    LogLevel::Error,
+                    format_args!("invalid listen address {}", ip),
+                );
+                self.emit("error", &format!("invalid_addr:{}", ip));
+                return Err(RsipError::InvalidArgument);
+            }
+        };
+        let config = self.config.locked().clone();
+        let socket = match bind_udp(SocketAddr::new(ip, port), &config) {
+            Ok(s) => s,
+            Err(e) => {
+                self.log(
+                    LogLevel::Error,
+                    format_args!("udp bind {}:{} failed: {}", ip, port, e),
+                );
+                self.emit("error", &format!("bind_err:{}", e));
+                return Err(RsipError::from_bind_error(&e));
+            }
+        };
+
+        let _ = socket.set_nonblocking(false);
+        let socket = Arc::new(socket);
+        if let Ok(addr) = socket.local_addr() {
+            self.log(LogLevel::Info, format_args!("udp listener on {}", addr));
+        }
+        let id = self.next_listener_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let listening = Arc::new(AtomicBool::new(true));
+        // Held until the listener is registered, so while `running` is set
+        // `local_addr` has an answer.
+        let mut listeners = self.udp_listeners.locked();
+        self.running.store(true, Ordering::SeqCst);
+        self.start_workers();
+        let handle = self.spawn_udp_reader(id, socket.clone(), listening.clone(), &config);
+        listeners.insert(
+            id,
+            ListenerState {
+                socket,
+                listening,
+                thread: Some(handle),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Starts the thread reading the socket of UDP listener `id` until `flag` or
+    /// `running` is cleared, first applying the socket options `config` sets at any
+    /// time (read timeout, receive buffer size).
+    fn spawn_udp_reader(
+        self: &Arc<Self>,
+        id: u64,
+        socket: Arc<UdpSocket>,
+        flag: Arc<AtomicBool>,
+        config: &Config,
+    ) -> JoinHandle<()> {
+        // Shutdown wakes the thread with a datagram; the timeout is the fallback for when
+        // that wake-up is lost (e.g. filtered), bounding how long `shutdown` can block.
+        let tick_interval = config.tick_interval;
+        let read_timeout = tick_interval.map_or(SHUTDOWN_POLL_INTERVAL, |tick| {
+            tick.min(SHUTDOWN_POLL_INTERVAL)
+        });
+        let _ = socket.set_read_timeout(Some(read_timeout));
+        let buffer_size = config.recv_buffer_size;
+        // Best effort: the kernel may clamp or round the requested size.
+        let _ = SockRef::from(&*socket).set_recv_buffer_size(buffer_size);
+
+        let ctx = self.clone();
+        thread::spawn(move || {
+            let lifecycle = json!({
+                "transport": "udp",
+                "addr": socket.local_addr().map(|a| a.to_string()).unwrap_or_default(),
+                "listener": id,
+            })
+            .to_string();
+            ctx.emit("listener_started", &lifecycle);
+            let local_ip = socket.local_addr().ok().map(|a| a.ip().to_canonical());
+            let mut buf = vec![0u8; buffer_size];
+            let mut last_tick = Instant::now();
+            while flag.load(Ordering::SeqCst) && ctx.running.load(Ordering::SeqCst) {
+                // Checked on every pass, so a steady stream of messages cannot hold
+                // ticks back.
+                if let Some(tick) = tick_interval {
+                    if last_tick.elapsed() >= tick {
+                        last_tick = Instant::now();
+                        ctx.emit("tick", &lifecycle);
+                    }
+                }
+                match recv_timestamped(&socket, &mut buf) {
+                    Ok((n, src, rx_ts_ns)) => {
+                        if n == 0 {
+                            continue;
+                        }
+                        // Report IPv4 peers of a dual-stack socket as plain IPv4.
+                        let src = SocketAddr::new(src.ip().to_canonical(), src.port());
+                        ctx.capture(CAPTURE_IN, &socket, src, &buf[..n], rx_ts_ns);
+                        if ctx.drop_martian(n, src, local_ip, id) {
+                            continue;
+                        }
+                        // recv_from silently drops whatever does not fit, so a full
+                        // buffer almost certainly means the datagram was cut short.
+                        if n == buf.len() {
+                            Stats::add(&ctx.stats.truncated, 1);
+                            let mut payload =
+                                json!({ "src": src.to_string(), "len": n, "listener": id });
+                            if let Some(ts) = rx_ts_ns {
+                                payload["rx_ts_ns"] = json!(ts);
+                            }
+                            ctx.emit_from("sip_rx_truncated", &payload.to_string(), src);
+                        }
+                        ctx.dispatch_on(&buf[..n], src, Some(id), rx_ts_ns);
+                    }
+                    Err(e) if is_timeout(&e) => continue,
+                    Err(e) if is_unreachable(&e) => ctx.report_unreachable(&socket, &e),
+                    Err(e) => {
+                        ctx.log(LogLevel::Error, format_args!("udp recv failed: {}", e));
+                        // Sleep a bit to avoid busy loop
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                }
+            }
+            ctx.emit("listener_stopped", &lifecycle);
+        })
+    }
+
+    /// Replaces the thread reading each UDP listener with a fresh one on the same
+    /// socket, e.g. on a configuration reload, without giving up the port or racing
+    /// another process to rebind it. The new thread starts before the old one is told to stop,
+    /// so the socket is never left unread; options applied when binding (address,
+    /// reuse, DSCP, timestamps) are kept. Waits for the old threads, except the
+    /// calling one (a callback), which stops once the callback returns. `NotRunning`
+    /// without UDP listeners.
+    pub fn restart_listeners(self: &Arc<Self>) -> Result<(), RsipError> {
+        let config = self.config.locked().clone();
+        let mut retired = Vec::new();
+        {
+            let mut listeners = self.udp_listeners.locked();
+            if listeners.is_empty() {
+                return Err(RsipError::NotRunning);
+            }
+            for (id, listener) in listeners.iter_mut() {
+                let listening = Arc::new(AtomicBool::new(true));
+                let socket = listener.socket.clone();
+                let handle = self.spawn_udp_reader(*id, socket, listening.clone(), &config);
+                let old = std::mem::replace(&mut listener.listening, listening);
+                old.store(false, Ordering::SeqCst);
+                retired.push((listener.socket.clone(), listener.thread.replace(handle)));
+            }
+        }
+        // Joined without the registry lock: the old threads may need it to finish
+        // handling a message.
+        for (socket, handle) in retired {
+            wake_listener(&socket);
+            if let Some(handle) = handle {
+                if handle.thread().id() != thread::current().id() {
+                    let _ = handle.join();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the UDP listener `id` and waits for its thread, unless called from that
+    /// thread (a callback), which then stops after the callback returns. Removing the
+    /// last listener stops the context running. False for an unknown id.
+    pub fn remove_listener(&self, id: u64) -> bool {
+        let mut listeners = self.udp_listeners.locked();
+        let mut listener = match listeners.remove(&id) {
+            Some(listener) => listener,
+            None => return false,
+        };
+        if listeners.is_empty() {
+            self.running.store(false, Ordering::SeqCst);
+        }
+        drop(listeners);
+        listener.listening.store(false, Ordering::SeqCst);
+        wake_listener(&listener.socket);
+        if let Some(handle) = listener.thread.take() {
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+        true
+    }
+
+    /// The socket of the first UDP listener still running, which sends from the
+    /// listener go out of.
+    pub(crate) fn udp_socket(&self) -> Option<Arc<UdpSocket>> {
+        let listeners = self.udp_listeners.locked();
+        let first = listeners.keys().min()?;
+        Some(listeners[first].socket.clone())
+    }
+
+    pub(crate) fn listener_socket(&self, id: u64) -> Option<Arc<UdpSocket>> {
+        let listeners = self.udp_listeners.locked();
+        listeners.get(&id).map(|listener| listener.socket.clone())
+    }
+
+    /// The address the (first) listener socket is actually bound to, if one is running.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.udp_socket().and_then(|s| s.local_addr().ok())
+    }
+
+    /// The first UDP listener's bound port, or 0 if none is running.
+    pub fn listener_port(&self) -> u16 {
+        if !self.is_running() {
+            return 0;
+        }
+        self.local_addr().map_or(0, |addr| addr.port())
+    }
+
+    /// Brings the context back to its initial state: whatever runs is shut down and
+    /// every callback removed, as by `shutdown`. Configuration (bind address, buffer
+    /// sizes, filters, ...) is kept. Safe to call at any time and repeatedly.
+    pub fn init(&self) {
+        self.shutdown();
+    }
+
+    /// Unregisters every registration, stops every listener, joins their threads,
+    /// finishes queued async sends and removes all event callbacks. Safe to call when
+    /// nothing was started, after a failed start and repeatedly; the context can be
+    /// started again afterwards.
+    pub fn shutdown(&self) {
+        self.stop_client_work();
+        self.stop_receiving();
+        self.join_listeners();
+        self.stop_workers();
+        self.stop_sender();
+        self.events.clear();
+        *self.dialogs.locked() = Dialogs::default();
+        *self.last_listener_dest.locked() = None;
+        *self.sessions.locked() = Sessions::default();
+        self.ws_connections.locked().clear();
+        self.close_tcp_pool();
+        self.log(LogLevel::Debug, format_args!("shut down"));
+    }
+
+    /// Stops reading new messages, waits up to `timeout` for the callbacks already
+    /// running (and messages queued for workers) to finish, then shuts down. Returns
+    /// false if callbacks were still running at the deadline: their threads are then left
+    /// to finish on their own, and no further events are delivered.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        self.stop_client_work();
+        self.stop_receiving();
+        let busy = || self.events.in_flight() > 0 || self.queued_messages() > 0;
+        while busy() && Instant::now() < deadline {
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+        let drained = !busy();
+        if !drained {
+            self.log(
+                LogLevel::Warn,
+                format_args!(
+                    "drain timed out with {} callbacks running",
+                    self.events.in_flight()
+                ),
+            );
+        }
+        if drained {
+            self.join_listeners();
+            self.stop_workers();
+            self.stop_sender();
+            self.events.clear();
+        } else {
+            self.events.clear();
+            // Dropping the handles detaches the threads stuck in a callback.
+            self.udp_listeners.locked().clear();
+            self.tcp_listener.locked().take();
+            self.tls_listener.locked().take();
+            self.ws_listener.locked().take();
+            // Closing the queues lets idle workers exit; the rest follow once their
+            // callback returns, finding no subscribers left.
+            self.workers.locked().take();
+            self.outbound.locked().take();
+        }
+        drained
+    }
+
+    /// Ends registrations (sending their Expires: 0, which needs the listener still
+    /// reading), stops OPTIONS pings, abandons client transactions and disarms timers.
+    fn stop_client_work(&self) {
+        let registrations: Vec<Registration> = self
+            .registrations
+            .locked()
+            .drain()
+            .map(|(_, r)| r)
+            .collect();
+        for registration in registrations {
+            registration.stop();
+        }
+        let pings: Vec<Ping> = self.pings.locked().drain().map(|(_, p)| p).collect();
+        for ping in pings {
+            ping.stop();
+        }
+        let transactions: Vec<Transaction> =
+            self.transactions.locked().drain().map(|(_, t)| t).collect();
+        for transaction in transactions {
+            transaction.stop();
+        }
+        self.stop_timers();
+    }
+
+    /// Tells every listener to stop reading; messages being handled run to completion.
+    fn stop_receiving(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        for listener in self.udp_listeners.locked().values() {
+            wake_listener(&listener.socket);
+        }
+        for slot in [&self.tcp_listener, &self.tls_listener, &self.ws_listener].iter() {
+            if let Some(listener) = slot.locked().as_ref() {
+                listener.halt();
+            }
+        }
+    }
+
+    fn join_listeners(&self) {
+        let listeners: Vec<ListenerState> = self
+            .udp_listeners
+            .locked()
+            .drain()
+            .map(|(_, l)| l)
+            .collect();
+        for mut listener in listeners {
+            if let Some(handle) = listener.thread.take() {
+                let _ = handle.join();
+            }
+        }
+        for slot in [&self.tcp_listener, &self.tls_listener, &self.ws_listener].iter() {
+            let listener = slot.locked().take();
+            if let Some(listener) = listener {
+                listener.stop();
+            }
+        }
+    }
+}
+
+impl Default for RsipContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `SO_REUSEPORT` can be set on this platform.
+const REUSE_PORT_SUPPORTED: bool = cfg!(all(
+    unix,
+    not(any(
+        target_os = "solaris",
+        target_os = "illumos",
+        target_os = "cygwin"
+    ))
+));
+
+/// Where socket2 has no `set_reuse_port` (see `REUSE_PORT_SUPPORTED`), calls resolve to
+/// this instead of its inherent method, so they compile on every platform.
+#[allow(dead_code)] // never called where the inherent method exists
+trait NoReusePort {
+    fn set_reuse_port(&self, _: bool) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl NoReusePort for Socket {}
+
+// Binds `addr`, turning an unspecified address into a dual-stack `[::]` socket when
+// requested. IPv6 sockets always set IPV6_V6ONLY explicitly since the OS default varies.
+fn bind_udp(addr: SocketAddr, config: &Config) -> io::Result<UdpSocket> {
+    let dual_stack = config.dual_stack;
+    let addr = if dual_stack && addr.ip().is_unspecified() {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), addr.port())
+    } else {
+        addr
+    };
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    if config.reuse_addr {
+        socket.set_reuse_address(true)?;
+    }
+    if REUSE_PORT_SUPPORTED && config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    enable_unreachable_errors(&socket, addr.is_ipv6())?;
+    if let Some(dscp) = config.dscp {
+        mark_dscp(&socket, addr.is_ipv6(), dscp)?;
+    }
+    if config.rx_timestamps {
+        enable_rx_timestamps(&socket, true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+// Only the discriminant matters when clearing a default callback.
+extern "C" fn cb_placeholder(_: *const c_char, _: *const c_char) {}
+
+// The recv thread blocks in `recv_from`; poke it with an empty datagram so it
+// re-checks the running flag instead of waiting for real traffic.
+fn wake_listener(socket: &UdpSocket) {
+    let mut addr = match socket.local_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    if addr.ip().is_unspecified() {
+        let loopback: IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        };
+        addr.set_ip(loopback);
+    }
+    let _ = socket.send_to(&[], addr);
+}
+
+/// Borrows the `Arc` behind a raw context handle without touching its refcount.
+pub(crate) fn with_context<R>(
+    ctx: *mut RsipContext,
+    f: impl FnOnce(&Arc<RsipContext>) -> R,
+) -> Option<R> {
+    if ctx.is_null() {
+        return None;
+    }
+    let ctx = ManuallyDrop::new(unsafe { Arc::from_raw(ctx as *const RsipContext) });
+    Some(f(&ctx))
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_new() -> *mut RsipContext {
+    Arc::into_raw(Arc::new(RsipContext::new())) as *mut RsipContext
+}
+
+/// Shuts down the context and releases the handle. Passing NULL is a no-op.
+#[no_mangle]
+pub extern "C" fn rsip_context_free(ctx: *mut RsipContext) {
+    if ctx.is_null() {
+        return;
+    }
+    let ctx = unsafe { Arc::from_raw(ctx as *const RsipContext) };
+    ctx.shutdown();
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_event_callback(ctx: *mut RsipContext, cb: EventCallback) {
+    with_context(ctx, |ctx| ctx.set_callback(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_clear_event_callback(ctx: *mut RsipContext) {
+    with_context(ctx, |ctx| ctx.clear_callback());
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_udp_listener(ctx: *mut RsipContext, port: u16) -> bool {
+    rsip_context_start_udp_listener_ex(ctx, port) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_udp_listener_ex(ctx: *mut RsipContext, port: u16) -> i32 {
+    with_context(ctx, |ctx| to_code(ctx.start_udp_listener(port)))
+        .unwrap_or_else(|| RsipError::InvalidArgument.code())
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_udp_listener_on(
+    ctx: *mut RsipContext,
+    ip: *const c_char,
+    port: u16,
+) -> bool {
+    rsip_context_start_udp_listener_on_ex(ctx, ip, port) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_udp_listener_on_ex(
+    ctx: *mut RsipContext,
+    ip: *const c_char,
+    port: u16,
+) -> i32 {
+    let ip = match str_arg(ip) {
+        Some(ip) => ip,
+        None => return RsipError::InvalidArgument.code(),
+    };
+    with_context(ctx, |ctx| to_code(ctx.start_udp_listener_on(ip, port)))
+        .unwrap_or_else(|| RsipError::InvalidArgument.code())
+}
+
+/// Adds a UDP listener on `port` and returns its id; 0 if the bind fails or `ctx` is
+/// NULL.
+#[no_mangle]
+pub extern "C" fn rsip_context_add_udp_listener(ctx: *mut RsipContext, port: u16) -> u64 {
+    with_context(ctx, |ctx| ctx.add_udp_listener(port).unwrap_or(0)).unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_remove_listener(ctx: *mut RsipContext, id: u64) -> bool {
+    with_context(ctx, |ctx| ctx.remove_listener(id)).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_restart_listener(ctx: *mut RsipContext) -> bool {
+    with_context(ctx, |ctx| ctx.restart_listeners().is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_is_running(ctx: *mut RsipContext) -> bool {
+    with_context(ctx, |ctx| ctx.is_running()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_listener_port(ctx: *mut RsipContext) -> u16 {
+    with_context(ctx, |ctx| ctx.listener_port()).unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_listener_local_addr(
+    ctx: *mut RsipContext,
+    buf: *mut c_char,
+    len: usize,
+) -> bool {
+    with_context(ctx, |ctx| match ctx.local_addr() {
+        Some(addr) => write_to_buf(&addr.to_string(), buf, len),
+        None => false,
+    })
+    .unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_recv_buffer_size(ctx: *mut RsipContext, bytes: usize) -> bool {
+    with_context(ctx, |ctx| ctx.set_recv_buffer_size(bytes).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_recv_timeout_ms(ctx: *mut RsipContext, ms: u32) -> bool {
+    with_context(ctx, |ctx| ctx.set_recv_timeout(ms).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_bind_address(ctx: *mut RsipContext, ip: *const c_char) -> bool {
+    match str_arg(ip) {
+        Some(ip) => with_context(ctx, |ctx| ctx.set_bind_address(ip).is_ok()).unwrap_or(false),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_reuse_addr(ctx: *mut RsipContext, enabled: bool) -> bool {
+    with_context(ctx, |ctx| ctx.set_reuse_addr(enabled).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_reuse_port(ctx: *mut RsipContext, enabled: bool) -> bool {
+    with_context(ctx, |ctx| ctx.set_reuse_port(enabled).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_dscp(ctx: *mut RsipContext, value: u8) -> bool {
+    with_context(ctx, |ctx| ctx.set_dscp(value).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_via_sentby(
+    ctx: *mut RsipContext,
+    host: *const c_char,
+    port: u16,
+) -> bool {
+    let sentby = str_arg(host).map(|host| (host, port));
+    with_context(ctx, |ctx| ctx.set_via_sentby(sentby).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_default_max_forwards(
+    ctx: *mut RsipContext,
+    max_forwards: u8,
+) -> bool {
+    with_context(ctx, |ctx| {
+        ctx.set_default_max_forwards(max_forwards).is_ok()
+    })
+    .unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_user_agent(
+    ctx: *mut RsipContext,
+    user_agent: *const c_char,
+) -> bool {
+    let user_agent = str_arg(user_agent);
+    with_context(ctx, |ctx| ctx.set_user_agent(user_agent).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_use_rport(ctx: *mut RsipContext, enabled: bool) {
+    with_context(ctx, |ctx| ctx.set_use_rport(enabled));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_compact_headers(ctx: *mut RsipContext, enabled: bool) {
+    with_context(ctx, |ctx| ctx.set_compact_headers(enabled));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_dual_stack(ctx: *mut RsipContext, enabled: bool) -> bool {
+    with_context(ctx, |ctx| ctx.set_dual_stack(enabled).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_send_udp_from_listener(
+    ctx: *mut RsipContext,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> bool {
+    rsip_context_send_udp_from_listener_ex(ctx, dest_ip, dest_port, data) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_send_udp_from_listener_ex(
+    ctx: *mut RsipContext,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> i32 {
+    let result = send_args(dest_ip, data).and_then(|(ip, payload)| {
+        with_context(ctx, |ctx| ctx.send_from_listener(ip, dest_port, payload))
+            .unwrap_or(Err(RsipError::InvalidArgument))
+    });
+    to_code(result)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_shutdown(ctx: *mut RsipContext) {
+    with_context(ctx, |ctx| ctx.shutdown());
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_drain(ctx: *mut RsipContext, timeout_ms: u32) -> bool {
+    with_context(ctx, |ctx| {
+        ctx.drain(Duration::from_millis(u64::from(timeout_ms)))
+    })
+    .unwrap_or(false)
+}
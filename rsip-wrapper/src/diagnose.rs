@@ -0,0 +1,451 @@
+//! Explains why a message failed to parse: where, in which header, and what kind of
+//! problem, for diagnosing bad peer traffic. rsip's own errors only name the tokenizer
+//! that gave up, so the message is re-scanned against the grammar rsip implements.
+//! Runs only after a failed parse and never panics, whatever the input.
+
+use crate::ffi::into_c_string;
+use crate::parse::parse_message;
+use crate::raw::normalize_headers;
+use rsip::{Method, SipMessage, Uri, Version};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::str::FromStr;
+
+/// What went wrong, reported as its snake_case name. Part of the API: only append.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    Empty,
+    /// The input is not valid UTF-8.
+    InvalidUtf8,
+    /// The start line does not end in CRLF.
+    TruncatedStartLine,
+    /// The start line does not have three space-separated parts.
+    BadStartLine,
+    BadMethod,
+    BadUri,
+    BadVersion,
+    BadStatusCode,
+    /// There are no headers, or the last header line does not end in CRLF.
+    TruncatedHeaders,
+    /// A continuation line with no header before it.
+    BadFolding,
+    MissingColon,
+    BadHeaderName,
+    /// rsip rejected the message but the scan found nothing wrong.
+    Other,
+}
+
+impl ErrorCategory {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::InvalidUtf8 => "invalid_utf8",
+            Self::TruncatedStartLine => "truncated_start_line",
+            Self::BadStartLine => "bad_start_line",
+            Self::BadMethod => "bad_method",
+            Self::BadUri => "bad_uri",
+            Self::BadVersion => "bad_version",
+            Self::BadStatusCode => "bad_status_code",
+            Self::TruncatedHeaders => "truncated_headers",
+            Self::BadFolding => "bad_folding",
+            Self::MissingColon => "missing_colon",
+            Self::BadHeaderName => "bad_header_name",
+            Self::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub category: ErrorCategory,
+    /// Byte offset of the problem in the input; `None` for `Other` and problems only
+    /// found after header normalization.
+    pub offset: Option<usize>,
+    /// The header being parsed, as named in the input.
+    pub header: Option<String>,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(category: ErrorCategory, offset: usize, message: impl Into<String>) -> Self {
+        Self {
+            category,
+            offset: Some(offset),
+            header: None,
+            message: message.into(),
+        }
+    }
+
+    fn in_header(mut self, name: &[u8]) -> Self {
+        self.header = Some(String::from_utf8_lossy(name).into_owned());
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "category": self.category.as_str(),
+            "offset": self.offset,
+            "header": self.header,
+            "message": self.message,
+        })
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<ParseError>> = const { RefCell::new(None) };
+}
+
+/// Records the outcome of a parse on this thread for `rsip_last_parse_error`.
+fn set_last_error(error: Option<ParseError>) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = error);
+}
+
+/// `parse_message` for `rsip_message_parse`: a NULL or non-UTF-8 `raw` fails too, and
+/// the outcome is recorded for `rsip_last_parse_error`.
+pub(crate) fn parse_recording(raw: *const c_char) -> Option<SipMessage> {
+    let result = if raw.is_null() {
+        Err(ParseError::new(ErrorCategory::Empty, 0, "NULL message"))
+    } else {
+        let data = unsafe { CStr::from_ptr(raw) }.to_bytes();
+        match std::str::from_utf8(data) {
+            Ok(_) => parse_message(data).map_err(|e| diagnose(data, &e.to_string())),
+            Err(e) => Err(ParseError::new(
+                ErrorCategory::InvalidUtf8,
+                e.valid_up_to(),
+                "message is not UTF-8",
+            )),
+        }
+    };
+    match result {
+        Ok(msg) => {
+            set_last_error(None);
+            Some(msg)
+        }
+        Err(error) => {
+            set_last_error(Some(error));
+            None
+        }
+    }
+}
+
+/// RFC 3261 token characters, as rsip accepts them in header names.
+pub(crate) fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-.!%*_+`'~".contains(&b)
+}
+
+/// The line starting at `from`: `(content end, start of next line)`. Like rsip, only
+/// CRLF ends a line; `None` if there is none.
+fn crlf_line(data: &[u8], from: usize) -> Option<(usize, usize)> {
+    let rest = data.get(from..)?;
+    let i = rest.windows(2).position(|w| w == b"\r\n")?;
+    Some((from + i, from + i + 2))
+}
+
+/// The error for a line at `from` without CRLF, pointing at a bare LF if there is one.
+fn unterminated(data: &[u8], from: usize, category: ErrorCategory, what: &str) -> ParseError {
+    match data[from..].iter().position(|b| *b == b'\n') {
+        Some(i) => ParseError::new(category, from + i, format!("{} ends in a bare LF", what)),
+        None => ParseError::new(category, data.len(), format!("{} not terminated", what)),
+    }
+}
+
+fn check_start_line(line: &str) -> Result<(), ParseError> {
+    let parts: Vec<&str> = line.splitn(3, ' ').collect();
+    if parts.len() != 3 {
+        return Err(ParseError::new(
+            ErrorCategory::BadStartLine,
+            0,
+            "expected three space-separated parts",
+        ));
+    }
+    let offset_of = |i: usize| parts[..i].iter().map(|p| p.len() + 1).sum::<usize>();
+    let check_version = |i: usize| {
+        // rsip's tokenizer stops after the version; whatever follows is not allowed.
+        Version::try_from(parts[i].as_bytes())
+            .ok()
+            .filter(|version| version.to_string() == parts[i])
+            .map(|_| ())
+            .ok_or_else(|| {
+                ParseError::new(
+                    ErrorCategory::BadVersion,
+                    offset_of(i),
+                    format!("unsupported version: {}", parts[i]),
+                )
+            })
+    };
+
+    if parts[0].starts_with("SIP/") {
+        check_version(0)?;
+        let code = parts[1];
+        if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) || code < "100" {
+            return Err(ParseError::new(
+                ErrorCategory::BadStatusCode,
+                offset_of(1),
+                format!("invalid status code: {}", code),
+            ));
+        }
+        return Ok(());
+    }
+    if Method::from_str(parts[0]).is_err() {
+        return Err(ParseError::new(
+            ErrorCategory::BadMethod,
+            0,
+            format!("unknown method: {}", parts[0]),
+        ));
+    }
+    if Uri::try_from(parts[1]).is_err() {
+        return Err(ParseError::new(
+            ErrorCategory::BadUri,
+            offset_of(1),
+            format!("invalid Request-URI: {}", parts[1]),
+        ));
+    }
+    // The URI must not contain spaces, so anything after it belongs to the version.
+    check_version(2)
+}
+
+/// Finds the first problem in `data` that makes rsip (after header normalization)
+/// reject it. `rsip_error` is rsip's message, used when the scan finds nothing.
+pub(crate) fn diagnose(data: &[u8], rsip_error: &str) -> ParseError {
+    if let Err(error) = scan(data) {
+        return error;
+    }
+    // Normalization splits lines at bare LFs too, which can expose a problem only in
+    // the rewritten message; its offsets would not match the input.
+    if let Some(Err(error)) = normalize_headers(data).map(|normalized| scan(&normalized)) {
+        return ParseError {
+            offset: None,
+            message: format!("{} (after unfolding headers)", error.message),
+            ..error
+        };
+    }
+    ParseError {
+        category: ErrorCategory::Other,
+        offset: None,
+        header: None,
+        message: rsip_error.to_string(),
+    }
+}
+
+fn scan(data: &[u8]) -> Result<(), ParseError> {
+    if data.is_empty() {
+        return Err(ParseError::new(ErrorCategory::Empty, 0, "empty message"));
+    }
+    let (start_end, mut pos) = crlf_line(data, 0)
+        .ok_or_else(|| unterminated(data, 0, ErrorCategory::TruncatedStartLine, "start line"))?;
+    let start_line = std::str::from_utf8(&data[..start_end]).map_err(|e| {
+        ParseError::new(
+            ErrorCategory::InvalidUtf8,
+            e.valid_up_to(),
+            "start line is not UTF-8",
+        )
+    })?;
+    check_start_line(start_line)?;
+
+    let mut seen_header = false;
+    loop {
+        // rsip accepts a header section ending without the empty line.
+        if pos == data.len() && seen_header {
+            return Ok(());
+        }
+        if pos == data.len() {
+            return Err(ParseError::new(
+                ErrorCategory::TruncatedHeaders,
+                pos,
+                "no headers",
+            ));
+        }
+        let (end, next) = crlf_line(data, pos).ok_or_else(|| {
+            unterminated(data, pos, ErrorCategory::TruncatedHeaders, "header line")
+        })?;
+        let line = &data[pos..end];
+        if line.is_empty() {
+            return Ok(());
+        }
+        if line[0] == b' ' || line[0] == b'\t' {
+            if !seen_header {
+                return Err(ParseError::new(
+                    ErrorCategory::BadFolding,
+                    pos,
+                    "continuation line before the first header",
+                ));
+            }
+        } else {
+            let colon = line.iter().position(|b| *b == b':').ok_or_else(|| {
+                ParseError::new(ErrorCategory::MissingColon, pos, "header line has no colon")
+            })?;
+            let name = &line[..colon];
+            if name.is_empty() {
+                return Err(ParseError::new(
+                    ErrorCategory::BadHeaderName,
+                    pos,
+                    "empty header name",
+                ));
+            }
+            if let Some(bad) = name.iter().position(|b| !is_token(*b)) {
+                return Err(ParseError::new(
+                    ErrorCategory::BadHeaderName,
+                    pos + bad,
+                    format!("invalid character {:?} in header name", name[bad] as char),
+                )
+                .in_header(name));
+            }
+            if let Err(e) = std::str::from_utf8(&line[colon + 1..]) {
+                return Err(ParseError::new(
+                    ErrorCategory::InvalidUtf8,
+                    pos + colon + 1 + e.valid_up_to(),
+                    "header value is not UTF-8",
+                )
+                .in_header(name));
+            }
+            seen_header = true;
+        }
+        pos = next;
+    }
+}
+
+/// Why the last `rsip_message_parse` on this thread failed, as JSON {category, offset,
+/// header, message}, or NULL if it succeeded (or none ran yet). Caller-owned.
+#[no_mangle]
+pub extern "C" fn rsip_last_parse_error() -> *mut c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(error) => into_c_string(error.to_json().to_string()),
+        None => std::ptr::null_mut(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{rsip_message_free, rsip_message_parse};
+    use crate::parse::parse_message;
+    use std::ffi::CString;
+
+    const INVITE: &[u8] = b"INVITE sip:bob@example.com SIP/2.0\r\n\
+        Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bK776\r\n\
+        f: <sip:alice@example.com>;tag=1928\r\n\
+        To: <sip:bob@example.com>\r\n\
+        Call-ID: a84b4c76e66710\r\n\
+        CSeq: 1 INVITE\r\n\r\n";
+
+    fn category(data: &[u8]) -> (ErrorCategory, Option<usize>, Option<String>) {
+        let error = diagnose(data, "rsip");
+        (error.category, error.offset, error.header)
+    }
+
+    #[test]
+    fn locates_problems() {
+        use ErrorCategory::*;
+        assert_eq!(category(b""), (Empty, Some(0), None));
+        assert_eq!(
+            category(b"INVITE sip:a@b"),
+            (TruncatedStartLine, Some(14), None)
+        );
+        assert_eq!(
+            category(b"INVITE sip:a@b SIP/2.0\n"),
+            (TruncatedStartLine, Some(22), None)
+        );
+        assert_eq!(category(b"INVITE\r\n\r\n"), (BadStartLine, Some(0), None));
+        assert_eq!(category(b"FETCH sip:a@b SIP/2.0\r\n\r\n").0, BadMethod);
+        assert_eq!(
+            category(b"INVITE sip:[::1 SIP/2.0\r\n\r\n"),
+            (BadUri, Some(7), None)
+        );
+        assert_eq!(
+            category(b"INVITE sip:a@b SIP/3.0\r\n\r\n"),
+            (BadVersion, Some(15), None)
+        );
+        assert_eq!(
+            category(b"SIP/2.0 2000 OK\r\n\r\n"),
+            (BadStatusCode, Some(8), None)
+        );
+        assert_eq!(
+            category(b"SIP/2.0 200 OK\r\nVia: x"),
+            (TruncatedHeaders, Some(22), None)
+        );
+        assert_eq!(
+            category(b"SIP/2.0 200 OK\r\n"),
+            (TruncatedHeaders, Some(16), None)
+        );
+        assert_eq!(
+            category(b"SIP/2.0 200 OK\r\n x\r\n\r\n"),
+            (BadFolding, Some(16), None)
+        );
+        assert_eq!(
+            category(b"SIP/2.0 200 OK\r\nVia x\r\n\r\n"),
+            (MissingColon, Some(16), None)
+        );
+        assert_eq!(
+            category(b"SIP/2.0 200 OK\r\nVia : x\r\n\r\n"),
+            (BadHeaderName, Some(19), Some("Via ".into()))
+        );
+        assert_eq!(
+            category(b"SIP/2.0 200 OK\r\nv: \xff\r\n\r\n"),
+            (InvalidUtf8, Some(19), Some("v".into()))
+        );
+        let other = diagnose(INVITE, "rsip said no");
+        assert_eq!((other.category, other.offset), (Other, None));
+        assert_eq!(other.message, "rsip said no");
+    }
+
+    #[test]
+    fn last_error_follows_rsip_message_parse() {
+        let last = || {
+            let raw = rsip_last_parse_error();
+            (!raw.is_null()).then(|| {
+                let json = unsafe { CString::from_raw(raw) };
+                serde_json::from_str::<Value>(json.to_str().unwrap()).unwrap()
+            })
+        };
+        let bad = CString::new("SIP/2.0 200 OK\r\nVia x\r\n\r\n").unwrap();
+        assert!(rsip_message_parse(bad.as_ptr()).is_null());
+        assert_eq!(
+            last().unwrap(),
+            json!({"category": "missing_colon", "offset": 16, "header": null,
+                   "message": "header line has no colon"})
+        );
+        assert!(rsip_message_parse(std::ptr::null()).is_null());
+        assert_eq!(last().unwrap()["category"], "empty");
+
+        let good = CString::new(INVITE).unwrap();
+        let msg = rsip_message_parse(good.as_ptr());
+        assert!(!msg.is_null());
+        assert_eq!(last(), None);
+        rsip_message_free(msg);
+    }
+
+    #[test]
+    fn every_failing_mutation_is_explained() {
+        // Truncations and single-byte corruptions of a valid message: the scan must
+        // not panic, offsets stay within the input, and whatever rsip rejects gets a
+        // category.
+        let mut inputs: Vec<Vec<u8>> = (0..INVITE.len()).map(|n| INVITE[..n].to_vec()).collect();
+        for i in 0..INVITE.len() {
+            for b in [b'\0', b' ', b':', b'\r', b'\n', 0xc3] {
+                let mut mutated = INVITE.to_vec();
+                mutated[i] = b;
+                inputs.push(mutated);
+            }
+        }
+        for input in &inputs {
+            let error = diagnose(input, "rsip");
+            assert!(error.offset.is_none_or(|offset| offset <= input.len()));
+            if parse_message(input).is_err() {
+                assert_ne!(
+                    error.category,
+                    ErrorCategory::Other,
+                    "{:?}",
+                    String::from_utf8_lossy(input)
+                );
+            }
+        }
+        assert_eq!(
+            scan(INVITE),
+            Ok(()),
+            "a valid message has nothing to report"
+        );
+    }
+}
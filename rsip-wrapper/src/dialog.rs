@@ -0,0 +1,491 @@
+//! Dialog tracking (RFC 3261 section 12), keyed by Call-ID, local tag and remote tag.
+//!
+//! Dialogs are learned from the traffic itself: a 2xx to an INVITE creates one, from
+//! the UAC side when it is received and the UAS side when it is sent through this
+//! context, and a BYE in either direction ends it. In-dialog requests advance the
+//! local or remote CSeq. Only confirmed dialogs are tracked, not early ones.
+
+use crate::context::{with_context, RsipContext};
+use crate::ffi::{into_c_string, str_arg};
+use crate::lock::Lock;
+use rsip::prelude::*;
+use rsip::{Method, SipMessage};
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::os::raw::c_char;
+use std::time::Instant;
+
+type DialogKey = (String, String, String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Uac,
+    Uas,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Uac => "uac",
+            Self::Uas => "uas",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Dialog {
+    pub id: u64,
+    pub role: Role,
+    pub call_id: String,
+    pub local_tag: String,
+    pub remote_tag: String,
+    /// Highest CSeq sent in the dialog.
+    pub local_seq: Option<u32>,
+    /// Highest CSeq received in the dialog.
+    pub remote_seq: Option<u32>,
+    /// URI of the other party: the To of our INVITE, or the From of theirs.
+    pub remote_uri: Option<String>,
+    /// Where the last message of the dialog came from.
+    pub remote_addr: Option<SocketAddr>,
+    pub created: Instant,
+    /// When a message of the dialog was last sent or received.
+    pub last_activity: Instant,
+}
+
+impl Dialog {
+    fn payload(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "role": self.role.as_str(),
+            "call_id": self.call_id,
+            "local_tag": self.local_tag,
+            "remote_tag": self.remote_tag,
+        })
+    }
+
+    /// `payload` plus the state `rsip_list_dialogs` reports.
+    fn state(&self, now: Instant) -> serde_json::Value {
+        let mut state = self.payload();
+        state["local_seq"] = json!(self.local_seq);
+        state["remote_seq"] = json!(self.remote_seq);
+        state["remote_uri"] = json!(self.remote_uri);
+        state["remote_addr"] = json!(self.remote_addr.map(|a| a.to_string()));
+        state["age_ms"] = json!(now.duration_since(self.created).as_millis() as u64);
+        state["idle_ms"] = json!(now.duration_since(self.last_activity).as_millis() as u64);
+        state
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Dialogs {
+    by_key: HashMap<DialogKey, Dialog>,
+    next_id: u64,
+}
+
+impl Dialogs {
+    pub fn get(&self, call_id: &str, local_tag: &str, remote_tag: &str) -> Option<&Dialog> {
+        self.by_key
+            .get(&(call_id.into(), local_tag.into(), remote_tag.into()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+
+    /// Every tracked dialog, oldest first.
+    pub fn list(&self) -> Vec<Dialog> {
+        let mut dialogs: Vec<Dialog> = self.by_key.values().cloned().collect();
+        dialogs.sort_by_key(|dialog| dialog.id);
+        dialogs
+    }
+}
+
+/// What a message means for dialog state.
+enum Change {
+    Created(Dialog),
+    Terminated(Dialog),
+}
+
+/// The dialog identifiers of `msg` seen from this side: Call-ID, local tag, remote tag.
+/// The local tag is the From tag of requests we send and of responses we receive.
+fn key_of(msg: &SipMessage, direction: Direction) -> Option<DialogKey> {
+    let call_id = msg.call_id_header().ok()?.value().to_string();
+    let from = msg.from_header().ok()?.tag().ok()??.to_string();
+    let to = msg.to_header().ok()?.tag().ok()??.to_string();
+    let ours_in_from = matches!(
+        (msg, direction),
+        (SipMessage::Request(_), Direction::Sent) | (SipMessage::Response(_), Direction::Received)
+    );
+    Some(if ours_in_from {
+        (call_id, from, to)
+    } else {
+        (call_id, to, from)
+    })
+}
+
+/// The URI of the party at the other end of `msg`, which carries `direction`.
+fn remote_uri(msg: &SipMessage, direction: Direction) -> Option<String> {
+    let uri = match (msg, direction) {
+        (SipMessage::Request(_), Direction::Sent)
+        | (SipMessage::Response(_), Direction::Received) => msg.to_header().ok()?.typed().ok()?.uri,
+        _ => msg.from_header().ok()?.typed().ok()?.uri,
+    };
+    Some(uri.to_string())
+}
+
+/// Applies `msg`, which was received from `src` or sent (`src` is `None`).
+fn update(
+    dialogs: &mut Dialogs,
+    msg: &SipMessage,
+    direction: Direction,
+    src: Option<SocketAddr>,
+) -> Option<Change> {
+    let key = key_of(msg, direction)?;
+    let cseq = msg.cseq_header().ok()?.typed().ok()?;
+    let now = Instant::now();
+    match msg {
+        SipMessage::Response(response) => {
+            let status = response.status_code().code();
+            if cseq.method != Method::Invite
+                || !(200..300).contains(&status)
+                || dialogs.by_key.contains_key(&key)
+            {
+                return None;
+            }
+            dialogs.next_id += 1;
+            let (role, local_seq, remote_seq) = match direction {
+                Direction::Received => (Role::Uac, Some(cseq.seq), None),
+                Direction::Sent => (Role::Uas, None, Some(cseq.seq)),
+            };
+            let dialog = Dialog {
+                id: dialogs.next_id,
+                role,
+                call_id: key.0.clone(),
+                local_tag: key.1.clone(),
+                remote_tag: key.2.clone(),
+                local_seq,
+                remote_seq,
+                remote_uri: remote_uri(msg, direction),
+                remote_addr: src,
+                created: now,
+                last_activity: now,
+            };
+            dialogs.by_key.insert(key, dialog.clone());
+            Some(Change::Created(dialog))
+        }
+        SipMessage::Request(request) => {
+            if request.method == Method::Bye {
+                return dialogs.by_key.remove(&key).map(Change::Terminated);
+            }
+            let dialog = dialogs.by_key.get_mut(&key)?;
+            dialog.last_activity = now;
+            if src.is_some() {
+                dialog.remote_addr = src;
+            }
+            // ACK and CANCEL reuse the INVITE's number rather than taking a new one.
+            let seq = match direction {
+                Direction::Sent => &mut dialog.local_seq,
+                Direction::Received => &mut dialog.remote_seq,
+            };
+            *seq = Some(seq.map_or(cseq.seq, |seq| seq.max(cseq.seq)));
+            None
+        }
+    }
+}
+
+impl RsipContext {
+    fn dialog_change(&self, change: Option<Change>, direction: Direction, src: Option<SocketAddr>) {
+        let (event, mut payload) = match change {
+            Some(Change::Created(dialog)) => ("dialog_created", dialog.payload()),
+            Some(Change::Terminated(dialog)) => ("dialog_terminated", dialog.payload()),
+            None => return,
+        };
+        if event == "dialog_terminated" {
+            payload["by"] = json!(match direction {
+                Direction::Sent => "local",
+                Direction::Received => "remote",
+            });
+        }
+        self.events.emit(event, &payload.to_string(), src);
+    }
+
+    /// Updates dialogs from a message received from `src`.
+    pub(crate) fn dialog_received(&self, msg: &SipMessage, src: SocketAddr) {
+        let change = update(
+            &mut self.dialogs.locked(),
+            msg,
+            Direction::Received,
+            Some(src),
+        );
+        self.dialog_change(change, Direction::Received, Some(src));
+    }
+
+    /// Whether `data` may create, refresh or end a dialog, judged without parsing it:
+    /// 2xx responses and, while dialogs exist, requests.
+    pub(crate) fn dialog_relevant(&self, data: &[u8]) -> bool {
+        if data.starts_with(b"SIP/2.0 ") {
+            data.get(8) == Some(&b'2')
+        } else {
+            !self.dialogs.locked().is_empty()
+        }
+    }
+
+    /// Updates dialogs from a message this context sent, if `dialog_relevant`.
+    pub(crate) fn dialog_sent(&self, data: &[u8]) {
+        if !self.dialog_relevant(data) {
+            return;
+        }
+        let msg = match SipMessage::try_from(data) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        let change = update(&mut self.dialogs.locked(), &msg, Direction::Sent, None);
+        self.dialog_change(change, Direction::Sent, None);
+    }
+
+    /// The tracked dialogs as a JSON array, oldest first; see `rsip_list_dialogs`.
+    pub fn dialogs_json(&self) -> serde_json::Value {
+        let now = Instant::now();
+        let dialogs = self.dialogs.locked().list();
+        dialogs.iter().map(|dialog| dialog.state(now)).collect()
+    }
+
+    /// The id of the dialog a received message belongs to.
+    pub fn dialog_match(&self, msg: &SipMessage) -> Option<u64> {
+        let (call_id, local, remote) = key_of(msg, Direction::Received)?;
+        self.dialogs
+            .locked()
+            .get(&call_id, &local, &remote)
+            .map(|dialog| dialog.id)
+    }
+}
+
+fn match_raw(ctx: &RsipContext, raw: *const c_char) -> u64 {
+    match str_arg(raw).map(SipMessage::try_from) {
+        Some(Ok(msg)) => ctx.dialog_match(&msg).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// The id of the dialog the received message `raw` belongs to, or 0 if it belongs to
+/// none (or is NULL or does not parse).
+#[no_mangle]
+pub extern "C" fn rsip_dialog_match(raw: *const c_char) -> u64 {
+    match_raw(crate::default_context(), raw)
+}
+
+/// The tracked dialogs as a caller-owned JSON array, oldest first: each with its
+/// `dialog_created` fields, CSeqs, remote URI and address, age and idle time.
+#[no_mangle]
+pub extern "C" fn rsip_list_dialogs() -> *mut c_char {
+    into_c_string(crate::default_context().dialogs_json().to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_dialog_match(ctx: *mut RsipContext, raw: *const c_char) -> u64 {
+    with_context(ctx, |ctx| match_raw(ctx, raw)).unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_list_dialogs(ctx: *mut RsipContext) -> *mut c_char {
+    with_context(ctx, |ctx| into_c_string(ctx.dialogs_json().to_string()))
+        .unwrap_or(std::ptr::null_mut())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::ffi::{CStr, CString};
+    use std::sync::Mutex;
+
+    static EVENTS: Mutex<Vec<(String, serde_json::Value)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(event: *const c_char, payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }
+            .to_string_lossy()
+            .into_owned();
+        let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+        EVENTS
+            .lock()
+            .unwrap()
+            .push((event, serde_json::from_str(payload).unwrap()));
+    }
+
+    /// A message between alice (tag "a") and bob (tag "b") in call `call_id`.
+    fn message(start: &str, call_id: &str, from_tag: &str, to_tag: &str, cseq: &str) -> String {
+        format!(
+            "{}\r\n\
+             Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bK{}\r\n\
+             Max-Forwards: 70\r\n\
+             From: <sip:{}@example.com>;tag={}\r\n\
+             To: <sip:{}@example.com>;tag={}\r\n\
+             Call-ID: {}\r\n\
+             CSeq: {}\r\n\
+             Content-Length: 0\r\n\r\n",
+            start,
+            cseq.replace(' ', ""),
+            from_tag,
+            from_tag,
+            to_tag,
+            to_tag,
+            call_id,
+            cseq
+        )
+    }
+
+    fn events_for(call_id: &str) -> Vec<(String, serde_json::Value)> {
+        EVENTS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, p)| p["call_id"] == call_id)
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn uac_dialog_from_received_2xx() {
+        let ctx = RsipContext::new();
+        ctx.events.subscribe(
+            Some(vec!["dialog_created".into(), "dialog_terminated".into()]),
+            Sink::Basic(record),
+        );
+        let src: SocketAddr = "192.0.2.2:5060".parse().unwrap();
+        // we are alice: our INVITE got a 200 from bob
+        let ok = message("SIP/2.0 200 OK", "uac", "a", "b", "1 INVITE");
+        ctx.handle_datagram(ok.as_bytes(), src);
+        ctx.handle_datagram(ok.as_bytes(), src); // retransmission
+        let created = events_for("uac");
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].0, "dialog_created");
+        assert_eq!(created[0].1["role"], "uac");
+        assert_eq!(created[0].1["local_tag"], "a");
+        assert_eq!(created[0].1["remote_tag"], "b");
+        let id = created[0].1["id"].as_u64().unwrap();
+
+        let reinvite = message(
+            "INVITE sip:a@192.0.2.1 SIP/2.0",
+            "uac",
+            "b",
+            "a",
+            "7 INVITE",
+        );
+        let stranger = message(
+            "INVITE sip:a@192.0.2.1 SIP/2.0",
+            "uac",
+            "c",
+            "a",
+            "7 INVITE",
+        );
+        let raw = CString::new(reinvite.as_str()).unwrap();
+        assert_eq!(
+            ctx.dialog_match(&SipMessage::try_from(reinvite.as_str()).unwrap()),
+            Some(id)
+        );
+        assert_eq!(
+            ctx.dialog_match(&SipMessage::try_from(stranger.as_str()).unwrap()),
+            None
+        );
+        assert_eq!(rsip_dialog_match(raw.as_ptr()), 0, "other context");
+        assert_eq!(rsip_dialog_match(std::ptr::null()), 0);
+
+        ctx.handle_datagram(reinvite.as_bytes(), src);
+        {
+            let dialogs = ctx.dialogs.lock().unwrap();
+            let dialog = dialogs.get("uac", "a", "b").unwrap();
+            assert_eq!(dialog.local_seq, Some(1));
+            assert_eq!(dialog.remote_seq, Some(7));
+        }
+        let listed = ctx.dialogs_json();
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["id"], id);
+        assert_eq!(listed[0]["local_seq"], 1);
+        assert_eq!(listed[0]["remote_seq"], 7);
+        assert_eq!(listed[0]["remote_uri"], "sip:b@example.com");
+        assert_eq!(listed[0]["remote_addr"], "192.0.2.2:5060");
+        assert!(listed[0]["idle_ms"].as_u64().unwrap() <= listed[0]["age_ms"].as_u64().unwrap());
+        let bye = message("BYE sip:a@192.0.2.1 SIP/2.0", "uac", "b", "a", "8 BYE");
+        ctx.handle_datagram(bye.as_bytes(), src);
+        let events = events_for("uac");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].0, "dialog_terminated");
+        assert_eq!(events[1].1["id"], id);
+        assert_eq!(events[1].1["by"], "remote");
+        assert_eq!(ctx.dialogs_json(), json!([]));
+        assert_eq!(
+            ctx.dialog_match(&SipMessage::try_from(bye.as_str()).unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn dialogs_are_tracked_with_only_a_raw_callback() {
+        extern "C" fn ignore(_: *const crate::raw::RsipRawMessage) {}
+
+        let ctx = RsipContext::new();
+        ctx.events.set_raw_callback(Some(ignore));
+        let src: SocketAddr = "192.0.2.2:5060".parse().unwrap();
+        let ok = message("SIP/2.0 200 OK", "raw-only", "a", "b", "1 INVITE");
+        ctx.handle_datagram(ok.as_bytes(), src);
+        let listed = ctx.dialogs_json();
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["call_id"], "raw-only");
+        assert_eq!(listed[0]["remote_addr"], "192.0.2.2:5060");
+
+        let bye = message("BYE sip:a@192.0.2.1 SIP/2.0", "raw-only", "b", "a", "2 BYE");
+        ctx.handle_datagram(bye.as_bytes(), src);
+        assert_eq!(ctx.dialogs_json(), json!([]));
+    }
+
+    #[test]
+    fn uas_dialog_from_sent_2xx() {
+        let ctx = RsipContext::new();
+        ctx.events.subscribe(
+            Some(vec!["dialog_created".into(), "dialog_terminated".into()]),
+            Sink::Basic(record),
+        );
+        // we are bob: alice's INVITE was answered with our 200; a 180 or 486 is not enough
+        ctx.dialog_sent(message("SIP/2.0 180 Ringing", "uas", "a", "b", "1 INVITE").as_bytes());
+        ctx.dialog_sent(message("SIP/2.0 200 OK", "uas", "a", "b", "2 OPTIONS").as_bytes());
+        assert!(events_for("uas").is_empty());
+        ctx.dialog_sent(message("SIP/2.0 200 OK", "uas", "a", "b", "1 INVITE").as_bytes());
+        let created = events_for("uas");
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].1["role"], "uas");
+        assert_eq!(created[0].1["local_tag"], "b");
+        assert_eq!(created[0].1["remote_tag"], "a");
+        let listed = ctx.dialogs_json();
+        assert_eq!(listed[0]["remote_uri"], "sip:a@example.com");
+        assert_eq!(listed[0]["remote_addr"], serde_json::Value::Null);
+
+        ctx.dialog_sent(
+            message("INFO sip:a@192.0.2.1 SIP/2.0", "uas", "b", "a", "1 INFO").as_bytes(),
+        );
+        assert_eq!(
+            ctx.dialogs
+                .lock()
+                .unwrap()
+                .get("uas", "b", "a")
+                .unwrap()
+                .local_seq,
+            Some(1)
+        );
+        ctx.dialog_sent(
+            message("BYE sip:a@192.0.2.1 SIP/2.0", "uas", "b", "a", "2 BYE").as_bytes(),
+        );
+        let events = events_for("uas");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].0, "dialog_terminated");
+        assert_eq!(events[1].1["by"], "local");
+        assert!(ctx.dialogs.lock().unwrap().is_empty());
+    }
+}
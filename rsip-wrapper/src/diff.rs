@@ -0,0 +1,184 @@
+//! Header-level comparison of two messages, for interop debugging: what we sent
+//! against a captured reference that a peer accepted.
+
+use crate::ffi::{into_c_string, str_arg};
+use crate::message::{canonical_name, name_value};
+use crate::parse::parse_message;
+use rsip::prelude::*;
+use rsip::SipMessage;
+use serde_json::{json, Value};
+use std::os::raw::c_char;
+
+/// Headers of `msg` grouped by name (compact forms expanded, case-insensitive), in
+/// order of first appearance, each with its values in message order.
+fn grouped(msg: &SipMessage) -> Vec<(String, Vec<String>)> {
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+    for (name, value) in msg.headers().iter().map(name_value) {
+        let name = canonical_name(&name).to_string();
+        match groups
+            .iter_mut()
+            .find(|(seen, _)| seen.eq_ignore_ascii_case(&name))
+        {
+            Some((_, values)) => values.push(value),
+            None => groups.push((name, vec![value])),
+        }
+    }
+    groups
+}
+
+fn start_line(msg: &SipMessage) -> String {
+    msg.to_string()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The differences between `a` and `b`, each a JSON object with a `kind`:
+/// `start_line` (`a`, `b`), `only_in_a` / `only_in_b` (`header`, `values`),
+/// `value_differs` (`header`, `a`, `b`: every value of the header, in order),
+/// `reordered` (`a`, `b`: the header names both have, in each message's order) and
+/// `body` (`a_len`, `b_len`). Empty when the messages match.
+pub(crate) fn diff(a: &SipMessage, b: &SipMessage) -> Vec<Value> {
+    let mut out = Vec::new();
+    let (line_a, line_b) = (start_line(a), start_line(b));
+    if line_a != line_b {
+        out.push(json!({ "kind": "start_line", "a": line_a, "b": line_b }));
+    }
+
+    let (headers_a, headers_b) = (grouped(a), grouped(b));
+    let find = |headers: &[(String, Vec<String>)], name: &str| {
+        headers
+            .iter()
+            .position(|(other, _)| other.eq_ignore_ascii_case(name))
+    };
+    for (name, values) in &headers_a {
+        match find(&headers_b, name) {
+            None => out.push(json!({ "kind": "only_in_a", "header": name, "values": values })),
+            Some(i) if headers_b[i].1 != *values => out.push(json!({
+                "kind": "value_differs",
+                "header": name,
+                "a": values,
+                "b": headers_b[i].1,
+            })),
+            Some(_) => {}
+        }
+    }
+    for (name, values) in &headers_b {
+        if find(&headers_a, name).is_none() {
+            out.push(json!({ "kind": "only_in_b", "header": name, "values": values }));
+        }
+    }
+
+    let common = |headers: &[(String, Vec<String>)], other: &[(String, Vec<String>)]| {
+        headers
+            .iter()
+            .filter(|(name, _)| find(other, name).is_some())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>()
+    };
+    let (order_a, order_b) = (
+        common(&headers_a, &headers_b),
+        common(&headers_b, &headers_a),
+    );
+    let same_order = order_a
+        .iter()
+        .zip(&order_b)
+        .all(|(a, b)| a.eq_ignore_ascii_case(b));
+    if !same_order {
+        out.push(json!({ "kind": "reordered", "a": order_a, "b": order_b }));
+    }
+
+    if a.body() != b.body() {
+        out.push(json!({ "kind": "body", "a_len": a.body().len(), "b_len": b.body().len() }));
+    }
+    out
+}
+
+/// Compares two messages header by header and returns the differences as a
+/// caller-owned JSON array (see `diff`), `[]` if they match, or NULL if either is NULL
+/// or does not parse.
+#[no_mangle]
+pub extern "C" fn rsip_message_diff(a: *const c_char, b: *const c_char) -> *mut c_char {
+    let parse =
+        |raw: *const c_char| str_arg(raw).and_then(|raw| parse_message(raw.as_bytes()).ok());
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => into_c_string(Value::from(diff(&a, &b)).to_string()),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{CStr, CString};
+
+    const SENT: &str = "INVITE sip:bob@example.com SIP/2.0\r\n\
+        Via: SIP/2.0/UDP 10.0.0.5;branch=z9hG4bK1\r\n\
+        From: <sip:alice@example.com>;tag=1\r\n\
+        To: <sip:bob@example.com>\r\n\
+        Call-ID: diff@example.com\r\n\
+        CSeq: 1 INVITE\r\n\
+        Allow: INVITE, ACK\r\n\
+        Content-Length: 0\r\n\r\n";
+
+    fn diff_of(a: &str, b: &str) -> Vec<Value> {
+        diff(
+            &parse_message(a.as_bytes()).unwrap(),
+            &parse_message(b.as_bytes()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn reports_header_level_differences() {
+        assert!(diff_of(SENT, SENT).is_empty());
+        // compact forms and case do not count as differences
+        let compact = SENT.replace("Call-ID:", "i:").replace("Allow:", "ALLOW:");
+        assert!(diff_of(SENT, &compact).is_empty());
+
+        let reference = "INVITE sip:bob@example.com SIP/2.0\r\n\
+            Via: SIP/2.0/UDP 10.0.0.5;branch=z9hG4bK1\r\n\
+            To: <sip:bob@example.com>\r\n\
+            From: <sip:alice@example.com>;tag=1\r\n\
+            Call-ID: diff@example.com\r\n\
+            CSeq: 2 INVITE\r\n\
+            Supported: timer\r\n\
+            Content-Length: 2\r\n\r\nhi";
+        assert_eq!(
+            diff_of(SENT, reference),
+            vec![
+                json!({ "kind": "value_differs", "header": "CSeq", "a": ["1 INVITE"], "b": ["2 INVITE"] }),
+                json!({ "kind": "only_in_a", "header": "Allow", "values": ["INVITE, ACK"] }),
+                json!({ "kind": "value_differs", "header": "Content-Length", "a": ["0"], "b": ["2"] }),
+                json!({ "kind": "only_in_b", "header": "Supported", "values": ["timer"] }),
+                json!({
+                    "kind": "reordered",
+                    "a": ["Via", "From", "To", "Call-ID", "CSeq", "Content-Length"],
+                    "b": ["Via", "To", "From", "Call-ID", "CSeq", "Content-Length"],
+                }),
+                json!({ "kind": "body", "a_len": 0, "b_len": 2 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn ffi_returns_json_or_null() {
+        let a = CString::new(SENT).unwrap();
+        let b = CString::new(SENT.replace("INVITE sip:bob", "INVITE sip:carol")).unwrap();
+        let out = rsip_message_diff(a.as_ptr(), b.as_ptr());
+        let json: Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        crate::rsip_free_string(out);
+        assert_eq!(
+            json,
+            json!([{
+                "kind": "start_line",
+                "a": "INVITE sip:bob@example.com SIP/2.0",
+                "b": "INVITE sip:carol@example.com SIP/2.0",
+            }])
+        );
+        let garbage = CString::new("not sip").unwrap();
+        assert!(rsip_message_diff(a.as_ptr(), garbage.as_ptr()).is_null());
+        assert!(rsip_message_diff(std::ptr::null(), a.as_ptr()).is_null());
+    }
+}
@@ -0,0 +1,343 @@
+//! Minimal DNS stub resolver for the NAPTR and SRV lookups of RFC 3263: one UDP question
+//! at a time to the name servers in /etc/resolv.conf, no caching and no TCP fallback
+//! (a truncated answer is used as far as it goes). A and AAAA lookups are left to the
+//! OS resolver, which also honours hosts files.
+
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+pub(crate) const TYPE_SRV: u16 = 33;
+pub(crate) const TYPE_NAPTR: u16 = 35;
+const CLASS_IN: u16 = 1;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Compression pointers followed per name before it is considered a loop.
+const MAX_POINTERS: usize = 16;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Naptr {
+    pub order: u16,
+    pub preference: u16,
+    pub flags: String,
+    pub service: String,
+    pub regexp: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Record {
+    Srv(Srv),
+    Naptr(Naptr),
+}
+
+/// A recursion-desired query for `name` with a single question.
+pub(crate) fn encode_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(name.len() + 18);
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&[0x01, 0x00]); // RD
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // QDCOUNT 1
+    for label in name.trim_end_matches('.').split('.') {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    msg
+}
+
+fn u16_at(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+/// Reads a possibly compressed domain name at `*pos`, advancing past it.
+fn read_name(msg: &[u8], pos: &mut usize) -> Option<String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut at = *pos;
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(at)? as usize;
+        match len {
+            0 => {
+                *pos = end.unwrap_or(at + 1);
+                return Some(labels.join("."));
+            }
+            l if l & 0xc0 == 0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(at + 2);
+                at = (u16_at(msg, at)? & 0x3fff) as usize;
+            }
+            l if l < 64 => {
+                let label = msg.get(at + 1..at + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + l;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// A `<character-string>`: a length byte and that many bytes.
+fn read_string(msg: &[u8], pos: &mut usize) -> Option<String> {
+    let len = *msg.get(*pos)? as usize;
+    let text = msg.get(*pos + 1..*pos + 1 + len)?;
+    *pos += 1 + len;
+    Some(String::from_utf8_lossy(text).into_owned())
+}
+
+fn parse_record(msg: &[u8], rtype: u16, start: usize) -> Option<Record> {
+    let mut pos = start;
+    match rtype {
+        TYPE_SRV => {
+            let priority = u16_at(msg, pos)?;
+            let weight = u16_at(msg, pos + 2)?;
+            let port = u16_at(msg, pos + 4)?;
+            pos += 6;
+            let target = read_name(msg, &mut pos)?;
+            Some(Record::Srv(Srv {
+                priority,
+                weight,
+                port,
+                target,
+            }))
+        }
+        TYPE_NAPTR => {
+            let order = u16_at(msg, pos)?;
+            let preference = u16_at(msg, pos + 2)?;
+            pos += 4;
+            let flags = read_string(msg, &mut pos)?;
+            let service = read_string(msg, &mut pos)?;
+            let regexp = read_string(msg, &mut pos)?;
+            let replacement = read_name(msg, &mut pos)?;
+            Some(Record::Naptr(Naptr {
+                order,
+                preference,
+                flags,
+                service,
+                regexp,
+                replacement,
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// The `qtype` answers of a response to query `id`. A name error (NXDOMAIN) is an empty
+/// answer; `None` means the response is not usable (wrong id, server failure, garbage).
+pub(crate) fn parse_response(msg: &[u8], id: u16, qtype: u16) -> Option<Vec<Record>> {
+    if u16_at(msg, 0)? != id || msg.get(2)? & 0x80 == 0 {
+        return None;
+    }
+    match msg.get(3)? & 0x0f {
+        0 => {}
+        3 => return Some(Vec::new()),
+        _ => return None,
+    }
+    let questions = u16_at(msg, 4)?;
+    let answers = u16_at(msg, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        read_name(msg, &mut pos)?;
+        pos += 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        read_name(msg, &mut pos)?;
+        let rtype = u16_at(msg, pos)?;
+        let rdlen = u16_at(msg, pos + 8)? as usize;
+        let rdata = pos + 10;
+        if rdata + rdlen > msg.len() {
+            // Truncated: keep what arrived whole.
+            break;
+        }
+        if rtype == qtype {
+            records.extend(parse_record(msg, rtype, rdata));
+        }
+        pos = rdata + rdlen;
+    }
+    Some(records)
+}
+
+/// The `nameserver` entries of /etc/resolv.conf; empty where there is none (Windows).
+pub(crate) fn nameservers() -> Vec<SocketAddr> {
+    std::fs::read_to_string(RESOLV_CONF)
+        .map(|conf| parse_resolv_conf(&conf))
+        .unwrap_or_default()
+}
+
+fn parse_resolv_conf(conf: &str) -> Vec<SocketAddr> {
+    conf.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                // Drop a zone index ("fe80::1%eth0"), which IpAddr does not parse.
+                (Some("nameserver"), Some(addr)) => addr.split('%').next()?.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect()
+}
+
+fn ask(server: SocketAddr, query: &[u8], id: u16, qtype: u16) -> Option<Vec<Record>> {
+    let any: IpAddr = if server.is_ipv4() {
+        std::net::Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        std::net::Ipv6Addr::UNSPECIFIED.into()
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(any, 0)).ok()?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT)).ok()?;
+    socket.connect(server).ok()?;
+    socket.send(query).ok()?;
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = socket.recv(&mut buf).ok()?;
+        // Ignore stray datagrams that do not answer this query.
+        if let Some(records) = parse_response(&buf[..n], id, qtype) {
+            return Some(records);
+        }
+    }
+}
+
+/// Looks up `qtype` records for `name`, asking each configured server in turn until one
+/// answers. Empty if none does.
+pub(crate) fn query(name: &str, qtype: u16) -> Vec<Record> {
+    let id: u16 = rand::random();
+    let query = encode_query(id, name, qtype);
+    nameservers()
+        .into_iter()
+        .find_map(|server| ask(server, &query, id, qtype))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to `encode_query(7, "_sip._udp.example.com", SRV)` with two answers,
+    /// the second using a compression pointer into the question.
+    fn srv_response() -> Vec<u8> {
+        let mut msg = encode_query(7, "_sip._udp.example.com", TYPE_SRV);
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[7] = 2; // ANCOUNT
+        for (priority, weight, port, target) in
+            [(10u16, 60u16, 5060u16, "sip1"), (20, 0, 5070, "")].iter()
+        {
+            msg.extend_from_slice(&[0xc0, 12]); // owner: the question name
+            msg.extend_from_slice(&TYPE_SRV.to_be_bytes());
+            msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+            msg.extend_from_slice(&300u32.to_be_bytes());
+            let mut rdata = Vec::new();
+            rdata.extend_from_slice(&priority.to_be_bytes());
+            rdata.extend_from_slice(&weight.to_be_bytes());
+            rdata.extend_from_slice(&port.to_be_bytes());
+            if target.is_empty() {
+                // "example.com" at offset 12 + len("\x04_sip\x04_udp") = 22
+                rdata.extend_from_slice(&[0xc0, 22]);
+            } else {
+                rdata.push(target.len() as u8);
+                rdata.extend_from_slice(target.as_bytes());
+                rdata.extend_from_slice(&[0xc0, 22]);
+            }
+            msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&rdata);
+        }
+        msg
+    }
+
+    #[test]
+    fn parses_srv_answers_with_compression() {
+        let records = parse_response(&srv_response(), 7, TYPE_SRV).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                Record::Srv(Srv {
+                    priority: 10,
+                    weight: 60,
+                    port: 5060,
+                    target: "sip1.example.com".into()
+                }),
+                Record::Srv(Srv {
+                    priority: 20,
+                    weight: 0,
+                    port: 5070,
+                    target: "example.com".into()
+                }),
+            ]
+        );
+        // wrong id, or cut mid-record
+        assert!(parse_response(&srv_response(), 8, TYPE_SRV).is_none());
+        let response = srv_response();
+        let cut = parse_response(&response[..response.len() - 3], 7, TYPE_SRV).unwrap();
+        assert_eq!(cut.len(), 1);
+    }
+
+    #[test]
+    fn parses_naptr_and_name_errors() {
+        let mut msg = encode_query(9, "example.com", TYPE_NAPTR);
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[7] = 1;
+        msg.extend_from_slice(&[0xc0, 12]);
+        msg.extend_from_slice(&TYPE_NAPTR.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&60u32.to_be_bytes());
+        let mut rdata = vec![0, 50, 0, 10];
+        for s in ["s", "SIPS+D2T", ""].iter() {
+            rdata.push(s.len() as u8);
+            rdata.extend_from_slice(s.as_bytes());
+        }
+        rdata.extend_from_slice(b"\x05_sips\x04_tcp\xc0\x0c");
+        msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&rdata);
+        assert_eq!(
+            parse_response(&msg, 9, TYPE_NAPTR).unwrap(),
+            vec![Record::Naptr(Naptr {
+                order: 50,
+                preference: 10,
+                flags: "s".into(),
+                service: "SIPS+D2T".into(),
+                regexp: "".into(),
+                replacement: "_sips._tcp.example.com".into(),
+            })]
+        );
+
+        msg[3] = 0x83; // NXDOMAIN
+        assert_eq!(parse_response(&msg, 9, TYPE_NAPTR), Some(Vec::new()));
+        msg[3] = 0x82; // SERVFAIL
+        assert_eq!(parse_response(&msg, 9, TYPE_NAPTR), None);
+    }
+
+    #[test]
+    fn pointer_loops_are_rejected() {
+        let msg = [0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xc0, 12];
+        assert!(read_name(&msg, &mut 12).is_none());
+    }
+
+    #[test]
+    fn reads_resolv_conf() {
+        let conf = "# comment\nsearch example.com\nnameserver 192.0.2.53\n\
+                    nameserver fe80::1%eth0\nnameserver bogus\n";
+        assert_eq!(
+            parse_resolv_conf(conf),
+            vec![
+                "192.0.2.53:53".parse().unwrap(),
+                "[fe80::1]:53".parse().unwrap()
+            ]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::io;
+use std::os::raw::c_char;
+
+/// Stable error codes returned by the `_ex` functions. The numeric values are part of
+/// the C ABI: never renumber a variant, only append new ones.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RsipError {
+    Ok = 0,
+    BindFailed = -1,
+    AddrInUse = -2,
+    PermissionDenied = -3,
+    InvalidArgument = -4,
+    AlreadyRunning = -5,
+    NotRunning = -6,
+    SendFailed = -7,
+    Io = -8,
+}
+
+impl RsipError {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(Self::Ok),
+            -1 => Some(Self::BindFailed),
+            -2 => Some(Self::AddrInUse),
+            -3 => Some(Self::PermissionDenied),
+            -4 => Some(Self::InvalidArgument),
+            -5 => Some(Self::AlreadyRunning),
+            -6 => Some(Self::NotRunning),
+            -7 => Some(Self::SendFailed),
+            -8 => Some(Self::Io),
+            _ => None,
+        }
+    }
+
+    /// Maps a bind error to the most specific code available.
+    pub fn from_bind_error(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::AddrInUse => Self::AddrInUse,
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied,
+            io::ErrorKind::AddrNotAvailable | io::ErrorKind::InvalidInput => Self::InvalidArgument,
+            _ => Self::BindFailed,
+        }
+    }
+
+    // NUL-terminated so it can be handed out as a `'static` C string.
+    fn message(self) -> &'static [u8] {
+        match self {
+            Self::Ok => b"ok\0",
+            Self::BindFailed => b"failed to bind socket\0",
+            Self::AddrInUse => b"address already in use\0",
+            Self::PermissionDenied => b"permission denied\0",
+            Self::InvalidArgument => b"invalid argument\0",
+            Self::AlreadyRunning => b"listener already running\0",
+            Self::NotRunning => b"listener not running\0",
+            Self::SendFailed => b"failed to send datagram\0",
+            Self::Io => b"i/o error\0",
+        }
+    }
+}
+
+impl std::fmt::Display for RsipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = self.message();
+        write!(f, "{}", String::from_utf8_lossy(&msg[..msg.len() - 1]))
+    }
+}
+
+impl std::error::Error for RsipError {}
+
+pub(crate) fn to_code(result: Result<(), RsipError>) -> i32 {
+    match result {
+        Ok(()) => RsipError::Ok.code(),
+        Err(e) => e.code(),
+    }
+}
+
+/// Returns a static, human readable description of an error code. Never NULL; the
+/// string must not be freed.
+#[no_mangle]
+pub extern "C" fn rsip_strerror(code: i32) -> *const c_char {
+    match RsipError::from_code(code) {
+        Some(e) => e.message().as_ptr() as *const c_char,
+        None => b"unknown error\0".as_ptr() as *const c_char,
+    }
+}
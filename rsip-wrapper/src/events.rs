@@ -0,0 +1,806 @@
+//! Fan-out of events to the callbacks a host has registered on a context.
+
+use crate::batch::Batcher;
+use crate::context::{with_context, EventCallback, RsipContext};
+use crate::ffi::str_arg;
+use crate::lock::Lock;
+use crate::poll::{EventMode, EventQueue};
+use crate::raw::RawCallback;
+use crate::router::Router;
+use std::any::Any;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::mem::Discriminant;
+use std::net::{IpAddr, SocketAddr};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Callback that additionally receives the peer address of network events. For events
+/// that have no peer (e.g. lifecycle errors) `src_ip` is "" and `src_port` is 0.
+pub type EventCallbackEx = extern "C" fn(
+    event: *const c_char,
+    payload: *const c_char,
+    src_ip: *const c_char,
+    src_port: u16,
+);
+
+/// Callback receiving an event's data as bytes with an explicit length, plus the peer
+/// address as for `EventCallbackEx`. For `sip_rx` `data` is the message exactly as
+/// received (it may contain NULs or invalid UTF-8); for other events it is the payload.
+pub type EventCallbackBytes = extern "C" fn(
+    event: *const c_char,
+    data: *const u8,
+    len: usize,
+    src_ip: *const c_char,
+    src_port: u16,
+);
+
+/// An event as one plain struct, delivered without allocating anything per event.
+/// Only valid during the callback.
+#[repr(C)]
+pub struct RsipEvent {
+    /// The event's entry in `EVENT_NAMES` (see `event_kind`), 0 for names not listed.
+    pub kind: u32,
+    /// As for `EventCallbackBytes`: the datagram for `sip_rx`, else the payload.
+    pub data: *const u8,
+    pub len: usize,
+    /// The peer's IPv6 address, IPv4 as v4-mapped (`::ffff:a.b.c.d`); all zero, like
+    /// `src_port`, for events without a peer.
+    pub src_ip: [u8; 16],
+    pub src_port: u16,
+    /// The event's number on its context (see `EventBus::last_seq`); a gap means
+    /// events were lost on the way.
+    pub seq: u64,
+}
+
+pub type EventCallbackStruct = extern "C" fn(event: *const RsipEvent);
+
+/// Event names by kind code, from 1, NUL-terminated for `rsip_event_name`. Part of the
+/// C ABI: never reorder, only append.
+const EVENT_NAMES: &[&str] = &[
+    "sip_rx\0",
+    "sip_rx_parsed\0",
+    "sip_rx_malformed\0",
+    "sip_rx_invalid\0",
+    "sip_rx_truncated\0",
+    "sdp_parsed\0",
+    "error\0",
+    "filtered\0",
+    "rate_limited\0",
+    "listener_started\0",
+    "listener_stopped\0",
+    "tick\0",
+    "sent_ok\0",
+    "send_failed\0",
+    "dest_unreachable\0",
+    "registered\0",
+    "register_refresh\0",
+    "register_failed\0",
+    "ping_ok\0",
+    "ping_timeout\0",
+    "txn_provisional\0",
+    "txn_final\0",
+    "txn_timeout\0",
+    "dialog_created\0",
+    "dialog_terminated\0",
+    "tls_error\0",
+    "ws_error\0",
+    "sip_rx_retransmission\0",
+    "too_large\0",
+    "keepalive_ping\0",
+    "martian_dropped\0",
+    "trying_sent\0",
+    "too_many_headers\0",
+];
+
+/// `RsipEvent::src_ip` for a peer address.
+pub(crate) fn source_octets(src: Option<SocketAddr>) -> [u8; 16] {
+    src.map_or([0; 16], |s| match s.ip() {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    })
+}
+
+/// The kind code of an event name, 0 if it has none.
+pub(crate) fn event_kind(event: &str) -> u32 {
+    EVENT_NAMES
+        .iter()
+        .position(|name| name.strip_suffix('\0') == Some(event))
+        .map_or(0, |i| i as u32 + 1)
+}
+
+/// The callback flavours a host can register.
+#[derive(Clone, Copy)]
+pub(crate) enum Sink {
+    Basic(EventCallback),
+    WithSource(EventCallbackEx),
+    Bytes(EventCallbackBytes),
+    Struct(EventCallbackStruct),
+}
+
+/// What the C string flavours receive; built once per event, and only if needed.
+struct EventStrings {
+    event: CString,
+    payload: CString,
+    src_ip: CString,
+}
+
+#[derive(Clone)]
+struct Subscriber {
+    id: u64,
+    // `None` means every event.
+    filter: Option<Vec<String>>,
+    sink: Sink,
+}
+
+impl Subscriber {
+    fn wants(&self, event: &str) -> bool {
+        match &self.filter {
+            Some(names) => names.iter().any(|name| name == event),
+            None => true,
+        }
+    }
+}
+
+/// The set of event callbacks registered on a context. The single-callback APIs
+/// (`rsip_set_event_callback[_ex]`) are subscriptions for all events that replace the
+/// previous one of the same flavour, tracked separately so they never remove listeners
+/// added by id.
+#[derive(Default)]
+pub(crate) struct EventBus {
+    /// Copied on write, so `emit` takes a snapshot without allocating.
+    subscribers: Mutex<Arc<Vec<Subscriber>>>,
+    default_ids: Mutex<HashMap<Discriminant<Sink>, u64>>,
+    next_id: AtomicU64,
+    /// Callback invocations currently running, on any thread.
+    in_flight: AtomicUsize,
+    raw: Mutex<Option<RawCallback>>,
+    pub(crate) router: Mutex<Router>,
+    /// Events waiting for `rsip_poll_event`, and whether callbacks still run.
+    pub(crate) queue: EventQueue,
+    /// Events buffered for the batch callback, and the thread delivering them.
+    pub(crate) batch: Batcher,
+    /// The last sequence number handed out; see `next_seq`.
+    seq: AtomicU64,
+}
+
+impl EventBus {
+    /// Registers `cb` for the given event names (all events if `filter` is `None`).
+    /// Ids start at 1 so 0 can signal failure across the FFI.
+    pub fn subscribe(&self, filter: Option<Vec<String>>, sink: Sink) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut subscribers = self.subscribers.locked();
+        Arc::make_mut(&mut subscribers).push(Subscriber { id, filter, sink });
+        id
+    }
+
+    pub fn unsubscribe(&self, id: u64) -> bool {
+        let mut subscribers = self.subscribers.locked();
+        if !subscribers.iter().any(|s| s.id == id) {
+            return false;
+        }
+        Arc::make_mut(&mut subscribers).retain(|s| s.id != id);
+        true
+    }
+
+    pub fn set_default(&self, sink: Sink) {
+        let mut default_ids = self.default_ids.locked();
+        if let Some(id) = default_ids.remove(&std::mem::discriminant(&sink)) {
+            self.unsubscribe(id);
+        }
+        default_ids.insert(std::mem::discriminant(&sink), self.subscribe(None, sink));
+    }
+
+    /// Removes the catch-all callback of the same flavour as `like`.
+    pub fn clear_default(&self, like: Sink) {
+        if let Some(id) = self
+            .default_ids
+            .locked()
+            .remove(&std::mem::discriminant(&like))
+        {
+            self.unsubscribe(id);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn has_default(&self) -> bool {
+        !self.default_ids.locked().is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.batch.stop();
+        self.batch.set_callback(None);
+        self.default_ids.locked().clear();
+        *self.subscribers.locked() = Arc::default();
+        *self.raw.locked() = None;
+        *self.router.locked() = Router::default();
+        self.queue.set_mode(EventMode::Callbacks, 0);
+    }
+
+    /// True if any event callback is registered (the raw callback aside) or events
+    /// are queued for polling or batched.
+    pub fn has_subscribers(&self) -> bool {
+        self.queue.mode() != EventMode::Callbacks
+            || self.batch.enabled()
+            || !self.subscribers.locked().is_empty()
+    }
+
+    pub fn has_handlers(&self) -> bool {
+        !self.router.locked().is_empty()
+    }
+
+    pub fn set_raw_callback(&self, cb: Option<RawCallback>) {
+        *self.raw.locked() = cb;
+    }
+
+    pub fn raw_callback(&self) -> Option<RawCallback> {
+        *self.raw.locked()
+    }
+
+    /// Runs a host callback invoked outside `emit` (raw callback, message handlers),
+    /// counted as in flight like event callbacks. A panic in the library's own code
+    /// around the call is caught and reported; the callbacks themselves are `extern "C"`,
+    /// so one unwinding out of them aborts the process at the FFI boundary instead.
+    pub fn run_counted(&self, call: impl FnOnce()) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let result = catch_unwind(AssertUnwindSafe(call));
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        if let Err(panic) = result {
+            self.report_panic("", &*panic);
+        }
+    }
+
+    /// Reports a panic caught while dispatching a callback as an `error` event,
+    /// `callback_panic:<message>`, unless it was raised dispatching an `error` event,
+    /// which would only panic again.
+    fn report_panic(&self, event: &str, panic: &(dyn Any + Send)) {
+        if event == "error" {
+            return;
+        }
+        let message = match panic.downcast_ref::<&str>() {
+            Some(message) => message,
+            None => panic.downcast_ref::<String>().map_or("", String::as_str),
+        };
+        self.emit("error", &format!("callback_panic:{}", message), None);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Numbers every event emitted, from 1, before it can be dropped (by a full poll
+    /// queue, say), so that the host sees the loss as a gap.
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Uses up a sequence number for a message dropped before it produced any event.
+    pub fn skip_seq(&self) {
+        self.next_seq();
+    }
+
+    /// The sequence number of the last event emitted, 0 before the first.
+    pub fn last_seq(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    pub fn emit(&self, event: &str, payload: &str, src: Option<SocketAddr>) {
+        self.emit_with_bytes(event, payload, payload.as_bytes(), src);
+    }
+
+    /// Like `emit`, but `Bytes` callbacks receive `data` instead of the payload.
+    pub fn emit_with_bytes(
+        &self,
+        event: &str,
+        payload: &str,
+        data: &[u8],
+        src: Option<SocketAddr>,
+    ) {
+        let seq = self.next_seq();
+        match self.queue.mode() {
+            EventMode::Callbacks => {}
+            EventMode::Poll => return self.queue.push(event, data, src, seq),
+            EventMode::Both => self.queue.push(event, data, src, seq),
+        }
+        self.batch.push(event, data, src, seq);
+        // Snapshot the callbacks so they run without the lock held; a callback may then
+        // add or remove listeners without deadlocking.
+        let subscribers = self.subscribers.locked().clone();
+        let mut sinks = subscribers
+            .iter()
+            .filter(|s| s.wants(event))
+            .map(|s| s.sink)
+            .peekable();
+        if sinks.peek().is_none() {
+            return;
+        }
+
+        let strings = OnceCell::new();
+        let strings = || {
+            strings.get_or_init(|| EventStrings {
+                event: CString::new(event).unwrap_or_else(|_| CString::new("err").unwrap()),
+                payload: CString::new(payload).unwrap_or_else(|_| CString::new("").unwrap()),
+                src_ip: CString::new(src.map(|s| s.ip().to_string()).unwrap_or_default()).unwrap(),
+            })
+        };
+        let src_port = src.map(|s| s.port()).unwrap_or(0);
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let mut panicked = None;
+        for sink in sinks {
+            let call = || match sink {
+                Sink::Basic(cb) => {
+                    let s = strings();
+                    cb(s.event.as_ptr(), s.payload.as_ptr())
+                }
+                Sink::WithSource(cb) => {
+                    let s = strings();
+                    cb(
+                        s.event.as_ptr(),
+                        s.payload.as_ptr(),
+                        s.src_ip.as_ptr(),
+                        src_port,
+                    )
+                }
+                Sink::Bytes(cb) => {
+                    let s = strings();
+                    cb(
+                        s.event.as_ptr(),
+                        data.as_ptr(),
+                        data.len(),
+                        s.src_ip.as_ptr(),
+                        src_port,
+                    )
+                }
+                Sink::Struct(cb) => cb(&RsipEvent {
+                    kind: event_kind(event),
+                    data: data.as_ptr(),
+                    len: data.len(),
+                    src_ip: source_octets(src),
+                    src_port,
+                    seq,
+                }),
+            };
+            // Catches panics of ours (building the strings, say); the `extern "C"`
+            // callback itself cannot unwind into here.
+            if let Err(panic) = catch_unwind(AssertUnwindSafe(call)) {
+                panicked.get_or_insert(panic);
+            }
+        }
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        // CStrings drop here; the callee must copy data if it is needed beyond the call
+        if let Some(panic) = panicked {
+            self.report_panic(event, &*panic);
+        }
+    }
+}
+
+/// Parses `"sip_rx, error"` into event names. NULL, empty, or `"*"` means all events.
+fn parse_filter(events_csv: *const c_char) -> Option<Vec<String>> {
+    let csv = str_arg(events_csv)?;
+    let names: Vec<String> = csv
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect();
+    if names.is_empty() || names.iter().any(|name| name == "*") {
+        None
+    } else {
+        Some(names)
+    }
+}
+
+/// Registers an additional callback for a comma separated list of event names and
+/// returns its subscription id (never 0).
+#[no_mangle]
+pub extern "C" fn rsip_add_event_listener(events_csv: *const c_char, cb: EventCallback) -> u64 {
+    crate::default_context()
+        .events
+        .subscribe(parse_filter(events_csv), Sink::Basic(cb))
+}
+
+/// Like `rsip_add_event_listener` but the callback also receives the peer address.
+#[no_mangle]
+pub extern "C" fn rsip_add_event_listener_ex(
+    events_csv: *const c_char,
+    cb: EventCallbackEx,
+) -> u64 {
+    crate::default_context()
+        .events
+        .subscribe(parse_filter(events_csv), Sink::WithSource(cb))
+}
+
+/// Sets the catch-all callback that also receives the peer address. It has its own
+/// slot, independent of `rsip_set_event_callback`; both fire if both are set.
+#[no_mangle]
+pub extern "C" fn rsip_set_event_callback_ex(cb: EventCallbackEx) {
+    crate::default_context()
+        .events
+        .set_default(Sink::WithSource(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_clear_event_callback_ex() {
+    crate::default_context()
+        .events
+        .clear_default(Sink::WithSource(ex_placeholder));
+}
+
+/// Sets the catch-all callback that receives event data as bytes; `sip_rx` delivers the
+/// datagram untouched. It has its own slot, like `rsip_set_event_callback_ex`.
+#[no_mangle]
+pub extern "C" fn rsip_set_event_callback_bytes(cb: EventCallbackBytes) {
+    crate::default_context().events.set_default(Sink::Bytes(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_clear_event_callback_bytes() {
+    crate::default_context()
+        .events
+        .clear_default(Sink::Bytes(bytes_placeholder));
+}
+
+/// Sets the catch-all callback that receives each event as an `RsipEvent`, with no
+/// per-event allocation. It has its own slot, like `rsip_set_event_callback_ex`.
+#[no_mangle]
+pub extern "C" fn rsip_set_event_callback_struct(cb: EventCallbackStruct) {
+    crate::default_context()
+        .events
+        .set_default(Sink::Struct(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_clear_event_callback_struct() {
+    crate::default_context()
+        .events
+        .clear_default(Sink::Struct(struct_placeholder));
+}
+
+/// The name of event kind `kind` (static, do not free), or NULL for 0 and unknown kinds.
+#[no_mangle]
+pub extern "C" fn rsip_event_name(kind: u32) -> *const c_char {
+    match kind
+        .checked_sub(1)
+        .and_then(|i| EVENT_NAMES.get(i as usize))
+    {
+        Some(name) => name.as_ptr() as *const c_char,
+        None => std::ptr::null(),
+    }
+}
+
+// Only the discriminant matters when clearing a default callback.
+extern "C" fn struct_placeholder(_: *const RsipEvent) {}
+extern "C" fn ex_placeholder(_: *const c_char, _: *const c_char, _: *const c_char, _: u16) {}
+extern "C" fn bytes_placeholder(
+    _: *const c_char,
+    _: *const u8,
+    _: usize,
+    _: *const c_char,
+    _: u16,
+) {
+}
+
+/// Like `rsip_add_event_listener` but the callback receives event data as bytes.
+#[no_mangle]
+pub extern "C" fn rsip_add_event_listener_bytes(
+    events_csv: *const c_char,
+    cb: EventCallbackBytes,
+) -> u64 {
+    crate::default_context()
+        .events
+        .subscribe(parse_filter(events_csv), Sink::Bytes(cb))
+}
+
+/// Like `rsip_add_event_listener` but the callback receives an `RsipEvent`.
+#[no_mangle]
+pub extern "C" fn rsip_add_event_listener_struct(
+    events_csv: *const c_char,
+    cb: EventCallbackStruct,
+) -> u64 {
+    crate::default_context()
+        .events
+        .subscribe(parse_filter(events_csv), Sink::Struct(cb))
+}
+
+/// Removes a listener added with `rsip_add_event_listener`. Returns false for an unknown id.
+#[no_mangle]
+pub extern "C" fn rsip_remove_event_listener(id: u64) -> bool {
+    crate::default_context().events.unsubscribe(id)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_add_event_listener(
+    ctx: *mut RsipContext,
+    events_csv: *const c_char,
+    cb: EventCallback,
+) -> u64 {
+    with_context(ctx, |ctx| {
+        ctx.events
+            .subscribe(parse_filter(events_csv), Sink::Basic(cb))
+    })
+    .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_add_event_listener_ex(
+    ctx: *mut RsipContext,
+    events_csv: *const c_char,
+    cb: EventCallbackEx,
+) -> u64 {
+    with_context(ctx, |ctx| {
+        ctx.events
+            .subscribe(parse_filter(events_csv), Sink::WithSource(cb))
+    })
+    .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_event_callback_ex(ctx: *mut RsipContext, cb: EventCallbackEx) {
+    with_context(ctx, |ctx| ctx.events.set_default(Sink::WithSource(cb)));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_clear_event_callback_ex(ctx: *mut RsipContext) {
+    with_context(ctx, |ctx| {
+        ctx.events.clear_default(Sink::WithSource(ex_placeholder))
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_add_event_listener_bytes(
+    ctx: *mut RsipContext,
+    events_csv: *const c_char,
+    cb: EventCallbackBytes,
+) -> u64 {
+    with_context(ctx, |ctx| {
+        ctx.events
+            .subscribe(parse_filter(events_csv), Sink::Bytes(cb))
+    })
+    .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_event_callback_bytes(
+    ctx: *mut RsipContext,
+    cb: EventCallbackBytes,
+) {
+    with_context(ctx, |ctx| ctx.events.set_default(Sink::Bytes(cb)));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_clear_event_callback_bytes(ctx: *mut RsipContext) {
+    with_context(ctx, |ctx| {
+        ctx.events.clear_default(Sink::Bytes(bytes_placeholder))
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_add_event_listener_struct(
+    ctx: *mut RsipContext,
+    events_csv: *const c_char,
+    cb: EventCallbackStruct,
+) -> u64 {
+    with_context(ctx, |ctx| {
+        ctx.events
+            .subscribe(parse_filter(events_csv), Sink::Struct(cb))
+    })
+    .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_event_callback_struct(
+    ctx: *mut RsipContext,
+    cb: EventCallbackStruct,
+) {
+    with_context(ctx, |ctx| ctx.events.set_default(Sink::Struct(cb)));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_clear_event_callback_struct(ctx: *mut RsipContext) {
+    with_context(ctx, |ctx| {
+        ctx.events.clear_default(Sink::Struct(struct_placeholder))
+    });
+}
+
+/// The sequence number of the last event emitted (`RsipEvent::seq`), 0 before the first.
+#[no_mangle]
+pub extern "C" fn rsip_last_event_seq() -> u64 {
+    crate::default_context().events.last_seq()
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_last_event_seq(ctx: *mut RsipContext) -> u64 {
+    with_context(ctx, |ctx| ctx.events.last_seq()).unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_remove_event_listener(ctx: *mut RsipContext, id: u64) -> bool {
+    with_context(ctx, |ctx| ctx.events.unsubscribe(id)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    static RX_SEEN: AtomicU64 = AtomicU64::new(0);
+    static ERRORS_SEEN: AtomicU64 = AtomicU64::new(0);
+    static ALL_SEEN: AtomicU64 = AtomicU64::new(0);
+
+    extern "C" fn on_rx(event: *const c_char, _payload: *const c_char) {
+        assert_eq!(unsafe { CStr::from_ptr(event) }.to_str().unwrap(), "sip_rx");
+        RX_SEEN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn on_error(event: *const c_char, _payload: *const c_char) {
+        assert_eq!(unsafe { CStr::from_ptr(event) }.to_str().unwrap(), "error");
+        ERRORS_SEEN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn on_any(_event: *const c_char, _payload: *const c_char) {
+        ALL_SEEN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn listeners_only_receive_their_events() {
+        let bus = EventBus::default();
+        let rx = bus.subscribe(Some(vec!["sip_rx".into()]), Sink::Basic(on_rx));
+        bus.subscribe(
+            Some(vec!["error".into(), "other".into()]),
+            Sink::Basic(on_error),
+        );
+        bus.set_default(Sink::Basic(on_any));
+        assert!(rx > 0);
+
+        bus.emit("sip_rx", "a", None);
+        bus.emit("error", "b", None);
+        bus.emit("sip_rx_parsed", "c", None);
+        assert_eq!(RX_SEEN.load(Ordering::SeqCst), 1);
+        assert_eq!(ERRORS_SEEN.load(Ordering::SeqCst), 1);
+        assert_eq!(ALL_SEEN.load(Ordering::SeqCst), 3);
+
+        assert!(bus.unsubscribe(rx));
+        assert!(!bus.unsubscribe(rx), "second removal reports unknown id");
+        bus.emit("sip_rx", "d", None);
+        assert_eq!(RX_SEEN.load(Ordering::SeqCst), 1);
+
+        // replacing the default callback keeps id-based listeners intact
+        bus.set_default(Sink::Basic(on_any));
+        bus.clear_default(Sink::Basic(on_any));
+        bus.emit("error", "e", None);
+        assert_eq!(ERRORS_SEEN.load(Ordering::SeqCst), 2);
+        assert_eq!(ALL_SEEN.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn source_is_delivered_to_ex_callbacks() {
+        static SRC: Mutex<Option<(String, u16)>> = Mutex::new(None);
+        extern "C" fn on_src(
+            _event: *const c_char,
+            _payload: *const c_char,
+            src_ip: *const c_char,
+            src_port: u16,
+        ) {
+            let ip = unsafe { CStr::from_ptr(src_ip) }
+                .to_str()
+                .unwrap()
+                .to_string();
+            *SRC.lock().unwrap() = Some((ip, src_port));
+        }
+
+        extern "C" fn ignore(_event: *const c_char, _payload: *const c_char) {}
+
+        let bus = EventBus::default();
+        bus.set_default(Sink::WithSource(on_src));
+        bus.set_default(Sink::Basic(ignore));
+        bus.emit("sip_rx", "x", Some("192.0.2.10:5070".parse().unwrap()));
+        assert_eq!(*SRC.lock().unwrap(), Some(("192.0.2.10".to_string(), 5070)));
+
+        bus.emit("error", "y", None);
+        assert_eq!(*SRC.lock().unwrap(), Some((String::new(), 0)));
+
+        // flavours have independent default slots
+        bus.clear_default(Sink::Basic(ignore));
+        assert!(bus.has_default());
+    }
+
+    #[test]
+    fn struct_callbacks_get_kind_data_and_source() {
+        type Seen = (u32, Vec<u8>, [u8; 16], u16, u64);
+        static SEEN: Mutex<Vec<Seen>> = Mutex::new(Vec::new());
+        extern "C" fn on_event(event: *const RsipEvent) {
+            let event = unsafe { &*event };
+            let data = unsafe { std::slice::from_raw_parts(event.data, event.len) }.to_vec();
+            SEEN.lock()
+                .unwrap()
+                .push((event.kind, data, event.src_ip, event.src_port, event.seq));
+        }
+
+        let bus = EventBus::default();
+        bus.subscribe(
+            Some(vec!["sip_rx".into(), "error".into(), "custom".into()]),
+            Sink::Struct(on_event),
+        );
+        bus.emit_with_bytes(
+            "sip_rx",
+            "lossy",
+            b"raw\0bytes",
+            Some("192.0.2.10:5070".parse().unwrap()),
+        );
+        bus.emit(
+            "error",
+            "bind_err:x",
+            Some("[2001:db8::1]:5060".parse().unwrap()),
+        );
+        bus.emit("custom", "", None);
+        bus.emit("tick", "ignored", None);
+
+        let mapped = "::ffff:192.0.2.10".parse::<std::net::Ipv6Addr>().unwrap();
+        let v6 = "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap();
+        assert_eq!(
+            *SEEN.lock().unwrap(),
+            vec![
+                (1, b"raw\0bytes".to_vec(), mapped.octets(), 5070, 1),
+                (7, b"bind_err:x".to_vec(), v6.octets(), 5060, 2),
+                (0, Vec::new(), [0; 16], 0, 3),
+            ]
+        );
+        // numbered too, though nobody listens
+        assert_eq!(bus.last_seq(), 4);
+
+        let name = |kind| {
+            let name = rsip_event_name(kind);
+            (!name.is_null()).then(|| unsafe { CStr::from_ptr(name) }.to_str().unwrap())
+        };
+        assert_eq!(name(1), Some("sip_rx"));
+        assert_eq!(name(0), None);
+        assert_eq!(name(EVENT_NAMES.len() as u32 + 1), None);
+        for kind in 1..=EVENT_NAMES.len() as u32 {
+            assert_eq!(event_kind(name(kind).unwrap()), kind);
+        }
+    }
+
+    #[test]
+    fn panics_while_dispatching_do_not_take_the_bus_down() {
+        static ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        extern "C" fn on_error(_event: *const c_char, payload: *const c_char) {
+            let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+            ERRORS.lock().unwrap().push(payload.to_string());
+        }
+
+        let bus = Arc::new(EventBus::default());
+        bus.subscribe(Some(vec!["error".into()]), Sink::Basic(on_error));
+        // Library code panicking around a callback is caught; a registered
+        // `extern "C"` callback that panics would abort instead.
+        bus.run_counted(|| panic!("boom"));
+        assert_eq!(bus.in_flight(), 0);
+        assert_eq!(*ERRORS.lock().unwrap(), ["callback_panic:boom"]);
+
+        // a panic with the subscriber list locked leaves it usable
+        let poisoner = bus.clone();
+        let _ = std::thread::spawn(move || {
+            let _subscribers = poisoner.subscribers.lock().unwrap();
+            panic!("while locked");
+        })
+        .join();
+        assert!(bus.subscribers.is_poisoned());
+        bus.emit("error", "still delivered", None);
+        assert!(bus.unsubscribe(1));
+        assert_eq!(ERRORS.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn filter_parsing() {
+        let csv = CString::new(" sip_rx ,error,, ").unwrap();
+        assert_eq!(
+            parse_filter(csv.as_ptr()),
+            Some(vec!["sip_rx".to_string(), "error".to_string()])
+        );
+        let star = CString::new("*").unwrap();
+        assert_eq!(parse_filter(star.as_ptr()), None);
+        assert_eq!(parse_filter(std::ptr::null()), None);
+    }
+}
@@ -0,0 +1,95 @@
+//! Small helpers shared by the `extern "C"` functions for moving strings across the boundary.
+//!
+//! Ownership convention: a function returning `*const c_char` hands out a static string
+//! that must not be freed; a function returning `*mut c_char` hands out a heap string
+//! owned by the caller, who must release it with [`rsip_free_string`]. Byte buffers
+//! returned as `*mut u8` with their length are released with [`rsip_free_bytes`].
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+
+/// Borrows a NUL-terminated UTF-8 argument, or `None` for NULL / invalid UTF-8.
+pub(crate) fn str_arg<'a>(ptr: *const c_char) -> Option<&'a str> {
+    if ptr.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(ptr) }.to_str().ok()
+}
+
+/// Copies `s` plus a trailing NUL into a caller-provided buffer of `len` bytes.
+/// Returns false (leaving the buffer untouched) if it does not fit.
+pub(crate) fn write_to_buf(s: &str, buf: *mut c_char, len: usize) -> bool {
+    if buf.is_null() || s.len() >= len {
+        return false;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(s.as_ptr() as *const c_char, buf, s.len());
+        *buf.add(s.len()) = 0;
+    }
+    true
+}
+
+/// Hands a Rust string to the caller as a heap C string (caller-owned, see module docs).
+/// Returns NULL if `s` contains an interior NUL.
+pub(crate) fn into_c_string(s: String) -> *mut c_char {
+    match CString::new(s) {
+        Ok(s) => s.into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Releases a string previously returned as `*mut c_char` by this library. NULL is a no-op.
+/// Passing any other pointer, or freeing twice, is undefined behaviour.
+#[no_mangle]
+pub extern "C" fn rsip_free_string(ptr: *mut c_char) {
+    if ptr.is_null() {
+        return;
+    }
+    drop(unsafe { CString::from_raw(ptr) });
+}
+
+/// Hands `bytes` to the caller as a heap buffer, writing its length to `out_len`
+/// (caller-owned, released with [`rsip_free_bytes`]).
+pub(crate) fn into_c_bytes(bytes: Vec<u8>, out_len: &mut usize) -> *mut u8 {
+    *out_len = bytes.len();
+    Box::into_raw(bytes.into_boxed_slice()) as *mut u8
+}
+
+/// Releases a buffer of `len` bytes previously returned as `*mut u8` by this library,
+/// `len` being the length reported with it. NULL is a no-op. Any other pointer or
+/// length, or freeing twice, is undefined behaviour.
+#[no_mangle]
+pub extern "C" fn rsip_free_bytes(ptr: *mut u8, len: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)) });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returned_strings_round_trip_through_free() {
+        for i in 0..1000 {
+            let ptr = CString::new(format!("value-{}", i)).unwrap().into_raw();
+            assert!(!ptr.is_null());
+            assert_eq!(str_arg(ptr), Some(format!("value-{}", i).as_str()));
+            rsip_free_string(ptr);
+        }
+        rsip_free_string(std::ptr::null_mut());
+    }
+
+    #[test]
+    fn returned_bytes_round_trip_through_free() {
+        for bytes in [Vec::new(), b"a\0b".to_vec()] {
+            let mut len = usize::MAX;
+            let ptr = into_c_bytes(bytes.clone(), &mut len);
+            assert_eq!(len, bytes.len());
+            assert_eq!(unsafe { std::slice::from_raw_parts(ptr, len) }, &bytes[..]);
+            rsip_free_bytes(ptr, len);
+        }
+        rsip_free_bytes(std::ptr::null_mut(), 0);
+    }
+}
@@ -0,0 +1,232 @@
+//! P-Asserted-Identity (RFC 3325) and Privacy (RFC 3323) headers, which carrier trunks
+//! send and expect on calls between trusted networks.
+//!
+//! rsip keeps both as untyped headers, so they are looked up by name in the raw header
+//! section, like the From/To tag lookups, and built here.
+
+use crate::ffi::{into_c_string, str_arg};
+use crate::message::{raw_header_values, split_list};
+use crate::reason::{quote, unquote};
+use serde_json::json;
+use std::os::raw::c_char;
+
+/// Privacy values (RFC 3323 section 4.2, `id` from RFC 3325) as bits of the mask
+/// `privacy_flags` returns. Part of the C ABI (`RSIP_PRIVACY_*`).
+pub const PRIVACY_HEADER: u32 = 1 << 0;
+pub const PRIVACY_SESSION: u32 = 1 << 1;
+pub const PRIVACY_USER: u32 = 1 << 2;
+pub const PRIVACY_NONE: u32 = 1 << 3;
+pub const PRIVACY_CRITICAL: u32 = 1 << 4;
+pub const PRIVACY_ID: u32 = 1 << 5;
+
+const PRIVACY_VALUES: &[(&str, u32)] = &[
+    ("header", PRIVACY_HEADER),
+    ("session", PRIVACY_SESSION),
+    ("user", PRIVACY_USER),
+    ("none", PRIVACY_NONE),
+    ("critical", PRIVACY_CRITICAL),
+    ("id", PRIVACY_ID),
+];
+
+/// One identity asserted by a P-Asserted-Identity header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub display: Option<String>,
+    /// A sip, sips or tel URI.
+    pub uri: String,
+}
+
+/// Whether `uri` is a sip, sips or tel URI that can stand between angle brackets.
+fn is_identity_uri(uri: &str) -> bool {
+    let (scheme, rest) = match uri.split_once(':') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    ["sip", "sips", "tel"]
+        .iter()
+        .any(|s| s.eq_ignore_ascii_case(scheme))
+        && !rest.is_empty()
+        && !uri
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "<>\"".contains(c))
+}
+
+/// Parses one entry, `"Display" <uri>`, `Display <uri>` or a bare URI; parameters
+/// after the closing bracket are ignored. `None` if it holds no identity URI.
+fn parse_identity(entry: &str) -> Option<Identity> {
+    let (display, uri) = match entry.split_once('<') {
+        Some((display, rest)) => {
+            let display = unquote(display)?;
+            let uri = rest.split_once('>')?.0.trim();
+            (Some(display).filter(|d| !d.is_empty()), uri)
+        }
+        None => (None, entry.trim()),
+    };
+    if !is_identity_uri(uri) {
+        return None;
+    }
+    Some(Identity {
+        display,
+        uri: uri.to_string(),
+    })
+}
+
+/// The identities of every P-Asserted-Identity header of `raw`, in order; entries
+/// that are not an identity are skipped, so one malformed value does not hide the
+/// others.
+pub fn asserted_identities(raw: &[u8]) -> Vec<Identity> {
+    raw_header_values(raw, "P-Asserted-Identity")
+        .iter()
+        .flat_map(|value| split_list(value))
+        .filter_map(parse_identity)
+        .collect()
+}
+
+/// A P-Asserted-Identity value for `uri` with an optional display name, e.g.
+/// `"Alice" <sip:alice@example.com>`. `None` if `uri` is not a sip, sips or tel URI
+/// or `display` cannot be quoted.
+pub fn build_pai(uri: &str, display: Option<&str>) -> Option<String> {
+    let uri = uri.trim();
+    if !is_identity_uri(uri) {
+        return None;
+    }
+    match display.map(str::trim).filter(|d| !d.is_empty()) {
+        Some(display) => Some(format!("{} <{}>", quote(display)?, uri)),
+        None => Some(format!("<{}>", uri)),
+    }
+}
+
+/// The Privacy values requested by `raw` as `PRIVACY_*` bits, 0 if it has no Privacy
+/// header. Values are separated by semicolons (commas are accepted too) and matched
+/// case-insensitively; unknown ones are ignored.
+pub fn privacy_flags(raw: &[u8]) -> u32 {
+    raw_header_values(raw, "Privacy")
+        .iter()
+        .flat_map(|value| value.split([';', ',']))
+        .filter_map(|value| {
+            PRIVACY_VALUES
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(value.trim()))
+        })
+        .fold(0, |flags, (_, flag)| flags | flag)
+}
+
+/// The P-Asserted-Identity headers of `raw` as a caller-owned JSON array of
+/// `{display, uri}`, or NULL if `raw` is NULL or asserts no identity.
+#[no_mangle]
+pub extern "C" fn rsip_get_pai(raw: *const c_char) -> *mut c_char {
+    let identities = match str_arg(raw).map(|raw| asserted_identities(raw.as_bytes())) {
+        Some(identities) if !identities.is_empty() => identities,
+        _ => return std::ptr::null_mut(),
+    };
+    let identities: Vec<serde_json::Value> = identities
+        .into_iter()
+        .map(|i| json!({ "display": i.display, "uri": i.uri }))
+        .collect();
+    into_c_string(serde_json::Value::from(identities).to_string())
+}
+
+/// A caller-owned P-Asserted-Identity value for `uri`; `display` may be NULL. NULL if
+/// `uri` is NULL or not a sip, sips or tel URI, or `display` contains control
+/// characters.
+#[no_mangle]
+pub extern "C" fn rsip_build_pai(uri: *const c_char, display: *const c_char) -> *mut c_char {
+    str_arg(uri)
+        .and_then(|uri| build_pai(uri, str_arg(display)))
+        .map_or(std::ptr::null_mut(), into_c_string)
+}
+
+/// The Privacy values requested by `raw` as `RSIP_PRIVACY_*` bits; 0 if `raw` is NULL
+/// or has no Privacy header.
+#[no_mangle]
+pub extern "C" fn rsip_get_privacy(raw: *const c_char) -> u32 {
+    str_arg(raw).map_or(0, |raw| privacy_flags(raw.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::rsip_free_string;
+    use std::ffi::{CStr, CString};
+
+    const INVITE: &str = "INVITE sip:+15551230000@trunk.example.com SIP/2.0\r\n\
+        Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKpai\r\n\
+        From: \"Anonymous\" <sip:anonymous@anonymous.invalid>;tag=1\r\n\
+        P-Asserted-Identity: \"Alice, Sales\" <sip:+15550001111@pbx.example.com;user=phone>,\r\n \
+        <tel:+15550001111>\r\n\
+        p-asserted-identity: not an identity, sip:bob@example.com\r\n\
+        Privacy: id;Header ; critical\r\n\
+        Content-Length: 0\r\n\r\n";
+
+    #[test]
+    fn reads_asserted_identities_and_privacy() {
+        assert_eq!(
+            asserted_identities(INVITE.as_bytes()),
+            [
+                Identity {
+                    display: Some("Alice, Sales".into()),
+                    uri: "sip:+15550001111@pbx.example.com;user=phone".into(),
+                },
+                Identity {
+                    display: None,
+                    uri: "tel:+15550001111".into(),
+                },
+                Identity {
+                    display: None,
+                    uri: "sip:bob@example.com".into(),
+                },
+            ]
+        );
+        assert_eq!(
+            privacy_flags(INVITE.as_bytes()),
+            PRIVACY_ID | PRIVACY_HEADER | PRIVACY_CRITICAL
+        );
+
+        let raw = CString::new(INVITE).unwrap();
+        let out = rsip_get_pai(raw.as_ptr());
+        let json: serde_json::Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        rsip_free_string(out);
+        assert_eq!(json[0]["display"], "Alice, Sales");
+        assert_eq!(json[1]["display"], serde_json::Value::Null);
+        assert_eq!(json[2]["uri"], "sip:bob@example.com");
+
+        let headers_before = &INVITE[..INVITE.find("P-Asserted").unwrap()];
+        let plain = CString::new(format!("{}Content-Length: 0\r\n\r\n", headers_before)).unwrap();
+        assert!(rsip_get_pai(plain.as_ptr()).is_null());
+        assert_eq!(rsip_get_privacy(plain.as_ptr()), 0);
+        assert_eq!(rsip_get_privacy(std::ptr::null()), 0);
+    }
+
+    #[test]
+    fn builds_asserted_identities() {
+        assert_eq!(
+            build_pai("sip:alice@example.com", Some("Alice \"A\" Smith")).as_deref(),
+            Some("\"Alice \\\"A\\\" Smith\" <sip:alice@example.com>")
+        );
+        assert_eq!(
+            build_pai("tel:+15550001111", None).as_deref(),
+            Some("<tel:+15550001111>")
+        );
+        assert_eq!(build_pai("mailto:alice@example.com", None), None);
+        assert_eq!(build_pai("sip:alice@example.com>, <sip:x", None), None);
+        assert_eq!(
+            build_pai("sip:alice@example.com", Some("a\r\nVia: x")),
+            None
+        );
+
+        let built = build_pai("sips:bob@example.com", Some("Bob")).unwrap();
+        let raw = format!(
+            "{}P-Asserted-Identity: {}\r\n\r\n",
+            &INVITE[..INVITE.find("From").unwrap()],
+            built
+        );
+        assert_eq!(
+            asserted_identities(raw.as_bytes()),
+            [Identity {
+                display: Some("Bob".into()),
+                uri: "sips:bob@example.com".into(),
+            }]
+        );
+    }
+}
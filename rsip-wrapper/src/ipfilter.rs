@@ -0,0 +1,436 @@
+//! Source address filtering: an allowlist or denylist of CIDR ranges, checked for every
+//! received message before rate limiting and parsing.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::ffi::str_arg;
+use crate::lock::Lock;
+use crate::stats::Stats;
+use serde_json::json;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::raw::c_char;
+use std::str::FromStr;
+
+/// An address range such as `10.0.0.0/8` or `2001:db8::/32`. A bare address is a
+/// single-host range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| ())?;
+        let width = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u32>().map_err(|_| ())?,
+            None => width,
+        };
+        if prefix > width {
+            return Err(());
+        }
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Cidr {
+    /// IPv4-mapped IPv6 sources match IPv4 ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                (u32::from(net) ^ u32::from(ip)) & mask == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                (u128::from(net) ^ u128::from(ip)) & mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+const fn v4(a: u8, b: u8, c: u8, d: u8, prefix: u32) -> Cidr {
+    Cidr {
+        network: IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        prefix,
+    }
+}
+
+const fn v6(network: Ipv6Addr, prefix: u32) -> Cidr {
+    Cidr {
+        network: IpAddr::V6(network),
+        prefix,
+    }
+}
+
+/// Sources no genuine packet can come from: "this network", documentation and
+/// benchmarking ranges, multicast, reserved and broadcast (RFC 6890).
+const MARTIANS: &[Cidr] = &[
+    v4(0, 0, 0, 0, 8),
+    v4(192, 0, 2, 0, 24),
+    v4(198, 18, 0, 0, 15),
+    v4(198, 51, 100, 0, 24),
+    v4(203, 0, 113, 0, 24),
+    v4(224, 0, 0, 0, 4),
+    v4(240, 0, 0, 0, 4),
+    v6(Ipv6Addr::UNSPECIFIED, 128),
+    v6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 32),
+    v6(Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8),
+];
+
+/// Loopback, private, shared (CGNAT) and link-local ranges: fine on a listener bound to
+/// such an address or to all of them, spoofed on one bound to a public address.
+const LOCAL_SCOPE: &[Cidr] = &[
+    v4(127, 0, 0, 0, 8),
+    v4(10, 0, 0, 0, 8),
+    v4(172, 16, 0, 0, 12),
+    v4(192, 168, 0, 0, 16),
+    v4(100, 64, 0, 0, 10),
+    v4(169, 254, 0, 0, 16),
+    v6(Ipv6Addr::LOCALHOST, 128),
+    v6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+    v6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+];
+
+fn in_any(ranges: &[Cidr], ip: IpAddr) -> bool {
+    ranges.iter().any(|range| range.contains(ip))
+}
+
+/// Whether `src` is a martian for a listener bound to `local`: always for reserved
+/// ranges, and for loopback or private sources when `local` is a specific public
+/// address (a wildcard listener cannot tell which interface a datagram came in on).
+pub(crate) fn is_martian(src: IpAddr, local: Option<IpAddr>) -> bool {
+    if in_any(MARTIANS, src) {
+        return true;
+    }
+    let public = local.is_some_and(|local| !local.is_unspecified() && !in_any(LOCAL_SCOPE, local));
+    public && in_any(LOCAL_SCOPE, src)
+}
+
+/// The numeric values are part of the C ABI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    Off = 0,
+    /// Only sources inside a listed range are accepted.
+    Allow = 1,
+    /// Sources inside a listed range are rejected.
+    Deny = 2,
+}
+
+impl FilterMode {
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(Self::Off),
+            1 => Some(Self::Allow),
+            2 => Some(Self::Deny),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct IpFilter {
+    mode: FilterMode,
+    ranges: Vec<Cidr>,
+    drop_martians: bool,
+}
+
+impl Default for IpFilter {
+    fn default() -> Self {
+        Self {
+            mode: FilterMode::Off,
+            ranges: Vec::new(),
+            drop_martians: false,
+        }
+    }
+}
+
+impl IpFilter {
+    /// An empty allowlist permits nothing.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let listed = || self.ranges.iter().any(|range| range.contains(ip));
+        match self.mode {
+            FilterMode::Off => true,
+            FilterMode::Allow => listed(),
+            FilterMode::Deny => !listed(),
+        }
+    }
+}
+
+impl RsipContext {
+    /// Takes effect immediately, for every listener.
+    pub fn set_ip_filter_mode(&self, mode: FilterMode) {
+        self.ip_filter.locked().mode = mode;
+    }
+
+    pub fn ip_filter_add(&self, cidr: &str) -> Result<(), RsipError> {
+        let cidr = cidr.parse().map_err(|_| RsipError::InvalidArgument)?;
+        self.ip_filter.locked().ranges.push(cidr);
+        Ok(())
+    }
+
+    /// Removes every range; the mode is kept.
+    pub fn ip_filter_clear(&self) {
+        self.ip_filter.locked().ranges.clear();
+    }
+
+    /// Drops datagrams the UDP listeners receive from martian sources (see
+    /// `is_martian`). Takes effect immediately; off by default.
+    pub fn set_drop_martians(&self, enabled: bool) {
+        self.ip_filter.locked().drop_martians = enabled;
+    }
+
+    /// Whether a datagram from `src` to the UDP listener `listener`, bound to `local`,
+    /// is dropped as a martian; dropped ones are counted as filtered and reported as
+    /// `martian_dropped`.
+    pub(crate) fn drop_martian(
+        &self,
+        len: usize,
+        src: SocketAddr,
+        local: Option<IpAddr>,
+        listener: u64,
+    ) -> bool {
+        if !self.ip_filter.locked().drop_martians || !is_martian(src.ip(), local) {
+            return false;
+        }
+        Stats::add(&self.stats.filtered, 1);
+        let payload = json!({ "src": src.to_string(), "len": len, "listener": listener });
+        self.emit_from("martian_dropped", &payload.to_string(), src);
+        true
+    }
+
+    /// Whether the filter accepts messages from `src`; rejected messages are counted and
+    /// reported as `filtered`.
+    pub(crate) fn filter_source(&self, len: usize, src: SocketAddr) -> bool {
+        if self.ip_filter.locked().permits(src.ip()) {
+            return true;
+        }
+        Stats::add(&self.stats.filtered, 1);
+        let payload = json!({ "src": src.to_string(), "len": len });
+        self.emit_from("filtered", &payload.to_string(), src);
+        false
+    }
+}
+
+/// Sets the source filter mode: 0 off (default), 1 allowlist, 2 denylist. Returns false
+/// for an unknown mode.
+#[no_mangle]
+pub extern "C" fn rsip_set_ip_filter_mode(mode: i32) -> bool {
+    match FilterMode::from_code(mode) {
+        Some(mode) => {
+            crate::default_context().set_ip_filter_mode(mode);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Adds a range ("192.0.2.0/24", "2001:db8::/32", or a bare address) to the filter
+/// list. Returns false if it does not parse.
+#[no_mangle]
+pub extern "C" fn rsip_ip_filter_add(cidr: *const c_char) -> bool {
+    match str_arg(cidr) {
+        Some(cidr) => crate::default_context().ip_filter_add(cidr).is_ok(),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_ip_filter_clear() {
+    crate::default_context().ip_filter_clear();
+}
+
+/// Drops datagrams from martian sources on the UDP listeners, reporting each as
+/// `martian_dropped`.
+#[no_mangle]
+pub extern "C" fn rsip_set_drop_martians(enabled: bool) {
+    crate::default_context().set_drop_martians(enabled);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_drop_martians(ctx: *mut RsipContext, enabled: bool) {
+    with_context(ctx, |ctx| ctx.set_drop_martians(enabled));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_ip_filter_mode(ctx: *mut RsipContext, mode: i32) -> bool {
+    match FilterMode::from_code(mode) {
+        Some(mode) => with_context(ctx, |ctx| ctx.set_ip_filter_mode(mode)).is_some(),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_ip_filter_add(ctx: *mut RsipContext, cidr: *const c_char) -> bool {
+    match str_arg(cidr) {
+        Some(cidr) => with_context(ctx, |ctx| ctx.ip_filter_add(cidr).is_ok()).unwrap_or(false),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_ip_filter_clear(ctx: *mut RsipContext) {
+    with_context(ctx, |ctx| ctx.ip_filter_clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::ffi::CStr;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_and_matches_ranges() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.1.2")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("192.0.2.7").contains(ip("192.0.2.7")));
+        assert!(!cidr("192.0.2.7").contains(ip("192.0.2.8")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(cidr("::/0").contains(ip("::1")));
+        // Families never match each other, except for IPv4-mapped sources.
+        assert!(!cidr("::/0").contains(ip("192.0.2.1")));
+        assert!(cidr("192.0.2.0/24").contains(ip("::ffff:192.0.2.1")));
+
+        for bad in ["", "10.0.0.0/33", "::/129", "10.0.0.0/x", "example.com/8"].iter() {
+            assert!(bad.parse::<Cidr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn modes() {
+        let mut filter = IpFilter::default();
+        assert!(filter.permits(ip("192.0.2.1")));
+        filter.mode = FilterMode::Allow;
+        assert!(!filter.permits(ip("192.0.2.1")), "empty allowlist");
+        filter.ranges.push(cidr("192.0.2.0/24"));
+        assert!(filter.permits(ip("192.0.2.1")));
+        assert!(!filter.permits(ip("198.51.100.1")));
+        filter.mode = FilterMode::Deny;
+        assert!(!filter.permits(ip("192.0.2.1")));
+        assert!(filter.permits(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn recognizes_martians() {
+        let public = Some(ip("198.52.0.10"));
+        for src in [
+            "0.1.2.3",
+            "192.0.2.1",
+            "198.19.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::",
+            "2001:db8::1",
+            "ff02::1",
+        ]
+        .iter()
+        {
+            assert!(is_martian(ip(src), None), "{}", src);
+            assert!(is_martian(ip(src), Some(ip("0.0.0.0"))), "{}", src);
+        }
+        for src in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.31.0.1",
+            "192.168.1.1",
+            "100.64.0.1",
+            "169.254.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ]
+        .iter()
+        {
+            assert!(is_martian(ip(src), public), "{}", src);
+            assert!(!is_martian(ip(src), Some(ip("0.0.0.0"))), "{}", src);
+            assert!(!is_martian(ip(src), Some(ip("::"))), "{}", src);
+            assert!(!is_martian(ip(src), Some(ip("192.168.1.2"))), "{}", src);
+        }
+        for src in ["198.52.0.1", "2a00::1", "172.32.0.1"].iter() {
+            assert!(!is_martian(ip(src), public), "{}", src);
+        }
+    }
+
+    static MARTIANS_DROPPED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    extern "C" fn record_martian(_: *const c_char, payload: *const c_char) {
+        let payload = unsafe { CStr::from_ptr(payload) }
+            .to_string_lossy()
+            .into_owned();
+        MARTIANS_DROPPED.lock().unwrap().push(payload);
+    }
+
+    #[test]
+    fn drops_martians_only_when_enabled() {
+        let ctx = RsipContext::new();
+        ctx.events.subscribe(
+            Some(vec!["martian_dropped".into()]),
+            Sink::Basic(record_martian),
+        );
+        let public = Some(ip("198.52.0.10"));
+        let loopback = "127.0.0.1:5060".parse().unwrap();
+        assert!(!ctx.drop_martian(10, loopback, public, 1));
+        ctx.set_drop_martians(true);
+        assert!(!ctx.drop_martian(10, loopback, Some(ip("0.0.0.0")), 1));
+        assert!(ctx.drop_martian(10, loopback, public, 1));
+        assert_eq!(ctx.stats.filtered.load(Ordering::Relaxed), 1);
+        let dropped: serde_json::Value =
+            serde_json::from_str(&MARTIANS_DROPPED.lock().unwrap()[0]).unwrap();
+        assert_eq!(
+            dropped,
+            json!({ "src": "127.0.0.1:5060", "len": 10, "listener": 1 })
+        );
+    }
+
+    static FILTERED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(_: *const c_char, payload: *const c_char) {
+        let payload = unsafe { CStr::from_ptr(payload) }
+            .to_string_lossy()
+            .into_owned();
+        FILTERED.lock().unwrap().push(payload);
+    }
+
+    #[test]
+    fn denied_sources_never_reach_the_pipeline() {
+        let ctx = RsipContext::new();
+        ctx.events
+            .subscribe(Some(vec!["filtered".into()]), Sink::Basic(record));
+        assert!(ctx.ip_filter_add("2001:db8::/32").is_ok());
+        assert!(ctx.ip_filter_add("10.0.0.0/33").is_err());
+        ctx.set_ip_filter_mode(FilterMode::Deny);
+
+        let msg = b"OPTIONS sip:a@b SIP/2.0\r\n\r\n";
+        ctx.dispatch(msg, "[2001:db8::5]:5060".parse().unwrap());
+        ctx.dispatch(msg, "192.0.2.1:5060".parse().unwrap());
+        assert_eq!(ctx.stats.packets_received.load(Ordering::Relaxed), 1);
+        assert_eq!(ctx.stats.filtered.load(Ordering::Relaxed), 1);
+        let filtered: serde_json::Value =
+            serde_json::from_str(&FILTERED.lock().unwrap()[0]).unwrap();
+        assert_eq!(filtered["src"], "[2001:db8::5]:5060");
+
+        ctx.ip_filter_clear();
+        ctx.dispatch(msg, "[2001:db8::5]:5060".parse().unwrap());
+        assert_eq!(ctx.stats.packets_received.load(Ordering::Relaxed), 2);
+    }
+}
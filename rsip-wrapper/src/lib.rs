@@ -1,178 +1,478 @@
+// The C API is made of safe `extern "C"` functions that null-check their pointer
+// arguments before dereferencing them, which is the contract documented in the header.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 use lazy_static::lazy_static;
-use std::ffi::{CStr, CString};
+use std::ffi::CStr;
 use std::os::raw::c_char;
-use std::net::UdpSocket;
-use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub mod auth;
+pub mod batch;
+pub mod body;
+pub mod builder;
+pub mod capture;
+mod config;
+pub mod context;
+mod diagnose;
+pub mod dialog;
+mod diff;
+mod dns;
+pub mod error;
+pub mod events;
+mod ffi;
+pub mod identity;
+mod ipfilter;
+pub mod locate;
+mod lock;
+pub mod log;
+pub mod message;
+pub mod nat;
+mod outbound;
+mod parse;
+mod parsecache;
+pub mod ping;
+pub mod poll;
+pub mod pool;
+pub mod proxy;
+pub mod random;
+mod ratelimit;
+pub mod raw;
+pub mod reason;
+mod receive;
+pub mod register;
+pub mod router;
+pub mod sdp;
+mod send;
+pub mod session;
+mod stats;
+pub mod stream;
+pub mod timer;
+mod timestamp;
+pub mod tls;
+pub mod transaction;
+pub mod transport;
+pub mod uri;
+pub mod validate;
+pub mod workers;
+pub mod ws;
 
-type EventCallback = extern "C" fn(event: *const c_char, payload: *const c_char);
+pub use context::{EventCallback, RsipContext};
+pub use error::RsipError;
+pub use events::EventCallbackEx;
+pub use ffi::rsip_free_string;
+pub use message::RsipMessage;
 
 lazy_static! {
-    static ref CALLBACK: Mutex<Option<EventCallback>> = Mutex::new(None);
-    static ref LISTENER_THREAD: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
-    static ref RUNNING: AtomicBool = AtomicBool::new(false);
+    // Backs the context-less API below so existing single-stack hosts keep working.
+    static ref DEFAULT_CONTEXT: Arc<RsipContext> = Arc::new(RsipContext::new());
+}
+
+pub(crate) fn default_context() -> &'static Arc<RsipContext> {
+    &DEFAULT_CONTEXT
 }
 
 #[no_mangle]
 pub extern "C" fn rsip_init() -> bool {
-    // Set running to false and clear callback
-    RUNNING.store(false, Ordering::SeqCst);
-    let mut cb = CALLBACK.lock().unwrap();
-    *cb = None;
-    true
+    rsip_init_ex() == 0
+}
+
+// Lifecycle functions come in pairs: the original `bool` form and an `_ex` form that
+// returns an `RsipError` code (0 on success) so hosts can tell failures apart.
+#[no_mangle]
+pub extern "C" fn rsip_init_ex() -> i32 {
+    // Stops whatever still runs, so its sockets and threads do not outlive the init.
+    default_context().init();
+    RsipError::Ok.code()
 }
 
 #[no_mangle]
 pub extern "C" fn rsip_set_event_callback(cb: EventCallback) {
-    let mut guard = CALLBACK.lock().unwrap();
-    *guard = Some(cb);
+    default_context().set_callback(cb);
 }
 
 #[no_mangle]
 pub extern "C" fn rsip_clear_event_callback() {
-    let mut guard = CALLBACK.lock().unwrap();
-    *guard = None;
+    default_context().clear_callback();
 }
 
-fn call_callback(event: &str, payload: &str) {
-    let guard = CALLBACK.lock().unwrap();
-    if let Some(cb) = *guard {
-        let ev = CString::new(event).unwrap_or_else(|_| CString::new("err").unwrap());
-        let pl = CString::new(payload).unwrap_or_else(|_| CString::new("").unwrap());
-        cb(ev.as_ptr(), pl.as_ptr());
-        // CString drops here; the callee must copy data if it is needed beyond the call
-    }
+#[no_mangle]
+pub extern "C" fn rsip_start_udp_listener(port: u16) -> bool {
+    rsip_start_udp_listener_ex(port) == 0
 }
 
 #[no_mangle]
-pub extern "C" fn rsip_start_udp_listener(port: u16) -> bool {
-    if RUNNING.load(Ordering::SeqCst) {
-        // already running
-        return false;
-    }
+pub extern "C" fn rsip_start_udp_listener_ex(port: u16) -> i32 {
+    error::to_code(default_context().start_udp_listener(port))
+}
 
-    let bind = format!("0.0.0.0:{}", port);
-    let socket = match UdpSocket::bind(bind) {
-        Ok(s) => s,
-        Err(_) => return false,
+#[no_mangle]
+pub extern "C" fn rsip_start_udp_listener_on(ip: *const c_char, port: u16) -> bool {
+    rsip_start_udp_listener_on_ex(ip, port) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_start_udp_listener_on_ex(ip: *const c_char, port: u16) -> i32 {
+    let ip = match ffi::str_arg(ip) {
+        Some(ip) => ip,
+        None => return RsipError::InvalidArgument.code(),
     };
+    error::to_code(default_context().start_udp_listener_on(ip, port))
+}
 
-    // make socket non-blocking to allow clean shutdown if desired
-    let _ = socket.set_nonblocking(false);
-    let socket = Arc::new(socket);
-    RUNNING.store(true, Ordering::SeqCst);
-
-    let socket_clone = socket.clone();
-
-    let handle = thread::spawn(move || {
-        let mut buf = vec![0u8; 65535];
-        while RUNNING.load(Ordering::SeqCst) {
-            match socket_clone.recv_from(&mut buf) {
-                Ok((n, src)) => {
-                    if n == 0 { continue; }
-                    // Try to parse SIP message using rsip (best-effort) and forward raw message
-                    let msg = String::from_utf8_lossy(&buf[..n]).to_string();
-                    // Optionally parse with rsip::message here to validate
-                    // For now, just call callback with event "sip_rx" and payload as the raw message
-                    call_callback("sip_rx", &msg);
-                }
-                Err(e) => {
-                    // On error, call error callback and continue or break for interrupt
-                    call_callback("error", &format!("recv_err:{}", e));
-                    // Sleep a bit to avoid busy loop
-                    std::thread::sleep(std::time::Duration::from_millis(50));
-                }
-            }
-        }
-    });
+// Adds a UDP listener on `port`, next to any already running, and returns its id; 0 if
+// the bind fails.
+#[no_mangle]
+pub extern "C" fn rsip_add_udp_listener(port: u16) -> u64 {
+    default_context().add_udp_listener(port).unwrap_or(0)
+}
 
-    let mut guard = LISTENER_THREAD.lock().unwrap();
-    *guard = Some(handle);
-    true
+// Stops and removes the UDP listener `id`; false if there is no such listener.
+#[no_mangle]
+pub extern "C" fn rsip_remove_listener(id: u64) -> bool {
+    default_context().remove_listener(id)
 }
 
+// Restarts the receive thread of every UDP listener on its bound socket, keeping the
+// port; false if no UDP listener runs.
 #[no_mangle]
-pub extern "C" fn rsip_shutdown() {
-    // signal thread to stop
-    RUNNING.store(false, Ordering::SeqCst);
+pub extern "C" fn rsip_restart_listener() -> bool {
+    default_context().restart_listeners().is_ok()
+}
 
-    // join thread if present
-    let mut guard = LISTENER_THREAD.lock().unwrap();
-    if let Some(handle) = guard.take() {
-        let _ = handle.join();
+// Whether the UDP listener is running.
+#[no_mangle]
+pub extern "C" fn rsip_is_running() -> bool {
+    default_context().is_running()
+}
+
+// The UDP listener's bound port (the real one when started on port 0), 0 if not running.
+#[no_mangle]
+pub extern "C" fn rsip_listener_port() -> u16 {
+    default_context().listener_port()
+}
+
+// Writes the bound "ip:port" of the running listener into `buf`; false if not running
+// or the buffer is too small.
+#[no_mangle]
+pub extern "C" fn rsip_listener_local_addr(buf: *mut c_char, len: usize) -> bool {
+    match default_context().local_addr() {
+        Some(addr) => ffi::write_to_buf(&addr.to_string(), buf, len),
+        None => false,
     }
+}
+
+// Must be called before the listener starts; the size must be within 576..=1048576.
+#[no_mangle]
+pub extern "C" fn rsip_set_recv_buffer_size(bytes: usize) -> bool {
+    default_context().set_recv_buffer_size(bytes).is_ok()
+}
+
+// Must be called before the listener starts; 0 (the default) disables the tick event.
+#[no_mangle]
+pub extern "C" fn rsip_set_recv_timeout_ms(ms: u32) -> bool {
+    default_context().set_recv_timeout(ms).is_ok()
+}
+
+// Address rsip_start_udp_listener binds to ("0.0.0.0" by default, "::" for IPv6).
+// Must be called before the listener starts.
+#[no_mangle]
+pub extern "C" fn rsip_set_bind_address(ip: *const c_char) -> bool {
+    match ffi::str_arg(ip) {
+        Some(ip) => default_context().set_bind_address(ip).is_ok(),
+        None => false,
+    }
+}
+
+// Listen on IPv4 and IPv6 with one socket when bound to an unspecified address.
+#[no_mangle]
+pub extern "C" fn rsip_set_dual_stack(enabled: bool) -> bool {
+    default_context().set_dual_stack(enabled).is_ok()
+}
+
+// SO_REUSEADDR / SO_REUSEPORT on the UDP listener socket. Must be called before the
+// listener starts; SO_REUSEPORT is refused where the platform lacks it.
+#[no_mangle]
+pub extern "C" fn rsip_set_reuse_addr(enabled: bool) -> bool {
+    default_context().set_reuse_addr(enabled).is_ok()
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_set_reuse_port(enabled: bool) -> bool {
+    default_context().set_reuse_port(enabled).is_ok()
+}
+
+// Via sent-by advertised in generated requests, e.g. a NAT's public address; port 0
+// keeps the listener port. NULL host goes back to the listener address.
+#[no_mangle]
+pub extern "C" fn rsip_set_via_sentby(host: *const c_char, port: u16) -> bool {
+    default_context()
+        .set_via_sentby(ffi::str_arg(host).map(|host| (host, port)))
+        .is_ok()
+}
+
+// Max-Forwards of requests the stack builds (rsip_build_request, registrations, pings);
+// 70 by default. False for 0.
+#[no_mangle]
+pub extern "C" fn rsip_set_default_max_forwards(max_forwards: u8) -> bool {
+    default_context()
+        .set_default_max_forwards(max_forwards)
+        .is_ok()
+}
+
+// User-Agent of requests and Server of responses the stack builds; NULL removes it (the
+// default). False for an empty value or one spanning lines.
+#[no_mangle]
+pub extern "C" fn rsip_set_user_agent(user_agent: *const c_char) -> bool {
+    default_context()
+        .set_user_agent(ffi::str_arg(user_agent))
+        .is_ok()
+}
+
+// Adds an empty rport to the Via of requests the stack builds (RFC 3581), asking the
+// server to respond to the port the request came from rather than the one in Via.
+#[no_mangle]
+pub extern "C" fn rsip_set_use_rport(enabled: bool) {
+    default_context().set_use_rport(enabled);
+}
+
+// Writes header names in their RFC 3261 compact form (Via as v, Call-ID as i, ...) in
+// every message the stack builds or serializes, to keep them small.
+#[no_mangle]
+pub extern "C" fn rsip_set_compact_headers(enabled: bool) {
+    default_context().set_compact_headers(enabled);
+}
 
-    // clear callback
-    let mut cb = CALLBACK.lock().unwrap();
-    *cb = None;
+// DSCP (0..=63, e.g. 24 for CS3 or 46 for EF) for every UDP packet sent from now on,
+// by the listeners and by one-shot sends. False for a value above 63 or where the
+// platform cannot mark packets.
+#[no_mangle]
+pub extern "C" fn rsip_set_dscp(value: u8) -> bool {
+    default_context().set_dscp(value).is_ok()
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_shutdown() {
+    default_context().shutdown();
+}
+
+// Graceful alternative to rsip_shutdown: stop reading, let callbacks that are already
+// running finish (up to timeout_ms), then shut down. False if it had to force-stop.
+#[no_mangle]
+pub extern "C" fn rsip_drain(timeout_ms: u32) -> bool {
+    default_context().drain(std::time::Duration::from_millis(u64::from(timeout_ms)))
 }
 
 // Convenience: send raw SIP datagram to a destination
 #[no_mangle]
-pub extern "C" fn rsip_send_udp(dest_ip: *const c_char, dest_port: u16, data: *const c_char) -> bool {
-    if dest_ip.is_null() || data.is_null() { return false; }
-    let cstr_ip = unsafe { CStr::from_ptr(dest_ip) };
-    let cstr_data = unsafe { CStr::from_ptr(data) };
-    let ip = match cstr_ip.to_str() { Ok(s) => s, Err(_) => return false };
-    let payload = cstr_data.to_bytes();
+pub extern "C" fn rsip_send_udp(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> bool {
+    rsip_send_udp_ex(dest_ip, dest_port, data) == 0
+}
 
-    let addr = format!("{}:{}", ip, dest_port);
-    match std::net::UdpSocket::bind("0.0.0.0:0") {
-        Ok(s) => {
-            let _ = s.send_to(payload, addr);
-            true
-        }
-        Err(_) => false,
+#[no_mangle]
+pub extern "C" fn rsip_send_udp_ex(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> i32 {
+    let result = send::send_args(dest_ip, data)
+        .and_then(|(ip, payload)| send_udp_counted(0, ip, dest_port, payload));
+    error::to_code(result)
+}
+
+// Same as rsip_send_udp but sends exactly `len` bytes, which may include NULs (binary
+// bodies, multipart MIME).
+#[no_mangle]
+pub extern "C" fn rsip_send_udp_bytes(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const u8,
+    len: usize,
+) -> bool {
+    rsip_send_udp_bytes_ex(dest_ip, dest_port, data, len) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_send_udp_bytes_ex(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const u8,
+    len: usize,
+) -> i32 {
+    let result = send::bytes_args(dest_ip, data, len)
+        .and_then(|(ip, payload)| send_udp_counted(0, ip, dest_port, payload));
+    error::to_code(result)
+}
+
+// Same as rsip_send_udp but sends from local port src_port, bound for this datagram
+// only, so the peer sees a source port the caller chose (e.g. a NAT pinhole) without
+// running a listener. Fails with AddrInUse if the port is taken, including by one of
+// our listeners (send from those with rsip_send_udp_from_listener).
+#[no_mangle]
+pub extern "C" fn rsip_send_udp_from_port(
+    src_port: u16,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> bool {
+    rsip_send_udp_from_port_ex(src_port, dest_ip, dest_port, data) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_send_udp_from_port_ex(
+    src_port: u16,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> i32 {
+    let result = send::send_args(dest_ip, data)
+        .and_then(|(ip, payload)| send_udp_counted(src_port, ip, dest_port, payload));
+    error::to_code(result)
+}
+
+/// A one-shot send from `src_port` (0: ephemeral), counted in the default context's
+/// stats and logged on failure.
+fn send_udp_counted(
+    src_port: u16,
+    ip: &str,
+    port: u16,
+    payload: &[u8],
+) -> Result<(), error::RsipError> {
+    let result = send::send_udp_from_port(default_context(), src_port, ip, port, payload);
+    default_context().stats.record_send(&result);
+    if result.is_ok() {
+        default_context().dialog_sent(payload);
     }
+    result.inspect_err(|e| {
+        default_context().log(
+            log::LogLevel::Warn,
+            format_args!("send to {} failed: {:?}", send::host_port(ip, port), e),
+        );
+    })
 }
 
-// Minimal example: expose a helper that returns a static string to test FFI linkage
+// Same as rsip_send_udp but sends from the running listener's socket, so responses to
+// the datagram come back to the listener. Fails with NotRunning if there is none.
+#[no_mangle]
+pub extern "C" fn rsip_send_udp_from_listener(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> bool {
+    rsip_send_udp_from_listener_ex(dest_ip, dest_port, data) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_send_udp_from_listener_ex(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> i32 {
+    let result = send::send_args(dest_ip, data)
+        .and_then(|(ip, payload)| default_context().send_from_listener(ip, dest_port, payload));
+    error::to_code(result)
+}
+
+/// The version string, NUL-terminated at compile time so it lives in the binary's
+/// read-only data: no allocation per call, and the pointer is valid for the process.
+const VERSION: &CStr = match CStr::from_bytes_with_nul(
+    concat!("rsip-wrapper-", env!("CARGO_PKG_VERSION"), "\0").as_bytes(),
+) {
+    Ok(version) => version,
+    Err(_) => panic!("version contains a NUL"),
+};
+
+// Minimal example: expose a helper that returns a static string to test FFI linkage.
+// The string must not be freed.
 #[no_mangle]
 pub extern "C" fn rsip_version() -> *const c_char {
-    let s = CString::new("rsip-wrapper-0.1.0").unwrap();
-    let p = s.as_ptr();
-    std::mem::forget(s); // leak intentionally; caller treats as static.
-    p
+    VERSION.as_ptr()
+}
+
+/// Writes the crate version's components; NULL pointers are skipped.
+#[no_mangle]
+pub extern "C" fn rsip_version_parts(major: *mut u32, minor: *mut u32, patch: *mut u32) {
+    let parts = [
+        (major, env!("CARGO_PKG_VERSION_MAJOR")),
+        (minor, env!("CARGO_PKG_VERSION_MINOR")),
+        (patch, env!("CARGO_PKG_VERSION_PATCH")),
+    ];
+    for (out, value) in parts.iter() {
+        if !out.is_null() {
+            unsafe { **out = value.parse().unwrap_or(0) };
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::sync::Arc;
+    use crate::context::*;
+    use std::ffi::{CStr, CString};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
     #[test]
     fn test_rsip_init() {
         let result = rsip_init();
         assert!(result, "rsip_init should return true");
-        assert!(!RUNNING.load(Ordering::SeqCst), "RUNNING should be false after init");
+        assert!(
+            !default_context().running.load(Ordering::SeqCst),
+            "running should be false after init"
+        );
     }
 
     #[test]
     fn test_rsip_version() {
         let ptr = rsip_version();
-        assert!(!ptr.is_null(), "rsip_version should return non-null pointer");
+        assert!(
+            !ptr.is_null(),
+            "rsip_version should return non-null pointer"
+        );
         let cstr = unsafe { CStr::from_ptr(ptr) };
         let s = cstr.to_str().expect("version should be valid UTF-8");
         assert_eq!(s, "rsip-wrapper-0.1.0", "version string should match");
     }
 
+    #[test]
+    fn test_rsip_version_is_static() {
+        let first = rsip_version();
+        // Every call returns the same read-only bytes; nothing is allocated or leaked.
+        for _ in 0..100_000 {
+            assert_eq!(rsip_version(), first);
+        }
+
+        let (mut major, mut minor, mut patch) = (u32::MAX, u32::MAX, u32::MAX);
+        for _ in 0..100_000 {
+            rsip_version_parts(&mut major, &mut minor, &mut patch);
+        }
+        assert_eq!((major, minor, patch), (0, 1, 0));
+        let version = unsafe { CStr::from_ptr(first) }.to_str().unwrap();
+        assert!(version.ends_with(&format!("{}.{}.{}", major, minor, patch)));
+        rsip_version_parts(std::ptr::null_mut(), &mut minor, std::ptr::null_mut());
+    }
+
     #[test]
     fn test_callback_registration() {
-        rsip_init();
-        
+        let ctx = rsip_context_new();
+
         // Define a dummy callback
         extern "C" fn dummy_cb(_event: *const c_char, _payload: *const c_char) {}
-        
-        rsip_set_event_callback(dummy_cb);
-        let guard = CALLBACK.lock().unwrap();
-        assert!(guard.is_some(), "callback should be registered");
-        drop(guard);
-        
-        rsip_clear_event_callback();
-        let guard = CALLBACK.lock().unwrap();
-        assert!(guard.is_none(), "callback should be cleared");
+
+        rsip_context_set_event_callback(ctx, dummy_cb);
+        context::with_context(ctx, |ctx| {
+            assert!(ctx.events.has_default(), "callback should be registered");
+        });
+
+        rsip_context_clear_event_callback(ctx);
+        context::with_context(ctx, |ctx| {
+            assert!(!ctx.events.has_default(), "callback should be cleared");
+        });
+
+        rsip_context_free(ctx);
     }
 
     #[test]
@@ -187,6 +487,34 @@ mod tests {
         assert!(!result, "should return false for null data");
     }
 
+    #[test]
+    fn test_udp_send_bytes_keeps_embedded_nuls() {
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let port = peer.local_addr().unwrap().port();
+        let ip = CString::new("127.0.0.1").unwrap();
+        let payload = b"MESSAGE sip:a@b SIP/2.0\r\n\r\n\x00\x01binary\x00tail";
+
+        assert!(rsip_send_udp_bytes(
+            ip.as_ptr(),
+            port,
+            payload.as_ptr(),
+            payload.len()
+        ));
+        let mut buf = [0u8; 256];
+        let n = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &payload[..]);
+
+        assert!(!rsip_send_udp_bytes(ip.as_ptr(), port, std::ptr::null(), 4));
+        assert!(!rsip_send_udp_bytes(
+            std::ptr::null(),
+            port,
+            payload.as_ptr(),
+            payload.len()
+        ));
+    }
+
     #[test]
     fn test_udp_send_invalid_address() {
         // Attempt to send to an address that may fail (invalid IP)
@@ -199,34 +527,738 @@ mod tests {
     }
 
     #[test]
-    fn test_listener_already_running() {
-        rsip_init();
-        
+    fn test_start_while_running_adds_a_listener() {
+        let ctx = rsip_context_new();
+
         // First start should succeed
-        let result1 = rsip_start_udp_listener(15060);
+        let result1 = rsip_context_start_udp_listener(ctx, 15060);
         assert!(result1, "first start_udp_listener should succeed");
-        
-        // Second start without shutdown should fail
-        let result2 = rsip_start_udp_listener(15061);
-        assert!(!result2, "second start_udp_listener without shutdown should fail");
-        
-        rsip_shutdown();
-        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // A second start without shutdown adds another listener
+        let result2 = rsip_context_start_udp_listener(ctx, 15061);
+        assert!(result2, "second start_udp_listener should add a listener");
+        let listeners = context::with_context(ctx, |ctx| ctx.udp_listeners.lock().unwrap().len());
+        assert_eq!(listeners, Some(2));
+        // The same port twice is still a bind failure
+        assert!(!rsip_context_start_udp_listener(ctx, 15061));
+
+        rsip_context_free(ctx);
     }
 
     #[test]
     fn test_shutdown_clears_state() {
-        rsip_init();
-        
+        let ctx = rsip_context_new();
+
+        extern "C" fn dummy_cb(_event: *const c_char, _payload: *const c_char) {}
+        rsip_context_set_event_callback(ctx, dummy_cb);
+
+        rsip_context_shutdown(ctx);
+
+        context::with_context(ctx, |ctx| {
+            assert!(
+                !ctx.events.has_default(),
+                "callback should be cleared after shutdown"
+            );
+            assert!(!ctx.is_running(), "running should be false after shutdown");
+        });
+
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_init_while_running_releases_the_listener() {
+        extern "C" fn dummy_cb(_event: *const c_char, _payload: *const c_char) {}
+        let ctx = Arc::new(RsipContext::new());
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        ctx.start_udp_listener(0).unwrap();
+        let port = ctx.listener_port();
+        ctx.set_callback(dummy_cb);
+
+        ctx.init();
+        assert!(!ctx.is_running());
+        assert!(ctx.udp_listeners.lock().unwrap().is_empty());
+        assert!(!ctx.events.has_default());
+        // the port is free again, and init is idempotent
+        ctx.init();
+        ctx.start_udp_listener(port).unwrap();
+        assert!(ctx.is_running());
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn test_shutdown_after_failed_start() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert_eq!(ctx.start_udp_listener(port), Err(RsipError::AddrInUse));
+        assert!(!ctx.is_running());
+        ctx.shutdown();
+        ctx.shutdown();
+
+        drop(taken);
+        ctx.start_udp_listener(port).unwrap();
+        assert_eq!(ctx.listener_port(), port);
+        ctx.shutdown();
+        assert!(!ctx.is_running());
+    }
+
+    #[test]
+    fn test_contexts_are_independent() {
+        let a = rsip_context_new();
+        let b = rsip_context_new();
+
+        assert!(
+            rsip_context_start_udp_listener(a, 15062),
+            "first context should bind"
+        );
+        assert!(
+            rsip_context_start_udp_listener(b, 15063),
+            "second context should bind independently"
+        );
+
         extern "C" fn dummy_cb(_event: *const c_char, _payload: *const c_char) {}
-        rsip_set_event_callback(dummy_cb);
-        
-        rsip_shutdown();
-        
-        let guard = CALLBACK.lock().unwrap();
-        assert!(guard.is_none(), "callback should be cleared after shutdown");
-        drop(guard);
-        
-        assert!(!RUNNING.load(Ordering::SeqCst), "RUNNING should be false after shutdown");
+        rsip_context_set_event_callback(a, dummy_cb);
+        context::with_context(b, |ctx| {
+            assert!(
+                !ctx.events.has_default(),
+                "callbacks must not leak across contexts"
+            );
+        });
+
+        rsip_context_free(a);
+        rsip_context_free(b);
+    }
+
+    #[test]
+    fn test_context_null_handle() {
+        extern "C" fn dummy_cb(_event: *const c_char, _payload: *const c_char) {}
+        let ctx = std::ptr::null_mut();
+        assert!(!rsip_context_start_udp_listener(ctx, 15064));
+        rsip_context_set_event_callback(ctx, dummy_cb);
+        rsip_context_shutdown(ctx);
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_listener_on_specific_address() {
+        let ctx = rsip_context_new();
+        let ip = CString::new("127.0.0.1").unwrap();
+        assert!(rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), 0));
+
+        let mut buf = [0 as c_char; 64];
+        assert!(rsip_context_listener_local_addr(
+            ctx,
+            buf.as_mut_ptr(),
+            buf.len()
+        ));
+        let addr = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().unwrap();
+        assert!(
+            addr.starts_with("127.0.0.1:"),
+            "unexpected local addr {}",
+            addr
+        );
+        assert!(!addr.ends_with(":0"), "ephemeral port should be resolved");
+
+        // too small a buffer is rejected rather than truncated
+        let mut tiny = [0 as c_char; 4];
+        assert!(!rsip_context_listener_local_addr(
+            ctx,
+            tiny.as_mut_ptr(),
+            tiny.len()
+        ));
+
+        rsip_context_free(ctx);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reuse_options_allow_sharing_the_port() {
+        let first = Arc::new(RsipContext::new());
+        first.set_reuse_addr(true).unwrap();
+        first.set_reuse_port(true).unwrap();
+        first.start_udp_listener_on("127.0.0.1", 0).unwrap();
+        assert!(matches!(
+            first.set_reuse_addr(false),
+            Err(error::RsipError::AlreadyRunning)
+        ));
+        let port = first.local_addr().unwrap().port();
+
+        let plain = Arc::new(RsipContext::new());
+        assert!(matches!(
+            plain.start_udp_listener_on("127.0.0.1", port),
+            Err(error::RsipError::AddrInUse)
+        ));
+        // Linux lets sockets that all set SO_REUSEPORT share the port.
+        let second = Arc::new(RsipContext::new());
+        second.set_reuse_port(true).unwrap();
+        second.start_udp_listener_on("127.0.0.1", port).unwrap();
+        second.shutdown();
+        first.shutdown();
+    }
+
+    #[test]
+    fn test_multiple_udp_listeners() {
+        static ARRIVED: std::sync::Mutex<Vec<u64>> = std::sync::Mutex::new(Vec::new());
+        extern "C" fn record(_: *const c_char, payload: *const c_char) {
+            let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+            let summary: serde_json::Value = serde_json::from_str(payload).unwrap();
+            ARRIVED
+                .lock()
+                .unwrap()
+                .push(summary["listener"].as_u64().unwrap());
+        }
+
+        let ctx = rsip_context_new();
+        let events = CString::new("sip_rx_parsed").unwrap();
+        events::rsip_context_add_event_listener(ctx, events.as_ptr(), record);
+        let first = rsip_context_add_udp_listener(ctx, 0);
+        let second = rsip_context_add_udp_listener(ctx, 0);
+        assert!(first != 0 && second != 0 && first != second);
+        let ports: Vec<u16> = context::with_context(ctx, |ctx| {
+            let listeners = ctx.udp_listeners.lock().unwrap();
+            [first, second]
+                .iter()
+                .map(|id| listeners[id].socket.local_addr().unwrap().port())
+                .collect()
+        })
+        .unwrap();
+        assert_eq!(rsip_context_listener_port(ctx), ports[0]);
+
+        let options = "OPTIONS sip:bob@127.0.0.1 SIP/2.0\r\n\
+            Via: SIP/2.0/UDP 127.0.0.1;branch=z9hG4bKmulti\r\n\
+            From: <sip:alice@127.0.0.1>;tag=1\r\n\
+            To: <sip:bob@127.0.0.1>\r\n\
+            Call-ID: multi@127.0.0.1\r\n\
+            CSeq: 1 OPTIONS\r\n\
+            Content-Length: 0\r\n\r\n";
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for port in ports.iter().rev() {
+            peer.send_to(options.as_bytes(), ("127.0.0.1", *port))
+                .unwrap();
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+            let expected = ARRIVED.lock().unwrap().len() + 1;
+            while ARRIVED.lock().unwrap().len() < expected && std::time::Instant::now() < deadline {
+                std::thread::sleep(std::time::Duration::from_millis(5));
+            }
+        }
+        assert_eq!(*ARRIVED.lock().unwrap(), vec![second, first]);
+
+        assert!(rsip_context_remove_listener(ctx, first));
+        assert!(!rsip_context_remove_listener(ctx, first));
+        assert!(rsip_context_is_running(ctx));
+        assert_eq!(rsip_context_listener_port(ctx), ports[1]);
+        assert!(rsip_context_remove_listener(ctx, second));
+        assert!(!rsip_context_is_running(ctx));
+        assert_eq!(rsip_context_listener_port(ctx), 0);
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_running_state_and_port() {
+        let ctx = rsip_context_new();
+        assert!(!rsip_context_is_running(ctx));
+        assert_eq!(rsip_context_listener_port(ctx), 0);
+        let ip = CString::new("127.0.0.1").unwrap();
+        assert!(rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), 0));
+        assert!(rsip_context_is_running(ctx));
+        let port = rsip_context_listener_port(ctx);
+        assert_ne!(port, 0, "ephemeral port should be resolved");
+        assert_eq!(
+            context::with_context(ctx, |ctx| ctx.local_addr().unwrap().port()),
+            Some(port)
+        );
+        rsip_context_shutdown(ctx);
+        assert!(!rsip_context_is_running(ctx));
+        assert_eq!(rsip_context_listener_port(ctx), 0);
+        assert!(!rsip_context_is_running(std::ptr::null_mut()));
+        assert_eq!(rsip_context_listener_port(std::ptr::null_mut()), 0);
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_listener_on_reports_invalid_vs_in_use() {
+        static LAST_ERROR: std::sync::Mutex<String> = std::sync::Mutex::new(String::new());
+        extern "C" fn record_error(event: *const c_char, payload: *const c_char) {
+            let event = unsafe { CStr::from_ptr(event) }.to_str().unwrap();
+            if event == "error" {
+                let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+                *LAST_ERROR.lock().unwrap() = payload.to_string();
+            }
+        }
+
+        let ctx = rsip_context_new();
+        rsip_context_set_event_callback(ctx, record_error);
+        let bad = CString::new("not-an-ip").unwrap();
+        assert!(!rsip_context_start_udp_listener_on(ctx, bad.as_ptr(), 0));
+        assert!(LAST_ERROR.lock().unwrap().starts_with("invalid_addr:"));
+
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let ip = CString::new("127.0.0.1").unwrap();
+        assert!(!rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), port));
+        assert!(LAST_ERROR.lock().unwrap().starts_with("bind_err:"));
+
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_ex_functions_return_typed_errors() {
+        let ctx = rsip_context_new();
+        assert_eq!(rsip_context_start_udp_listener_ex(ctx, 0), 0);
+        // Starting again adds a second listener instead of failing.
+        assert_eq!(rsip_context_start_udp_listener_ex(ctx, 0), 0);
+        rsip_context_free(ctx);
+
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let ctx = rsip_context_new();
+        let ip = CString::new("127.0.0.1").unwrap();
+        assert_eq!(
+            rsip_context_start_udp_listener_on_ex(ctx, ip.as_ptr(), port),
+            RsipError::AddrInUse.code()
+        );
+        let bad = CString::new("nope").unwrap();
+        assert_eq!(
+            rsip_context_start_udp_listener_on_ex(ctx, bad.as_ptr(), 0),
+            RsipError::InvalidArgument.code()
+        );
+        rsip_context_free(ctx);
+
+        assert_eq!(
+            rsip_context_start_udp_listener_ex(std::ptr::null_mut(), 0),
+            RsipError::InvalidArgument.code()
+        );
+        assert_eq!(
+            rsip_send_udp_ex(std::ptr::null(), 5060, std::ptr::null()),
+            RsipError::InvalidArgument.code()
+        );
+    }
+
+    #[test]
+    fn test_strerror() {
+        let msg = |code| {
+            unsafe { CStr::from_ptr(error::rsip_strerror(code)) }
+                .to_str()
+                .unwrap()
+        };
+        assert_eq!(msg(0), "ok");
+        assert_eq!(msg(RsipError::AddrInUse.code()), "address already in use");
+        assert_eq!(msg(-1000), "unknown error");
+        assert_eq!(RsipError::PermissionDenied.to_string(), "permission denied");
+    }
+
+    #[test]
+    fn test_recv_buffer_size_validation() {
+        let ctx = rsip_context_new();
+        assert!(
+            !rsip_context_set_recv_buffer_size(ctx, 100),
+            "below the SIP minimum"
+        );
+        assert!(
+            !rsip_context_set_recv_buffer_size(ctx, 1 << 30),
+            "above the sane maximum"
+        );
+        assert!(rsip_context_set_recv_buffer_size(ctx, 1500));
+        context::with_context(ctx, |ctx| {
+            assert_eq!(ctx.config.lock().unwrap().recv_buffer_size, 1500);
+        });
+
+        assert!(rsip_context_start_udp_listener(ctx, 0));
+        assert!(
+            !rsip_context_set_recv_buffer_size(ctx, 4096),
+            "cannot resize while the listener runs"
+        );
+        rsip_context_free(ctx);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dscp_marks_listener_and_sender_sockets() {
+        let ctx = rsip_context_new();
+        assert!(!rsip_context_set_dscp(ctx, 64), "DSCP is six bits");
+        assert!(rsip_context_start_udp_listener(ctx, 0));
+        // CS3, applied to the running listener
+        assert!(rsip_context_set_dscp(ctx, 24));
+        context::with_context(ctx, |ctx| {
+            let socket = ctx.udp_socket().unwrap();
+            assert_eq!(socket2::SockRef::from(&*socket).tos().unwrap(), 24 << 2);
+            // listeners started later are marked when bound
+            let id = ctx.add_udp_listener(0).unwrap();
+            let added = ctx.udp_listeners.lock().unwrap()[&id].socket.clone();
+            assert_eq!(socket2::SockRef::from(&*added).tos().unwrap(), 24 << 2);
+
+            let dest = "127.0.0.1:5060".parse().unwrap();
+            let sender = send::ephemeral_socket(dest, ctx.dscp()).unwrap();
+            assert_eq!(socket2::SockRef::from(&sender).tos().unwrap(), 24 << 2);
+        });
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_shutdown_idle_listener_is_fast() {
+        static STARTED: AtomicBool = AtomicBool::new(false);
+        extern "C" fn on_started(_: *const c_char, _: *const c_char) {
+            STARTED.store(true, Ordering::SeqCst);
+        }
+
+        let ctx = rsip_context_new();
+        context::with_context(ctx, |ctx| {
+            ctx.events.subscribe(
+                Some(vec!["listener_started".into()]),
+                events::Sink::Basic(on_started),
+            )
+        });
+        assert!(rsip_context_start_udp_listener(ctx, 0));
+        // wait until the thread is in its receive loop
+        while !STARTED.load(Ordering::SeqCst) {
+            std::thread::yield_now();
+        }
+
+        let started = std::time::Instant::now();
+        rsip_context_shutdown(ctx);
+        assert!(
+            started.elapsed() < std::time::Duration::from_millis(100),
+            "shutdown took {:?}",
+            started.elapsed()
+        );
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_listener_lifecycle_events() {
+        static LIFECYCLE: std::sync::Mutex<Vec<(String, String)>> =
+            std::sync::Mutex::new(Vec::new());
+        extern "C" fn record(event: *const c_char, payload: *const c_char) {
+            let event = unsafe { CStr::from_ptr(event) }
+                .to_string_lossy()
+                .into_owned();
+            let payload = unsafe { CStr::from_ptr(payload) }
+                .to_string_lossy()
+                .into_owned();
+            LIFECYCLE.lock().unwrap().push((event, payload));
+        }
+
+        let ctx = rsip_context_new();
+        context::with_context(ctx, |ctx| {
+            ctx.events.subscribe(
+                Some(vec!["listener_started".into(), "listener_stopped".into()]),
+                events::Sink::Basic(record),
+            )
+        });
+        let ip = CString::new("127.0.0.1").unwrap();
+        assert!(rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), 0));
+        let addr = context::with_context(ctx, |ctx| ctx.local_addr().unwrap()).unwrap();
+        while LIFECYCLE.lock().unwrap().is_empty() {
+            std::thread::yield_now();
+        }
+        rsip_context_shutdown(ctx);
+
+        let expected =
+            serde_json::json!({ "transport": "udp", "addr": addr.to_string(), "listener": 1 });
+        let lifecycle = LIFECYCLE.lock().unwrap();
+        let events: Vec<&str> = lifecycle.iter().map(|(e, _)| e.as_str()).collect();
+        assert_eq!(events, ["listener_started", "listener_stopped"]);
+        for (_, payload) in lifecycle.iter() {
+            let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+            assert_eq!(payload, expected);
+        }
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_restart_keeps_the_socket_and_loses_nothing() {
+        static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+        static STARTED: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn count(event: *const c_char, _: *const c_char) {
+            match unsafe { CStr::from_ptr(event) }.to_bytes() {
+                b"sip_rx" => RECEIVED.fetch_add(1, Ordering::SeqCst),
+                _ => STARTED.fetch_add(1, Ordering::SeqCst),
+            };
+        }
+
+        let ctx = rsip_context_new();
+        assert!(!rsip_context_restart_listener(ctx), "nothing to restart");
+        context::with_context(ctx, |ctx| {
+            ctx.events.subscribe(
+                Some(vec!["sip_rx".into(), "listener_started".into()]),
+                events::Sink::Basic(count),
+            )
+        });
+        let ip = CString::new("127.0.0.1").unwrap();
+        assert!(rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), 0));
+        let addr = context::with_context(ctx, |ctx| ctx.local_addr().unwrap()).unwrap();
+
+        const SENT: usize = 300;
+        let sender = std::thread::spawn(move || {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            for i in 0..SENT {
+                let msg = format!("OPTIONS sip:{}@example.com SIP/2.0\r\n\r\n", i);
+                socket.send_to(msg.as_bytes(), addr).unwrap();
+                std::thread::sleep(std::time::Duration::from_micros(200));
+            }
+        });
+        for _ in 0..5 {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            assert!(rsip_context_restart_listener(ctx));
+        }
+        sender.join().unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while RECEIVED.load(Ordering::SeqCst) < SENT && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        // Loopback does not drop; anything missing would have been lost in a restart.
+        assert_eq!(RECEIVED.load(Ordering::SeqCst), SENT);
+        assert_eq!(STARTED.load(Ordering::SeqCst), 6);
+        assert_eq!(
+            context::with_context(ctx, |ctx| ctx.local_addr().unwrap()).unwrap(),
+            addr
+        );
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_tick_events() {
+        static TICKS: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn count(_: *const c_char, _: *const c_char) {
+            TICKS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let ctx = rsip_context_new();
+        context::with_context(ctx, |ctx| {
+            ctx.events
+                .subscribe(Some(vec!["tick".into()]), events::Sink::Basic(count))
+        });
+        assert!(rsip_context_set_recv_timeout_ms(ctx, 10));
+        let ip = CString::new("127.0.0.1").unwrap();
+        assert!(rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), 0));
+        assert!(
+            !rsip_context_set_recv_timeout_ms(ctx, 20),
+            "cannot change while running"
+        );
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while TICKS.load(Ordering::SeqCst) < 3 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert!(TICKS.load(Ordering::SeqCst) >= 3);
+        rsip_context_shutdown(ctx);
+
+        // Disabled by default and with 0.
+        let ticks = TICKS.load(Ordering::SeqCst);
+        assert!(rsip_context_set_recv_timeout_ms(ctx, 0));
+        assert!(rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), 0));
+        context::with_context(ctx, |ctx| {
+            ctx.events
+                .subscribe(Some(vec!["tick".into()]), events::Sink::Basic(count))
+        });
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(TICKS.load(Ordering::SeqCst), ticks);
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_send_from_listener_uses_listener_port() {
+        let ctx = rsip_context_new();
+        let data = CString::new("OPTIONS sip:a@b SIP/2.0\r\n\r\n").unwrap();
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(2)))
+            .unwrap();
+        let peer_port = peer.local_addr().unwrap().port();
+        let ip = CString::new("127.0.0.1").unwrap();
+
+        assert_eq!(
+            rsip_context_send_udp_from_listener_ex(ctx, ip.as_ptr(), peer_port, data.as_ptr()),
+            RsipError::NotRunning.code()
+        );
+
+        assert!(rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), 0));
+        let listener_port =
+            context::with_context(ctx, |ctx| ctx.local_addr().unwrap().port()).unwrap();
+        assert!(rsip_context_send_udp_from_listener(
+            ctx,
+            ip.as_ptr(),
+            peer_port,
+            data.as_ptr()
+        ));
+
+        let mut buf = [0u8; 128];
+        let (_, src) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            src.port(),
+            listener_port,
+            "source port must be the listener port"
+        );
+
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_send_from_port_binds_the_requested_port() {
+        let data = CString::new("OPTIONS sip:a@b SIP/2.0\r\n\r\n").unwrap();
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(2)))
+            .unwrap();
+        let peer_port = peer.local_addr().unwrap().port();
+        let ip = CString::new("127.0.0.1").unwrap();
+
+        let taken = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert_eq!(
+            rsip_send_udp_from_port_ex(port, ip.as_ptr(), peer_port, data.as_ptr()),
+            RsipError::AddrInUse.code()
+        );
+        drop(taken);
+        assert!(rsip_send_udp_from_port(
+            port,
+            ip.as_ptr(),
+            peer_port,
+            data.as_ptr()
+        ));
+        let mut buf = [0u8; 128];
+        let (_, src) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(src.port(), port);
+    }
+
+    #[test]
+    fn test_ipv6_listener_receives_from_ipv6_sender() {
+        static FROM: std::sync::Mutex<Option<(String, u16)>> = std::sync::Mutex::new(None);
+        extern "C" fn record_src(
+            event: *const c_char,
+            _payload: *const c_char,
+            src_ip: *const c_char,
+            src_port: u16,
+        ) {
+            if unsafe { CStr::from_ptr(event) }.to_bytes() == b"sip_rx" {
+                let ip = unsafe { CStr::from_ptr(src_ip) }.to_string_lossy();
+                *FROM.lock().unwrap() = Some((ip.into_owned(), src_port));
+            }
+        }
+
+        let ctx = rsip_context_new();
+        let any6 = CString::new("::").unwrap();
+        assert!(rsip_context_set_bind_address(ctx, any6.as_ptr()));
+        events::rsip_context_set_event_callback_ex(ctx, record_src);
+        assert!(rsip_context_start_udp_listener(ctx, 15074));
+
+        let ip = CString::new("::1").unwrap();
+        let data = CString::new("OPTIONS sip:a@b SIP/2.0\r\n\r\n").unwrap();
+        assert!(rsip_send_udp(ip.as_ptr(), 15074, data.as_ptr()));
+        for _ in 0..100 {
+            if FROM.lock().unwrap().is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(FROM.lock().unwrap().as_ref().unwrap().0, "::1");
+
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_dual_stack_listener_accepts_both_families() {
+        let ctx = rsip_context_new();
+        assert!(rsip_context_set_dual_stack(ctx, true));
+        assert!(rsip_context_start_udp_listener(ctx, 0));
+        assert!(
+            !rsip_context_set_dual_stack(ctx, false),
+            "cannot change while the listener runs"
+        );
+        let port = context::with_context(ctx, |ctx| {
+            let addr = ctx.local_addr().unwrap();
+            assert!(addr.is_ipv6(), "dual stack binds [::], got {}", addr);
+            addr.port()
+        })
+        .unwrap();
+
+        // replies from the listener socket reach both an IPv4 and an IPv6 peer
+        let data = CString::new("OPTIONS sip:a@b SIP/2.0\r\n\r\n").unwrap();
+        for (bind, dest) in [("127.0.0.1:0", "127.0.0.1"), ("[::1]:0", "::1")] {
+            let peer = std::net::UdpSocket::bind(bind).unwrap();
+            peer.set_read_timeout(Some(std::time::Duration::from_secs(2)))
+                .unwrap();
+            let dest = CString::new(dest).unwrap();
+            assert!(rsip_context_send_udp_from_listener(
+                ctx,
+                dest.as_ptr(),
+                peer.local_addr().unwrap().port(),
+                data.as_ptr()
+            ));
+            let mut buf = [0u8; 64];
+            let (_, src) = peer.recv_from(&mut buf).unwrap();
+            assert_eq!(src.port(), port);
+        }
+
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_drain_waits_for_running_callbacks() {
+        static STARTED: AtomicBool = AtomicBool::new(false);
+        static FINISHED: AtomicBool = AtomicBool::new(false);
+        extern "C" fn slow(event: *const c_char, _payload: *const c_char) {
+            if unsafe { CStr::from_ptr(event) }.to_bytes() == b"sip_rx" {
+                STARTED.store(true, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                FINISHED.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let ctx = rsip_context_new();
+        rsip_context_set_event_callback(ctx, slow);
+        let ip = CString::new("127.0.0.1").unwrap();
+        assert!(rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), 0));
+        let addr = context::with_context(ctx, |ctx| ctx.local_addr().unwrap()).unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender
+            .send_to(b"OPTIONS sip:a@b SIP/2.0\r\n\r\n", addr)
+            .unwrap();
+        while !STARTED.load(Ordering::SeqCst) {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+
+        assert!(rsip_context_drain(ctx, 2000));
+        assert!(
+            FINISHED.load(Ordering::SeqCst),
+            "drain returned before the callback"
+        );
+        context::with_context(ctx, |ctx| {
+            assert!(!ctx.is_running());
+            assert!(ctx.local_addr().is_none());
+            assert_eq!(ctx.events.in_flight(), 0);
+        });
+        rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn test_drain_force_stops_after_timeout() {
+        static STARTED: AtomicBool = AtomicBool::new(false);
+        extern "C" fn stuck(event: *const c_char, _payload: *const c_char) {
+            if unsafe { CStr::from_ptr(event) }.to_bytes() == b"sip_rx" {
+                STARTED.store(true, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(1000));
+            }
+        }
+
+        let ctx = rsip_context_new();
+        rsip_context_set_event_callback(ctx, stuck);
+        let ip = CString::new("127.0.0.1").unwrap();
+        assert!(rsip_context_start_udp_listener_on(ctx, ip.as_ptr(), 0));
+        let addr = context::with_context(ctx, |ctx| ctx.local_addr().unwrap()).unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender
+            .send_to(b"OPTIONS sip:a@b SIP/2.0\r\n\r\n", addr)
+            .unwrap();
+        while !STARTED.load(Ordering::SeqCst) {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+
+        let started = std::time::Instant::now();
+        assert!(!rsip_context_drain(ctx, 50));
+        assert!(started.elapsed() < std::time::Duration::from_millis(500));
+        context::with_context(ctx, |ctx| {
+            assert!(!ctx.is_running());
+            assert!(!ctx.events.has_default());
+        });
+        rsip_context_free(ctx);
     }
 }
@@ -0,0 +1,405 @@
+//! Locating SIP servers (RFC 3263 section 4): turns a SIP or SIPS URI into the ordered
+//! list of transport, address and port to try, through NAPTR, SRV and A/AAAA lookups.
+//!
+//! SRV records of equal priority are ordered by descending weight rather than by the
+//! weighted random pick of RFC 2782, so the result is deterministic.
+
+use crate::dns::{self, Naptr, Record, Srv};
+use crate::ffi::{into_c_string, str_arg};
+use crate::uri::{self, ParsedUri};
+use rsip::common::uri::Scheme;
+use serde_json::json;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::os::raw::c_char;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+impl Transport {
+    fn from_param(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "udp" => Some(Self::Udp),
+            "tcp" => Some(Self::Tcp),
+            "tls" => Some(Self::Tls),
+            _ => None,
+        }
+    }
+
+    fn from_naptr_service(service: &str) -> Option<Self> {
+        match service.to_ascii_uppercase().as_str() {
+            "SIP+D2U" => Some(Self::Udp),
+            "SIP+D2T" => Some(Self::Tcp),
+            "SIPS+D2T" => Some(Self::Tls),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+            Self::Tls => "tls",
+        }
+    }
+
+    fn default_port(self) -> u16 {
+        match self {
+            Self::Tls => 5061,
+            _ => 5060,
+        }
+    }
+
+    /// The SRV owner name prefix for `domain` (section 4.1).
+    fn srv_name(self, domain: &str) -> String {
+        match self {
+            Self::Udp => format!("_sip._udp.{}", domain),
+            Self::Tcp => format!("_sip._tcp.{}", domain),
+            Self::Tls => format!("_sips._tcp.{}", domain),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub transport: Transport,
+    pub ip: IpAddr,
+    pub port: u16,
+}
+
+/// The lookups RFC 3263 needs, so the procedure can run against canned answers.
+pub(crate) trait Lookup {
+    fn naptr(&self, domain: &str) -> Vec<Naptr>;
+    fn srv(&self, name: &str) -> Vec<Srv>;
+    fn addrs(&self, host: &str) -> Vec<IpAddr>;
+}
+
+struct SystemLookup;
+
+impl Lookup for SystemLookup {
+    fn naptr(&self, domain: &str) -> Vec<Naptr> {
+        dns::query(domain, dns::TYPE_NAPTR)
+            .into_iter()
+            .filter_map(|r| match r {
+                Record::Naptr(naptr) => Some(naptr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn srv(&self, name: &str) -> Vec<Srv> {
+        dns::query(name, dns::TYPE_SRV)
+            .into_iter()
+            .filter_map(|r| match r {
+                Record::Srv(srv) => Some(srv),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn addrs(&self, host: &str) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = Vec::new();
+        for addr in (host, 0).to_socket_addrs().into_iter().flatten() {
+            if !ips.contains(&addr.ip()) {
+                ips.push(addr.ip());
+            }
+        }
+        ips
+    }
+}
+
+fn targets_at(lookup: &dyn Lookup, host: &str, transport: Transport, port: u16) -> Vec<Target> {
+    lookup
+        .addrs(host)
+        .into_iter()
+        .map(|ip| Target {
+            transport,
+            ip,
+            port,
+        })
+        .collect()
+}
+
+/// The addresses behind `name`'s SRV records, in priority order. A target of "."
+/// means the service is explicitly not offered.
+fn srv_targets(lookup: &dyn Lookup, name: &str, transport: Transport) -> Vec<Target> {
+    let mut records = lookup.srv(name);
+    records.sort_by_key(|srv| (srv.priority, std::cmp::Reverse(srv.weight)));
+    records
+        .iter()
+        .filter(|srv| !srv.target.is_empty() && srv.target != ".")
+        .flat_map(|srv| targets_at(lookup, &srv.target, transport, srv.port))
+        .collect()
+}
+
+pub(crate) fn locate_with(lookup: &dyn Lookup, uri: &ParsedUri) -> Vec<Target> {
+    let sips = uri.scheme == Scheme::Sips;
+    // maddr overrides the host as the destination (section 4).
+    let host = match uri.param("maddr").or(uri.host.as_deref()) {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return Vec::new(),
+    };
+    let transport = match uri.param("transport") {
+        // A SIPS URI is only ever reached over TLS.
+        Some(_) if sips => Some(Transport::Tls),
+        Some(param) => match Transport::from_param(param) {
+            Some(transport) => Some(transport),
+            None => return Vec::new(),
+        },
+        None => None,
+    };
+    let fallback = transport.unwrap_or(if sips { Transport::Tls } else { Transport::Udp });
+
+    // Numeric address or explicit port: no NAPTR or SRV (sections 4.1 and 4.2).
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        let port = uri.port.unwrap_or_else(|| fallback.default_port());
+        return vec![Target {
+            transport: fallback,
+            ip,
+            port,
+        }];
+    }
+    if let Some(port) = uri.port {
+        return targets_at(lookup, host, fallback, port);
+    }
+
+    let mut targets = Vec::new();
+    if let Some(transport) = transport {
+        targets = srv_targets(lookup, &transport.srv_name(host), transport);
+    } else {
+        let mut naptrs: Vec<(Naptr, Transport)> = lookup
+            .naptr(host)
+            .into_iter()
+            .filter(|n| n.flags.eq_ignore_ascii_case("s"))
+            .filter_map(|n| {
+                let transport = Transport::from_naptr_service(&n.service)?;
+                Some((n, transport))
+            })
+            .filter(|(_, transport)| !sips || *transport == Transport::Tls)
+            .collect();
+        naptrs.sort_by_key(|(n, _)| (n.order, n.preference));
+        for (naptr, transport) in &naptrs {
+            targets.extend(srv_targets(lookup, &naptr.replacement, *transport));
+        }
+        if naptrs.is_empty() {
+            let transports: &[Transport] = if sips {
+                &[Transport::Tls]
+            } else {
+                &[Transport::Udp, Transport::Tcp]
+            };
+            for transport in transports {
+                targets.extend(srv_targets(lookup, &transport.srv_name(host), *transport));
+            }
+        }
+    }
+    if targets.is_empty() {
+        targets = targets_at(lookup, host, fallback, fallback.default_port());
+    }
+    targets
+}
+
+/// The destinations for `uri` in the order to try them; empty if nothing resolves.
+pub fn locate(uri: &ParsedUri) -> Vec<Target> {
+    locate_with(&SystemLookup, uri)
+}
+
+/// Resolves a SIP or SIPS URI per RFC 3263 into caller-owned JSON: an ordered array of
+/// `{transport, ip, port}`. Returns NULL if the URI is NULL, does not parse or is a tel
+/// URI; `[]` if nothing resolves. Blocks for the DNS lookups.
+#[no_mangle]
+pub extern "C" fn rsip_resolve_target(sip_uri: *const c_char) -> *mut c_char {
+    let uri = match str_arg(sip_uri).map(uri::parse) {
+        Some(Ok(uri)) if uri.scheme != Scheme::Tel => uri,
+        _ => return std::ptr::null_mut(),
+    };
+    let targets: Vec<serde_json::Value> = locate(&uri)
+        .iter()
+        .map(|t| json!({ "transport": t.transport.as_str(), "ip": t.ip.to_string(), "port": t.port }))
+        .collect();
+    into_c_string(serde_json::Value::from(targets).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct Canned {
+        naptr: HashMap<&'static str, Vec<Naptr>>,
+        srv: HashMap<&'static str, Vec<Srv>>,
+        addrs: HashMap<&'static str, Vec<IpAddr>>,
+    }
+
+    impl Lookup for Canned {
+        fn naptr(&self, domain: &str) -> Vec<Naptr> {
+            self.naptr.get(domain).cloned().unwrap_or_default()
+        }
+        fn srv(&self, name: &str) -> Vec<Srv> {
+            self.srv.get(name).cloned().unwrap_or_default()
+        }
+        fn addrs(&self, host: &str) -> Vec<IpAddr> {
+            self.addrs.get(host).cloned().unwrap_or_default()
+        }
+    }
+
+    fn naptr(order: u16, service: &str, replacement: &str) -> Naptr {
+        Naptr {
+            order,
+            preference: 10,
+            flags: "S".into(),
+            service: service.into(),
+            regexp: String::new(),
+            replacement: replacement.into(),
+        }
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> Srv {
+        Srv {
+            priority,
+            weight,
+            port,
+            target: target.into(),
+        }
+    }
+
+    fn canned() -> Canned {
+        let mut dns = Canned::default();
+        dns.naptr.insert(
+            "example.com",
+            vec![
+                naptr(20, "SIP+D2U", "_sip._udp.example.com"),
+                naptr(10, "SIPS+D2T", "_sips._tcp.example.com"),
+                naptr(5, "E2U+sip", "ignored.example.com"),
+            ],
+        );
+        dns.srv.insert(
+            "_sips._tcp.example.com",
+            vec![srv(10, 0, 5061, "tls.example.com")],
+        );
+        dns.srv.insert(
+            "_sip._udp.example.com",
+            vec![
+                srv(20, 0, 5060, "backup.example.com"),
+                srv(10, 10, 5062, "b.example.com"),
+                srv(10, 90, 5060, "a.example.com"),
+                srv(5, 0, 0, "."),
+            ],
+        );
+        dns.srv.insert(
+            "_sip._tcp.srvonly.example",
+            vec![srv(0, 0, 5080, "a.example.com")],
+        );
+        for (host, ip) in [
+            ("tls.example.com", "192.0.2.10"),
+            ("a.example.com", "192.0.2.1"),
+            ("b.example.com", "2001:db8::2"),
+            ("backup.example.com", "192.0.2.3"),
+            ("plain.example", "198.51.100.7"),
+        ]
+        .iter()
+        {
+            dns.addrs.insert(host, vec![ip.parse().unwrap()]);
+        }
+        dns
+    }
+
+    fn located(dns: &Canned, raw: &str) -> Vec<(&'static str, String, u16)> {
+        locate_with(dns, &uri::parse(raw).unwrap())
+            .into_iter()
+            .map(|t| (t.transport.as_str(), t.ip.to_string(), t.port))
+            .collect()
+    }
+
+    fn t(transport: &'static str, ip: &str, port: u16) -> (&'static str, String, u16) {
+        (transport, ip.to_string(), port)
+    }
+
+    #[test]
+    fn follows_naptr_then_srv() {
+        let dns = canned();
+        assert_eq!(
+            located(&dns, "sip:alice@example.com"),
+            vec![
+                t("tls", "192.0.2.10", 5061),
+                t("udp", "192.0.2.1", 5060),
+                t("udp", "2001:db8::2", 5062),
+                t("udp", "192.0.2.3", 5060),
+            ]
+        );
+        assert_eq!(
+            located(&dns, "sips:alice@example.com"),
+            vec![t("tls", "192.0.2.10", 5061)]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_srv_then_addresses() {
+        let dns = canned();
+        let mut with_addr = canned();
+        with_addr
+            .addrs
+            .insert("srvonly.example", vec!["203.0.113.1".parse().unwrap()]);
+        assert_eq!(
+            located(&dns, "sip:srvonly.example"),
+            vec![t("tcp", "192.0.2.1", 5080)]
+        );
+        assert_eq!(
+            located(&dns, "sip:plain.example"),
+            vec![t("udp", "198.51.100.7", 5060)]
+        );
+        assert_eq!(
+            located(&dns, "sips:plain.example"),
+            vec![t("tls", "198.51.100.7", 5061)]
+        );
+        // An explicit transport skips NAPTR; with no SRV for it, A/AAAA at the default port.
+        assert_eq!(
+            located(&with_addr, "sip:srvonly.example;transport=udp"),
+            vec![t("udp", "203.0.113.1", 5060)]
+        );
+        assert!(located(&dns, "sip:nowhere.example").is_empty());
+    }
+
+    #[test]
+    fn numeric_hosts_and_ports_skip_dns() {
+        let dns = Canned::default();
+        assert_eq!(
+            located(&dns, "sip:bob@192.0.2.5;transport=TCP"),
+            vec![t("tcp", "192.0.2.5", 5060)]
+        );
+        assert_eq!(
+            located(&dns, "sips:[2001:db8::9]"),
+            vec![t("tls", "2001:db8::9", 5061)]
+        );
+        assert_eq!(
+            located(&canned(), "sip:a.example.com:5070"),
+            vec![t("udp", "192.0.2.1", 5070)]
+        );
+        assert_eq!(
+            located(&dns, "sip:bob@example.com;maddr=239.255.255.1"),
+            vec![t("udp", "239.255.255.1", 5060)]
+        );
+        assert!(located(&dns, "sip:bob@192.0.2.5;transport=sctp").is_empty());
+    }
+
+    #[test]
+    fn ffi_rejects_bad_uris() {
+        let tel = std::ffi::CString::new("tel:+15550123").unwrap();
+        assert!(rsip_resolve_target(tel.as_ptr()).is_null());
+        assert!(rsip_resolve_target(std::ptr::null()).is_null());
+        let ip = std::ffi::CString::new("sip:127.0.0.1:5080").unwrap();
+        let out = rsip_resolve_target(ip.as_ptr());
+        let json = unsafe { std::ffi::CStr::from_ptr(out) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        crate::ffi::rsip_free_string(out);
+        assert_eq!(
+            json,
+            r#"[{"ip":"127.0.0.1","port":5080,"transport":"udp"}]"#
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Operational log lines, kept apart from SIP events: events describe traffic the host
+//! acts on, log lines describe what the stack itself is doing.
+
+use crate::context::{with_context, RsipContext};
+use crate::lock::Lock;
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_char;
+use std::sync::atomic::Ordering;
+
+pub type LogCallback = extern "C" fn(level: i32, msg: *const c_char);
+
+/// Log levels; the numeric values are part of the C ABI.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl LogLevel {
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            1 => Some(Self::Error),
+            2 => Some(Self::Warn),
+            3 => Some(Self::Info),
+            4 => Some(Self::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl RsipContext {
+    /// Hands `args` to the log callback if one is set and `level` passes the threshold.
+    /// Formatting only happens in that case, so debug lines are cheap when disabled.
+    pub(crate) fn log(&self, level: LogLevel, args: fmt::Arguments<'_>) {
+        if level as i32 > self.log_level.load(Ordering::Relaxed) {
+            return;
+        }
+        let cb = match *self.log_callback.locked() {
+            Some(cb) => cb,
+            None => return,
+        };
+        let msg = CString::new(args.to_string().replace('\0', " ")).unwrap_or_default();
+        cb(level as i32, msg.as_ptr());
+    }
+
+    pub fn set_log_callback(&self, cb: Option<LogCallback>) {
+        *self.log_callback.locked() = cb;
+    }
+
+    /// Only lines at `level` or more severe reach the callback. Defaults to `Info`.
+    pub fn set_log_level(&self, level: LogLevel) {
+        self.log_level.store(level as i32, Ordering::Relaxed);
+    }
+}
+
+/// Sets the log callback, replacing any previous one; NULL disables logging.
+#[no_mangle]
+pub extern "C" fn rsip_set_log_callback(cb: Option<LogCallback>) {
+    crate::default_context().set_log_callback(cb);
+}
+
+/// Sets the most verbose level passed to the log callback (1 = ERROR .. 4 = DEBUG).
+/// Returns false for an unknown level.
+#[no_mangle]
+pub extern "C" fn rsip_set_log_level(level: i32) -> bool {
+    match LogLevel::from_code(level) {
+        Some(level) => {
+            crate::default_context().set_log_level(level);
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_log_callback(ctx: *mut RsipContext, cb: Option<LogCallback>) {
+    with_context(ctx, |ctx| ctx.set_log_callback(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_log_level(ctx: *mut RsipContext, level: i32) -> bool {
+    match LogLevel::from_code(level) {
+        Some(level) => with_context(ctx, |ctx| ctx.set_log_level(level)).is_some(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::net::UdpSocket;
+    use std::sync::{Arc, Mutex};
+
+    static LINES: Mutex<Vec<(i32, String)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(level: i32, msg: *const c_char) {
+        let msg = unsafe { CStr::from_ptr(msg) }
+            .to_string_lossy()
+            .into_owned();
+        LINES.lock().unwrap().push((level, msg));
+    }
+
+    #[test]
+    fn levels_and_bind_failures() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.set_log_callback(Some(record));
+        ctx.log(LogLevel::Debug, format_args!("hidden {}", 1));
+        ctx.set_log_level(LogLevel::Debug);
+        ctx.log(LogLevel::Debug, format_args!("shown {}", 2));
+
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert!(ctx.start_udp_listener_on("127.0.0.1", port).is_err());
+
+        ctx.set_log_callback(None);
+        ctx.log(LogLevel::Error, format_args!("after clear"));
+
+        let lines = LINES.lock().unwrap();
+        let messages: Vec<&str> = lines.iter().map(|(_, m)| m.as_str()).collect();
+        assert!(!messages.contains(&"hidden 1"));
+        assert!(messages.contains(&"shown 2"));
+        assert!(!messages.contains(&"after clear"));
+        assert!(lines
+            .iter()
+            .any(|(level, msg)| *level == LogLevel::Error as i32 && msg.contains("bind")));
+    }
+
+    #[test]
+    fn rejects_unknown_levels() {
+        assert!(!rsip_set_log_level(0));
+        assert!(!rsip_set_log_level(5));
+        assert_eq!(LogLevel::from_code(2), Some(LogLevel::Warn));
+    }
+}
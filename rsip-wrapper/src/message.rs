@@ -0,0 +1,812 @@
+//! An opaque handle over a parsed message, so C callers can query headers without
+//! re-parsing or going through JSON.
+
+use crate::diagnose::parse_recording;
+use crate::ffi::{into_c_bytes, into_c_string, str_arg};
+use crate::parse::parse_message;
+use crate::raw::{scan, unfold};
+use rsip::headers::{ContentLength, Header};
+use rsip::prelude::*;
+use rsip::{Method, SipMessage};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+
+/// A parsed SIP message owned by the C side; see `rsip_message_parse`.
+pub struct RsipMessage(pub(crate) SipMessage);
+
+/// RFC 3261 §7.3.3 compact forms. rsip keeps unknown names verbatim, so `v:` would
+/// otherwise never match a lookup for `Via`.
+const COMPACT_FORMS: &[(&str, &str)] = &[
+    ("a", "Accept-Contact"),
+    ("b", "Referred-By"),
+    ("c", "Content-Type"),
+    ("e", "Content-Encoding"),
+    ("f", "From"),
+    ("i", "Call-ID"),
+    ("k", "Supported"),
+    ("l", "Content-Length"),
+    ("m", "Contact"),
+    ("o", "Event"),
+    ("r", "Refer-To"),
+    ("s", "Subject"),
+    ("t", "To"),
+    ("u", "Allow-Events"),
+    ("v", "Via"),
+];
+
+/// The compact forms RFC 3261 itself defines, the only ones written; the others above
+/// come from extensions a peer may not implement.
+const WRITTEN_COMPACT_FORMS: &[&str] = &["c", "e", "f", "i", "k", "l", "m", "s", "t", "v"];
+
+/// Renames the headers that have a compact form in `WRITTEN_COMPACT_FORMS` to it.
+pub(crate) fn compact_headers(headers: &mut rsip::Headers) {
+    for header in headers.iter_mut() {
+        let (name, value) = name_value(header);
+        let name = canonical_name(&name);
+        if let Some((compact, _)) = COMPACT_FORMS.iter().find(|(compact, full)| {
+            WRITTEN_COMPACT_FORMS.contains(compact) && full.eq_ignore_ascii_case(name)
+        }) {
+            *header = Header::Other(compact.to_string(), value);
+        }
+    }
+}
+
+pub(crate) fn canonical_name(name: &str) -> &str {
+    let name = name.trim();
+    COMPACT_FORMS
+        .iter()
+        .find(|(compact, _)| compact.eq_ignore_ascii_case(name))
+        .map_or(name, |(_, full)| full)
+}
+
+/// Splits a header into its name and value. Every variant, typed or `Other`,
+/// displays as `Name: value`.
+pub(crate) fn name_value(header: &Header) -> (String, String) {
+    let line = header.to_string();
+    match line.split_once(':') {
+        Some((name, value)) => (name.trim().to_string(), value.trim().to_string()),
+        None => (line, String::new()),
+    }
+}
+
+/// Values of every header named `name` (case-insensitive, compact forms accepted), in
+/// message order.
+pub(crate) fn header_values(msg: &SipMessage, name: &str) -> Vec<String> {
+    let wanted = canonical_name(name);
+    msg.headers()
+        .iter()
+        .map(name_value)
+        .filter(|(name, _)| canonical_name(name).eq_ignore_ascii_case(wanted))
+        .map(|(_, value)| value)
+        .collect()
+}
+
+impl RsipMessage {
+    /// Values of every header named `name` (case-insensitive, compact forms accepted),
+    /// in message order.
+    pub fn header_values(&self, name: &str) -> Vec<String> {
+        header_values(&self.0, name)
+    }
+
+    /// The message as sent on the wire: CRLF line endings, the body byte for byte, and
+    /// a Content-Length matching it. The first Content-Length (or `l`) header is
+    /// replaced where it stands and any others dropped; one is appended if none exists.
+    /// Header names are written in compact form if `compact` is set.
+    pub fn to_bytes(&self, compact: bool) -> Vec<u8> {
+        let mut msg = self.0.clone();
+        let body = std::mem::take(msg.body_mut());
+        let mut headers: rsip::Headers = Default::default();
+        let mut has_length = false;
+        for header in msg.headers().iter() {
+            let (name, _) = name_value(header);
+            if !canonical_name(&name).eq_ignore_ascii_case("Content-Length") {
+                headers.push(header.clone());
+            } else if !has_length {
+                headers.push(ContentLength::from(body.len() as u32).into());
+                has_length = true;
+            }
+        }
+        if !has_length {
+            headers.push(ContentLength::from(body.len() as u32).into());
+        }
+        if compact {
+            compact_headers(&mut headers);
+        }
+        *msg.headers_mut() = headers;
+        let mut out = msg.to_string().into_bytes();
+        out.extend_from_slice(&body);
+        out
+    }
+}
+
+/// `rsip_message_kind` result for input that is NULL or does not parse.
+pub const KIND_PARSE_ERROR: i32 = -1;
+/// Responses are reported as this plus their status code (`1200` for `200 OK`).
+pub const KIND_RESPONSE_BASE: i32 = 1000;
+
+/// Stable dispatch code for a request method. Part of the C ABI: never renumber,
+/// only append.
+fn method_code(method: &Method) -> i32 {
+    match method {
+        Method::Invite => 1,
+        Method::Ack => 2,
+        Method::Bye => 3,
+        Method::Cancel => 4,
+        Method::Options => 5,
+        Method::Register => 6,
+        Method::Info => 7,
+        Method::Message => 8,
+        Method::Notify => 9,
+        Method::PRack => 10,
+        Method::Publish => 11,
+        Method::Refer => 12,
+        Method::Subscribe => 13,
+        Method::Update => 14,
+    }
+}
+
+/// The method code of a request, or `KIND_RESPONSE_BASE` + status for a response.
+pub fn kind_code(msg: &SipMessage) -> i32 {
+    match msg {
+        SipMessage::Request(req) => method_code(&req.method),
+        SipMessage::Response(res) => KIND_RESPONSE_BASE + i32::from(res.status_code.code()),
+    }
+}
+
+fn with_message<R>(msg: *const RsipMessage, f: impl FnOnce(&RsipMessage) -> R) -> Option<R> {
+    if msg.is_null() {
+        return None;
+    }
+    Some(f(unsafe { &*msg }))
+}
+
+/// Parses `raw` into a handle that must be released with `rsip_message_free`. Returns
+/// NULL if `raw` is NULL or not a SIP message.
+#[no_mangle]
+pub extern "C" fn rsip_message_parse(raw: *const c_char) -> *mut RsipMessage {
+    match parse_recording(raw) {
+        Some(msg) => Box::into_raw(Box::new(RsipMessage(msg))),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Classifies `raw` without building JSON: 1..=14 for request methods (see
+/// `rsip_wrapper.h`), 1000 + status for responses, `KIND_PARSE_ERROR` if `raw` is NULL
+/// or does not parse. 0 is reserved for methods rsip does not know; rsip currently
+/// rejects those, so they are reported as parse errors.
+#[no_mangle]
+pub extern "C" fn rsip_message_kind(raw: *const c_char) -> i32 {
+    match str_arg(raw).map(|raw| parse_message(raw.as_bytes())) {
+        Some(Ok(msg)) => kind_code(&msg),
+        _ => KIND_PARSE_ERROR,
+    }
+}
+
+/// Status code and reason phrase from a response's status line (RFC 3261 section
+/// 7.2), without parsing anything after it. `None` for requests and malformed lines.
+pub(crate) fn status_line(raw: &[u8]) -> Option<(u16, String)> {
+    let end = raw.iter().position(|b| *b == b'\n').unwrap_or(raw.len());
+    let line = raw[..end].strip_suffix(b"\r").unwrap_or(&raw[..end]);
+    let line = std::str::from_utf8(line).ok()?;
+    let (version, rest) = line.split_once(' ')?;
+    if !version.eq_ignore_ascii_case("SIP/2.0") {
+        return None;
+    }
+    let (code, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u16 = code.parse().ok()?;
+    (100..=699)
+        .contains(&code)
+        .then(|| (code, reason.trim().to_string()))
+}
+
+/// Fast path for branching on responses: writes the status code of `raw` to `code_out`
+/// and its reason phrase to `reason_out` (caller-owned, free with `rsip_free_string`).
+/// Either may be NULL. Only the status line is read. Returns false, writing nothing, if
+/// `raw` is NULL, a request or has a malformed status line.
+#[no_mangle]
+pub extern "C" fn rsip_response_status(
+    raw: *const c_char,
+    code_out: *mut u16,
+    reason_out: *mut *mut c_char,
+) -> bool {
+    let (code, reason) = match str_arg(raw).and_then(|raw| status_line(raw.as_bytes())) {
+        Some(status) => status,
+        None => return false,
+    };
+    if !code_out.is_null() {
+        unsafe { *code_out = code };
+    }
+    if !reason_out.is_null() {
+        unsafe { *reason_out = into_c_string(reason) };
+    }
+    true
+}
+
+/// The CSeq of `msg` by rsip's typed parsing; `None` if missing or not
+/// `<number> <method>`.
+pub(crate) fn cseq(msg: &SipMessage) -> Option<rsip::headers::typed::CSeq> {
+    msg.cseq_header().ok()?.typed().ok()
+}
+
+/// Writes the CSeq number of `raw` to `num_out` and its method to `method_out`
+/// (caller-owned, free with `rsip_free_string`). Either may be NULL. Returns false,
+/// writing nothing, if `raw` is NULL, does not parse or has no valid CSeq.
+#[no_mangle]
+pub extern "C" fn rsip_get_cseq(
+    raw: *const c_char,
+    num_out: *mut u32,
+    method_out: *mut *mut c_char,
+) -> bool {
+    let cseq = match str_arg(raw)
+        .and_then(|raw| parse_message(raw.as_bytes()).ok())
+        .and_then(|msg| cseq(&msg))
+    {
+        Some(cseq) => cseq,
+        None => return false,
+    };
+    if !num_out.is_null() {
+        unsafe { *num_out = cseq.seq };
+    }
+    if !method_out.is_null() {
+        unsafe { *method_out = into_c_string(cseq.method.to_string()) };
+    }
+    true
+}
+
+/// Whether the CSeq method of `raw` matches its request line (RFC 3261 section 8.1.1.5).
+/// Responses, having none, only need a valid CSeq. False if `raw` is NULL, does not
+/// parse or has no valid CSeq.
+#[no_mangle]
+pub extern "C" fn rsip_cseq_method_matches(raw: *const c_char) -> bool {
+    let msg = match str_arg(raw).and_then(|raw| parse_message(raw.as_bytes()).ok()) {
+        Some(msg) => msg,
+        None => return false,
+    };
+    match (cseq(&msg), &msg) {
+        (Some(cseq), SipMessage::Request(request)) => cseq.method == request.method,
+        (Some(_), SipMessage::Response(_)) => true,
+        (None, _) => false,
+    }
+}
+
+/// Values of every header named `wanted` in `raw` (case-insensitive, compact forms
+/// accepted), unfolded, in message order. Only the header section is scanned, so
+/// headers rsip has no type for, or a message it would not parse, are read as well.
+pub(crate) fn raw_header_values(raw: &[u8], wanted: &str) -> Vec<String> {
+    let wanted = canonical_name(wanted);
+    let mut headers = Vec::new();
+    // A malformed line further down does not matter for the headers before it.
+    let _ = scan(raw, &mut headers);
+    headers
+        .iter()
+        .filter(|h| {
+            canonical_name(&String::from_utf8_lossy(h.name.slice(raw))).eq_ignore_ascii_case(wanted)
+        })
+        .map(|h| String::from_utf8_lossy(&unfold(h.value.slice(raw))).into_owned())
+        .collect()
+}
+
+/// The tag of the first From (`from == true`) or To header in `raw`. Only the header
+/// section is scanned and only that header parsed, by rsip's typed From/To parsing.
+/// `None` if the header or its tag is missing or empty, or the header is malformed.
+pub(crate) fn header_tag(raw: &[u8], from: bool) -> Option<String> {
+    let wanted = if from { "From" } else { "To" };
+    let value = raw_header_values(raw, wanted).into_iter().next()?;
+    let tag = if from {
+        rsip::headers::From::new(value).typed().ok()?.tag().cloned()
+    } else {
+        rsip::headers::To::new(value).typed().ok()?.tag().cloned()
+    };
+    tag.map(|tag| tag.value().to_string())
+        .filter(|tag| !tag.is_empty())
+}
+
+/// The From tag of `raw` (caller-owned), or NULL if `raw` is NULL or has no From tag.
+#[no_mangle]
+pub extern "C" fn rsip_get_from_tag(raw: *const c_char) -> *mut c_char {
+    str_arg(raw)
+        .and_then(|raw| header_tag(raw.as_bytes(), true))
+        .map_or(std::ptr::null_mut(), into_c_string)
+}
+
+/// The To tag of `raw` (caller-owned), or NULL if `raw` is NULL or has no To tag, as in
+/// an initial request.
+#[no_mangle]
+pub extern "C" fn rsip_get_to_tag(raw: *const c_char) -> *mut c_char {
+    str_arg(raw)
+        .and_then(|raw| header_tag(raw.as_bytes(), false))
+        .map_or(std::ptr::null_mut(), into_c_string)
+}
+
+/// Splits a header value listing several entries (`a, <b>;p="x,y"`) at the commas
+/// outside angle brackets and quotes.
+pub(crate) fn split_list(value: &str) -> Vec<&str> {
+    let (mut entries, mut start) = (Vec::new(), 0);
+    let (mut in_angle, mut in_quote) = (false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => in_quote = !in_quote,
+            '<' if !in_quote => in_angle = true,
+            '>' if !in_quote => in_angle = false,
+            ',' if !in_quote && !in_angle => {
+                entries.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    entries.push(value[start..].trim());
+    entries.retain(|e| !e.is_empty());
+    entries
+}
+
+/// Every Contact of `msg` (several per header line included) with the expiry that
+/// applies to it: its `expires` param, else the Expires header, else `None`.
+pub(crate) fn contact_expires(msg: &SipMessage) -> Vec<(String, Option<u32>)> {
+    let header = msg.expires_header().and_then(|e| e.seconds().ok());
+    header_values(msg, "Contact")
+        .iter()
+        .flat_map(|value| split_list(value))
+        .map(|contact| {
+            let param = rsip::headers::Contact::new(contact)
+                .expires()
+                .ok()
+                .flatten()
+                .and_then(|e| e.seconds().ok());
+            (contact.to_string(), param.or(header))
+        })
+        .collect()
+}
+
+/// The expiry of a registration request or response: the first Contact's `expires`
+/// param, else the Expires header.
+pub(crate) fn effective_expires(msg: &SipMessage) -> Option<u32> {
+    match contact_expires(msg).into_iter().next() {
+        Some((_, expires)) => expires,
+        None => msg.expires_header().and_then(|e| e.seconds().ok()),
+    }
+}
+
+/// The effective expiry of `raw` in seconds (see `effective_expires`), or -1 if it has
+/// none or `raw` is NULL or does not parse.
+#[no_mangle]
+pub extern "C" fn rsip_get_effective_expires(raw: *const c_char) -> i64 {
+    str_arg(raw)
+        .and_then(|raw| parse_message(raw.as_bytes()).ok())
+        .and_then(|msg| effective_expires(&msg))
+        .map_or(-1, i64::from)
+}
+
+/// JSON array with an object `{contact, expires}` per Contact of `raw`, in order, where
+/// `expires` is the one that applies to it (see `contact_expires`) or null. NULL if
+/// `raw` is NULL or does not parse. Caller-owned.
+#[no_mangle]
+pub extern "C" fn rsip_get_contact_expires(raw: *const c_char) -> *mut c_char {
+    let msg = match str_arg(raw).and_then(|raw| parse_message(raw.as_bytes()).ok()) {
+        Some(msg) => msg,
+        None => return std::ptr::null_mut(),
+    };
+    let contacts: Vec<_> = contact_expires(&msg)
+        .into_iter()
+        .map(|(contact, expires)| serde_json::json!({ "contact": contact, "expires": expires }))
+        .collect();
+    into_c_string(serde_json::Value::from(contacts).to_string())
+}
+
+/// Value of the first header named `name`, or NULL if there is none. Caller-owned.
+#[no_mangle]
+pub extern "C" fn rsip_message_header(msg: *const RsipMessage, name: *const c_char) -> *mut c_char {
+    let name = match str_arg(name) {
+        Some(name) => name,
+        None => return std::ptr::null_mut(),
+    };
+    with_message(msg, |msg| msg.header_values(name).into_iter().next())
+        .flatten()
+        .map_or(std::ptr::null_mut(), into_c_string)
+}
+
+/// JSON array with the values of every header named `name` (`[]` if none), or NULL on a
+/// NULL argument. Caller-owned.
+#[no_mangle]
+pub extern "C" fn rsip_message_headers(
+    msg: *const RsipMessage,
+    name: *const c_char,
+) -> *mut c_char {
+    let name = match str_arg(name) {
+        Some(name) => name,
+        None => return std::ptr::null_mut(),
+    };
+    with_message(msg, |msg| {
+        into_c_string(serde_json::Value::from(msg.header_values(name)).to_string())
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+pub type HeaderCallback =
+    extern "C" fn(name: *const c_char, value: *const c_char, user_data: *mut c_void);
+
+/// Calls `cb` with the name and value of every header of `msg`, in message order, and
+/// `user_data`. Headers rsip knows are named in their canonical form. Both strings
+/// are only valid during the call. False if `msg` is NULL.
+#[no_mangle]
+pub extern "C" fn rsip_message_for_each_header(
+    msg: *const RsipMessage,
+    cb: HeaderCallback,
+    user_data: *mut c_void,
+) -> bool {
+    with_message(msg, |msg| {
+        for (name, value) in msg.0.headers().iter().map(name_value) {
+            let name = CString::new(name).unwrap_or_default();
+            let value = CString::new(value).unwrap_or_default();
+            cb(name.as_ptr(), value.as_ptr(), user_data);
+        }
+    })
+    .is_some()
+}
+
+/// Serializes `msg` to wire bytes (see `RsipMessage::to_bytes`; compact if the
+/// default context is set to), writing their count to `out_len`. The buffer is not
+/// NUL-terminated and is released with `rsip_free_bytes`. NULL if an argument is NULL.
+#[no_mangle]
+pub extern "C" fn rsip_message_to_bytes(msg: *const RsipMessage, out_len: *mut usize) -> *mut u8 {
+    let out_len = match unsafe { out_len.as_mut() } {
+        Some(out_len) => out_len,
+        None => return std::ptr::null_mut(),
+    };
+    let compact = crate::default_context().message_defaults().compact;
+    with_message(msg, |msg| into_c_bytes(msg.to_bytes(compact), out_len))
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Releases a handle from `rsip_message_parse`. NULL is a no-op.
+#[no_mangle]
+pub extern "C" fn rsip_message_free(msg: *mut RsipMessage) {
+    if !msg.is_null() {
+        drop(unsafe { Box::from_raw(msg) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::rsip_free_string;
+    use std::ffi::{CStr, CString};
+
+    fn take(s: *mut c_char) -> Option<String> {
+        if s.is_null() {
+            return None;
+        }
+        let out = unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string();
+        rsip_free_string(s);
+        Some(out)
+    }
+
+    #[test]
+    fn reads_the_status_line() {
+        let status = |raw: &str| {
+            let raw = CString::new(raw).unwrap();
+            let mut code = 0u16;
+            let mut reason = std::ptr::null_mut();
+            rsip_response_status(raw.as_ptr(), &mut code, &mut reason).then(|| (code, take(reason)))
+        };
+        assert_eq!(
+            status("SIP/2.0 486 Busy Here\r\nVia: garbage\r\n\r\n"),
+            Some((486, Some("Busy Here".to_string())))
+        );
+        assert_eq!(
+            status("SIP/2.0 200 OK\nContent-Length: 0\n\n"),
+            Some((200, Some("OK".to_string())))
+        );
+        assert_eq!(
+            status("SIP/2.0 183 \r\n\r\n"),
+            Some((183, Some(String::new())))
+        );
+        assert_eq!(status("OPTIONS sip:bob@example.com SIP/2.0\r\n\r\n"), None);
+        assert_eq!(status("SIP/2.0 99 Low\r\n\r\n"), None);
+        assert_eq!(status("SIP/2.0 2000 OK\r\n\r\n"), None);
+        assert_eq!(status("HTTP/1.1 200 OK\r\n\r\n"), None);
+
+        let raw = CString::new("SIP/2.0 404 Not Found\r\n\r\n").unwrap();
+        let mut code = 0u16;
+        assert!(rsip_response_status(
+            raw.as_ptr(),
+            &mut code,
+            std::ptr::null_mut()
+        ));
+        assert_eq!(code, 404);
+        assert!(!rsip_response_status(
+            std::ptr::null(),
+            &mut code,
+            std::ptr::null_mut()
+        ));
+    }
+
+    #[test]
+    fn extracts_tags() {
+        let tags = |raw: &str| {
+            let raw = CString::new(raw).unwrap();
+            (
+                take(rsip_get_from_tag(raw.as_ptr())),
+                take(rsip_get_to_tag(raw.as_ptr())),
+            )
+        };
+        assert_eq!(
+            tags(
+                "SIP/2.0 200 OK\r\n\
+                 Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKa\r\n\
+                 From: \"Alice\" <sip:alice@example.com>;tag=1928301774\r\n\
+                 To: <sip:bob@example.com;transport=udp>;tag=a6c85cf\r\n\
+                 Call-ID: a84b4c76e66710\r\n\r\n"
+            ),
+            (Some("1928301774".into()), Some("a6c85cf".into()))
+        );
+        // compact forms, a folded From, an initial request without a To tag
+        assert_eq!(
+            tags(
+                "INVITE sip:bob@example.com SIP/2.0\r\n\
+                 f:\r\n\
+                 \x20sip:alice@example.com;tag=88sja8x\r\n\
+                 t: sip:bob@example.com\r\n\r\n"
+            ),
+            (Some("88sja8x".into()), None)
+        );
+        // the uri's own parameters are not the header's
+        assert_eq!(
+            tags("INVITE sip:b@c SIP/2.0\r\nFrom: <sip:a@b;tag=uri>\r\nTo: <<<\r\n\r\n"),
+            (None, None)
+        );
+        assert_eq!(tags("garbage"), (None, None));
+        assert!(rsip_get_from_tag(std::ptr::null()).is_null());
+    }
+
+    #[test]
+    fn reads_and_checks_cseq() {
+        let message = |start: &str, cseq: &str| {
+            CString::new(format!(
+                "{}\r\nCall-ID: c\r\n{}Content-Length: 0\r\n\r\n",
+                start, cseq
+            ))
+            .unwrap()
+        };
+        let invite = message("INVITE sip:b@c SIP/2.0", "CSeq: 314159 INVITE\r\n");
+        let (mut num, mut method) = (0u32, std::ptr::null_mut());
+        assert!(rsip_get_cseq(invite.as_ptr(), &mut num, &mut method));
+        assert_eq!(num, 314159);
+        assert_eq!(
+            unsafe { CStr::from_ptr(method) }.to_str().unwrap(),
+            "INVITE"
+        );
+        rsip_free_string(method);
+        assert!(rsip_cseq_method_matches(invite.as_ptr()));
+
+        let mismatch = message("INVITE sip:b@c SIP/2.0", "CSeq: 2 BYE\r\n");
+        assert!(rsip_get_cseq(
+            mismatch.as_ptr(),
+            &mut num,
+            std::ptr::null_mut()
+        ));
+        assert_eq!(num, 2);
+        assert!(!rsip_cseq_method_matches(mismatch.as_ptr()));
+        let response = message("SIP/2.0 200 OK", "CSeq: 2 BYE\r\n");
+        assert!(rsip_cseq_method_matches(response.as_ptr()));
+
+        for bad in &["", "CSeq: INVITE\r\n", "CSeq: x INVITE\r\n"] {
+            let raw = message("INVITE sip:b@c SIP/2.0", bad);
+            num = 7;
+            assert!(
+                !rsip_get_cseq(raw.as_ptr(), &mut num, std::ptr::null_mut()),
+                "{}",
+                bad
+            );
+            assert_eq!(num, 7, "nothing written");
+            assert!(!rsip_cseq_method_matches(raw.as_ptr()));
+        }
+        assert!(!rsip_get_cseq(
+            std::ptr::null(),
+            &mut num,
+            std::ptr::null_mut()
+        ));
+    }
+
+    #[test]
+    fn resolves_expires() {
+        let expires = |headers: &str| {
+            let raw = CString::new(format!(
+                "SIP/2.0 200 OK\r\nCSeq: 1 REGISTER\r\n{}Content-Length: 0\r\n\r\n",
+                headers
+            ))
+            .unwrap();
+            rsip_get_effective_expires(raw.as_ptr())
+        };
+        assert_eq!(expires(""), -1);
+        assert_eq!(expires("Expires: 300\r\n"), 300);
+        assert_eq!(
+            expires("Contact: <sip:a@192.0.2.1>\r\nExpires: 300\r\n"),
+            300
+        );
+        assert_eq!(
+            expires("Contact: <sip:a@192.0.2.1>;expires=60\r\nExpires: 300\r\n"),
+            60
+        );
+        assert_eq!(expires("m: <sip:a@192.0.2.1>;expires=0\r\n"), 0);
+        assert_eq!(rsip_get_effective_expires(std::ptr::null()), -1);
+
+        let raw = CString::new(
+            "SIP/2.0 200 OK\r\n\
+             CSeq: 1 REGISTER\r\n\
+             Contact: \"A, B\" <sip:a@192.0.2.1>;expires=60, <sip:a@[2001:db8::1]>\r\n\
+             Contact: <sip:a@192.0.2.2;transport=tcp>;expires=120\r\n\
+             Expires: 3600\r\n\
+             Content-Length: 0\r\n\r\n",
+        )
+        .unwrap();
+        let out = rsip_get_contact_expires(raw.as_ptr());
+        let json: serde_json::Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        rsip_free_string(out);
+        assert_eq!(
+            json,
+            serde_json::json!([
+                { "contact": "\"A, B\" <sip:a@192.0.2.1>;expires=60", "expires": 60 },
+                { "contact": "<sip:a@[2001:db8::1]>", "expires": 3600 },
+                { "contact": "<sip:a@192.0.2.2;transport=tcp>;expires=120", "expires": 120 },
+            ])
+        );
+        assert!(rsip_get_contact_expires(std::ptr::null()).is_null());
+    }
+
+    #[test]
+    fn header_lookup() {
+        let raw = CString::new(
+            "INVITE sip:bob@example.com SIP/2.0\r\n\
+             v: SIP/2.0/UDP proxy.example.com;branch=z9hG4bKp\r\n\
+             Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKa\r\n\
+             From: <sip:alice@example.com>;tag=1\r\n\
+             To: <sip:bob@example.com>\r\n\
+             Call-ID: abc@192.0.2.1\r\n\
+             CSeq: 1 INVITE\r\n\
+             X-Account: 42\r\n\
+             Content-Length: 0\r\n\r\n",
+        )
+        .unwrap();
+        let msg = rsip_message_parse(raw.as_ptr());
+        assert!(!msg.is_null());
+
+        let header = |name: &str| {
+            let name = CString::new(name).unwrap();
+            take(rsip_message_header(msg, name.as_ptr()))
+        };
+        assert_eq!(header("call-id").as_deref(), Some("abc@192.0.2.1"));
+        assert_eq!(header("i").as_deref(), Some("abc@192.0.2.1"));
+        assert_eq!(header("X-Account").as_deref(), Some("42"));
+        assert_eq!(
+            header("Via").as_deref(),
+            Some("SIP/2.0/UDP proxy.example.com;branch=z9hG4bKp")
+        );
+        assert_eq!(header("Subject"), None);
+
+        let name = CString::new("via").unwrap();
+        let all: Vec<String> =
+            serde_json::from_str(&take(rsip_message_headers(msg, name.as_ptr())).unwrap()).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[1], "SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKa");
+
+        let missing = CString::new("Route").unwrap();
+        assert_eq!(
+            take(rsip_message_headers(msg, missing.as_ptr())).as_deref(),
+            Some("[]")
+        );
+        assert!(rsip_message_header(msg, std::ptr::null()).is_null());
+
+        rsip_message_free(msg);
+        rsip_message_free(std::ptr::null_mut());
+    }
+
+    extern "C" fn collect(name: *const c_char, value: *const c_char, user_data: *mut c_void) {
+        let headers = unsafe { &mut *(user_data as *mut Vec<(String, String)>) };
+        let text = |s| unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string();
+        headers.push((text(name), text(value)));
+    }
+
+    #[test]
+    fn iterates_headers_in_order() {
+        let raw = CString::new(
+            "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+             v: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKa\r\n\
+             Call-ID: it@192.0.2.1\r\n\
+             X-Empty:\r\n\
+             CSeq: 1 OPTIONS\r\n\r\n",
+        )
+        .unwrap();
+        let msg = rsip_message_parse(raw.as_ptr());
+        let mut headers: Vec<(String, String)> = Vec::new();
+        let user_data = &mut headers as *mut Vec<(String, String)> as *mut c_void;
+        assert!(rsip_message_for_each_header(msg, collect, user_data));
+        rsip_message_free(msg);
+        let expected = [
+            ("Via", "SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKa"),
+            ("Call-ID", "it@192.0.2.1"),
+            ("X-Empty", ""),
+            ("CSeq", "1 OPTIONS"),
+        ];
+        assert_eq!(
+            headers,
+            expected
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        );
+        assert!(!rsip_message_for_each_header(
+            std::ptr::null(),
+            collect,
+            user_data
+        ));
+    }
+
+    #[test]
+    fn serializes_with_a_fresh_content_length() {
+        let bytes = |raw: &str| {
+            let raw = CString::new(raw).unwrap();
+            let msg = rsip_message_parse(raw.as_ptr());
+            assert!(!msg.is_null());
+            let mut len = 0;
+            let ptr = rsip_message_to_bytes(msg, &mut len);
+            rsip_message_free(msg);
+            let out = unsafe { std::slice::from_raw_parts(ptr, len) }.to_vec();
+            crate::ffi::rsip_free_bytes(ptr, len);
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            bytes(
+                "MESSAGE sip:bob@example.com SIP/2.0\n\
+                 l: 2\n\
+                 Call-ID: wire@192.0.2.1\n\
+                 Content-Length: 99\n\n\
+                 hello"
+            ),
+            "MESSAGE sip:bob@example.com SIP/2.0\r\n\
+             Content-Length: 5\r\n\
+             Call-ID: wire@192.0.2.1\r\n\r\n\
+             hello"
+        );
+        assert_eq!(
+            bytes("SIP/2.0 200 OK\r\nCSeq: 1 OPTIONS\r\n\r\n"),
+            "SIP/2.0 200 OK\r\nCSeq: 1 OPTIONS\r\nContent-Length: 0\r\n\r\n"
+        );
+        let mut len = 0;
+        assert!(rsip_message_to_bytes(std::ptr::null(), &mut len).is_null());
+    }
+
+    #[test]
+    fn kind_codes() {
+        let kind = |raw: &str| rsip_message_kind(CString::new(raw).unwrap().as_ptr());
+        let request = |method: &str| {
+            kind(&format!(
+                "{} sip:bob@example.com SIP/2.0\r\nCSeq: 1 {}\r\n\r\n",
+                method, method
+            ))
+        };
+        assert_eq!(request("INVITE"), 1);
+        assert_eq!(request("ACK"), 2);
+        assert_eq!(request("REGISTER"), 6);
+        assert_eq!(request("UPDATE"), 14);
+        assert_eq!(kind("SIP/2.0 200 OK\r\n\r\n"), 1200);
+        assert_eq!(kind("SIP/2.0 487 Request Terminated\r\n\r\n"), 1487);
+        assert_eq!(kind("SIP/2.0 599 Custom\r\n\r\n"), 1599);
+        assert_eq!(kind("not sip"), KIND_PARSE_ERROR);
+        assert_eq!(rsip_message_kind(std::ptr::null()), KIND_PARSE_ERROR);
+
+        let codes: std::collections::HashSet<i32> = Method::all().iter().map(method_code).collect();
+        assert_eq!(codes.len(), Method::all().len());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let raw = CString::new("not sip").unwrap();
+        assert!(rsip_message_parse(raw.as_ptr()).is_null());
+        assert!(rsip_message_parse(std::ptr::null()).is_null());
+    }
+}
@@ -0,0 +1,318 @@
+//! NAT helpers for the UAS side: recording where a request really came from in its
+//! top Via (RFC 3261 section 18.2.1, RFC 3581), so responses find their way back, and
+//! reading back where that is (section 18.2.2).
+//!
+//! The rewrite is done on the text, leaving every other byte of the message as
+//! received.
+
+use crate::ffi::{into_c_string, str_arg};
+use crate::raw;
+use crate::send::host_port;
+use rsip::SipMessage;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::os::raw::c_char;
+
+/// Sets `received` (and `rport`, when the client asked for it with an empty `rport`)
+/// on the top Via parameters. `received` is added when the sent-by host differs from
+/// the source address, and always when `rport` is filled in.
+fn rewrite_via(via: &str, src: SocketAddr) -> String {
+    let mut parts = via.split(';');
+    let sent = parts.next().unwrap_or_default();
+    let params: Vec<&str> = parts.collect();
+    let wants_rport = params.iter().any(|p| param_name(p) == "rport");
+
+    let (host, _) = sent_by(sent);
+    let same_host = host.parse::<IpAddr>().ok() == Some(src.ip());
+    let received = format!("received={}", src.ip());
+
+    let mut out = sent.to_string();
+    let mut has_received = false;
+    for param in params {
+        out.push(';');
+        match param_name(param).as_str() {
+            "rport" => out.push_str(&format!("rport={}", src.port())),
+            "received" if wants_rport || !same_host => {
+                out.push_str(&received);
+                has_received = true;
+            }
+            _ => out.push_str(param),
+        }
+    }
+    if !has_received && (wants_rport || !same_host) {
+        out.push(';');
+        out.push_str(&received);
+    }
+    out
+}
+
+fn param_name(param: &str) -> String {
+    param
+        .split('=')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn param_value<'a>(params: &[&'a str], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|p| param_name(p) == name)
+        .and_then(|p| p.split_once('='))
+        .map(|(_, value)| value.trim())
+}
+
+/// "SIP/2.0/UDP host:port" -> host, unbracketed, and port if given.
+fn sent_by(sent: &str) -> (&str, Option<u16>) {
+    let sent_by = sent.split_whitespace().nth(1).unwrap_or_default();
+    match sent_by.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest.split_once(']').unwrap_or((rest, ""));
+            (host, port.strip_prefix(':').and_then(|p| p.parse().ok()))
+        }
+        None => {
+            let mut parts = sent_by.splitn(2, ':');
+            let host = parts.next().unwrap_or_default();
+            (host, parts.next().and_then(|p| p.parse().ok()))
+        }
+    }
+}
+
+/// Byte range of the top Via value of `raw`, if it is a request with a Via header.
+fn top_via(raw: &str) -> Option<(usize, usize)> {
+    if !matches!(SipMessage::try_from(raw), Ok(SipMessage::Request(_))) {
+        return None;
+    }
+    let mut headers = Vec::new();
+    raw::scan(raw.as_bytes(), &mut headers)?;
+    let via = headers.iter().find(|h| {
+        let name = &raw[h.name.offset as usize..(h.name.offset + h.name.len) as usize];
+        name.eq_ignore_ascii_case("via") || name.eq_ignore_ascii_case("v")
+    })?;
+    let start = via.value.offset as usize;
+    let value = &raw[start..start + via.value.len as usize];
+    // Only the first of several comma-separated values is the top Via.
+    let end = start + value.find(',').unwrap_or(value.len());
+    Some((start, start + raw[start..end].trim_end().len()))
+}
+
+/// `raw` with its top Via rewritten for a request received from `src`, or `None` if it
+/// is not a request with a Via header.
+pub fn apply_rport(raw: &str, src: SocketAddr) -> Option<String> {
+    let (start, end) = top_via(raw)?;
+    let src = SocketAddr::new(src.ip().to_canonical(), src.port());
+    let top = &raw[start..end];
+
+    let mut out = String::with_capacity(raw.len() + 32);
+    out.push_str(&raw[..start]);
+    out.push_str(&rewrite_via(top, src));
+    out.push_str(&raw[end..]);
+    Some(out)
+}
+
+/// Where responses to `raw` go, as `host:port` (RFC 3261 section 18.2.2, RFC 3581):
+/// `maddr` if set, else `received` (or the sent-by host), on the `rport` port if it is
+/// filled in, else the sent-by port, else the transport's default port. `None` if it
+/// is not a request with a Via header.
+pub fn response_destination(raw: &str) -> Option<String> {
+    let (start, end) = top_via(raw)?;
+    let mut parts = raw[start..end].split(';');
+    let sent = parts.next().unwrap_or_default();
+    let params: Vec<&str> = parts.collect();
+    let (host, port) = sent_by(sent);
+    let secure = sent
+        .split_whitespace()
+        .next()
+        .and_then(|protocol| protocol.rsplit('/').next())
+        .is_some_and(|transport| {
+            transport.eq_ignore_ascii_case("tls") || transport.eq_ignore_ascii_case("wss")
+        });
+    let default_port = if secure { 5061 } else { 5060 };
+    let rport = param_value(&params, "rport").and_then(|p| p.parse::<u16>().ok());
+    let host = param_value(&params, "maddr")
+        .or_else(|| param_value(&params, "received"))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Some(host_port(host, rport.or(port).unwrap_or(default_port)))
+}
+
+/// Rewrites the top Via of `raw_request` with `received`/`rport` for a request that
+/// arrived from `src_ip:src_port` (e.g. the `src` of `sip_rx_parsed`). Returns the
+/// caller-owned message, or NULL if an argument is invalid or it is not a request
+/// with a Via header.
+#[no_mangle]
+pub extern "C" fn rsip_apply_rport(
+    raw_request: *const c_char,
+    src_ip: *const c_char,
+    src_port: u16,
+) -> *mut c_char {
+    let ip = match str_arg(src_ip).and_then(|ip| ip.parse::<IpAddr>().ok()) {
+        Some(ip) => ip,
+        None => return std::ptr::null_mut(),
+    };
+    str_arg(raw_request)
+        .and_then(|raw| apply_rport(raw, SocketAddr::new(ip, src_port)))
+        .map_or(std::ptr::null_mut(), into_c_string)
+}
+
+/// Where to send responses to `raw_request`, from its top Via: `"ip:port"` (IPv6
+/// bracketed; a host name if the Via has neither `received` nor `maddr`), honoring
+/// `rport` and `received`. Returns a caller-owned string, or NULL if the argument is
+/// NULL or not a request with a Via header.
+#[no_mangle]
+pub extern "C" fn rsip_response_destination(raw_request: *const c_char) -> *mut c_char {
+    str_arg(raw_request)
+        .and_then(response_destination)
+        .map_or(std::ptr::null_mut(), into_c_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(via: &str) -> String {
+        format!(
+            "INVITE sip:bob@example.com SIP/2.0\r\n\
+             Via: {}\r\n\
+             Via: SIP/2.0/UDP proxy.example.com;branch=z9hG4bKp\r\n\
+             To: <sip:bob@example.com>\r\n\
+             From: <sip:alice@example.com>;tag=1\r\n\
+             Call-ID: rport-test\r\n\
+             CSeq: 1 INVITE\r\n\
+             Content-Length: 0\r\n\r\n",
+            via
+        )
+    }
+
+    fn top_via(via: &str, src: &str) -> String {
+        let out = apply_rport(&request(via), src.parse().unwrap()).unwrap();
+        out.lines()
+            .nth(1)
+            .unwrap()
+            .trim_start_matches("Via: ")
+            .to_string()
+    }
+
+    #[test]
+    fn fills_rport_and_received() {
+        assert_eq!(
+            top_via(
+                "SIP/2.0/UDP 10.0.0.5:5060;rport;branch=z9hG4bK1",
+                "203.0.113.7:40123"
+            ),
+            "SIP/2.0/UDP 10.0.0.5:5060;rport=40123;branch=z9hG4bK1;received=203.0.113.7"
+        );
+        // received is required with rport even when the host matches (RFC 3581).
+        assert_eq!(
+            top_via(
+                "SIP/2.0/UDP 203.0.113.7;branch=z9hG4bK1;rport",
+                "203.0.113.7:40123"
+            ),
+            "SIP/2.0/UDP 203.0.113.7;branch=z9hG4bK1;rport=40123;received=203.0.113.7"
+        );
+        // a stale received is replaced in place
+        assert_eq!(
+            top_via(
+                "SIP/2.0/UDP host.invalid;received=1.1.1.1;rport;branch=z9hG4bK1",
+                "[::ffff:198.51.100.2]:5062"
+            ),
+            "SIP/2.0/UDP host.invalid;received=198.51.100.2;rport=5062;branch=z9hG4bK1"
+        );
+    }
+
+    #[test]
+    fn without_rport_only_adds_received_when_needed() {
+        assert_eq!(
+            top_via(
+                "SIP/2.0/UDP 10.0.0.5:5060;branch=z9hG4bK1",
+                "203.0.113.7:5060"
+            ),
+            "SIP/2.0/UDP 10.0.0.5:5060;branch=z9hG4bK1;received=203.0.113.7"
+        );
+        assert_eq!(
+            top_via(
+                "SIP/2.0/UDP [2001:db8::1]:5060;branch=z9hG4bK1",
+                "[2001:db8::1]:5060"
+            ),
+            "SIP/2.0/UDP [2001:db8::1]:5060;branch=z9hG4bK1"
+        );
+    }
+
+    #[test]
+    fn leaves_the_rest_untouched() {
+        let raw = request("SIP/2.0/UDP a.invalid;rport;branch=z9hG4bK1, SIP/2.0/UDP b.invalid");
+        let out = apply_rport(&raw, "192.0.2.1:1".parse().unwrap()).unwrap();
+        assert!(out.contains(
+            "Via: SIP/2.0/UDP a.invalid;rport=1;branch=z9hG4bK1;received=192.0.2.1, \
+             SIP/2.0/UDP b.invalid\r\n"
+        ));
+        assert_eq!(
+            out.replace(";rport=1", ";rport")
+                .replace(";received=192.0.2.1", ""),
+            raw
+        );
+    }
+
+    #[test]
+    fn rejects_responses_and_bad_input() {
+        let response = "SIP/2.0 200 OK\r\nVia: SIP/2.0/UDP a;rport\r\nContent-Length: 0\r\n\r\n";
+        assert!(apply_rport(response, "192.0.2.1:1".parse().unwrap()).is_none());
+
+        let raw = std::ffi::CString::new(request("SIP/2.0/UDP a;rport")).unwrap();
+        let bad_ip = std::ffi::CString::new("not-an-ip").unwrap();
+        assert!(rsip_apply_rport(raw.as_ptr(), bad_ip.as_ptr(), 5060).is_null());
+        assert!(rsip_apply_rport(std::ptr::null(), bad_ip.as_ptr(), 5060).is_null());
+    }
+
+    #[test]
+    fn finds_the_response_destination() {
+        let dest = |via: &str| response_destination(&request(via));
+        assert_eq!(
+            dest("SIP/2.0/UDP 10.0.0.5:5070;branch=z9hG4bK1").unwrap(),
+            "10.0.0.5:5070"
+        );
+        assert_eq!(
+            dest("SIP/2.0/TLS client.example.com;branch=z9hG4bK1").unwrap(),
+            "client.example.com:5061"
+        );
+        assert_eq!(
+            dest("SIP/2.0/UDP 10.0.0.5:5070;branch=z9hG4bK1;received=203.0.113.7").unwrap(),
+            "203.0.113.7:5070"
+        );
+        assert_eq!(
+            dest("SIP/2.0/UDP [2001:db8::1];branch=z9hG4bK1").unwrap(),
+            "[2001:db8::1]:5060"
+        );
+        assert_eq!(
+            dest("SIP/2.0/UDP 10.0.0.5;maddr=239.255.255.1;received=203.0.113.7").unwrap(),
+            "239.255.255.1:5060"
+        );
+        // an empty rport (not yet filled in) falls back to the sent-by port
+        assert_eq!(
+            dest("SIP/2.0/UDP 10.0.0.5:5070;rport;branch=z9hG4bK1").unwrap(),
+            "10.0.0.5:5070"
+        );
+
+        // the round trip with apply_rport
+        let raw = apply_rport(
+            &request("SIP/2.0/UDP [2001:db8::5]:5060;rport;branch=z9hG4bK1"),
+            "[2001:db8::9]:40123".parse().unwrap(),
+        )
+        .unwrap();
+        let raw = std::ffi::CString::new(raw).unwrap();
+        let out = rsip_response_destination(raw.as_ptr());
+        assert_eq!(
+            unsafe { std::ffi::CStr::from_ptr(out) }.to_str().unwrap(),
+            "[2001:db8::9]:40123"
+        );
+        crate::rsip_free_string(out);
+
+        let response = "SIP/2.0 200 OK\r\nVia: SIP/2.0/UDP a\r\nContent-Length: 0\r\n\r\n";
+        assert!(response_destination(response).is_none());
+        assert!(rsip_response_destination(std::ptr::null()).is_null());
+    }
+}
@@ -0,0 +1,209 @@
+//! Asynchronous sends: messages are queued to a per-context sender thread, started on
+//! first use, and each outcome is reported as a `sent_ok` or `send_failed` event
+//! carrying the id `send_async` returned.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::lock::Lock;
+use crate::log::LogLevel;
+use crate::send::{ephemeral_socket, host_port, resolve, send_args};
+use serde_json::json;
+use std::io;
+use std::os::raw::c_char;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+struct Outgoing {
+    id: u64,
+    ip: String,
+    port: u16,
+    data: Vec<u8>,
+}
+
+pub(crate) struct Outbound {
+    queue: Sender<Outgoing>,
+    thread: JoinHandle<()>,
+}
+
+impl Outbound {
+    fn spawn(ctx: &Arc<RsipContext>) -> Self {
+        let (queue, rx) = mpsc::channel::<Outgoing>();
+        let ctx = ctx.clone();
+        let thread = thread::spawn(move || {
+            // Ends once the context drops its sender and the queue is empty.
+            for msg in rx {
+                ctx.send_queued(msg);
+            }
+        });
+        Self { queue, thread }
+    }
+
+    /// Sends what is still queued, then waits for the thread.
+    fn stop(self) {
+        drop(self.queue);
+        let _ = self.thread.join();
+    }
+}
+
+/// Why a send failed, with the OS error code when there is one.
+fn failure(e: &RsipError, io_error: Option<&io::Error>) -> serde_json::Value {
+    match io_error {
+        Some(io_error) => {
+            json!({ "error": io_error.to_string(), "os_error": io_error.raw_os_error() })
+        }
+        None => json!({ "error": format!("{:?}", e), "os_error": null }),
+    }
+}
+
+impl RsipContext {
+    /// Queues `data` for `ip:port` and returns its id at once. The datagram goes out
+    /// from an ephemeral socket, as with `rsip_send_udp`.
+    pub fn send_async(
+        self: &Arc<Self>,
+        ip: &str,
+        port: u16,
+        data: &[u8],
+    ) -> Result<u64, RsipError> {
+        let id = self.next_send_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let msg = Outgoing {
+            id,
+            ip: ip.to_string(),
+            port,
+            data: data.to_vec(),
+        };
+        let mut outbound = self.outbound.locked();
+        outbound
+            .get_or_insert_with(|| Outbound::spawn(self))
+            .queue
+            .send(msg)
+            .map_err(|_| RsipError::SendFailed)?;
+        Ok(id)
+    }
+
+    fn send_queued(&self, msg: Outgoing) {
+        let dest = host_port(&msg.ip, msg.port);
+        let mut io_error = None;
+        let result = resolve(&msg.ip, msg.port).and_then(|addr| {
+            self.send_captured(&ephemeral_socket(addr, self.dscp())?, &msg.data, addr)
+                .map_err(|e| {
+                    io_error = Some(e);
+                    RsipError::SendFailed
+                })
+        });
+        self.stats.record_send(&result);
+        match result {
+            Ok(_) => {
+                self.dialog_sent(&msg.data);
+                let payload = json!({ "id": msg.id, "dest": dest, "len": msg.data.len() });
+                self.emit("sent_ok", &payload.to_string());
+            }
+            Err(e) => {
+                let mut payload = failure(&e, io_error.as_ref());
+                self.log(
+                    LogLevel::Warn,
+                    format_args!(
+                        "async send {} to {} failed: {}",
+                        msg.id, dest, payload["error"]
+                    ),
+                );
+                payload["id"] = json!(msg.id);
+                payload["dest"] = json!(dest);
+                self.emit("send_failed", &payload.to_string());
+            }
+        }
+    }
+
+    /// Finishes the queued sends and stops the sender thread.
+    pub(crate) fn stop_sender(&self) {
+        let outbound = self.outbound.locked().take();
+        if let Some(outbound) = outbound {
+            outbound.stop();
+        }
+    }
+}
+
+/// Queues `data` for `dest_ip:dest_port` on a background sender thread and returns at
+/// once with a message id, or 0 if an argument is NULL. The outcome is reported as
+/// `sent_ok` or `send_failed`.
+#[no_mangle]
+pub extern "C" fn rsip_send_async(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> u64 {
+    send_args(dest_ip, data)
+        .and_then(|(ip, data)| crate::default_context().send_async(ip, dest_port, data))
+        .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_send_async(
+    ctx: *mut RsipContext,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> u64 {
+    send_args(dest_ip, data)
+        .and_then(|(ip, data)| {
+            with_context(ctx, |ctx| ctx.send_async(ip, dest_port, data))
+                .unwrap_or(Err(RsipError::InvalidArgument))
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::ffi::CStr;
+    use std::net::UdpSocket;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    static EVENTS: Mutex<Vec<(String, serde_json::Value)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(event: *const c_char, payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }
+            .to_string_lossy()
+            .into_owned();
+        let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+        EVENTS
+            .lock()
+            .unwrap()
+            .push((event, serde_json::from_str(payload).unwrap()));
+    }
+
+    #[test]
+    fn confirms_each_send_by_id() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let port = peer.local_addr().unwrap().port();
+
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events.subscribe(
+            Some(vec!["sent_ok".into(), "send_failed".into()]),
+            Sink::Basic(record),
+        );
+        let first = ctx.send_async("127.0.0.1", port, b"ping").unwrap();
+        // Too large for one datagram: the OS refuses it.
+        let second = ctx.send_async("127.0.0.1", port, &[b'x'; 70_000]).unwrap();
+        assert_ne!(first, second);
+        let mut buf = [0u8; 16];
+        assert_eq!(peer.recv(&mut buf).unwrap(), 4);
+        // Shutting down finishes the queue before callbacks are removed.
+        ctx.shutdown();
+
+        let events = EVENTS.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].0, "sent_ok");
+        assert_eq!(events[0].1["id"], first);
+        assert_eq!(events[0].1["dest"], format!("127.0.0.1:{}", port));
+        assert_eq!(events[1].0, "send_failed");
+        assert_eq!(events[1].1["id"], second);
+        assert!(events[1].1["os_error"].is_i64());
+        assert_eq!(ctx.stats.send_ok.load(Ordering::Relaxed), 1);
+        assert_eq!(ctx.stats.send_failed.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,49 @@
+//! Turns parsed rsip messages into the flat JSON summaries delivered to C hosts.
+
+use crate::raw::normalize_headers;
+use rsip::prelude::*;
+use rsip::SipMessage;
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+
+/// Parses a message after expanding compact header names and unfolding continuation
+/// lines (see `normalize_headers`), which rsip would otherwise not recognize.
+pub(crate) fn parse_message(data: &[u8]) -> Result<SipMessage, rsip::Error> {
+    match normalize_headers(data) {
+        Some(normalized) => SipMessage::try_from(&normalized[..]),
+        None => SipMessage::try_from(data),
+    }
+}
+
+/// The fields a host typically needs to route a message without re-parsing it.
+/// Headers that are missing or malformed are reported as `null`.
+pub(crate) fn summarize(msg: &SipMessage) -> Value {
+    let (kind, method, uri, status) = match msg {
+        SipMessage::Request(req) => (
+            "request",
+            Some(req.method().to_string()),
+            Some(req.uri().to_string()),
+            None,
+        ),
+        SipMessage::Response(res) => ("response", None, None, Some(res.status_code().code())),
+    };
+
+    let cseq = msg.cseq_header().ok().map(|cseq| {
+        json!({
+            "seq": cseq.seq().ok(),
+            "method": cseq.method().ok().map(|m| m.to_string()),
+        })
+    });
+
+    json!({
+        "kind": kind,
+        "method": method,
+        "uri": uri,
+        "status": status,
+        "call_id": msg.call_id_header().ok().map(|h| h.value().to_string()),
+        "cseq": cseq,
+        "from_tag": msg.from_header().ok().and_then(|h| h.tag().ok().flatten()).map(|t| t.to_string()),
+        "to_tag": msg.to_header().ok().and_then(|h| h.tag().ok().flatten()).map(|t| t.to_string()),
+        "via_branch": msg.via_header().ok().and_then(|h| h.branch().ok()).map(|b| b.to_string()),
+    })
+}
@@ -0,0 +1,235 @@
+//! An optional LRU cache of parsed messages keyed by their raw bytes. UDP
+//! retransmissions are byte-identical, so a retransmission storm is parsed once per
+//! distinct message instead of once per datagram.
+
+use crate::context::{with_context, RsipContext};
+use crate::lock::Lock;
+use crate::parse::parse_message;
+use rsip::SipMessage;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Identical bytes seen again within this long are reported as a retransmission:
+/// 64*T1, the lifetime of a client transaction (RFC 3261 Timers B and F).
+pub(crate) const RETRANSMISSION_WINDOW: Duration = Duration::from_secs(32);
+
+struct Entry {
+    /// The message itself, as the hash alone could collide.
+    raw: Box<[u8]>,
+    msg: Arc<SipMessage>,
+    last_seen: Instant,
+    /// Position in `ParseCache::order`.
+    stamp: u64,
+}
+
+pub(crate) struct ParseCache {
+    capacity: usize,
+    /// Randomly keyed, so senders cannot aim for collisions.
+    hasher: RandomState,
+    entries: HashMap<u64, Entry>,
+    /// Entry keys by last use, oldest first.
+    order: BTreeMap<u64, u64>,
+    next_stamp: u64,
+}
+
+impl ParseCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            hasher: RandomState::new(),
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_stamp: 0,
+        }
+    }
+
+    fn key(&self, data: &[u8]) -> u64 {
+        self.hasher.hash_one(data)
+    }
+
+    /// The cached parse of `data` and how long ago the same bytes were last seen. A hit
+    /// becomes the most recently used entry.
+    pub fn get(&mut self, data: &[u8], now: Instant) -> Option<(Arc<SipMessage>, Duration)> {
+        let key = self.key(data);
+        let entry = self.entries.get_mut(&key)?;
+        if &*entry.raw != data {
+            return None;
+        }
+        let since = now.saturating_duration_since(entry.last_seen);
+        entry.last_seen = now;
+        self.order.remove(&entry.stamp);
+        entry.stamp = self.next_stamp;
+        self.order.insert(entry.stamp, key);
+        self.next_stamp += 1;
+        Some((entry.msg.clone(), since))
+    }
+
+    /// Caches the parse of `data`, evicting the least recently used entries to stay
+    /// within capacity.
+    pub fn insert(&mut self, data: &[u8], msg: Arc<SipMessage>, now: Instant) {
+        let key = self.key(data);
+        if let Some(old) = self.entries.remove(&key) {
+            self.order.remove(&old.stamp);
+        }
+        while self.entries.len() >= self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => return,
+            };
+        }
+        let entry = Entry {
+            raw: data.into(),
+            msg,
+            last_seen: now,
+            stamp: self.next_stamp,
+        };
+        self.order.insert(entry.stamp, key);
+        self.next_stamp += 1;
+        self.entries.insert(key, entry);
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl RsipContext {
+    /// Keeps the parses of the last `entries` distinct messages; 0 (the default)
+    /// disables the cache. Takes effect immediately and drops anything cached.
+    pub fn set_parse_cache_size(&self, entries: usize) {
+        *self.parse_cache.locked() = if entries == 0 {
+            None
+        } else {
+            Some(ParseCache::new(entries))
+        };
+    }
+
+    /// Parses `data` (see `parse_message`), taking the result from the parse cache when
+    /// it holds the same bytes. The second value is set when those bytes were last seen within
+    /// `RETRANSMISSION_WINDOW`, to how long ago that was. Failed parses are not cached.
+    pub(crate) fn parse_cached(
+        &self,
+        data: &[u8],
+    ) -> (Result<Arc<SipMessage>, rsip::Error>, Option<Duration>) {
+        let now = Instant::now();
+        let cached = match self.parse_cache.locked().as_mut() {
+            Some(cache) => cache.get(data, now),
+            None => return (parse_message(data).map(Arc::new), None),
+        };
+        if let Some((msg, since)) = cached {
+            return (Ok(msg), Some(since).filter(|s| *s < RETRANSMISSION_WINDOW));
+        }
+        let parsed = parse_message(data).map(Arc::new);
+        if let Ok(msg) = &parsed {
+            if let Some(cache) = self.parse_cache.locked().as_mut() {
+                cache.insert(data, msg.clone(), now);
+            }
+        }
+        (parsed, None)
+    }
+}
+
+/// Caches the parses of the last `entries` distinct received messages, so
+/// byte-identical retransmissions are not parsed again; 0 (the default) disables it.
+#[no_mangle]
+pub extern "C" fn rsip_set_parse_cache_size(entries: usize) {
+    crate::default_context().set_parse_cache_size(entries);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_parse_cache_size(ctx: *mut RsipContext, entries: usize) {
+    with_context(ctx, |ctx| ctx.set_parse_cache_size(entries));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::convert::TryFrom;
+    use std::ffi::CStr;
+    use std::net::SocketAddr;
+    use std::os::raw::c_char;
+    use std::sync::Mutex;
+
+    fn options(n: u32) -> Vec<u8> {
+        format!(
+            "OPTIONS sip:a@example.com SIP/2.0\r\nCall-ID: {}@example.com\r\nCSeq: {} OPTIONS\r\n\r\n",
+            n, n
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let mut cache = ParseCache::new(2);
+        let start = Instant::now();
+        for n in 1..=2 {
+            let msg = Arc::new(SipMessage::try_from(&options(n)[..]).unwrap());
+            cache.insert(&options(n), msg, start);
+        }
+        let later = start + Duration::from_secs(1);
+        let (_, since) = cache.get(&options(1), later).unwrap();
+        assert_eq!(since, Duration::from_secs(1));
+        let msg = Arc::new(SipMessage::try_from(&options(3)[..]).unwrap());
+        cache.insert(&options(3), msg, later);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&options(2), later).is_none(), "evicted");
+        assert!(cache.get(&options(1), later).is_some());
+        assert!(cache.get(&options(3), later).is_some());
+        assert!(cache.get(b"OPTIONS", later).is_none());
+    }
+
+    static EVENTS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(event: *const c_char, payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }
+            .to_string_lossy()
+            .into_owned();
+        let payload = unsafe { CStr::from_ptr(payload) }
+            .to_string_lossy()
+            .into_owned();
+        EVENTS.lock().unwrap().push((event, payload));
+    }
+
+    #[test]
+    fn retransmissions_hit_the_cache() {
+        let ctx = RsipContext::new();
+        ctx.events.subscribe(
+            Some(vec!["sip_rx_parsed".into(), "sip_rx_retransmission".into()]),
+            Sink::Basic(record),
+        );
+        let src: SocketAddr = "192.0.2.1:5060".parse().unwrap();
+        ctx.handle_datagram(&options(1), src);
+        ctx.handle_datagram(&options(1), src);
+        assert!(ctx.parse_cache.lock().unwrap().is_none());
+
+        ctx.set_parse_cache_size(8);
+        ctx.handle_datagram(&options(1), src);
+        ctx.handle_datagram(&options(1), src);
+        ctx.handle_datagram(&options(2), src);
+
+        let events = EVENTS.lock().unwrap();
+        let names: Vec<&str> = events.iter().map(|(e, _)| e.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "sip_rx_parsed",
+                "sip_rx_parsed",
+                "sip_rx_parsed",
+                "sip_rx_retransmission",
+                "sip_rx_parsed",
+                "sip_rx_parsed",
+            ]
+        );
+        let payload: serde_json::Value = serde_json::from_str(&events[3].1).unwrap();
+        assert_eq!(payload["call_id"], "1@example.com");
+        assert_eq!(payload["cseq"]["seq"], 1);
+        assert_eq!(payload["src"], "192.0.2.1:5060");
+        assert!(payload["since_ms"].as_u64().unwrap() < 1000);
+        assert_eq!(events[4].1, events[2].1, "the cached parse is the same");
+    }
+}
@@ -0,0 +1,370 @@
+//! OPTIONS keep-alive: pings an upstream from the listener socket at a fixed interval
+//! and reports whether it still answers.
+//!
+//! Like registrations, each pinger runs on its own thread and gets its responses back
+//! through the context's response waiters (keyed by transaction). Any final response
+//! counts as alive, including 404 or 405: the peer only has to be there to send it.
+
+use crate::builder::{build_request, RequestParts};
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::ffi::str_arg;
+use crate::lock::Lock;
+use crate::random;
+use crate::register::{advertised_addr, stopped_within, transact, Failure, Wakeup};
+use crate::send::resolve;
+use crate::transaction::TIMER_F;
+use rsip::{Request, Response};
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::os::raw::c_char;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub(crate) struct Ping {
+    wake: Sender<Wakeup>,
+    thread: JoinHandle<()>,
+}
+
+impl Ping {
+    /// Abandons any outstanding OPTIONS and waits for the thread.
+    pub(crate) fn stop(self) {
+        let _ = self.wake.send(Wakeup::Stop);
+        let _ = self.thread.join();
+    }
+}
+
+struct Pinger {
+    ctx: Arc<RsipContext>,
+    id: u64,
+    dest: SocketAddr,
+    request_uri: String,
+    from: String,
+    call_id: String,
+    via_host: String,
+    via_port: u16,
+    cseq: u32,
+    interval: Duration,
+    wake: Sender<Wakeup>,
+    inbox: Receiver<Wakeup>,
+}
+
+impl Pinger {
+    fn request(&mut self) -> Result<Request, Failure> {
+        self.cseq += 1;
+        let defaults = self.ctx.message_defaults();
+        build_request(&RequestParts {
+            method: "OPTIONS",
+            request_uri: &self.request_uri,
+            from: &self.from,
+            to: &self.request_uri,
+            call_id: &self.call_id,
+            cseq: self.cseq,
+            via_host: &self.via_host,
+            via_port: self.via_port,
+            defaults: &defaults,
+        })
+        .map_err(|e| Failure::failed(e.to_string()))
+    }
+
+    /// A ping must be answered before the next one is due, and within Timer F.
+    fn ping(&mut self) -> Result<Response, Failure> {
+        let request = self.request()?;
+        let timeout = self.interval.min(TIMER_F);
+        transact(
+            &self.ctx,
+            self.dest,
+            &request,
+            &self.wake,
+            &self.inbox,
+            timeout,
+        )
+    }
+
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        self.ctx.emit(event, &payload.to_string());
+    }
+
+    fn run(mut self) {
+        let dest = self.dest.to_string();
+        loop {
+            let started = Instant::now();
+            match self.ping() {
+                Ok(response) => self.emit(
+                    "ping_ok",
+                    json!({
+                        "id": self.id,
+                        "dest": dest,
+                        "status": response.status_code().code(),
+                        "rtt_ms": started.elapsed().as_millis() as u64,
+                    }),
+                ),
+                Err(Failure::Stopped) => break,
+                Err(Failure::Failed { reason, .. }) => self.emit(
+                    "ping_timeout",
+                    json!({ "id": self.id, "dest": dest, "reason": reason }),
+                ),
+            }
+            let wait = self.interval.saturating_sub(started.elapsed());
+            if stopped_within(&self.inbox, wait) {
+                break;
+            }
+        }
+    }
+}
+
+impl RsipContext {
+    /// Starts sending OPTIONS to `dest_ip:port` from the running listener every
+    /// `interval_secs`, reporting each round as `ping_ok` or `ping_timeout`.
+    pub fn start_options_ping(
+        self: &Arc<Self>,
+        dest_ip: &str,
+        port: u16,
+        interval_secs: u32,
+    ) -> Result<u64, RsipError> {
+        if interval_secs == 0 {
+            return Err(RsipError::InvalidArgument);
+        }
+        let listener = self.local_addr().ok_or(RsipError::NotRunning)?;
+        let dest = resolve(dest_ip, port)?;
+        let local = advertised_addr(listener, dest);
+        let host = |ip: IpAddr| match ip {
+            IpAddr::V6(ip) => format!("[{}]", ip),
+            ip => ip.to_string(),
+        };
+
+        let (via_host, via_port) = self.via_sentby(local);
+
+        let id = self.next_ping_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let (wake, inbox) = mpsc::channel();
+        let pinger = Pinger {
+            ctx: self.clone(),
+            id,
+            dest,
+            request_uri: format!("sip:{}:{}", host(dest.ip()), dest.port()),
+            from: format!(
+                "<sip:ping@{}>;tag={}",
+                host(local.ip()),
+                random::generate_tag()
+            ),
+            call_id: random::generate_call_id(&local.ip().to_string()),
+            via_host,
+            via_port,
+            cseq: 0,
+            interval: Duration::from_secs(u64::from(interval_secs)),
+            wake: wake.clone(),
+            inbox,
+        };
+        let thread = thread::spawn(move || pinger.run());
+        self.pings.locked().insert(id, Ping { wake, thread });
+        Ok(id)
+    }
+
+    /// Stops pinging. False for an unknown id.
+    pub fn stop_options_ping(&self, id: u64) -> bool {
+        let ping = self.pings.locked().remove(&id);
+        match ping {
+            Some(ping) => {
+                ping.stop();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Sends OPTIONS to `dest_ip:dest_port` from the running listener every `interval_secs`
+/// and reports each round as `ping_ok` or `ping_timeout`. Returns a ping id, or 0 if the
+/// arguments are invalid or no listener runs.
+#[no_mangle]
+pub extern "C" fn rsip_start_options_ping(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    interval_secs: u32,
+) -> u64 {
+    str_arg(dest_ip)
+        .ok_or(RsipError::InvalidArgument)
+        .and_then(|ip| crate::default_context().start_options_ping(ip, dest_port, interval_secs))
+        .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_stop_options_ping(id: u64) -> bool {
+    crate::default_context().stop_options_ping(id)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_options_ping(
+    ctx: *mut RsipContext,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    interval_secs: u32,
+) -> u64 {
+    str_arg(dest_ip)
+        .ok_or(RsipError::InvalidArgument)
+        .and_then(|ip| {
+            with_context(ctx, |ctx| {
+                ctx.start_options_ping(ip, dest_port, interval_secs)
+            })
+            .unwrap_or(Err(RsipError::InvalidArgument))
+        })
+        .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_stop_options_ping(ctx: *mut RsipContext, id: u64) -> bool {
+    with_context(ctx, |ctx| ctx.stop_options_ping(id)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use rsip::prelude::*;
+    use rsip::SipMessage;
+    use std::convert::TryFrom;
+    use std::ffi::CStr;
+    use std::net::UdpSocket;
+    use std::sync::Mutex;
+
+    static EVENTS: Mutex<Vec<(String, serde_json::Value)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(event: *const c_char, payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+        EVENTS
+            .lock()
+            .unwrap()
+            .push((event, serde_json::from_str(payload).unwrap()));
+    }
+
+    fn wait_for(event: &str) -> serde_json::Value {
+        for _ in 0..300 {
+            if let Some((_, payload)) = EVENTS.lock().unwrap().iter().find(|(e, _)| e == event) {
+                return payload.clone();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no {} event", event);
+    }
+
+    #[test]
+    fn reports_answers_and_silence() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events.subscribe(
+            Some(vec!["ping_ok".into(), "ping_timeout".into()]),
+            Sink::Basic(record),
+        );
+        assert!(matches!(
+            ctx.start_options_ping("127.0.0.1", upstream_port, 1),
+            Err(RsipError::NotRunning)
+        ));
+        ctx.start_udp_listener_on("127.0.0.1", 0).unwrap();
+        assert!(matches!(
+            ctx.start_options_ping("127.0.0.1", upstream_port, 0),
+            Err(RsipError::InvalidArgument)
+        ));
+        let id = ctx
+            .start_options_ping("127.0.0.1", upstream_port, 1)
+            .unwrap();
+
+        let mut buf = [0u8; 4096];
+        let (n, src) = upstream.recv_from(&mut buf).unwrap();
+        let options = match SipMessage::try_from(&buf[..n]).unwrap() {
+            SipMessage::Request(req) => req,
+            _ => panic!("expected a request"),
+        };
+        assert_eq!(options.method().to_string(), "OPTIONS");
+        assert_eq!(
+            options.uri().to_string(),
+            format!("sip:127.0.0.1:{}", upstream_port)
+        );
+        let copy = |name: &str| {
+            options
+                .headers()
+                .iter()
+                .map(|h| h.to_string())
+                .find(|h| h.starts_with(name))
+                .unwrap()
+        };
+        let reply = format!(
+            "SIP/2.0 405 Method Not Allowed\r\n{}\r\n{}\r\n{};tag=up\r\n{}\r\n{}\r\nContent-Length: 0\r\n\r\n",
+            copy("Via:"),
+            copy("From:"),
+            copy("To:"),
+            copy("Call-ID:"),
+            copy("CSeq:"),
+        );
+        upstream.send_to(reply.as_bytes(), src).unwrap();
+        let ok = wait_for("ping_ok");
+        assert_eq!(ok["id"], id);
+        assert_eq!(ok["status"], 405);
+        assert_eq!(ok["dest"], format!("127.0.0.1:{}", upstream_port));
+
+        // Leave the next round unanswered.
+        let timeout = wait_for("ping_timeout");
+        assert_eq!(timeout["id"], id);
+        assert_eq!(timeout["reason"], "timeout");
+
+        assert!(ctx.stop_options_ping(id));
+        assert!(!ctx.stop_options_ping(id), "already stopped");
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn via_advertises_the_configured_sentby() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        let ctx = Arc::new(RsipContext::new());
+        assert!(matches!(
+            ctx.set_via_sentby(Some(("bad host", 5060))),
+            Err(RsipError::InvalidArgument)
+        ));
+        ctx.set_via_sentby(Some(("edge.example.com", 0))).unwrap();
+        ctx.start_udp_listener_on("127.0.0.1", 0).unwrap();
+        let listener = ctx.local_addr().unwrap();
+
+        let via = || {
+            let id = ctx
+                .start_options_ping("127.0.0.1", upstream_port, 60)
+                .unwrap();
+            let mut buf = [0u8; 4096];
+            let n = upstream.recv(&mut buf).unwrap();
+            ctx.stop_options_ping(id);
+            let via = SipMessage::try_from(&buf[..n])
+                .unwrap()
+                .via_header()
+                .unwrap()
+                .typed()
+                .unwrap();
+            (
+                via.uri.host().to_string(),
+                via.uri.port().map(|p| *p.value()),
+            )
+        };
+        assert_eq!(
+            via(),
+            ("edge.example.com".to_string(), Some(listener.port()))
+        );
+        ctx.set_via_sentby(Some(("203.0.113.7", 5080))).unwrap();
+        assert_eq!(via(), ("203.0.113.7".to_string(), Some(5080)));
+        ctx.set_via_sentby(None).unwrap();
+        assert_eq!(via(), ("127.0.0.1".to_string(), Some(listener.port())));
+        ctx.shutdown();
+    }
+}
@@ -0,0 +1,331 @@
+//! Pulling events instead of receiving callbacks: in poll mode the event bus copies
+//! each event into a bounded queue that `rsip_poll_event` drains, for hosts (scripting
+//! FFIs, mostly) where C callbacks are awkward.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::events::{event_kind, source_octets, RsipEvent};
+use crate::lock::{wait, wait_timeout, Lock};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Queue capacity used when `set_event_mode` is given 0.
+pub const DEFAULT_EVENT_QUEUE_SIZE: usize = 1024;
+
+/// How events are delivered. Part of the C ABI (`RSIP_EVENT_MODE_*`).
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventMode {
+    /// To the registered callbacks only (the default).
+    Callbacks = 0,
+    /// Into the queue only; callbacks are not invoked.
+    Poll = 1,
+    /// To the callbacks and into the queue.
+    Both = 2,
+}
+
+impl EventMode {
+    pub fn from_u32(mode: u32) -> Option<Self> {
+        match mode {
+            0 => Some(Self::Callbacks),
+            1 => Some(Self::Poll),
+            2 => Some(Self::Both),
+            _ => None,
+        }
+    }
+}
+
+/// An event copied out of `emit`, as `RsipEvent` describes it.
+pub(crate) struct Queued {
+    kind: u32,
+    data: Box<[u8]>,
+    src: Option<SocketAddr>,
+    seq: u64,
+    /// When the event was queued, which batching times partial batches from.
+    pub enqueued: Instant,
+}
+
+impl Queued {
+    pub fn new(event: &str, data: &[u8], src: Option<SocketAddr>, seq: u64) -> Self {
+        Self {
+            kind: event_kind(event),
+            data: data.into(),
+            src,
+            seq,
+            enqueued: Instant::now(),
+        }
+    }
+
+    /// The event as handed to the host; its data borrows from `self`.
+    pub fn to_event(&self) -> RsipEvent {
+        RsipEvent {
+            kind: self.kind,
+            data: self.data.as_ptr(),
+            len: self.data.len(),
+            src_ip: source_octets(self.src),
+            src_port: self.src.map_or(0, |s| s.port()),
+            seq: self.seq,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    events: VecDeque<Queued>,
+    capacity: usize,
+    /// The event last handed out, kept so its data stays valid until the next poll.
+    polled: Option<Queued>,
+}
+
+#[derive(Default)]
+pub(crate) struct EventQueue {
+    /// An `EventMode`, read on every emit without taking the lock.
+    mode: AtomicU32,
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+impl EventQueue {
+    pub fn mode(&self) -> EventMode {
+        EventMode::from_u32(self.mode.load(Ordering::SeqCst)).unwrap_or(EventMode::Callbacks)
+    }
+
+    /// Switches to `mode` with room for `capacity` events. Leaving the queued modes
+    /// discards what is queued and wakes blocked pollers; a smaller capacity only
+    /// affects later events.
+    pub fn set_mode(&self, mode: EventMode, capacity: usize) {
+        let mut state = self.state.locked();
+        self.mode.store(mode as u32, Ordering::SeqCst);
+        state.capacity = if capacity == 0 {
+            DEFAULT_EVENT_QUEUE_SIZE
+        } else {
+            capacity
+        };
+        if mode == EventMode::Callbacks {
+            state.events.clear();
+            state.polled = None;
+            drop(state);
+            self.ready.notify_all();
+        }
+    }
+
+    /// Queues a copy of the event. When the queue is full the event is discarded, so a
+    /// host that stops polling costs bounded memory.
+    pub fn push(&self, event: &str, data: &[u8], src: Option<SocketAddr>, seq: u64) {
+        let mut state = self.state.locked();
+        if self.mode() == EventMode::Callbacks || state.events.len() >= state.capacity {
+            return;
+        }
+        state.events.push_back(Queued::new(event, data, src, seq));
+        drop(state);
+        self.ready.notify_one();
+    }
+
+    /// Waits up to `timeout` (`None`: indefinitely) for an event and writes it to
+    /// `out`. Returns false on timeout; `InvalidArgument` if events are not queued.
+    pub fn poll(&self, timeout: Option<Duration>, out: &mut RsipEvent) -> Result<bool, RsipError> {
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+        let mut state = self.state.locked();
+        loop {
+            if self.mode() == EventMode::Callbacks {
+                return Err(RsipError::InvalidArgument);
+            }
+            if let Some(event) = state.events.pop_front() {
+                *out = event.to_event();
+                state.polled = Some(event);
+                return Ok(true);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    wait_timeout(&self.ready, state, deadline - now)
+                }
+                // A timeout too long to represent waits like a negative one.
+                None => wait(&self.ready, state),
+            };
+        }
+    }
+}
+
+impl RsipContext {
+    /// Selects how events are delivered; see `EventMode`. `queue_size` bounds the poll
+    /// queue, 0 meaning `DEFAULT_EVENT_QUEUE_SIZE`.
+    pub fn set_event_mode(&self, mode: EventMode, queue_size: usize) {
+        self.events.queue.set_mode(mode, queue_size);
+    }
+
+    /// `rsip_poll_event` for this context: 1 with an event in `out`, 0 on timeout.
+    pub fn poll_event(&self, timeout_ms: i32, out: &mut RsipEvent) -> Result<bool, RsipError> {
+        let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+        self.events.queue.poll(timeout, out)
+    }
+}
+
+fn poll_result(result: Result<bool, RsipError>) -> i32 {
+    match result {
+        Ok(got) => got as i32,
+        Err(e) => e.code(),
+    }
+}
+
+/// Selects how events are delivered: RSIP_EVENT_MODE_CALLBACK (the default),
+/// RSIP_EVENT_MODE_POLL (queued for `rsip_poll_event`, no callbacks) or
+/// RSIP_EVENT_MODE_BOTH. `queue_size` bounds the queue (0 for the default); events
+/// arriving while it is full are dropped. Returns false for an unknown mode.
+#[no_mangle]
+pub extern "C" fn rsip_set_event_mode(mode: u32, queue_size: usize) -> bool {
+    match EventMode::from_u32(mode) {
+        Some(mode) => {
+            crate::default_context().set_event_mode(mode, queue_size);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Waits up to `timeout_ms` (negative: indefinitely, 0: not at all) for the next
+/// queued event and writes it to `out_event`. Returns 1 with an event, 0 on timeout,
+/// or a negative error code: RSIP_ERR_INVALID_ARGUMENT if `out_event` is NULL or the event
+/// mode does not queue events (also when it is switched back while waiting). The
+/// event's data stays valid until the next `rsip_poll_event` call on the context.
+#[no_mangle]
+pub extern "C" fn rsip_poll_event(timeout_ms: i32, out_event: *mut RsipEvent) -> i32 {
+    match unsafe { out_event.as_mut() } {
+        Some(out) => poll_result(crate::default_context().poll_event(timeout_ms, out)),
+        None => RsipError::InvalidArgument.code(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_event_mode(
+    ctx: *mut RsipContext,
+    mode: u32,
+    queue_size: usize,
+) -> bool {
+    match EventMode::from_u32(mode) {
+        Some(mode) => with_context(ctx, |ctx| ctx.set_event_mode(mode, queue_size)).is_some(),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_poll_event(
+    ctx: *mut RsipContext,
+    timeout_ms: i32,
+    out_event: *mut RsipEvent,
+) -> i32 {
+    match unsafe { out_event.as_mut() } {
+        Some(out) => with_context(ctx, |ctx| poll_result(ctx.poll_event(timeout_ms, out)))
+            .unwrap_or_else(|| RsipError::InvalidArgument.code()),
+        None => RsipError::InvalidArgument.code(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::os::raw::c_char;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+    use std::thread;
+
+    static CALLED: AtomicU64 = AtomicU64::new(0);
+
+    extern "C" fn count(_: *const c_char, _: *const c_char) {
+        CALLED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn empty() -> RsipEvent {
+        RsipEvent {
+            kind: 0,
+            data: std::ptr::null(),
+            len: 0,
+            src_ip: [0; 16],
+            src_port: 0,
+            seq: 0,
+        }
+    }
+
+    #[test]
+    fn queues_events_in_poll_mode() {
+        let ctx = RsipContext::new();
+        ctx.events.subscribe(None, Sink::Basic(count));
+        let mut event = empty();
+        assert_eq!(
+            ctx.poll_event(0, &mut event),
+            Err(RsipError::InvalidArgument)
+        );
+
+        ctx.set_event_mode(EventMode::Poll, 2);
+        ctx.emit("error", "first");
+        ctx.emit_from("sip_rx", "second", "192.0.2.1:5060".parse().unwrap());
+        ctx.emit("tick", "dropped, the queue is full");
+        assert_eq!(
+            CALLED.load(Ordering::SeqCst),
+            0,
+            "no callbacks in poll mode"
+        );
+
+        assert_eq!(ctx.poll_event(0, &mut event), Ok(true));
+        assert_eq!(event.kind, event_kind("error"));
+        assert_eq!(event.seq, 1);
+        assert_eq!(
+            unsafe { std::slice::from_raw_parts(event.data, event.len) },
+            b"first"
+        );
+        assert_eq!(ctx.poll_event(-1, &mut event), Ok(true));
+        assert_eq!(event.kind, event_kind("sip_rx"));
+        assert_eq!(event.src_port, 5060);
+        assert_eq!(&event.src_ip[12..], [192, 0, 2, 1]);
+        assert_eq!(event.seq, 2);
+        assert_eq!(ctx.poll_event(10, &mut event), Ok(false));
+
+        ctx.set_event_mode(EventMode::Both, 0);
+        ctx.emit("tick", "");
+        assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+        assert_eq!(ctx.poll_event(0, &mut event), Ok(true));
+        assert_eq!(event.kind, event_kind("tick"));
+        // the tick dropped by the full queue shows as a gap
+        assert_eq!(event.seq, 4);
+        assert_eq!(ctx.events.last_seq(), 4);
+    }
+
+    #[test]
+    fn poll_blocks_until_an_event_or_mode_change() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.set_event_mode(EventMode::Poll, 0);
+        let emitter = ctx.clone();
+        let emit = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            emitter.emit("error", "late");
+        });
+        let mut event = empty();
+        assert_eq!(ctx.poll_event(5000, &mut event), Ok(true));
+        assert_eq!(event.kind, event_kind("error"));
+        emit.join().unwrap();
+
+        let switcher = ctx.clone();
+        let switch = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            switcher.shutdown();
+        });
+        assert_eq!(
+            ctx.poll_event(-1, &mut event),
+            Err(RsipError::InvalidArgument)
+        );
+        switch.join().unwrap();
+        assert_eq!(
+            rsip_poll_event(0, std::ptr::null_mut()),
+            RsipError::InvalidArgument.code()
+        );
+        assert!(!rsip_set_event_mode(3, 0));
+    }
+}
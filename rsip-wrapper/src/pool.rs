@@ -0,0 +1,363 @@
+//! Outbound SIP over TCP: one connection per destination, opened on first use and
+//! reused for every later request so responses and in-dialog requests share it. A
+//! thread per connection reads what the peer sends back into the receive pipeline;
+//! connections idle for longer than the configured timeout are closed.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::{to_code, RsipError};
+use crate::lock::Lock;
+use crate::log::LogLevel;
+use crate::send::{resolve, send_args};
+use crate::stream::{is_timeout, POLL_INTERVAL};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::raw::c_char;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long opening a connection may take before the send fails.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An open connection in the pool. The write half is shared between senders and the
+/// reader, which answers keep-alive pings on it.
+#[derive(Clone)]
+pub(crate) struct PooledConnection {
+    writer: Arc<Mutex<TcpStream>>,
+    last_used: Arc<Mutex<Instant>>,
+}
+
+impl PooledConnection {
+    fn write(&self, payload: &[u8]) -> io::Result<()> {
+        *self.last_used.locked() = Instant::now();
+        let mut writer = self.writer.locked();
+        writer.write_all(payload).and_then(|_| writer.flush())
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.writer, &other.writer)
+    }
+
+    fn close(&self) {
+        let _ = self.writer.locked().shutdown(Shutdown::Both);
+    }
+}
+
+/// The reader's view of a pooled connection: reads end (as at EOF) once it has been
+/// idle for the context's idle timeout, writes go through the shared write half.
+struct PooledStream<'a> {
+    ctx: &'a RsipContext,
+    reader: TcpStream,
+    conn: PooledConnection,
+}
+
+impl Read for PooledStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.reader.read(buf) {
+            Ok(n) => {
+                *self.conn.last_used.locked() = Instant::now();
+                Ok(n)
+            }
+            Err(e) if is_timeout(&e) => {
+                let idle = self.conn.last_used.locked().elapsed();
+                match self.ctx.config.locked().tcp_idle_timeout {
+                    Some(timeout) if idle >= timeout => Ok(0),
+                    _ => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Write for PooledStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.writer.locked().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.writer.locked().flush()
+    }
+}
+
+impl RsipContext {
+    /// Closes pooled TCP connections after `ms` milliseconds without traffic in either
+    /// direction, including connections already open; 0 keeps them open until the peer
+    /// closes them or the context shuts down.
+    pub fn set_tcp_idle_timeout(&self, ms: u32) {
+        self.config.locked().tcp_idle_timeout =
+            Some(Duration::from_millis(u64::from(ms))).filter(|d| !d.is_zero());
+    }
+
+    /// Sends `payload` to `ip:port` over the pooled TCP connection to that address,
+    /// connecting first if there is none. If writing to a reused connection fails (the
+    /// peer closed it meanwhile), it is dropped and the send retried once on a new one.
+    pub fn send_tcp(
+        self: &Arc<Self>,
+        ip: &str,
+        port: u16,
+        payload: &[u8],
+    ) -> Result<(), RsipError> {
+        let dest = resolve(ip, port)?;
+        let dest = SocketAddr::new(dest.ip().to_canonical(), dest.port());
+        let pooled = self.tcp_pool.locked().get(&dest).cloned();
+        let result = match pooled {
+            Some(conn) => match conn.write(payload) {
+                Ok(()) => Ok(()),
+                Err(e) => {
+                    self.log(
+                        LogLevel::Debug,
+                        format_args!("tcp connection to {} failed ({}), reconnecting", dest, e),
+                    );
+                    self.evict_tcp(dest, &conn);
+                    self.connect_tcp(dest).and_then(|conn| conn.write(payload))
+                }
+            },
+            None => self.connect_tcp(dest).and_then(|conn| conn.write(payload)),
+        };
+        self.stats.record_send(&result);
+        result.map_err(|_| RsipError::SendFailed)?;
+        self.dialog_sent(payload);
+        Ok(())
+    }
+
+    /// Opens a connection to `dest`, adds it to the pool and starts its reader.
+    fn connect_tcp(self: &Arc<Self>, dest: SocketAddr) -> io::Result<PooledConnection> {
+        let stream = TcpStream::connect_timeout(&dest, CONNECT_TIMEOUT).map_err(|e| {
+            self.log(
+                LogLevel::Warn,
+                format_args!("tcp connect to {} failed: {}", dest, e),
+            );
+            self.emit_from("error", &format!("connect_err:{}", e), dest);
+            e
+        })?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        reader.set_read_timeout(Some(POLL_INTERVAL))?;
+        let conn = PooledConnection {
+            writer: Arc::new(Mutex::new(stream)),
+            last_used: Arc::new(Mutex::new(Instant::now())),
+        };
+        let replaced = self.tcp_pool.locked().insert(dest, conn.clone());
+        if let Some(replaced) = replaced {
+            replaced.close();
+        }
+        self.log(
+            LogLevel::Debug,
+            format_args!("tcp connection to {} opened", dest),
+        );
+
+        let ctx = self.clone();
+        let own = conn.clone();
+        thread::spawn(move || {
+            let mut stream = PooledStream {
+                ctx: &ctx,
+                reader,
+                conn: own.clone(),
+            };
+            ctx.serve_stream(&mut stream, dest, &AtomicBool::new(true));
+            ctx.evict_tcp(dest, &own);
+            ctx.log(
+                LogLevel::Debug,
+                format_args!("tcp connection to {} closed", dest),
+            );
+        });
+        Ok(conn)
+    }
+
+    /// Closes `conn` and removes it from the pool, unless it was replaced already.
+    fn evict_tcp(&self, dest: SocketAddr, conn: &PooledConnection) {
+        let mut pool = self.tcp_pool.locked();
+        if pool.get(&dest).is_some_and(|pooled| pooled.same(conn)) {
+            pool.remove(&dest);
+        }
+        conn.close();
+    }
+
+    /// Closes every pooled connection; their readers exit on their own.
+    pub(crate) fn close_tcp_pool(&self) {
+        let pool: HashMap<SocketAddr, PooledConnection> =
+            std::mem::take(&mut *self.tcp_pool.locked());
+        for conn in pool.values() {
+            conn.close();
+        }
+    }
+
+    /// The destinations with an open pooled TCP connection.
+    pub fn tcp_pool_destinations(&self) -> Vec<SocketAddr> {
+        self.tcp_pool.locked().keys().copied().collect()
+    }
+}
+
+/// Sends `data` to `dest_ip:dest_port` over TCP, reusing the open connection to that
+/// address or opening one. Whatever the peer sends back on it is reported like any
+/// received message.
+#[no_mangle]
+pub extern "C" fn rsip_send_tcp(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> bool {
+    rsip_send_tcp_ex(dest_ip, dest_port, data) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_send_tcp_ex(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> i32 {
+    let result = send_args(dest_ip, data)
+        .and_then(|(ip, payload)| crate::default_context().send_tcp(ip, dest_port, payload));
+    to_code(result)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_send_tcp(
+    ctx: *mut RsipContext,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> bool {
+    rsip_context_send_tcp_ex(ctx, dest_ip, dest_port, data) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_send_tcp_ex(
+    ctx: *mut RsipContext,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> i32 {
+    let result = send_args(dest_ip, data).and_then(|(ip, payload)| {
+        with_context(ctx, |ctx| ctx.send_tcp(ip, dest_port, payload))
+            .unwrap_or(Err(RsipError::InvalidArgument))
+    });
+    to_code(result)
+}
+
+/// Closes pooled TCP connections idle for `ms` milliseconds; 0 never closes them.
+#[no_mangle]
+pub extern "C" fn rsip_set_tcp_idle_timeout_ms(ms: u32) {
+    crate::default_context().set_tcp_idle_timeout(ms);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_tcp_idle_timeout_ms(ctx: *mut RsipContext, ms: u32) {
+    with_context(ctx, |ctx| ctx.set_tcp_idle_timeout(ms));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::ffi::CStr;
+    use std::net::TcpListener;
+
+    static RECEIVED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(_: *const c_char, data: *const c_char) {
+        let data = unsafe { CStr::from_ptr(data) }.to_str().unwrap();
+        let data: serde_json::Value = serde_json::from_str(data).unwrap();
+        RECEIVED
+            .lock()
+            .unwrap()
+            .push(data["call_id"].as_str().unwrap_or_default().to_string());
+    }
+
+    fn options(call_id: &str) -> Vec<u8> {
+        format!(
+            "OPTIONS sip:b@example.com SIP/2.0\r\n\
+             Via: SIP/2.0/TCP 127.0.0.1;branch=z9hG4bK{0}\r\n\
+             Call-ID: {0}\r\n\
+             CSeq: 1 OPTIONS\r\n\
+             Content-Length: 0\r\n\r\n",
+            call_id
+        )
+        .into_bytes()
+    }
+
+    fn read_message(peer: &mut TcpStream) -> Vec<u8> {
+        let mut buf = vec![0u8; 4096];
+        let n = peer.read(&mut buf).unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    #[test]
+    fn reuses_connections_and_reconnects() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events
+            .subscribe(Some(vec!["sip_rx_parsed".into()]), Sink::Basic(record));
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        ctx.send_tcp("127.0.0.1", port, &options("a")).unwrap();
+        let (mut peer, _) = server.accept().unwrap();
+        assert_eq!(read_message(&mut peer), options("a"));
+        ctx.send_tcp("127.0.0.1", port, &options("b")).unwrap();
+        assert_eq!(read_message(&mut peer), options("b"));
+        assert_eq!(ctx.tcp_pool_destinations().len(), 1);
+
+        // what the peer sends back on the connection is received
+        let response = String::from_utf8(options("reply"))
+            .unwrap()
+            .replace("OPTIONS sip:b@example.com SIP/2.0", "SIP/2.0 200 OK");
+        peer.write_all(response.as_bytes()).unwrap();
+        for _ in 0..200 {
+            if !RECEIVED.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(*RECEIVED.lock().unwrap(), ["reply"]);
+
+        // the peer goes away: the next send opens a new connection
+        drop(peer);
+        for _ in 0..200 {
+            if ctx.tcp_pool_destinations().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(ctx.tcp_pool_destinations().is_empty());
+        ctx.send_tcp("127.0.0.1", port, &options("c")).unwrap();
+        let (mut peer, _) = server.accept().unwrap();
+        assert_eq!(read_message(&mut peer), options("c"));
+
+        ctx.shutdown();
+        assert!(ctx.tcp_pool_destinations().is_empty());
+        assert_eq!(read_message(&mut peer), b"");
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.set_tcp_idle_timeout(100);
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        ctx.send_tcp("127.0.0.1", port, &options("idle")).unwrap();
+        let (mut peer, _) = server.accept().unwrap();
+        assert_eq!(read_message(&mut peer), options("idle"));
+        // closed by the pool after the idle timeout
+        assert_eq!(read_message(&mut peer), b"");
+        assert!(ctx.tcp_pool_destinations().is_empty());
+    }
+
+    #[test]
+    fn connection_refused_fails_the_send() {
+        let ctx = Arc::new(RsipContext::new());
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        assert_eq!(
+            ctx.send_tcp("127.0.0.1", port, b"OPTIONS"),
+            Err(RsipError::SendFailed)
+        );
+        assert!(ctx.tcp_pool_destinations().is_empty());
+    }
+}
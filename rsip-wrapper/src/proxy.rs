@@ -0,0 +1,470 @@
+//! Helpers for forwarding requests as a proxy (RFC 3261 section 16): Max-Forwards
+//! handling, loop detection through the Via branches already in a request, and editing
+//! the Route and Record-Route sets for loose routing.
+
+use crate::ffi::{into_c_string, str_arg};
+use rsip::common::uri::UriWithParams;
+use rsip::headers::{Header, ToTypedHeader, UntypedHeader};
+use rsip::prelude::*;
+use rsip::{Param, Request, Scheme, SipMessage, Uri};
+use std::convert::TryFrom;
+use std::os::raw::c_char;
+
+/// What to do with a request about to be forwarded.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Hops {
+    /// Forward it; Max-Forwards has been decremented (or set to 70 if it was missing).
+    Forward,
+    /// Max-Forwards is 0: answer 483 Too Many Hops instead.
+    Exhausted,
+}
+
+/// Decrements Max-Forwards in place (section 16.6 step 3). A request without one gets
+/// the default of 70; an unparsable value is an error.
+pub(crate) fn decrement_max_forwards(request: &mut Request) -> Result<Hops, rsip::Error> {
+    let remaining = match request.max_forwards_header() {
+        Ok(max_forwards) => max_forwards.num()?,
+        Err(_) => {
+            request
+                .headers
+                .push(rsip::headers::MaxForwards::default().into());
+            return Ok(Hops::Forward);
+        }
+    };
+    if remaining == 0 {
+        return Ok(Hops::Exhausted);
+    }
+    for header in request.headers.iter_mut() {
+        if let Header::MaxForwards(max_forwards) = header {
+            *max_forwards = (remaining - 1).into();
+        }
+    }
+    Ok(Hops::Forward)
+}
+
+/// The branch of every Via in `msg`, top first, including Vias combined into one
+/// header line with commas.
+pub(crate) fn via_branches(msg: &SipMessage) -> Vec<String> {
+    msg.headers()
+        .iter()
+        .filter_map(|h| match h {
+            Header::Via(via) => Some(via.value().to_string()),
+            _ => None,
+        })
+        .flat_map(|value| {
+            value
+                .split(',')
+                .filter_map(|via| {
+                    let via = rsip::headers::Via::new(via.trim()).typed().ok()?;
+                    Some(via.branch().ok()?.to_string())
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RouteSet {
+    Route,
+    RecordRoute,
+}
+
+/// A SIP or SIPS route URI given bare (`sip:p.example.com;lr`) or in angle brackets.
+fn route_uri(uri: &str) -> Option<UriWithParams> {
+    let uri = uri.trim();
+    let uri = uri
+        .strip_prefix('<')
+        .and_then(|u| u.strip_suffix('>'))
+        .unwrap_or(uri);
+    if uri.contains(char::is_whitespace) {
+        return None;
+    }
+    let uri = Uri::try_from(uri).ok()?;
+    match uri.scheme {
+        Some(Scheme::Sip) | Some(Scheme::Sips) => Some(UriWithParams {
+            uri,
+            params: vec![],
+        }),
+        _ => None,
+    }
+}
+
+/// Lets `edit` change the Request-URI and the values of the `set` headers, in order,
+/// then writes them back one value per header. They stay where the first of them was,
+/// or go right below the Vias if there were none. Other headers keep their order.
+fn edit_route_set(
+    request: &mut Request,
+    set: RouteSet,
+    edit: impl FnOnce(&mut Uri, &mut Vec<UriWithParams>),
+) -> Result<(), rsip::Error> {
+    let headers: Vec<Header> = request.headers.clone().into();
+    let mut uris = Vec::new();
+    let mut kept = Vec::with_capacity(headers.len());
+    let mut at = None;
+    for header in headers {
+        let values = match (&header, set) {
+            (Header::Route(route), RouteSet::Route) => route.typed()?.uris().to_vec(),
+            (Header::RecordRoute(record_route), RouteSet::RecordRoute) => {
+                record_route.typed()?.uris().to_vec()
+            }
+            _ => {
+                kept.push(header);
+                continue;
+            }
+        };
+        at.get_or_insert(kept.len());
+        uris.extend(values);
+    }
+    let at = at.unwrap_or_else(|| {
+        kept.iter()
+            .rposition(|h| matches!(h, Header::Via(_)))
+            .map_or(0, |i| i + 1)
+    });
+    edit(&mut request.uri, &mut uris);
+    let values = uris.into_iter().map(|uri| match set {
+        RouteSet::Route => rsip::headers::Route::new(uri.to_string()).into(),
+        RouteSet::RecordRoute => rsip::headers::RecordRoute::new(uri.to_string()).into(),
+    });
+    kept.splice(at..at, values);
+    request.headers = kept.into();
+    Ok(())
+}
+
+/// Section 16.6 step 7: if the next hop is a strict router (its route has no `lr`), its
+/// URI becomes the Request-URI and the Request-URI goes to the end of the route set.
+fn postprocess_routes(request_uri: &mut Uri, routes: &mut Vec<UriWithParams>) {
+    let strict = match routes.first() {
+        Some(first) => !first.uri.params.contains(&Param::Lr),
+        None => false,
+    };
+    if strict {
+        let next_hop = routes.remove(0);
+        routes.push(UriWithParams {
+            uri: std::mem::replace(request_uri, next_hop.uri),
+            params: vec![],
+        });
+    }
+}
+
+/// Pushes `uri` as the new first Route (section 16.6 step 6), then postprocesses the
+/// route set.
+pub(crate) fn push_route(request: &mut Request, uri: UriWithParams) -> Result<(), rsip::Error> {
+    edit_route_set(request, RouteSet::Route, |request_uri, routes| {
+        routes.insert(0, uri);
+        postprocess_routes(request_uri, routes);
+    })
+}
+
+/// Removes the first Route value, the one naming this proxy (section 16.4), then
+/// postprocesses the route set. False if there is no Route.
+pub(crate) fn pop_top_route(request: &mut Request) -> Result<bool, rsip::Error> {
+    let mut popped = false;
+    edit_route_set(request, RouteSet::Route, |request_uri, routes| {
+        if !routes.is_empty() {
+            routes.remove(0);
+            popped = true;
+            postprocess_routes(request_uri, routes);
+        }
+    })?;
+    Ok(popped)
+}
+
+/// Adds `uri` as the first Record-Route (section 16.6 step 4), with the `lr` parameter
+/// a loose-routing proxy must put there.
+pub(crate) fn record_route(
+    request: &mut Request,
+    mut uri: UriWithParams,
+) -> Result<(), rsip::Error> {
+    if !uri.uri.params.contains(&Param::Lr) {
+        uri.uri.params.push(Param::Lr);
+    }
+    edit_route_set(request, RouteSet::RecordRoute, |_, record_routes| {
+        record_routes.insert(0, uri)
+    })
+}
+
+/// Parses the `(raw_request, uri)` arguments of the route editing functions.
+fn route_args(raw: *const c_char, uri: *const c_char) -> Option<(Request, UriWithParams)> {
+    let request = match SipMessage::try_from(str_arg(raw)?) {
+        Ok(SipMessage::Request(request)) => request,
+        _ => return None,
+    };
+    Some((request, route_uri(str_arg(uri)?)?))
+}
+
+/// `raw_request` with `uri` (bare or in angle brackets, with `;lr` for a loose router)
+/// pushed as its first Route, as a caller-owned string. If that route has no `lr`, the
+/// request is rewritten for a strict router: `uri` becomes the Request-URI and the old
+/// Request-URI the last Route. NULL if an argument is NULL or does not parse, or
+/// `raw_request` is not a request.
+#[no_mangle]
+pub extern "C" fn rsip_push_route(raw_request: *const c_char, uri: *const c_char) -> *mut c_char {
+    let (mut request, uri) = match route_args(raw_request, uri) {
+        Some(args) => args,
+        None => return std::ptr::null_mut(),
+    };
+    match push_route(&mut request, uri) {
+        Ok(()) => into_c_string(request.to_string()),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// `raw_request` without its first Route value (a proxy removing its own), as a
+/// caller-owned string. If the next Route then has no `lr`, the request is rewritten for
+/// that strict router as in `rsip_push_route`. NULL if the request has no Route, or
+/// `raw_request` is NULL, not a request or malformed.
+#[no_mangle]
+pub extern "C" fn rsip_pop_top_route(raw_request: *const c_char) -> *mut c_char {
+    let mut request = match str_arg(raw_request).map(SipMessage::try_from) {
+        Some(Ok(SipMessage::Request(request))) => request,
+        _ => return std::ptr::null_mut(),
+    };
+    match pop_top_route(&mut request) {
+        Ok(true) => into_c_string(request.to_string()),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// `raw_request` with `uri` added as its first Record-Route, with `;lr` added if
+/// missing, as a caller-owned string. NULL as for `rsip_push_route`.
+#[no_mangle]
+pub extern "C" fn rsip_record_route(raw_request: *const c_char, uri: *const c_char) -> *mut c_char {
+    let (mut request, uri) = match route_args(raw_request, uri) {
+        Some(args) => args,
+        None => return std::ptr::null_mut(),
+    };
+    match record_route(&mut request, uri) {
+        Ok(()) => into_c_string(request.to_string()),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// `raw_request` with Max-Forwards decremented, ready to forward, as a caller-owned
+/// string. NULL if Max-Forwards is already 0 (answer 483), or if the argument is NULL,
+/// not a request or has a malformed Max-Forwards.
+#[no_mangle]
+pub extern "C" fn rsip_decrement_max_forwards(raw_request: *const c_char) -> *mut c_char {
+    let mut request = match str_arg(raw_request).map(SipMessage::try_from) {
+        Some(Ok(SipMessage::Request(request))) => request,
+        _ => return std::ptr::null_mut(),
+    };
+    match decrement_max_forwards(&mut request) {
+        Ok(Hops::Forward) => into_c_string(request.to_string()),
+        Ok(Hops::Exhausted) | Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// The Max-Forwards of `raw_request`, or -1 if it has none or the argument is NULL,
+/// not a request or malformed.
+#[no_mangle]
+pub extern "C" fn rsip_max_forwards(raw_request: *const c_char) -> i32 {
+    match str_arg(raw_request).map(SipMessage::try_from) {
+        Some(Ok(SipMessage::Request(request))) => request
+            .max_forwards_header()
+            .and_then(|max_forwards| max_forwards.num())
+            .map_or(-1, |n| i32::try_from(n).unwrap_or(i32::MAX)),
+        _ => -1,
+    }
+}
+
+/// True if any Via of `raw` carries exactly `branch`, e.g. the branch this proxy
+/// computes for the request (section 16.6 step 8): the request then went through this
+/// proxy before and is looping. False if an argument is NULL or `raw` does not parse.
+#[no_mangle]
+pub extern "C" fn rsip_has_via_branch(raw: *const c_char, branch: *const c_char) -> bool {
+    match (str_arg(raw).map(SipMessage::try_from), str_arg(branch)) {
+        (Some(Ok(msg)), Some(branch)) => via_branches(&msg).iter().any(|b| b == branch),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{CStr, CString};
+
+    fn request(max_forwards: Option<&str>) -> String {
+        format!(
+            "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+             Via: SIP/2.0/UDP proxy.example.com;branch=z9hG4bKproxy1, SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKua\r\n\
+             Via: SIP/2.0/TCP 192.0.2.9;branch=z9hG4bKfirst\r\n\
+             {}\
+             From: <sip:alice@example.com>;tag=1\r\n\
+             To: <sip:bob@example.com>\r\n\
+             Call-ID: proxy@192.0.2.1\r\n\
+             CSeq: 1 OPTIONS\r\n\
+             Content-Length: 0\r\n\r\n",
+            max_forwards
+                .map(|n| format!("Max-Forwards: {}\r\n", n))
+                .unwrap_or_default()
+        )
+    }
+
+    fn decremented(raw: &str) -> Option<String> {
+        let raw = CString::new(raw).unwrap();
+        let out = rsip_decrement_max_forwards(raw.as_ptr());
+        if out.is_null() {
+            return None;
+        }
+        let text = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_string();
+        crate::ffi::rsip_free_string(out);
+        Some(text)
+    }
+
+    fn max_forwards(raw: &str) -> i32 {
+        rsip_max_forwards(CString::new(raw).unwrap().as_ptr())
+    }
+
+    #[test]
+    fn decrements_until_exhausted() {
+        let once = decremented(&request(Some("2"))).unwrap();
+        assert_eq!(max_forwards(&once), 1);
+        let twice = decremented(&once).unwrap();
+        assert_eq!(max_forwards(&twice), 0);
+        assert_eq!(decremented(&twice), None, "483 Too Many Hops");
+
+        assert_eq!(max_forwards(&request(None)), -1);
+        assert_eq!(max_forwards(&decremented(&request(None)).unwrap()), 70);
+        assert_eq!(decremented(&request(Some("many"))), None);
+        let response = "SIP/2.0 200 OK\r\nMax-Forwards: 5\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(decremented(response), None);
+        assert!(rsip_decrement_max_forwards(std::ptr::null()).is_null());
+    }
+
+    #[test]
+    fn finds_branches_in_every_via() {
+        let msg = SipMessage::try_from(request(Some("70")).as_str()).unwrap();
+        assert_eq!(
+            via_branches(&msg),
+            vec!["z9hG4bKproxy1", "z9hG4bKua", "z9hG4bKfirst"]
+        );
+        let raw = CString::new(request(Some("70"))).unwrap();
+        let has = |branch: &str| {
+            rsip_has_via_branch(raw.as_ptr(), CString::new(branch).unwrap().as_ptr())
+        };
+        assert!(has("z9hG4bKua"));
+        assert!(has("z9hG4bKfirst"));
+        assert!(!has("z9hG4bKprox"));
+        assert!(!rsip_has_via_branch(raw.as_ptr(), std::ptr::null()));
+    }
+
+    fn routed(routes: &str) -> String {
+        format!(
+            "INVITE sip:bob@example.com SIP/2.0\r\n\
+             Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKroute\r\n\
+             Max-Forwards: 70\r\n\
+             {}\
+             From: <sip:alice@example.com>;tag=1\r\n\
+             To: <sip:bob@example.com>\r\n\
+             Call-ID: route@192.0.2.1\r\n\
+             CSeq: 1 INVITE\r\n\
+             Content-Length: 0\r\n\r\n",
+            routes
+        )
+    }
+
+    /// The Request-URI and the header lines up to From, which the edits may touch.
+    fn top(raw: Option<String>) -> Vec<String> {
+        let raw = raw.expect("edited request");
+        raw.lines()
+            .take_while(|line| !line.starts_with("From"))
+            .map(|line| line.strip_suffix(" SIP/2.0").unwrap_or(line).to_string())
+            .collect()
+    }
+
+    fn edited(
+        f: extern "C" fn(*const c_char, *const c_char) -> *mut c_char,
+    ) -> impl Fn(&str, &str) -> Option<String> {
+        move |raw, uri| {
+            let raw = CString::new(raw).unwrap();
+            let uri = CString::new(uri).unwrap();
+            let out = f(raw.as_ptr(), uri.as_ptr());
+            if out.is_null() {
+                return None;
+            }
+            let text = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_string();
+            crate::ffi::rsip_free_string(out);
+            Some(text)
+        }
+    }
+
+    fn popped(raw: &str) -> Option<String> {
+        let raw = CString::new(raw).unwrap();
+        let out = rsip_pop_top_route(raw.as_ptr());
+        if out.is_null() {
+            return None;
+        }
+        let text = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_string();
+        crate::ffi::rsip_free_string(out);
+        Some(text)
+    }
+
+    #[test]
+    fn pops_and_pushes_routes() {
+        let raw = routed(
+            "Route: <sip:p1.example.com;lr>, <sip:p2.example.com;lr>\r\n\
+             Route: <sip:p3.example.com;lr>\r\n",
+        );
+        assert_eq!(
+            top(popped(&raw)),
+            vec![
+                "INVITE sip:bob@example.com",
+                "Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKroute",
+                "Max-Forwards: 70",
+                "Route: <sip:p2.example.com;lr>",
+                "Route: <sip:p3.example.com;lr>",
+            ]
+        );
+        assert_eq!(popped(&routed("")), None);
+
+        // the next hop is a strict router: it becomes the Request-URI
+        let strict = routed("Route: <sip:p1.example.com;lr>, <sip:strict.example.com>\r\n");
+        assert_eq!(
+            top(popped(&strict))[..4],
+            [
+                "INVITE sip:strict.example.com",
+                "Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKroute",
+                "Max-Forwards: 70",
+                "Route: <sip:bob@example.com>",
+            ]
+        );
+
+        let push = edited(rsip_push_route);
+        assert_eq!(
+            top(push(&raw, "<sip:edge.example.com;lr>"))[3..],
+            [
+                "Route: <sip:edge.example.com;lr>",
+                "Route: <sip:p1.example.com;lr>",
+                "Route: <sip:p2.example.com;lr>",
+                "Route: <sip:p3.example.com;lr>",
+            ]
+        );
+        // without a route set the Route goes right below the Vias
+        assert_eq!(
+            top(push(&routed(""), "sip:edge.example.com;lr"))[1..],
+            [
+                "Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKroute",
+                "Route: <sip:edge.example.com;lr>",
+                "Max-Forwards: 70",
+            ]
+        );
+        assert_eq!(push(&raw, "not a uri"), None);
+    }
+
+    #[test]
+    fn record_routes_are_loose() {
+        let record = edited(rsip_record_route);
+        let raw = routed("Record-Route: <sip:downstream.example.com;lr>\r\n");
+        assert_eq!(
+            top(record(&raw, "sip:proxy.example.com"))[3..],
+            [
+                "Record-Route: <sip:proxy.example.com;lr>",
+                "Record-Route: <sip:downstream.example.com;lr>",
+            ]
+        );
+        let once = top(record(&routed(""), "<sip:proxy.example.com;lr>"));
+        assert_eq!(once[2], "Record-Route: <sip:proxy.example.com;lr>");
+        let response = "SIP/2.0 200 OK\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(record(response, "sip:proxy.example.com"), None);
+    }
+}
@@ -0,0 +1,169 @@
+//! Randomness for protocol identifiers (branches, tags, Call-IDs).
+//!
+//! By default everything is drawn from the thread-local CSPRNG seeded from the OS.
+//! `rsip_seed_random` swaps in a deterministic generator so tests (including C-side
+//! tests) get reproducible output; `rsip_unseed_random` switches back.
+
+use crate::ffi::{into_c_string, str_arg};
+use crate::lock::Lock;
+use lazy_static::lazy_static;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// RFC 3261 §8.1.1.7 magic cookie every compliant branch starts with.
+pub const BRANCH_MAGIC_COOKIE: &str = "z9hG4bK";
+
+lazy_static! {
+    static ref SEEDED: Mutex<Option<StdRng>> = Mutex::new(None);
+}
+
+pub(crate) fn fill_bytes(buf: &mut [u8]) {
+    let mut seeded = SEEDED.locked();
+    match seeded.as_mut() {
+        Some(rng) => rng.fill_bytes(buf),
+        None => rand::thread_rng().fill_bytes(buf),
+    }
+}
+
+/// `n` random bytes rendered as `2 * n` lowercase hex characters.
+pub(crate) fn random_hex(n: usize) -> String {
+    let mut buf = vec![0u8; n];
+    fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A new branch: the magic cookie followed by 128 bits of randomness.
+pub fn generate_branch() -> String {
+    format!("{}{}", BRANCH_MAGIC_COOKIE, random_hex(16))
+}
+
+// Mixed into tags and Call-IDs so two values drawn in this process can never collide,
+// even if the generator is re-seeded with the same value.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+fn next_sequence() -> u64 {
+    SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A From/To tag: 64 random bits plus the process-wide sequence number, all token chars.
+pub fn generate_tag() -> String {
+    format!("{}{:x}", random_hex(8), next_sequence())
+}
+
+/// A Call-ID of the form `<random>@host`, or just `<random>` when `host` is empty.
+pub fn generate_call_id(host: &str) -> String {
+    let id = format!("{}{:x}", random_hex(16), next_sequence());
+    if host.is_empty() {
+        id
+    } else {
+        format!("{}@{}", id, host)
+    }
+}
+
+/// Returns a caller-owned branch string (free with `rsip_free_string`).
+#[no_mangle]
+pub extern "C" fn rsip_generate_branch() -> *mut c_char {
+    into_c_string(generate_branch())
+}
+
+/// Returns a caller-owned tag (free with `rsip_free_string`).
+#[no_mangle]
+pub extern "C" fn rsip_generate_tag() -> *mut c_char {
+    into_c_string(generate_tag())
+}
+
+/// Returns a caller-owned `<random>@host` Call-ID (free with `rsip_free_string`).
+/// A NULL or empty `host` yields a Call-ID without the `@host` part.
+#[no_mangle]
+pub extern "C" fn rsip_generate_call_id(host: *const c_char) -> *mut c_char {
+    into_c_string(generate_call_id(str_arg(host).unwrap_or("")))
+}
+
+/// Makes every subsequent identifier deterministic for the given seed. Test use only.
+#[no_mangle]
+pub extern "C" fn rsip_seed_random(seed: u64) {
+    *SEEDED.locked() = Some(StdRng::seed_from_u64(seed));
+}
+
+/// Restores the OS-seeded CSPRNG after `rsip_seed_random`.
+#[no_mangle]
+pub extern "C" fn rsip_unseed_random() {
+    *SEEDED.locked() = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::ffi::CString;
+
+    #[test]
+    fn branches_have_the_magic_cookie_and_are_unique() {
+        let branches: HashSet<_> = (0..1000).map(|_| generate_branch()).collect();
+        assert_eq!(branches.len(), 1000);
+        for branch in branches {
+            assert!(branch.starts_with(BRANCH_MAGIC_COOKIE));
+            assert_eq!(branch.len(), BRANCH_MAGIC_COOKIE.len() + 32);
+            assert!(branch[BRANCH_MAGIC_COOKIE.len()..]
+                .chars()
+                .all(|c| c.is_ascii_hexdigit()));
+        }
+    }
+
+    #[test]
+    fn seeded_generator_is_reproducible() {
+        // Drives `StdRng` directly: the global source is shared with tests running in
+        // parallel, so sequences drawn through it are not guaranteed to be contiguous.
+        let draw = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut buf = [0u8; 16];
+            rng.fill_bytes(&mut buf);
+            buf
+        };
+        assert_eq!(draw(42), draw(42));
+        assert_ne!(draw(42), draw(43));
+
+        rsip_seed_random(42);
+        let ptr = rsip_generate_branch();
+        rsip_unseed_random();
+        let branch = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+        assert!(branch.starts_with(BRANCH_MAGIC_COOKIE));
+    }
+
+    #[test]
+    fn tags_and_call_ids_do_not_collide() {
+        // 128 (Call-ID) / 64 (tag) random bits make a collision astronomically unlikely;
+        // the sequence suffix turns that into a guarantee within one process, which
+        // is what this checks under a fixed seed.
+        rsip_seed_random(7);
+        let tags: HashSet<_> = (0..10_000).map(|_| generate_tag()).collect();
+        rsip_seed_random(7);
+        let more_tags: HashSet<_> = (0..10_000).map(|_| generate_tag()).collect();
+        rsip_unseed_random();
+        assert_eq!(tags.len(), 10_000);
+        assert!(tags.is_disjoint(&more_tags));
+
+        let call_ids: HashSet<_> = (0..10_000)
+            .map(|_| generate_call_id("example.com"))
+            .collect();
+        assert_eq!(call_ids.len(), 10_000);
+        assert!(call_ids.iter().all(|id| id.ends_with("@example.com")));
+    }
+
+    #[test]
+    fn generated_values_are_valid_in_headers() {
+        use rsip::prelude::*;
+
+        let tag = generate_tag();
+        let from = rsip::headers::From::new(format!("<sip:a@example.com>;tag={}", tag));
+        assert_eq!(from.tag().unwrap().unwrap().to_string(), tag);
+
+        let ptr = rsip_generate_call_id(std::ptr::null());
+        let call_id = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+        assert!(!call_id.contains('@'));
+        assert!(!call_id.is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+//! Flood protection applied to every received message before it is queued or parsed:
+//! a size cap, and a per-source-IP token bucket refilling at `max_pps` with a burst of
+//! one second's worth.
+
+use crate::context::{with_context, RsipContext};
+use crate::lock::Lock;
+use crate::log::LogLevel;
+use crate::stats::Stats;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// A bucket untouched this long has refilled completely, so forgetting it is lossless.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often idle buckets are swept, bounding memory to the sources seen recently.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether the last message from this source was dropped; only the first drop of a
+    /// run is reported as an event.
+    throttled: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Pass,
+    TooLarge,
+    Throttled { first: bool },
+}
+
+pub(crate) struct RateLimiter {
+    /// 0 disables the per-source rate check.
+    max_pps: u32,
+    /// 0 disables the size check.
+    max_msg_bytes: usize,
+    sources: HashMap<IpAddr, Bucket>,
+    last_sweep: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(max_pps: u32, max_msg_bytes: usize) -> Self {
+        Self {
+            max_pps,
+            max_msg_bytes,
+            sources: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    pub fn check(&mut self, len: usize, ip: IpAddr, now: Instant) -> Verdict {
+        if self.max_msg_bytes > 0 && len > self.max_msg_bytes {
+            return Verdict::TooLarge;
+        }
+        if self.max_pps == 0 {
+            return Verdict::Pass;
+        }
+        self.sweep(now);
+        let rate = f64::from(self.max_pps);
+        let bucket = self.sources.entry(ip).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+            throttled: false,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.throttled = false;
+            Verdict::Pass
+        } else {
+            let first = !bucket.throttled;
+            bucket.throttled = true;
+            Verdict::Throttled { first }
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+        self.last_sweep = now;
+        self.sources
+            .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < IDLE_TIMEOUT);
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.sources.len()
+    }
+}
+
+impl RsipContext {
+    /// Limits every source IP to `max_pps` messages per second and drops messages
+    /// larger than `max_msg_bytes`; 0 disables either check. Takes effect immediately
+    /// and resets all per-source state.
+    pub fn set_rate_limit(&self, max_pps: u32, max_msg_bytes: usize) {
+        *self.rate_limiter.locked() = if max_pps == 0 && max_msg_bytes == 0 {
+            None
+        } else {
+            Some(RateLimiter::new(max_pps, max_msg_bytes))
+        };
+    }
+
+    /// Whether a message of `len` bytes from `src` may enter the receive pipeline.
+    /// Drops are counted; oversized messages and the start of each throttled run are
+    /// reported as `rate_limited` events.
+    pub(crate) fn admit(&self, len: usize, src: SocketAddr) -> bool {
+        let verdict = match self.rate_limiter.locked().as_mut() {
+            Some(limiter) => limiter.check(len, src.ip(), Instant::now()),
+            None => return true,
+        };
+        let reason = match verdict {
+            Verdict::Pass => return true,
+            Verdict::TooLarge => "size",
+            Verdict::Throttled { .. } => "rate",
+        };
+        Stats::add(&self.stats.rate_limited, 1);
+        if verdict == (Verdict::Throttled { first: false }) {
+            return false;
+        }
+        self.log(
+            LogLevel::Debug,
+            format_args!("rate limited {} ({}, {} bytes)", src, reason, len),
+        );
+        let payload = json!({ "src": src.to_string(), "reason": reason, "len": len });
+        self.emit_from("rate_limited", &payload.to_string(), src);
+        false
+    }
+}
+
+/// Sets the flood limits for every listener: at most `max_pps` messages per second per
+/// source IP, and no message above `max_msg_bytes`. 0 disables either limit.
+#[no_mangle]
+pub extern "C" fn rsip_set_rate_limit(max_pps: u32, max_msg_bytes: usize) {
+    crate::default_context().set_rate_limit(max_pps, max_msg_bytes);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_rate_limit(
+    ctx: *mut RsipContext,
+    max_pps: u32,
+    max_msg_bytes: usize,
+) {
+    with_context(ctx, |ctx| ctx.set_rate_limit(max_pps, max_msg_bytes));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let mut limiter = RateLimiter::new(10, 0);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "2001:db8::1".parse().unwrap();
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(limiter.check(100, ip, start), Verdict::Pass);
+        }
+        assert_eq!(
+            limiter.check(100, ip, start),
+            Verdict::Throttled { first: true }
+        );
+        assert_eq!(
+            limiter.check(100, ip, start),
+            Verdict::Throttled { first: false }
+        );
+        // Sources are independent.
+        assert_eq!(limiter.check(100, other, start), Verdict::Pass);
+        // 10 pps: one token every 100ms.
+        let later = start + Duration::from_millis(100);
+        assert_eq!(limiter.check(100, ip, later), Verdict::Pass);
+        assert_eq!(
+            limiter.check(100, ip, later),
+            Verdict::Throttled { first: true }
+        );
+    }
+
+    #[test]
+    fn size_cap_and_sweep() {
+        let mut limiter = RateLimiter::new(5, 1000);
+        let start = Instant::now();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(limiter.check(1001, ip, start), Verdict::TooLarge);
+        assert_eq!(limiter.check(1000, ip, start), Verdict::Pass);
+        for n in 0..100u8 {
+            limiter.check(10, IpAddr::from([198, 51, 100, n]), start);
+        }
+        assert_eq!(limiter.tracked(), 101);
+        let later = start + SWEEP_INTERVAL;
+        limiter.check(10, ip, later);
+        assert_eq!(limiter.tracked(), 1);
+    }
+
+    static EVENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(_: *const c_char, payload: *const c_char) {
+        let payload = unsafe { CStr::from_ptr(payload) }
+            .to_string_lossy()
+            .into_owned();
+        EVENTS.lock().unwrap().push(payload);
+    }
+
+    #[test]
+    fn drops_are_reported_and_counted() {
+        let ctx = RsipContext::new();
+        ctx.events
+            .subscribe(Some(vec!["rate_limited".into()]), Sink::Basic(record));
+        ctx.set_rate_limit(2, 64);
+        let src: SocketAddr = "192.0.2.9:5060".parse().unwrap();
+        let msg = b"OPTIONS sip:a@b SIP/2.0\r\n\r\n";
+        for _ in 0..5 {
+            ctx.dispatch(msg, src);
+        }
+        ctx.dispatch(&[b'x'; 65], "192.0.2.10:5060".parse().unwrap());
+
+        assert_eq!(ctx.stats.packets_received.load(Ordering::Relaxed), 2);
+        assert_eq!(ctx.stats.rate_limited.load(Ordering::Relaxed), 4);
+        let events: Vec<serde_json::Value> = EVENTS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| serde_json::from_str(e).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["src"], "192.0.2.9:5060");
+        assert_eq!(events[0]["reason"], "rate");
+        assert_eq!(events[1]["reason"], "size");
+        assert_eq!(events[1]["len"], 65);
+
+        ctx.set_rate_limit(0, 0);
+        ctx.dispatch(msg, src);
+        assert_eq!(ctx.stats.packets_received.load(Ordering::Relaxed), 3);
+    }
+}
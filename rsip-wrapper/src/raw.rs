@@ -0,0 +1,525 @@
+//! Allocation-free delivery of received messages: the host gets the receive buffer
+//! itself plus the offsets of the start line, each header and the body, instead of
+//! owned strings and JSON.
+//!
+//! Everything handed to a `RawCallback` borrows from the receive buffer and from
+//! per-thread scratch space that is reused for the next message, so it is only valid
+//! for the duration of the call.
+
+use crate::context::{with_context, RsipContext};
+use crate::ffi::{into_c_string, str_arg};
+use crate::message::canonical_name;
+use serde_json::json;
+use std::cell::RefCell;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::raw::c_char;
+
+/// A byte range of `RsipRawMessage::data`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RsipSpan {
+    pub offset: u32,
+    pub len: u32,
+}
+
+impl RsipSpan {
+    fn new(start: usize, end: usize) -> Self {
+        Self {
+            offset: start as u32,
+            len: (end - start) as u32,
+        }
+    }
+
+    pub(crate) fn slice<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.offset as usize..(self.offset + self.len) as usize]
+    }
+}
+
+/// One header line: the name, and the value with surrounding whitespace trimmed. A
+/// folded value (continuation lines starting with whitespace) spans the line breaks.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RsipHeaderSpan {
+    pub name: RsipSpan,
+    pub value: RsipSpan,
+}
+
+/// INET6_ADDRSTRLEN: room for any textual IP address plus the NUL.
+const SRC_IP_LEN: usize = 46;
+
+/// A received message as seen by a `RawCallback`. All pointers are only valid during
+/// the callback.
+#[repr(C)]
+pub struct RsipRawMessage {
+    pub data: *const u8,
+    pub len: usize,
+    pub start_line: RsipSpan,
+    pub headers: *const RsipHeaderSpan,
+    pub header_count: usize,
+    pub body: RsipSpan,
+    /// False if the header section is not terminated by an empty line or a header line
+    /// has no colon; `headers` then holds the lines scanned before the problem and
+    /// `body` is empty.
+    pub well_formed: bool,
+    /// NUL-terminated source address.
+    pub src_ip: [c_char; SRC_IP_LEN],
+    pub src_port: u16,
+}
+
+pub type RawCallback = extern "C" fn(msg: *const RsipRawMessage);
+
+/// `(content end, start of next line)` of the line starting at `from`.
+fn line_end(data: &[u8], from: usize) -> Option<(usize, usize)> {
+    let newline = from + data[from..].iter().position(|b| *b == b'\n')?;
+    let end = if newline > from && data[newline - 1] == b'\r' {
+        newline - 1
+    } else {
+        newline
+    };
+    Some((end, newline + 1))
+}
+
+fn is_space(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+/// Shrinks `start..end` past leading and trailing spaces and tabs.
+fn trimmed(data: &[u8], mut start: usize, mut end: usize) -> RsipSpan {
+    while start < end && is_space(data[start]) {
+        start += 1;
+    }
+    while end > start && is_space(data[end - 1]) {
+        end -= 1;
+    }
+    RsipSpan::new(start, end)
+}
+
+/// Splits `data` into start line, headers (appended to `headers`) and body without
+/// copying. Returns `None` if the message is not well formed.
+pub(crate) fn scan(data: &[u8], headers: &mut Vec<RsipHeaderSpan>) -> Option<(RsipSpan, RsipSpan)> {
+    let (start_end, mut pos) = line_end(data, 0)?;
+    let start_line = RsipSpan::new(0, start_end);
+    loop {
+        let (end, next) = line_end(data, pos)?;
+        if end == pos {
+            return Some((start_line, RsipSpan::new(next, data.len())));
+        }
+        if is_space(data[pos]) {
+            let folded = headers.last_mut()?;
+            folded.value = trimmed(data, folded.value.offset as usize, end);
+        } else {
+            let colon = pos + data[pos..end].iter().position(|b| *b == b':')?;
+            headers.push(RsipHeaderSpan {
+                name: trimmed(data, pos, colon),
+                value: trimmed(data, colon + 1, end),
+            });
+        }
+        pos = next;
+    }
+}
+
+/// Whether the header section of `data` has more than `max` header lines, folded
+/// continuations not counting. Stops reading at header `max + 1`, and allocates
+/// nothing, so it is cheap to run before parsing an untrusted message.
+pub(crate) fn exceeds_header_count(data: &[u8], max: usize) -> bool {
+    let mut count = 0;
+    let mut pos = match line_end(data, 0) {
+        Some((_, next)) => next,
+        None => return false,
+    };
+    while let Some((end, next)) = line_end(data, pos) {
+        if end == pos {
+            break;
+        }
+        if !is_space(data[pos]) {
+            count += 1;
+            if count > max {
+                return true;
+            }
+        }
+        pos = next;
+    }
+    false
+}
+
+/// Splits `data` at the empty line ending the header section into `(headers, body)`.
+/// The headers keep the start line and their line endings, except the one before the
+/// empty line. A Content-Length (or `l`) shorter than what follows cuts the body, so
+/// empty lines inside the body are never taken for the separator. Without an empty
+/// line everything is headers.
+pub fn split_message(data: &[u8]) -> (&[u8], &[u8]) {
+    let mut pos = 0;
+    let mut headers_end = 0;
+    let mut content_length = None;
+    while let Some((end, next)) = line_end(data, pos) {
+        if end == pos {
+            let body = &data[next..];
+            let body = match content_length {
+                Some(len) if len < body.len() => &body[..len],
+                _ => body,
+            };
+            return (&data[..headers_end], body);
+        }
+        if let Some(colon) = data[pos..end].iter().position(|b| *b == b':') {
+            let name = String::from_utf8_lossy(&data[pos..pos + colon]);
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("l") {
+                content_length = String::from_utf8_lossy(&data[pos + colon + 1..end])
+                    .trim()
+                    .parse::<usize>()
+                    .ok();
+            }
+        }
+        headers_end = end;
+        pos = next;
+    }
+    let mut end = data.len();
+    while end > 0 && (data[end - 1] == b'\n' || data[end - 1] == b'\r') {
+        end -= 1;
+    }
+    (&data[..end], &[])
+}
+
+/// Joins the lines of a folded header value with single spaces (RFC 3261 section 7.3.1).
+pub(crate) fn unfold(value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len());
+    for line in value.split(|b| *b == b'\n') {
+        let span = trimmed(line, 0, line.strip_suffix(b"\r").unwrap_or(line).len());
+        if span.len == 0 {
+            continue;
+        }
+        if !out.is_empty() {
+            out.push(b' ');
+        }
+        out.extend_from_slice(span.slice(line));
+    }
+    out
+}
+
+/// Rewrites compact header names (`v:`) to their full names and unfolds continuation
+/// lines, so rsip's typed accessors find every header. Other header lines, the start
+/// line and the body are copied untouched. `None` if there is nothing to rewrite or the
+/// message is not well formed (see `scan`).
+pub(crate) fn normalize_headers(data: &[u8]) -> Option<Vec<u8>> {
+    let mut headers = Vec::new();
+    let (start_line, body) = scan(data, &mut headers)?;
+    let name_of = |header: &RsipHeaderSpan| String::from_utf8_lossy(header.name.slice(data));
+    let rewrite = |header: &RsipHeaderSpan| {
+        let name = name_of(header);
+        canonical_name(&name) != name || header.value.slice(data).contains(&b'\n')
+    };
+    if !headers.iter().any(rewrite) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(data.len() + 64);
+    out.extend_from_slice(start_line.slice(data));
+    out.extend_from_slice(b"\r\n");
+    for header in &headers {
+        if rewrite(header) {
+            out.extend_from_slice(canonical_name(&name_of(header)).as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(&unfold(header.value.slice(data)));
+        } else {
+            let start = header.name.offset as usize;
+            out.extend_from_slice(&data[start..(header.value.offset + header.value.len) as usize]);
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(body.slice(data));
+    Some(out)
+}
+
+thread_local! {
+    // Reused across messages so steady-state delivery does not allocate.
+    static HEADERS: RefCell<Vec<RsipHeaderSpan>> = RefCell::new(Vec::with_capacity(32));
+}
+
+impl RsipContext {
+    /// Runs the raw callback, if one is set, on `data`. Returns whether it ran.
+    pub(crate) fn emit_raw(&self, data: &[u8], src: SocketAddr) -> bool {
+        let cb = match self.events.raw_callback() {
+            Some(cb) => cb,
+            None => return false,
+        };
+        HEADERS.with(|headers| {
+            let mut headers = headers.borrow_mut();
+            headers.clear();
+            let scanned = scan(data, &mut headers);
+            let (start_line, body) = scanned.unwrap_or_else(|| {
+                let first_line = line_end(data, 0).map_or(data.len(), |(end, _)| end);
+                (
+                    RsipSpan::new(0, first_line),
+                    RsipSpan::new(data.len(), data.len()),
+                )
+            });
+
+            let mut src_ip = [0u8; SRC_IP_LEN];
+            // Cannot fail: the longest textual address is 45 bytes.
+            let _ = write!(&mut src_ip[..SRC_IP_LEN - 1], "{}", src.ip());
+            let msg = RsipRawMessage {
+                data: data.as_ptr(),
+                len: data.len(),
+                start_line,
+                headers: headers.as_ptr(),
+                header_count: headers.len(),
+                body,
+                well_formed: scanned.is_some(),
+                src_ip: src_ip.map(|b| b as c_char),
+                src_port: src.port(),
+            };
+            self.events.run_counted(|| cb(&msg));
+        });
+        true
+    }
+}
+
+/// Sets the raw message callback, replacing any previous one; NULL removes it. It runs
+/// on the thread handling the message before any event, for every received message.
+#[no_mangle]
+pub extern "C" fn rsip_set_raw_callback(cb: Option<RawCallback>) {
+    crate::default_context().events.set_raw_callback(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_raw_callback(ctx: *mut RsipContext, cb: Option<RawCallback>) {
+    with_context(ctx, |ctx| ctx.events.set_raw_callback(cb));
+}
+
+/// `raw` with compact header names expanded and folded header values unfolded onto one
+/// line, as caller-owned text; see `normalize_headers`. Returned unchanged when there is
+/// nothing to rewrite or the header section is malformed. NULL if `raw` is NULL.
+#[no_mangle]
+pub extern "C" fn rsip_normalize_headers(raw: *const c_char) -> *mut c_char {
+    match str_arg(raw) {
+        Some(raw) => match normalize_headers(raw.as_bytes()) {
+            Some(normalized) => into_c_string(String::from_utf8_lossy(&normalized).into_owned()),
+            None => into_c_string(raw.to_string()),
+        },
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Splits a message at the end of its headers into caller-owned JSON
+/// `{"headers": "...", "body": "..."}` (see `split_message`). NULL if `raw` is NULL.
+#[no_mangle]
+pub extern "C" fn rsip_split_message(raw: *const c_char) -> *mut c_char {
+    match str_arg(raw) {
+        Some(raw) => {
+            let (headers, body) = split_message(raw.as_bytes());
+            let payload = json!({
+                "headers": String::from_utf8_lossy(headers),
+                "body": String::from_utf8_lossy(body),
+            });
+            into_c_string(payload.to_string())
+        }
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsip::prelude::*;
+    use std::ffi::{CStr, CString};
+    use std::sync::Mutex;
+
+    const MSG: &[u8] = b"MESSAGE sip:bob@example.com SIP/2.0\r\n\
+        Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKraw\r\n\
+        Subject:  lunch,\r\n\
+        \tat noon \r\n\
+        l:5\r\n\r\nhello";
+
+    #[test]
+    fn counts_header_lines() {
+        // Via, the folded Subject and l; the body is not looked at
+        assert!(!exceeds_header_count(MSG, 3));
+        assert!(exceeds_header_count(MSG, 2));
+        assert!(!exceeds_header_count(b"garbage", 0));
+    }
+
+    #[test]
+    fn scans_without_copying() {
+        let mut headers = Vec::new();
+        let (start_line, body) = scan(MSG, &mut headers).unwrap();
+        assert_eq!(
+            start_line.slice(MSG),
+            b"MESSAGE sip:bob@example.com SIP/2.0"
+        );
+        assert_eq!(body.slice(MSG), b"hello");
+        let pairs: Vec<(&[u8], &[u8])> = headers
+            .iter()
+            .map(|h| (h.name.slice(MSG), h.value.slice(MSG)))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (&b"Via"[..], &b"SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKraw"[..]),
+                (&b"Subject"[..], &b"lunch,\r\n\tat noon"[..]),
+                (&b"l"[..], &b"5"[..]),
+            ]
+        );
+
+        // bare LF line endings
+        headers.clear();
+        let (_, body) = scan(b"OPTIONS sip:a SIP/2.0\nTo: a\n\n", &mut headers).unwrap();
+        assert_eq!((headers.len(), body.len), (1, 0));
+    }
+
+    #[test]
+    fn flags_malformed_input() {
+        let mut headers = Vec::new();
+        assert!(scan(b"OPTIONS sip:a SIP/2.0\r\nTo: a\r\n", &mut headers).is_none());
+        headers.clear();
+        assert!(scan(b"OPTIONS sip:a SIP/2.0\r\nno colon\r\n\r\n", &mut headers).is_none());
+        headers.clear();
+        assert!(scan(b"no newline at all", &mut headers).is_none());
+    }
+
+    fn split(raw: &str) -> (&str, &str) {
+        let (headers, body) = split_message(raw.as_bytes());
+        (
+            std::str::from_utf8(headers).unwrap(),
+            std::str::from_utf8(body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn normalizes_compact_and_folded_headers() {
+        // Compact forms as sent by some SBCs, and RFC 4475's folded Subject.
+        let raw = b"INVITE sip:bob@biloxi.example.com SIP/2.0\r\n\
+            v: SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bK776asdhds\r\n\
+            f: Alice <sip:alice@atlanta.example.com>;tag=1928301774\r\n\
+            t: Bob <sip:bob@biloxi.example.com>\r\n\
+            i: a84b4c76e66710@pc33.atlanta.example.com\r\n\
+            CSeq:314159 INVITE\r\n\
+            Subject: I know you're there,\r\n\
+            \x20    pick up the phone   \r\n\
+            \t and talk to me!\r\n\
+            l: 4\r\n\r\nv=0\n";
+        let normalized = normalize_headers(raw).unwrap();
+        assert_eq!(
+            String::from_utf8(normalized.clone()).unwrap(),
+            "INVITE sip:bob@biloxi.example.com SIP/2.0\r\n\
+            Via: SIP/2.0/UDP 192.0.2.1:5060;branch=z9hG4bK776asdhds\r\n\
+            From: Alice <sip:alice@atlanta.example.com>;tag=1928301774\r\n\
+            To: Bob <sip:bob@biloxi.example.com>\r\n\
+            Call-ID: a84b4c76e66710@pc33.atlanta.example.com\r\n\
+            CSeq:314159 INVITE\r\n\
+            Subject: I know you're there, pick up the phone and talk to me!\r\n\
+            Content-Length: 4\r\n\r\nv=0\n"
+        );
+        let msg = crate::parse::parse_message(raw).unwrap();
+        assert_eq!(
+            msg.via_header().unwrap().branch().unwrap().to_string(),
+            "z9hG4bK776asdhds"
+        );
+        assert_eq!(
+            msg.call_id_header().unwrap().value(),
+            "a84b4c76e66710@pc33.atlanta.example.com"
+        );
+
+        // LF-only endings are normalized too; nothing to do is None.
+        let lf = b"OPTIONS sip:a@example.com SIP/2.0\ni: lf@example.com\n\n";
+        assert_eq!(
+            normalize_headers(lf).unwrap(),
+            b"OPTIONS sip:a@example.com SIP/2.0\r\nCall-ID: lf@example.com\r\n\r\n"
+        );
+        assert_eq!(normalize_headers(&normalized), None);
+        assert_eq!(normalize_headers(b"not sip"), None);
+
+        let raw = CString::new(&raw[..]).unwrap();
+        let out = rsip_normalize_headers(raw.as_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(out) }.to_bytes(), &normalized[..]);
+        crate::rsip_free_string(out);
+        assert!(rsip_normalize_headers(std::ptr::null()).is_null());
+    }
+
+    #[test]
+    fn splits_headers_from_body() {
+        let sdp = "v=0\r\n\r\nm=audio 49170 RTP/AVP 0\r\n";
+        let raw = format!(
+            "INVITE sip:bob@example.com SIP/2.0\r\nContent-Length: {}\r\n\r\n{}",
+            sdp.len(),
+            sdp
+        );
+        assert_eq!(
+            split(&raw),
+            (
+                &*format!(
+                    "INVITE sip:bob@example.com SIP/2.0\r\nContent-Length: {}",
+                    sdp.len()
+                ),
+                sdp
+            )
+        );
+        // LF endings, compact form, and trailing bytes beyond Content-Length
+        assert_eq!(
+            split("MESSAGE sip:a SIP/2.0\nl: 2\n\nhi\r\n\r\n"),
+            ("MESSAGE sip:a SIP/2.0\nl: 2", "hi")
+        );
+        // without Content-Length the rest is the body
+        assert_eq!(
+            split("SIP/2.0 200 OK\r\nTo: a\r\n\r\nx\r\n\r\ny"),
+            ("SIP/2.0 200 OK\r\nTo: a", "x\r\n\r\ny")
+        );
+        assert_eq!(
+            split("OPTIONS sip:a SIP/2.0\r\nTo: a\r\n"),
+            ("OPTIONS sip:a SIP/2.0\r\nTo: a", "")
+        );
+
+        let raw = std::ffi::CString::new("OPTIONS sip:a SIP/2.0\r\nl: 0\r\n\r\n").unwrap();
+        let out = rsip_split_message(raw.as_ptr());
+        let json: serde_json::Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        crate::ffi::rsip_free_string(out);
+        assert_eq!(json["headers"], "OPTIONS sip:a SIP/2.0\r\nl: 0");
+        assert_eq!(json["body"], "");
+        assert!(rsip_split_message(std::ptr::null()).is_null());
+    }
+
+    /// (first header name, source ip, source port, well formed, body length)
+    type Seen = (String, String, u16, bool, usize);
+
+    static SEEN: Mutex<Vec<Seen>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(msg: *const RsipRawMessage) {
+        let msg = unsafe { &*msg };
+        let data = unsafe { std::slice::from_raw_parts(msg.data, msg.len) };
+        let headers = unsafe { std::slice::from_raw_parts(msg.headers, msg.header_count) };
+        let first = headers
+            .first()
+            .map(|h| String::from_utf8_lossy(h.name.slice(data)).into_owned())
+            .unwrap_or_default();
+        let ip = unsafe { CStr::from_ptr(msg.src_ip.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        SEEN.lock().unwrap().push((
+            first,
+            ip,
+            msg.src_port,
+            msg.well_formed,
+            msg.body.len as usize,
+        ));
+    }
+
+    #[test]
+    fn callback_gets_spans_and_source() {
+        let ctx = RsipContext::new();
+        ctx.events.set_raw_callback(Some(record));
+        ctx.handle_datagram(MSG, "[2001:db8::1]:5062".parse().unwrap());
+        ctx.handle_datagram(b"garbage", "192.0.2.7:5060".parse().unwrap());
+        ctx.events.set_raw_callback(None);
+        ctx.handle_datagram(MSG, "192.0.2.7:5060".parse().unwrap());
+
+        let seen = SEEN.lock().unwrap();
+        assert_eq!(
+            *seen,
+            vec![
+                ("Via".to_string(), "2001:db8::1".to_string(), 5062, true, 5),
+                (String::new(), "192.0.2.7".to_string(), 5060, false, 0),
+            ]
+        );
+    }
+}
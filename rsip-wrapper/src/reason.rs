@@ -0,0 +1,370 @@
+//! Reason (RFC 3326) and Warning (RFC 3261 section 20.43) headers: why a call was torn
+//! down or a request failed, as carriers and call analytics expect to find it.
+//!
+//! rsip keeps Reason as an untyped header and Warning as an opaque string, so both are
+//! built and read here. Quoted texts may contain commas and semicolons; values are
+//! split outside of quotes only.
+
+use crate::builder::serialize;
+use crate::diagnose::is_token;
+use crate::ffi::{into_c_string, str_arg};
+use rsip::headers::Header;
+use rsip::prelude::*;
+use rsip::SipMessage;
+use serde_json::json;
+use std::convert::TryFrom;
+use std::os::raw::c_char;
+
+/// One value of a Reason header, e.g. `Q.850;cause=16;text="Normal call clearing"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reason {
+    /// `SIP`, `Q.850` or another protocol token.
+    pub protocol: String,
+    pub cause: Option<u32>,
+    pub text: Option<String>,
+}
+
+/// One value of a Warning header, e.g. `370 devnull "Choose a bigger pipe"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub code: u16,
+    /// The host(:port) or pseudonym of the element that added it.
+    pub agent: String,
+    pub text: String,
+}
+
+fn is_token_str(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(is_token)
+}
+
+/// `text` as a quoted-string. `None` if it contains control characters (CR and LF
+/// among them), which a header value cannot carry.
+pub(crate) fn quote(text: &str) -> Option<String> {
+    if text.chars().any(char::is_control) {
+        return None;
+    }
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    Some(out)
+}
+
+/// The content of a quoted-string, escapes resolved; unquoted input is returned
+/// trimmed. `None` for an unterminated quote.
+pub(crate) fn unquote(s: &str) -> Option<String> {
+    let s = s.trim();
+    let inner = match s.strip_prefix('"') {
+        Some(inner) => inner.strip_suffix('"')?,
+        None => return Some(s.to_string()),
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        out.push(if c == '\\' { chars.next()? } else { c });
+    }
+    Some(out)
+}
+
+/// Splits `s` at every `sep` outside a quoted-string.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// `value` without a leading `name:`, if it has one.
+fn strip_name<'a>(value: &'a str, name: &str) -> &'a str {
+    match value.split_once(':') {
+        Some((prefix, rest)) if prefix.trim().eq_ignore_ascii_case(name) => rest,
+        _ => value,
+    }
+}
+
+/// A Reason value for `protocol` with `cause` and, if given, `text`. `None` if
+/// `protocol` is not a token or `text` cannot be quoted.
+pub fn build_reason(protocol: &str, cause: u32, text: Option<&str>) -> Option<String> {
+    let protocol = protocol.trim();
+    if !is_token_str(protocol) {
+        return None;
+    }
+    let mut value = format!("{};cause={}", protocol, cause);
+    if let Some(text) = text {
+        value.push_str(";text=");
+        value.push_str(&quote(text)?);
+    }
+    Some(value)
+}
+
+/// Parses a Reason header value, or several separated by commas; the `Reason:` name is
+/// optional. Unknown parameters are skipped. `None` if a value has no protocol token,
+/// a non-numeric cause or an unterminated quote.
+pub fn parse_reason(value: &str) -> Option<Vec<Reason>> {
+    let mut reasons = Vec::new();
+    for value in split_unquoted(strip_name(value, "Reason"), ',') {
+        let mut params = split_unquoted(value, ';').into_iter();
+        let protocol = params.next().unwrap_or_default().trim();
+        if !is_token_str(protocol) {
+            return None;
+        }
+        let mut reason = Reason {
+            protocol: protocol.to_string(),
+            cause: None,
+            text: None,
+        };
+        for param in params {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            match name.trim().to_ascii_lowercase().as_str() {
+                "cause" => reason.cause = Some(value.trim().parse().ok()?),
+                "text" => reason.text = Some(unquote(value)?),
+                _ => {}
+            }
+        }
+        reasons.push(reason);
+    }
+    Some(reasons)
+}
+
+/// A Warning value. `None` unless `code` has three digits, `agent` is a host(:port)
+/// or token, and `text` can be quoted.
+pub fn build_warning(code: u16, agent: &str, text: &str) -> Option<String> {
+    let agent = agent.trim();
+    let agent_ok = !agent.is_empty() && agent.bytes().all(|b| is_token(b) || b"[]:".contains(&b));
+    if !(100..=999).contains(&code) || !agent_ok {
+        return None;
+    }
+    Some(format!("{} {} {}", code, agent, quote(text)?))
+}
+
+/// Parses a Warning header value, or several separated by commas; the `Warning:` name
+/// is optional. `None` if a value is not `code agent "text"`.
+pub fn parse_warning(value: &str) -> Option<Vec<Warning>> {
+    let mut warnings = Vec::new();
+    for value in split_unquoted(strip_name(value, "Warning"), ',') {
+        let value = value.trim();
+        let (code, rest) = value.split_once(' ')?;
+        let (agent, text) = rest.trim_start().split_once(' ')?;
+        let text = text.trim();
+        if code.len() != 3 || !text.starts_with('"') {
+            return None;
+        }
+        warnings.push(Warning {
+            code: code.parse().ok()?,
+            agent: agent.to_string(),
+            text: unquote(text)?,
+        });
+    }
+    Some(warnings)
+}
+
+/// `raw` with a Reason header carrying `reason` added before its Content-Length (or
+/// last), serialized like the builders do. `None` if `raw` does not parse or
+/// `reason` is not a valid Reason value.
+pub(crate) fn add_reason(raw: &str, reason: &str, compact: bool) -> Option<String> {
+    let reason = strip_name(reason, "Reason").trim();
+    if reason.contains(['\r', '\n']) || parse_reason(reason)?.is_empty() {
+        return None;
+    }
+    let mut msg = SipMessage::try_from(raw).ok()?;
+    let header = Header::Other("Reason".into(), reason.into());
+    let mut headers: Vec<Header> = msg.headers().iter().cloned().collect();
+    let at = headers
+        .iter()
+        .position(|h| matches!(h, Header::ContentLength(_)))
+        .unwrap_or(headers.len());
+    headers.insert(at, header);
+    *msg.headers_mut() = headers.into();
+    Some(serialize(msg, compact))
+}
+
+/// A caller-owned Reason header value (RFC 3326) such as
+/// `SIP;cause=200;text="Call completed elsewhere"`; `text` may be NULL. NULL if
+/// `protocol` is NULL or not a token, or `text` contains control characters.
+#[no_mangle]
+pub extern "C" fn rsip_build_reason(
+    protocol: *const c_char,
+    cause: u32,
+    text: *const c_char,
+) -> *mut c_char {
+    str_arg(protocol)
+        .and_then(|protocol| build_reason(protocol, cause, str_arg(text)))
+        .map_or(std::ptr::null_mut(), into_c_string)
+}
+
+/// Parses a Reason header (the name is optional) into a caller-owned JSON array of
+/// `{protocol, cause, text}`. NULL if it is NULL or malformed.
+#[no_mangle]
+pub extern "C" fn rsip_parse_reason(header: *const c_char) -> *mut c_char {
+    let reasons = match str_arg(header).and_then(parse_reason) {
+        Some(reasons) => reasons,
+        None => return std::ptr::null_mut(),
+    };
+    let reasons: Vec<serde_json::Value> = reasons
+        .into_iter()
+        .map(|r| json!({ "protocol": r.protocol, "cause": r.cause, "text": r.text }))
+        .collect();
+    into_c_string(serde_json::Value::from(reasons).to_string())
+}
+
+/// A caller-owned Warning header value (RFC 3261 section 20.43) such as
+/// `399 pbx.example.com "Codec fallback"`. NULL if `code` is not three digits,
+/// `agent` is NULL or not a host or token, or `text` is NULL or contains control
+/// characters.
+#[no_mangle]
+pub extern "C" fn rsip_build_warning(
+    code: u16,
+    agent: *const c_char,
+    text: *const c_char,
+) -> *mut c_char {
+    match (str_arg(agent), str_arg(text)) {
+        (Some(agent), Some(text)) => {
+            build_warning(code, agent, text).map_or(std::ptr::null_mut(), into_c_string)
+        }
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Parses a Warning header (the name is optional) into a caller-owned JSON array of
+/// `{code, agent, text}`. NULL if it is NULL or malformed.
+#[no_mangle]
+pub extern "C" fn rsip_parse_warning(header: *const c_char) -> *mut c_char {
+    let warnings = match str_arg(header).and_then(parse_warning) {
+        Some(warnings) => warnings,
+        None => return std::ptr::null_mut(),
+    };
+    let warnings: Vec<serde_json::Value> = warnings
+        .into_iter()
+        .map(|w| json!({ "code": w.code, "agent": w.agent, "text": w.text }))
+        .collect();
+    into_c_string(serde_json::Value::from(warnings).to_string())
+}
+
+/// Adds a Reason header with the value `reason` (e.g. from `rsip_build_reason`) to
+/// `raw_message`, typically a BYE or CANCEL from the builders or a response from
+/// `rsip_build_response`. Returns the caller-owned message, or NULL if either argument
+/// is NULL, the message does not parse or `reason` is not a Reason value.
+#[no_mangle]
+pub extern "C" fn rsip_add_reason(
+    raw_message: *const c_char,
+    reason: *const c_char,
+) -> *mut c_char {
+    let compact = crate::default_context().message_defaults().compact;
+    match (str_arg(raw_message), str_arg(reason)) {
+        (Some(raw), Some(reason)) => {
+            add_reason(raw, reason, compact).map_or(std::ptr::null_mut(), into_c_string)
+        }
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::rsip_free_string;
+    use std::ffi::{CStr, CString};
+
+    #[test]
+    fn builds_and_parses_reasons() {
+        let built = build_reason("SIP", 200, Some("Call completed \"elsewhere\"")).unwrap();
+        assert_eq!(
+            built,
+            "SIP;cause=200;text=\"Call completed \\\"elsewhere\\\"\""
+        );
+        assert_eq!(
+            parse_reason(&built).unwrap(),
+            [Reason {
+                protocol: "SIP".into(),
+                cause: Some(200),
+                text: Some("Call completed \"elsewhere\"".into()),
+            }]
+        );
+        assert_eq!(build_reason("Q.850", 16, None).unwrap(), "Q.850;cause=16");
+        assert_eq!(build_reason("bad protocol", 16, None), None);
+        assert_eq!(build_reason("SIP", 200, Some("a\r\nVia: x")), None);
+
+        let reasons =
+            parse_reason("Reason: Q.850 ;cause=16 ;text=\"Normal, clearing\", SIP;cause=487;x=1")
+                .unwrap();
+        assert_eq!(reasons.len(), 2);
+        assert_eq!(reasons[0].protocol, "Q.850");
+        assert_eq!(reasons[0].text.as_deref(), Some("Normal, clearing"));
+        assert_eq!(reasons[1].cause, Some(487));
+        assert_eq!(reasons[1].text, None);
+        assert_eq!(parse_reason("SIP;cause=abc"), None);
+        assert_eq!(parse_reason("SIP;text=\"open"), None);
+    }
+
+    #[test]
+    fn builds_and_parses_warnings() {
+        let built = build_warning(370, "devnull", "Choose a bigger pipe").unwrap();
+        assert_eq!(built, "370 devnull \"Choose a bigger pipe\"");
+        assert_eq!(
+            build_warning(307, "[2001:db8::1]:5060", "x").as_deref(),
+            Some("307 [2001:db8::1]:5060 \"x\"")
+        );
+        assert_eq!(build_warning(37, "devnull", "x"), None);
+        assert_eq!(build_warning(370, "dev null", "x"), None);
+
+        let warnings = parse_warning(
+            "Warning: 301 isi.edu \"Incompatible network address type 'E.164'\", 399 pbx \"a, b\"",
+        )
+        .unwrap();
+        assert_eq!(
+            warnings,
+            [
+                Warning {
+                    code: 301,
+                    agent: "isi.edu".into(),
+                    text: "Incompatible network address type 'E.164'".into(),
+                },
+                Warning {
+                    code: 399,
+                    agent: "pbx".into(),
+                    text: "a, b".into(),
+                },
+            ]
+        );
+        assert_eq!(parse_warning("399 pbx"), None);
+        assert_eq!(parse_warning("39 pbx \"x\""), None);
+    }
+
+    #[test]
+    fn adds_a_reason_before_content_length() {
+        let bye = "BYE sip:bob@192.0.2.4 SIP/2.0\r\n\
+            Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKbye\r\n\
+            Call-ID: bye@192.0.2.1\r\n\
+            CSeq: 2 BYE\r\n\
+            Content-Length: 0\r\n\r\n";
+        let reason = CString::new(build_reason("Q.850", 16, None).unwrap()).unwrap();
+        let raw = CString::new(bye).unwrap();
+        let out = rsip_add_reason(raw.as_ptr(), reason.as_ptr());
+        assert_eq!(
+            unsafe { CStr::from_ptr(out) }.to_str().unwrap(),
+            bye.replace("Content-Length", "Reason: Q.850;cause=16\r\nContent-Length")
+        );
+        rsip_free_string(out);
+
+        let bad = CString::new("not a reason").unwrap();
+        assert!(rsip_add_reason(raw.as_ptr(), bad.as_ptr()).is_null());
+        assert!(rsip_add_reason(raw.as_ptr(), std::ptr::null()).is_null());
+        assert!(rsip_add_reason(bad.as_ptr(), reason.as_ptr()).is_null());
+    }
+}
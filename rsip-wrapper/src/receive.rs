@@ -0,0 +1,933 @@
+//! The receive pipeline every inbound datagram goes through before reaching the host.
+
+use crate::builder::{build_response, serialize, MessageDefaults};
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::lock::Lock;
+use crate::log::LogLevel;
+use crate::send::bytes_args;
+use crate::stats::Stats;
+use crate::{nat, parse, raw, sdp, validate};
+use rsip::headers::{Header, UntypedHeader};
+use rsip::prelude::*;
+use rsip::{Method, Request, SipMessage};
+use serde_json::json;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::os::raw::c_char;
+
+impl RsipContext {
+    /// Drops received messages larger than `max_bytes` (0, the default, accepts any
+    /// size) before any callback sees them, answering those that parse as requests with
+    /// 513 Message Too Large if `respond` is set. Takes effect immediately.
+    pub fn set_max_request_bytes(&self, max_bytes: usize, respond: bool) {
+        let mut config = self.config.locked();
+        config.max_request_bytes = max_bytes;
+        config.respond_too_large = respond;
+    }
+
+    /// Drops received messages with more than `max_headers` header lines before they
+    /// are parsed (0, the default, accepts any number), reporting them as
+    /// `too_many_headers`. Takes effect immediately.
+    pub fn set_max_headers(&self, max_headers: usize) {
+        self.config.locked().max_headers = max_headers;
+    }
+
+    /// Answers every INVITE received from now on with 100 Trying (RFC 3261 section
+    /// 17.2.1) before the host sees it, so the client stops retransmitting while the
+    /// host decides. Off by default.
+    pub fn set_auto_trying(&self, enabled: bool) {
+        self.config.locked().auto_trying = enabled;
+    }
+
+    /// Runs `data` through the pipeline of a datagram the UDP listener received from
+    /// `src` (source filter, rate limits, worker pool, then parsing and events), with no
+    /// socket involved. The checks tied to a listener socket, the martian filter and
+    /// packet capture, are skipped. Empty input is ignored, like an empty datagram.
+    pub fn feed_bytes(&self, data: &[u8], src: SocketAddr) {
+        if data.is_empty() {
+            return;
+        }
+        self.dispatch(data, SocketAddr::new(src.ip().to_canonical(), src.port()));
+    }
+
+    #[cfg(test)]
+    pub(crate) fn handle_datagram(&self, data: &[u8], src: SocketAddr) {
+        self.handle_datagram_on(data, src, None, None);
+    }
+
+    /// Parses a message and emits its events. Those with a JSON object payload carry
+    /// the id of the UDP listener it arrived on, if any, as `listener`, and its kernel
+    /// receive timestamp, if any, as `rx_ts_ns`.
+    pub(crate) fn handle_datagram_on(
+        &self,
+        data: &[u8],
+        src: SocketAddr,
+        listener: Option<u64>,
+        rx_ts_ns: Option<u64>,
+    ) {
+        let tag = |payload: &mut serde_json::Value| {
+            if let Some(id) = listener {
+                payload["listener"] = json!(id);
+            }
+            if let Some(ts) = rx_ts_ns {
+                payload["rx_ts_ns"] = json!(ts);
+            }
+        };
+        Stats::add(&self.stats.packets_received, 1);
+        Stats::add(&self.stats.bytes_received, data.len() as u64);
+        if self.reject_too_many_headers(data, src, listener)
+            || self.reject_too_large(data, src, listener)
+        {
+            return;
+        }
+        // With only a raw callback, no transaction waiting and no dialog to track,
+        // nobody needs the parsed message: skip the owned strings and JSON (and
+        // `parse_failures`).
+        if self.emit_raw(data, src)
+            && !self.events.has_subscribers()
+            && !self.events.has_handlers()
+            && self.waiters.locked().is_empty()
+            && !self.config.locked().auto_trying
+            && !self.dialog_relevant(data)
+        {
+            return;
+        }
+        let msg = String::from_utf8_lossy(data);
+        self.log(
+            LogLevel::Debug,
+            format_args!("received {} bytes from {}", data.len(), src),
+        );
+        self.emit_bytes_from("sip_rx", &msg, data, src);
+
+        let (parsed, since) = self.parse_cached(data);
+        match parsed {
+            Ok(parsed) => {
+                if let SipMessage::Request(request) = &*parsed {
+                    if request.method == Method::Invite {
+                        self.send_trying(request, &msg, src, listener);
+                    }
+                }
+                let mut summary = parse::summarize(&parsed);
+                summary["src"] = json!(src.to_string());
+                tag(&mut summary);
+                let summary_text = summary.to_string();
+                if let Some(since) = since {
+                    let mut payload = json!({
+                        "call_id": summary["call_id"],
+                        "cseq": summary["cseq"],
+                        "src": src.to_string(),
+                        "since_ms": since.as_millis() as u64,
+                    });
+                    tag(&mut payload);
+                    self.emit_from("sip_rx_retransmission", &payload.to_string(), src);
+                }
+                self.emit_from("sip_rx_parsed", &summary_text, src);
+                let violations = validate::validate(&parsed);
+                if !violations.is_empty() {
+                    let mut payload = json!({
+                        "call_id": summary["call_id"],
+                        "src": src.to_string(),
+                        "violations": violations,
+                    });
+                    tag(&mut payload);
+                    self.emit_from("sip_rx_invalid", &payload.to_string(), src);
+                }
+                self.emit_sdp(&parsed, &summary, src);
+                self.route(&parsed, &msg, &summary_text, src);
+                self.route_body(&parsed, &summary_text);
+                self.dialog_received(&parsed, src);
+                if let SipMessage::Response(response) = &*parsed {
+                    self.deliver_response(response);
+                }
+            }
+            Err(e) => {
+                Stats::add(&self.stats.parse_failures, 1);
+                self.log(
+                    LogLevel::Debug,
+                    format_args!("unparsable message from {}: {}", src, e),
+                );
+                let mut payload =
+                    json!({ "error": e.to_string(), "raw": msg, "src": src.to_string() });
+                tag(&mut payload);
+                self.emit_from("sip_rx_malformed", &payload.to_string(), src);
+            }
+        }
+    }
+
+    /// Drops `data` if it is larger than `max_request_bytes`, before any callback sees
+    /// it: emits `too_large` and, if enabled and `data` parses as a request, answers 513
+    /// Message Too Large (RFC 3261 section 21.5.11) from the listener it arrived on.
+    /// Returns whether `data` was dropped.
+    fn reject_too_large(&self, data: &[u8], src: SocketAddr, listener: Option<u64>) -> bool {
+        let len = data.len();
+        let (max, respond, defaults) = {
+            let config = self.config.locked();
+            let defaults = config.message_defaults.clone();
+            (config.max_request_bytes, config.respond_too_large, defaults)
+        };
+        if max == 0 || len <= max {
+            return false;
+        }
+        let parsed = SipMessage::try_from(data).ok();
+        let request = match &parsed {
+            Some(SipMessage::Request(request)) => Some(request),
+            _ => None,
+        };
+        // ACK is never answered; build_response refuses it.
+        let responded = respond
+            && request
+                .is_some_and(|request| self.respond_too_large(request, src, listener, &defaults));
+        self.log(
+            LogLevel::Debug,
+            format_args!("dropped {} byte message from {}", len, src),
+        );
+        let mut payload = json!({
+            "src": src.to_string(),
+            "len": len,
+            "max": max,
+            "method": request.map(|r| r.method().to_string()),
+            "call_id": parsed
+                .as_ref()
+                .and_then(|msg| msg.call_id_header().ok())
+                .map(|h| h.value().to_string()),
+            "responded": responded,
+        });
+        if let Some(id) = listener {
+            payload["listener"] = json!(id);
+        }
+        self.emit_from("too_large", &payload.to_string(), src);
+        true
+    }
+
+    /// Answers `request` with 513 Message Too Large; returns whether it was sent.
+    fn respond_too_large(
+        &self,
+        request: &Request,
+        src: SocketAddr,
+        listener: Option<u64>,
+        defaults: &MessageDefaults,
+    ) -> bool {
+        build_response(
+            request,
+            513,
+            Some("Message Too Large"),
+            None,
+            defaults.user_agent.as_deref(),
+        )
+        .map_err(|_| RsipError::InvalidArgument)
+        .and_then(|response| {
+            let response = serialize(response, defaults.compact);
+            let ip = src.ip().to_string();
+            self.send_from_listener_on(listener, &ip, src.port(), response.as_bytes())
+        })
+        .is_ok()
+    }
+
+    /// Drops `data` if it has more than `max_headers` header lines: counted without
+    /// parsing or allocating, so a message built with thousands of them costs no more
+    /// than a scan. Emits `too_many_headers` and returns whether `data` was dropped.
+    fn reject_too_many_headers(&self, data: &[u8], src: SocketAddr, listener: Option<u64>) -> bool {
+        let max = self.config.locked().max_headers;
+        if max == 0 || !raw::exceeds_header_count(data, max) {
+            return false;
+        }
+        self.log(
+            LogLevel::Debug,
+            format_args!("dropped message with over {} headers from {}", max, src),
+        );
+        let mut payload = json!({
+            "src": src.to_string(),
+            "len": data.len(),
+            "max": max,
+        });
+        if let Some(id) = listener {
+            payload["listener"] = json!(id);
+        }
+        self.emit_from("too_many_headers", &payload.to_string(), src);
+        true
+    }
+
+    /// Answers the INVITE `raw` with 100 Trying if `auto_trying` is set, sent from the
+    /// listener it arrived on to its response destination once `received`/`rport`
+    /// are recorded for `src`, and emits `trying_sent`. Retransmissions are answered
+    /// again, as the server transaction would.
+    fn send_trying(&self, request: &Request, raw: &str, src: SocketAddr, listener: Option<u64>) {
+        let defaults = {
+            let config = self.config.locked();
+            if !config.auto_trying {
+                return;
+            }
+            config.message_defaults.clone()
+        };
+        let answered = nat::apply_rport(raw, src);
+        let request = match answered.as_deref().map(SipMessage::try_from) {
+            Some(Ok(SipMessage::Request(answered))) => answered,
+            _ => request.clone(),
+        };
+        let dest = answered
+            .as_deref()
+            .and_then(nat::response_destination)
+            .and_then(|dest| {
+                let (host, port) = dest.rsplit_once(':')?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                Some((host.to_string(), port.parse::<u16>().ok()?))
+            })
+            .unwrap_or_else(|| (src.ip().to_string(), src.port()));
+        let server = defaults.user_agent.as_deref();
+        let sent = build_response(&request, 100, Some("Trying"), None, server)
+            .map_err(|_| RsipError::InvalidArgument)
+            .and_then(|response| {
+                let response = serialize(response, defaults.compact);
+                self.send_from_listener_on(listener, &dest.0, dest.1, response.as_bytes())
+            });
+        if let Err(e) = sent {
+            self.log(
+                LogLevel::Debug,
+                format_args!(
+                    "could not send 100 Trying to {}:{}: {:?}",
+                    dest.0, dest.1, e
+                ),
+            );
+            return;
+        }
+        let mut payload = json!({
+            "call_id": request.call_id_header().ok().map(|h| h.value().to_string()),
+            "src": src.to_string(),
+            "dest": crate::send::host_port(&dest.0, dest.1),
+        });
+        if let Some(id) = listener {
+            payload["listener"] = json!(id);
+        }
+        self.emit_from("trying_sent", &payload.to_string(), src);
+    }
+
+    /// Emits `sdp_parsed` for a message whose body is `application/sdp`. A body that
+    /// does not parse as SDP is silently skipped; `sip_rx` still carries it.
+    fn emit_sdp(&self, msg: &SipMessage, summary: &serde_json::Value, src: SocketAddr) {
+        let is_sdp = msg.headers().iter().any(|h| match h {
+            Header::ContentType(ct) => ct
+                .value()
+                .trim()
+                .to_ascii_lowercase()
+                .starts_with("application/sdp"),
+            _ => false,
+        });
+        if !is_sdp || msg.body().is_empty() {
+            return;
+        }
+        if let Ok(sdp) = sdp::parse(&String::from_utf8_lossy(msg.body())) {
+            let mut payload = json!({
+                "call_id": summary["call_id"],
+                "cseq": summary["cseq"],
+                "src": src.to_string(),
+                "sdp": sdp,
+            });
+            if let Some(listener) = summary.get("listener") {
+                payload["listener"] = listener.clone();
+            }
+            self.emit_from("sdp_parsed", &payload.to_string(), src);
+        }
+    }
+}
+
+fn feed_args<'a>(
+    data: *const u8,
+    len: usize,
+    src_ip: *const c_char,
+    src_port: u16,
+) -> Result<(&'a [u8], SocketAddr), RsipError> {
+    let (ip, data) = bytes_args(src_ip, data, len)?;
+    let ip: IpAddr = ip.parse().map_err(|_| RsipError::InvalidArgument)?;
+    Ok((data, SocketAddr::new(ip, src_port)))
+}
+
+/// Handles `len` bytes at `data` as if they had arrived from `src_ip:src_port`, firing
+/// the usual events. False if `src_ip` is NULL or not an IP address, or `data` is NULL
+/// with a non-zero `len`.
+#[no_mangle]
+pub extern "C" fn rsip_feed_bytes(
+    data: *const u8,
+    len: usize,
+    src_ip: *const c_char,
+    src_port: u16,
+) -> bool {
+    feed_args(data, len, src_ip, src_port)
+        .map(|(data, src)| crate::default_context().feed_bytes(data, src))
+        .is_ok()
+}
+
+/// Drops received messages larger than `max_bytes` with a `too_large` event, and
+/// answers requests among them with 513 Message Too Large if `respond` is set; 0
+/// accepts any size.
+#[no_mangle]
+pub extern "C" fn rsip_set_max_request_bytes(max_bytes: usize, respond: bool) {
+    crate::default_context().set_max_request_bytes(max_bytes, respond);
+}
+
+/// Drops received messages with more than `max_headers` header lines unparsed,
+/// reporting each as `too_many_headers`; 0 (the default) accepts any number.
+#[no_mangle]
+pub extern "C" fn rsip_set_max_headers(max_headers: usize) {
+    crate::default_context().set_max_headers(max_headers);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_max_headers(ctx: *mut RsipContext, max_headers: usize) {
+    with_context(ctx, |ctx| ctx.set_max_headers(max_headers));
+}
+
+/// Answers received INVITEs with 100 Trying right away (built like
+/// `rsip_build_response` and sent to the response destination), emitting
+/// `trying_sent` for each.
+#[no_mangle]
+pub extern "C" fn rsip_auto_trying(enabled: bool) {
+    crate::default_context().set_auto_trying(enabled);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_auto_trying(ctx: *mut RsipContext, enabled: bool) {
+    with_context(ctx, |ctx| ctx.set_auto_trying(enabled));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_max_request_bytes(
+    ctx: *mut RsipContext,
+    max_bytes: usize,
+    respond: bool,
+) {
+    with_context(ctx, |ctx| ctx.set_max_request_bytes(max_bytes, respond));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_feed_bytes(
+    ctx: *mut RsipContext,
+    data: *const u8,
+    len: usize,
+    src_ip: *const c_char,
+    src_port: u16,
+) -> bool {
+    match feed_args(data, len, src_ip, src_port) {
+        Ok((data, src)) => with_context(ctx, |ctx| ctx.feed_bytes(data, src)).is_some(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use serde_json::Value;
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    static EVENTS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(event: *const c_char, payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }
+            .to_string_lossy()
+            .into_owned();
+        let payload = unsafe { CStr::from_ptr(payload) }
+            .to_string_lossy()
+            .into_owned();
+        EVENTS.lock().unwrap().push((event, payload));
+    }
+
+    fn payload_of(event: &str) -> Option<Value> {
+        EVENTS
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(e, _)| e == event)
+            .map(|(_, p)| serde_json::from_str(p).unwrap())
+    }
+
+    #[test]
+    fn oversized_requests_get_513() {
+        static TOO_LARGE: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+        extern "C" fn record_too_large(_: *const c_char, payload: *const c_char) {
+            let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+            TOO_LARGE
+                .lock()
+                .unwrap()
+                .push(serde_json::from_str(payload).unwrap());
+        }
+
+        let ctx = std::sync::Arc::new(RsipContext::new());
+        ctx.events.subscribe(
+            Some(vec!["too_large".into()]),
+            Sink::Basic(record_too_large),
+        );
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        let id = ctx.add_udp_listener(0).unwrap();
+        let addr = ctx.local_addr().unwrap();
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let request = |method: &str, subject_len: usize| {
+            format!(
+                "{} sip:bob@example.com SIP/2.0\r\n\
+                 Via: SIP/2.0/UDP 127.0.0.1;branch=z9hG4bKbig\r\n\
+                 From: <sip:alice@example.com>;tag=1\r\n\
+                 To: <sip:bob@example.com>\r\n\
+                 Call-ID: big@127.0.0.1\r\n\
+                 CSeq: 1 {}\r\n\
+                 Subject: {}\r\n\
+                 Content-Length: 0\r\n\r\n",
+                method,
+                method,
+                "x".repeat(subject_len)
+            )
+        };
+
+        ctx.set_max_request_bytes(400, true);
+        let big = request("MESSAGE", 400);
+        peer.send_to(big.as_bytes(), addr).unwrap();
+        let mut buf = [0u8; 2048];
+        let n = peer.recv(&mut buf).unwrap();
+        let response = String::from_utf8_lossy(&buf[..n]);
+        assert!(
+            response.starts_with("SIP/2.0 513 Message Too Large\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("Call-ID: big@127.0.0.1"));
+        // the listener thread reports it right after answering
+        for _ in 0..200 {
+            if !TOO_LARGE.lock().unwrap().is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+
+        // ACK is never answered; small requests pass.
+        ctx.handle_datagram_on(
+            request("ACK", 400).as_bytes(),
+            peer.local_addr().unwrap(),
+            Some(id),
+            None,
+        );
+        ctx.handle_datagram(
+            request("MESSAGE", 10).as_bytes(),
+            peer.local_addr().unwrap(),
+        );
+        ctx.shutdown();
+
+        let events = TOO_LARGE.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["len"], big.len());
+        assert_eq!(events[0]["max"], 400);
+        assert_eq!(events[0]["method"], "MESSAGE");
+        assert_eq!(events[0]["call_id"], "big@127.0.0.1");
+        assert_eq!(events[0]["responded"], true);
+        assert_eq!(events[0]["listener"], id);
+        assert_eq!(events[1]["method"], "ACK");
+        assert_eq!(events[1]["responded"], false);
+    }
+
+    #[test]
+    fn oversized_messages_reach_no_callback() {
+        static RAW: AtomicUsize = AtomicUsize::new(0);
+        static SEEN: Mutex<Vec<(String, Value)>> = Mutex::new(Vec::new());
+        extern "C" fn count_raw(_: *const raw::RsipRawMessage) {
+            RAW.fetch_add(1, Ordering::SeqCst);
+        }
+        extern "C" fn record_all(event: *const c_char, payload: *const c_char) {
+            let event = unsafe { CStr::from_ptr(event) }.to_str().unwrap();
+            let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+            let payload = serde_json::from_str(payload).unwrap_or(Value::Null);
+            SEEN.lock().unwrap().push((event.to_string(), payload));
+        }
+
+        let ctx = RsipContext::new();
+        ctx.events.set_raw_callback(Some(count_raw));
+        ctx.events.subscribe(None, Sink::Basic(record_all));
+        ctx.set_max_request_bytes(200, true);
+        let src: SocketAddr = "192.0.2.1:5060".parse().unwrap();
+        let padding = "x".repeat(200);
+        let request = format!(
+            "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+             Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKhuge\r\n\
+             From: <sip:alice@example.com>;tag=1\r\n\
+             To: <sip:bob@example.com>\r\n\
+             Call-ID: huge@192.0.2.1\r\n\
+             CSeq: 1 OPTIONS\r\n\
+             Subject: {}\r\n\
+             Content-Length: 0\r\n\r\n",
+            padding
+        );
+        let response = request.replace("OPTIONS sip:bob@example.com SIP/2.0", "SIP/2.0 200 OK");
+        ctx.handle_datagram(request.as_bytes(), src);
+        ctx.handle_datagram(response.as_bytes(), src);
+        ctx.handle_datagram(padding.repeat(2).as_bytes(), src);
+
+        assert_eq!(RAW.load(Ordering::SeqCst), 0);
+        let seen = SEEN.lock().unwrap();
+        assert!(
+            seen.iter().all(|(event, _)| event == "too_large"),
+            "{:?}",
+            seen
+        );
+        assert_eq!(seen.len(), 3);
+        assert_eq!(seen[0].1["method"], "OPTIONS");
+        // no listener to answer from
+        assert_eq!(seen[0].1["responded"], false);
+        assert_eq!(seen[1].1["method"], Value::Null);
+        assert_eq!(seen[1].1["call_id"], "huge@192.0.2.1");
+        assert_eq!(seen[2].1["call_id"], Value::Null);
+        assert_eq!(seen[2].1["len"], 400);
+    }
+
+    #[test]
+    fn invites_get_100_trying_when_enabled() {
+        static TRYING: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+        extern "C" fn record_trying(_: *const c_char, payload: *const c_char) {
+            let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+            TRYING
+                .lock()
+                .unwrap()
+                .push(serde_json::from_str(payload).unwrap());
+        }
+
+        let ctx = std::sync::Arc::new(RsipContext::new());
+        ctx.events
+            .subscribe(Some(vec!["trying_sent".into()]), Sink::Basic(record_trying));
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        let id = ctx.add_udp_listener(0).unwrap();
+        let addr = ctx.local_addr().unwrap();
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_millis(300)))
+            .unwrap();
+        let port = peer.local_addr().unwrap().port();
+        let request = |method: &str| {
+            format!(
+                "{} sip:bob@example.com SIP/2.0\r\n\
+                 Via: SIP/2.0/UDP 192.0.2.7:5070;rport;branch=z9hG4bKtry\r\n\
+                 From: <sip:alice@example.com>;tag=1\r\n\
+                 To: <sip:bob@example.com>\r\n\
+                 Call-ID: try@192.0.2.7\r\n\
+                 CSeq: 1 {}\r\n\
+                 Content-Length: 0\r\n\r\n",
+                method, method
+            )
+        };
+        let mut buf = [0u8; 2048];
+
+        peer.send_to(request("INVITE").as_bytes(), addr).unwrap();
+        assert!(peer.recv(&mut buf).is_err(), "off by default");
+
+        ctx.set_auto_trying(true);
+        peer.send_to(request("INVITE").as_bytes(), addr).unwrap();
+        let n = peer.recv(&mut buf).unwrap();
+        let response = String::from_utf8_lossy(&buf[..n]);
+        assert!(
+            response.starts_with("SIP/2.0 100 Trying\r\n"),
+            "{}",
+            response
+        );
+        // rport brought it back here rather than to the Via sent-by
+        assert!(
+            response.contains(&format!(";rport={};", port)),
+            "{}",
+            response
+        );
+        assert!(response.contains(";received=127.0.0.1\r\n"));
+        assert!(response.contains("To: <sip:bob@example.com>\r\n"), "no tag");
+        for _ in 0..200 {
+            if !TRYING.lock().unwrap().is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        peer.send_to(request("OPTIONS").as_bytes(), addr).unwrap();
+        assert!(peer.recv(&mut buf).is_err(), "only INVITEs");
+        ctx.shutdown();
+
+        let events = TRYING.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["call_id"], "try@192.0.2.7");
+        assert_eq!(events[0]["dest"], format!("127.0.0.1:{}", port));
+        assert_eq!(events[0]["listener"], id);
+    }
+
+    #[test]
+    fn messages_with_too_many_headers_are_dropped_unparsed() {
+        static DROPPED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+        extern "C" fn record_dropped(event: *const c_char, payload: *const c_char) {
+            let event = unsafe { CStr::from_ptr(event) }.to_str().unwrap();
+            let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+            DROPPED
+                .lock()
+                .unwrap()
+                .push((event.to_string(), payload.to_string()));
+        }
+
+        let ctx = RsipContext::new();
+        ctx.set_callback(record_dropped);
+        let src: SocketAddr = "192.0.2.8:5060".parse().unwrap();
+        let message = |headers: usize| {
+            let mut raw = "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+                Via: SIP/2.0/UDP 192.0.2.8;branch=z9hG4bKmany\r\n\
+                Call-ID: many@192.0.2.8\r\n\
+                CSeq: 1 OPTIONS\r\n"
+                .to_string();
+            for i in 0..headers {
+                raw.push_str(&format!("X-Filler-{}: {}\r\n", i, i));
+            }
+            raw + "Content-Length: 0\r\n\r\n"
+        };
+        let huge = message(10_000);
+        ctx.handle_datagram(huge.as_bytes(), src);
+        assert!(
+            DROPPED
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|(e, _)| e == "sip_rx_parsed"),
+            "accepted by default"
+        );
+
+        DROPPED.lock().unwrap().clear();
+        ctx.set_max_headers(64);
+        ctx.handle_datagram(huge.as_bytes(), src);
+        {
+            let events = DROPPED.lock().unwrap();
+            assert_eq!(events.len(), 1, "{:?}", events);
+            assert_eq!(events[0].0, "too_many_headers");
+            let payload: Value = serde_json::from_str(&events[0].1).unwrap();
+            assert_eq!(payload["src"], "192.0.2.8:5060");
+            assert_eq!(payload["len"], huge.len());
+            assert_eq!(payload["max"], 64);
+        }
+
+        DROPPED.lock().unwrap().clear();
+        ctx.handle_datagram(message(60).as_bytes(), src);
+        let events = DROPPED.lock().unwrap();
+        assert!(events.iter().any(|(e, _)| e == "sip_rx_parsed"));
+        assert!(events.iter().all(|(e, _)| e != "too_many_headers"));
+    }
+
+    #[test]
+    fn parsed_and_malformed_events() {
+        let ctx = RsipContext::new();
+        ctx.set_callback(record);
+        let src: SocketAddr = "127.0.0.1:5060".parse().unwrap();
+
+        let invite = "INVITE sip:bob@biloxi.example.com SIP/2.0\r\n\
+            Via: SIP/2.0/UDP pc33.atlanta.example.com;branch=z9hG4bK776asdhds\r\n\
+            Max-Forwards: 70\r\n\
+            To: Bob <sip:bob@biloxi.example.com>\r\n\
+            From: Alice <sip:alice@atlanta.example.com>;tag=1928301774\r\n\
+            Call-ID: a84b4c76e66710@pc33.atlanta.example.com\r\n\
+            CSeq: 314159 INVITE\r\n\
+            Content-Length: 0\r\n\r\n";
+        ctx.handle_datagram(invite.as_bytes(), src);
+        ctx.handle_datagram(b"garbage\r\n\r\n", src);
+
+        let parsed = payload_of("sip_rx_parsed").expect("parsed event");
+        assert_eq!(parsed["kind"], "request");
+        assert_eq!(parsed["method"], "INVITE");
+        assert_eq!(parsed["call_id"], "a84b4c76e66710@pc33.atlanta.example.com");
+        assert_eq!(parsed["cseq"]["seq"], 314159);
+        assert_eq!(parsed["cseq"]["method"], "INVITE");
+        assert_eq!(parsed["from_tag"], "1928301774");
+        assert_eq!(parsed["to_tag"], Value::Null);
+        assert_eq!(parsed["via_branch"], "z9hG4bK776asdhds");
+        assert_eq!(parsed["src"], "127.0.0.1:5060");
+
+        let malformed = payload_of("sip_rx_malformed").expect("malformed event");
+        assert_eq!(malformed["raw"], "garbage\r\n\r\n");
+        assert!(!malformed["error"].as_str().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fed_bytes_take_the_listener_path() {
+        static FED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+        extern "C" fn record_fed(event: *const c_char, payload: *const c_char) {
+            let event = unsafe { CStr::from_ptr(event) }.to_str().unwrap();
+            let payload = unsafe { CStr::from_ptr(payload) }.to_string_lossy();
+            FED.lock()
+                .unwrap()
+                .push((event.to_string(), payload.into_owned()));
+        }
+
+        let ctx = crate::context::rsip_context_new();
+        let options = b"OPTIONS sip:bob@example.com SIP/2.0\r\n\
+            Via: SIP/2.0/UDP 192.0.2.9;branch=z9hG4bKfeed\r\n\
+            Max-Forwards: 70\r\n\
+            From: <sip:alice@example.com>;tag=1\r\n\
+            To: <sip:bob@example.com>\r\n\
+            Call-ID: feed@192.0.2.9\r\n\
+            CSeq: 1 OPTIONS\r\n\
+            Content-Length: 0\r\n\r\n";
+        let src_ip = std::ffi::CString::new("192.0.2.9").unwrap();
+        let bad_ip = std::ffi::CString::new("not-an-ip").unwrap();
+        let feed = |data: &[u8], ip: &std::ffi::CString| {
+            rsip_context_feed_bytes(ctx, data.as_ptr(), data.len(), ip.as_ptr(), 5070)
+        };
+        with_context(ctx, |ctx| ctx.set_callback(record_fed));
+
+        assert!(feed(options, &src_ip));
+        assert!(!feed(options, &bad_ip));
+        assert!(!rsip_context_feed_bytes(
+            ctx,
+            std::ptr::null(),
+            4,
+            src_ip.as_ptr(),
+            5070
+        ));
+        // the source filter applies as it does to the listener
+        with_context(ctx, |ctx| {
+            ctx.set_ip_filter_mode(crate::ipfilter::FilterMode::Deny);
+            ctx.ip_filter_add("192.0.2.0/24").unwrap();
+        });
+        assert!(feed(options, &src_ip));
+        with_context(ctx, |ctx| {
+            ctx.set_ip_filter_mode(crate::ipfilter::FilterMode::Off)
+        });
+        // arbitrary input never panics
+        for len in 0..512 {
+            let junk: Vec<u8> = (0..len).map(|_| rand::random::<u8>()).collect();
+            assert!(feed(&junk, &src_ip));
+            let mut cut = options.to_vec();
+            cut.truncate(len.min(cut.len()));
+            assert!(feed(&cut, &src_ip));
+        }
+
+        let fed = FED.lock().unwrap();
+        let parsed: Vec<Value> = fed
+            .iter()
+            .filter(|(e, _)| e == "sip_rx_parsed")
+            .map(|(_, p)| serde_json::from_str(p).unwrap())
+            .collect();
+        assert_eq!(parsed[0]["src"], "192.0.2.9:5070");
+        assert_eq!(parsed[0]["method"], "OPTIONS");
+        // 1 accepted, 1 filtered, then 511 non-empty junk inputs and truncations
+        let received = with_context(ctx, |ctx| {
+            ctx.stats
+                .packets_received
+                .load(std::sync::atomic::Ordering::Relaxed)
+        })
+        .unwrap();
+        assert_eq!(received, 1 + 2 * 511);
+        crate::context::rsip_context_free(ctx);
+    }
+
+    #[test]
+    fn bytes_callbacks_get_the_datagram_untouched() {
+        static RX: Mutex<Vec<(String, Vec<u8>, u16)>> = Mutex::new(Vec::new());
+        extern "C" fn record_bytes(
+            event: *const c_char,
+            data: *const u8,
+            len: usize,
+            _src_ip: *const c_char,
+            src_port: u16,
+        ) {
+            let event = unsafe { CStr::from_ptr(event) }
+                .to_string_lossy()
+                .into_owned();
+            let data = unsafe { std::slice::from_raw_parts(data, len) }.to_vec();
+            RX.lock().unwrap().push((event, data, src_port));
+        }
+
+        let ctx = RsipContext::new();
+        ctx.events.subscribe(
+            Some(vec!["sip_rx".into(), "sip_rx_malformed".into()]),
+            Sink::Bytes(record_bytes),
+        );
+        let src: SocketAddr = "127.0.0.1:5062".parse().unwrap();
+        // Latin-1 display name, and a binary body with a NUL.
+        let mut datagram = b"MESSAGE sip:bob@example.com SIP/2.0\r\n\
+            From: \"Jos\xe9\" <sip:jose@example.com>;tag=1\r\n\
+            Content-Length: 4\r\n\r\n"
+            .to_vec();
+        datagram.extend_from_slice(&[0xde, 0x00, 0xbe, 0xef]);
+        ctx.handle_datagram(&datagram, src);
+
+        let rx = RX.lock().unwrap();
+        assert_eq!(rx[0], ("sip_rx".to_string(), datagram.clone(), 5062));
+        // Other events carry their JSON payload.
+        assert!(rx[1..]
+            .iter()
+            .all(|(event, data, _)| event != "sip_rx"
+                && serde_json::from_slice::<Value>(data).is_ok()));
+    }
+
+    #[test]
+    fn invalid_messages_emit_sip_rx_invalid() {
+        static INVALID: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        extern "C" fn record_invalid(event: *const c_char, payload: *const c_char) {
+            if unsafe { CStr::from_ptr(event) }.to_bytes() == b"sip_rx_invalid" {
+                let payload = unsafe { CStr::from_ptr(payload) }.to_string_lossy();
+                INVALID.lock().unwrap().push(payload.into_owned());
+            }
+        }
+
+        let ctx = RsipContext::new();
+        ctx.set_callback(record_invalid);
+        let src: SocketAddr = "127.0.0.1:5060".parse().unwrap();
+        let options = "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+            Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKinv\r\n\
+            From: <sip:alice@example.com>;tag=1\r\n\
+            To: <sip:bob@example.com>\r\n\
+            Call-ID: invalid@192.0.2.1\r\n\
+            CSeq: 1 OPTIONS\r\n\r\n";
+        ctx.handle_datagram(options.as_bytes(), src);
+
+        let invalid = INVALID.lock().unwrap();
+        assert_eq!(invalid.len(), 1);
+        let payload: Value = serde_json::from_str(&invalid[0]).unwrap();
+        assert_eq!(payload["call_id"], "invalid@192.0.2.1");
+        assert_eq!(payload["src"], "127.0.0.1:5060");
+        assert_eq!(payload["violations"][0]["code"], "missing_header");
+        assert_eq!(payload["violations"][0]["header"], "Max-Forwards");
+    }
+
+    #[test]
+    fn sdp_bodies_emit_sdp_parsed() {
+        static SDP: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        extern "C" fn record_sdp(event: *const c_char, payload: *const c_char) {
+            if unsafe { CStr::from_ptr(event) }.to_bytes() == b"sdp_parsed" {
+                let payload = unsafe { CStr::from_ptr(payload) }.to_string_lossy();
+                SDP.lock().unwrap().push(payload.into_owned());
+            }
+        }
+
+        let ctx = RsipContext::new();
+        ctx.set_callback(record_sdp);
+        let src: SocketAddr = "127.0.0.1:5060".parse().unwrap();
+        let body = "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\ns=-\r\nc=IN IP4 192.0.2.1\r\n\
+            t=0 0\r\nm=audio 4000 RTP/AVP 0\r\n";
+        let invite = |content_type: &str| {
+            format!(
+                "INVITE sip:bob@example.com SIP/2.0\r\n\
+                 Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKsdp\r\n\
+                 From: <sip:alice@example.com>;tag=1\r\n\
+                 To: <sip:bob@example.com>\r\n\
+                 Call-ID: sdp@192.0.2.1\r\n\
+                 CSeq: 1 INVITE\r\n\
+                 Content-Type: {}\r\n\
+                 Content-Length: {}\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            )
+        };
+
+        ctx.handle_datagram(invite("text/plain").as_bytes(), src);
+        assert!(SDP.lock().unwrap().is_empty());
+
+        ctx.handle_datagram(invite("Application/SDP").as_bytes(), src);
+        let events = SDP.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let payload: Value = serde_json::from_str(&events[0]).unwrap();
+        assert_eq!(payload["call_id"], "sdp@192.0.2.1");
+        assert_eq!(payload["sdp"]["media"][0]["port"], 4000);
+        assert_eq!(
+            payload["sdp"]["media"][0]["connection"]["address"],
+            "192.0.2.1"
+        );
+    }
+}
@@ -0,0 +1,784 @@
+//! REGISTER client: registers an AOR from the listener socket, answers digest
+//! challenges and refreshes the binding before it expires.
+//!
+//! Each registration runs on its own thread. Requests go out through the listener
+//! socket, so responses arrive on the listener thread, which hands them back through
+//! the context's response waiters (keyed by `transaction_key`).
+
+use crate::auth::{self, DigestParams};
+use crate::builder::{build_request, serialize, RequestParts};
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::ffi::{into_c_string, str_arg};
+use crate::lock::Lock;
+use crate::random;
+use crate::send::resolve;
+use crate::transaction::{transaction_key, T1, T2, TIMER_F};
+use rsip::headers::{Header, UntypedHeader};
+use rsip::prelude::*;
+use rsip::{Request, Response, Uri};
+use serde_json::json;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::os::raw::c_char;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The Expires: 0 sent when stopping should not hold up `rsip_unregister`/shutdown.
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(2);
+/// Delay before retrying after a failed registration.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Challenges answered per request before giving up (wrong credentials).
+const MAX_AUTH_ATTEMPTS: usize = 2;
+
+/// What a registration thread can be woken up with.
+pub(crate) enum Wakeup {
+    Response(Response),
+    Stop,
+}
+
+pub(crate) struct Registration {
+    wake: Sender<Wakeup>,
+    thread: JoinHandle<()>,
+    state: Arc<Mutex<RegistrationState>>,
+}
+
+/// Where a registration stands, kept up to date by its thread for
+/// `rsip_list_registrations`.
+#[derive(Debug, Clone)]
+struct RegistrationState {
+    aor: String,
+    registrar: SocketAddr,
+    registered: bool,
+    /// The expiry the registrar last granted and when.
+    granted: Option<(u32, Instant)>,
+    /// Status of the last failed attempt, if it got a response.
+    failure_status: Option<u16>,
+    failed: bool,
+    last_activity: Instant,
+}
+
+impl RegistrationState {
+    fn to_json(&self, id: u64, now: Instant) -> serde_json::Value {
+        let state = if self.registered {
+            "registered"
+        } else if self.failed {
+            "failed"
+        } else {
+            "registering"
+        };
+        let expires_in = self.granted.map(|(expires, at)| {
+            Duration::from_secs(u64::from(expires))
+                .saturating_sub(now.duration_since(at))
+                .as_secs()
+        });
+        json!({
+            "id": id,
+            "aor": self.aor,
+            "registrar": self.registrar.to_string(),
+            "state": state,
+            "expires": self.granted.map(|(expires, _)| expires),
+            "expires_in": expires_in,
+            "status": self.failure_status,
+            "idle_ms": now.duration_since(self.last_activity).as_millis() as u64,
+        })
+    }
+}
+
+impl Registration {
+    /// Stops refreshing, sends Expires: 0 if registered and waits for the thread.
+    pub(crate) fn stop(self) {
+        let _ = self.wake.send(Wakeup::Stop);
+        let _ = self.thread.join();
+    }
+}
+
+pub(crate) enum Failure {
+    Stopped,
+    Failed { status: Option<u16>, reason: String },
+}
+
+impl Failure {
+    pub(crate) fn failed(reason: impl Into<String>) -> Self {
+        Failure::Failed {
+            status: None,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Per-registration state that stays fixed across refreshes (RFC 3261 §10.2.4).
+struct Registrar {
+    ctx: Arc<RsipContext>,
+    id: u64,
+    dest: SocketAddr,
+    aor: String,
+    request_uri: String,
+    contact: String,
+    username: String,
+    password: Option<String>,
+    call_id: String,
+    from: String,
+    via_host: String,
+    via_port: u16,
+    cseq: u32,
+    wake: Sender<Wakeup>,
+    inbox: Receiver<Wakeup>,
+    state: Arc<Mutex<RegistrationState>>,
+}
+
+impl Registrar {
+    /// Registers with `expires`, answering up to `MAX_AUTH_ATTEMPTS` challenges.
+    /// Returns the expiry granted by the registrar.
+    fn register(&mut self, expires: u32, timeout: Duration) -> Result<u32, Failure> {
+        let mut credentials: Option<Header> = None;
+        let mut expires = expires;
+        let mut attempts = 0;
+        loop {
+            let request = self.request(expires, credentials.clone())?;
+            let response = transact(
+                &self.ctx,
+                self.dest,
+                &request,
+                &self.wake,
+                &self.inbox,
+                timeout,
+            )?;
+            let status = response.status_code().code();
+            match status {
+                200..=299 => return Ok(granted_expires(&response, expires)),
+                401 | 407 if attempts < MAX_AUTH_ATTEMPTS && self.password.is_some() => {
+                    attempts += 1;
+                    credentials = Some(self.answer(&response)?);
+                }
+                // RFC 3261 §10.3: retry once with the registrar's minimum.
+                423 if attempts == 0 => {
+                    attempts += 1;
+                    expires = response
+                        .min_expires_header()
+                        .and_then(|h| h.seconds().ok())
+                        .ok_or_else(|| Failure::failed("423 without Min-Expires"))?;
+                }
+                _ => {
+                    return Err(Failure::Failed {
+                        status: Some(status),
+                        reason: response.status_code().to_string(),
+                    })
+                }
+            }
+        }
+    }
+
+    fn request(&mut self, expires: u32, credentials: Option<Header>) -> Result<Request, Failure> {
+        self.cseq += 1;
+        let defaults = self.ctx.message_defaults();
+        let mut request = build_request(&RequestParts {
+            method: "REGISTER",
+            request_uri: &self.request_uri,
+            from: &self.from,
+            to: &self.aor,
+            call_id: &self.call_id,
+            cseq: self.cseq,
+            via_host: &self.via_host,
+            via_port: self.via_port,
+            defaults: &defaults,
+        })
+        .map_err(|e| Failure::failed(e.to_string()))?;
+
+        // Keep Content-Length last.
+        request
+            .headers
+            .retain(|h| !matches!(h, Header::ContentLength(_)));
+        request
+            .headers
+            .push(rsip::headers::Contact::new(self.contact.clone()).into());
+        request
+            .headers
+            .push(rsip::headers::Expires::from(expires).into());
+        if let Some(credentials) = credentials {
+            request.headers.push(credentials);
+        }
+        request
+            .headers
+            .push(rsip::headers::ContentLength::default().into());
+        Ok(request)
+    }
+
+    /// Builds the Authorization / Proxy-Authorization header answering a 401 / 407.
+    fn answer(&self, response: &Response) -> Result<Header, Failure> {
+        let proxy = response.status_code().code() == 407;
+        let challenge = response
+            .headers()
+            .iter()
+            .find_map(|h| match h {
+                Header::WwwAuthenticate(h) if !proxy => Some(h.value().to_string()),
+                Header::ProxyAuthenticate(h) if proxy => Some(h.value().to_string()),
+                _ => None,
+            })
+            .ok_or_else(|| Failure::failed("challenge without authenticate header"))?;
+        let challenge = auth::parse_challenge(&challenge)
+            .map_err(|e| Failure::failed(format!("bad challenge: {}", e)))?;
+
+        let qop = challenge.qop.join(",");
+        let value = auth::authorization(&DigestParams {
+            username: &self.username,
+            password: self.password.as_deref().unwrap_or_default(),
+            realm: &challenge.realm,
+            nonce: &challenge.nonce,
+            method: "REGISTER",
+            uri: &self.request_uri,
+            qop: Some(qop.as_str()).filter(|q| !q.is_empty()),
+            nc: 1,
+            cnonce: None,
+            algorithm: challenge.algorithm,
+            opaque: challenge.opaque.as_deref(),
+        });
+        Ok(if proxy {
+            rsip::headers::ProxyAuthorization::new(value).into()
+        } else {
+            rsip::headers::Authorization::new(value).into()
+        })
+    }
+
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        self.ctx.emit(event, &payload.to_string());
+    }
+
+    /// Records the expiry granted or the failure status of an attempt, for
+    /// `rsip_list_registrations`.
+    fn record(&self, outcome: Result<u32, Option<u16>>) {
+        let now = Instant::now();
+        let mut state = self.state.locked();
+        state.registered = outcome.is_ok();
+        state.failed = outcome.is_err();
+        state.granted = outcome.ok().map(|granted| (granted, now));
+        state.failure_status = outcome.err().flatten();
+        state.last_activity = now;
+    }
+
+    fn run(mut self, expires: u32) {
+        let mut registered = false;
+        loop {
+            let wait = match self.register(expires, TIMER_F) {
+                Ok(granted) => {
+                    self.record(Ok(granted));
+                    let event = if registered {
+                        "register_refresh"
+                    } else {
+                        "registered"
+                    };
+                    self.emit(
+                        event,
+                        json!({ "id": self.id, "aor": self.aor, "expires": granted }),
+                    );
+                    registered = true;
+                    refresh_interval(granted)
+                }
+                Err(Failure::Stopped) => break,
+                Err(Failure::Failed { status, reason }) => {
+                    self.record(Err(status));
+                    self.emit(
+                        "register_failed",
+                        json!({ "id": self.id, "aor": self.aor, "status": status, "reason": reason }),
+                    );
+                    registered = false;
+                    RETRY_INTERVAL
+                }
+            };
+            if stopped_within(&self.inbox, wait) {
+                break;
+            }
+        }
+
+        if registered {
+            let _ = self.register(0, UNREGISTER_TIMEOUT);
+        }
+    }
+}
+
+/// Sends `request` from the listener socket to `dest`, retransmitting per RFC 3261
+/// §17.1.2, and waits on `inbox` for its final response. `wake` is registered as the
+/// waiter for the request's transaction key meanwhile.
+pub(crate) fn transact(
+    ctx: &RsipContext,
+    dest: SocketAddr,
+    request: &Request,
+    wake: &Sender<Wakeup>,
+    inbox: &Receiver<Wakeup>,
+    timeout: Duration,
+) -> Result<Response, Failure> {
+    let key = transaction_key(request).ok_or_else(|| Failure::failed("no Via branch or CSeq"))?;
+    ctx.waiters.locked().insert(key.clone(), wake.clone());
+    let result = retransmit_until_final(ctx, dest, request, inbox, timeout);
+    ctx.waiters.locked().remove(&key);
+    result
+}
+
+fn retransmit_until_final(
+    ctx: &RsipContext,
+    dest: SocketAddr,
+    request: &Request,
+    inbox: &Receiver<Wakeup>,
+    timeout: Duration,
+) -> Result<Response, Failure> {
+    let raw = serialize(request.clone(), ctx.message_defaults().compact);
+    let deadline = Instant::now() + timeout;
+    let mut interval = T1;
+    loop {
+        ctx.send_from_listener(&dest.ip().to_string(), dest.port(), raw.as_bytes())
+            .map_err(|e| Failure::failed(e.to_string()))?;
+        let next_send = (Instant::now() + interval).min(deadline);
+        loop {
+            let remaining = next_send.saturating_duration_since(Instant::now());
+            match inbox.recv_timeout(remaining) {
+                Ok(Wakeup::Response(res)) if res.status_code().code() >= 200 => return Ok(res),
+                // Provisional: stop retransmitting, keep waiting (§17.1.2.2).
+                Ok(Wakeup::Response(_)) => interval = T2,
+                Ok(Wakeup::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Failure::Stopped)
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(Failure::failed("timeout"));
+        }
+        interval = (interval * 2).min(T2);
+    }
+}
+
+/// Sleeps for `duration`; returns true if asked to stop meanwhile.
+pub(crate) fn stopped_within(inbox: &Receiver<Wakeup>, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match inbox.recv_timeout(remaining) {
+            // a late retransmitted response; ignore it
+            Ok(Wakeup::Response(_)) => continue,
+            Ok(Wakeup::Stop) | Err(RecvTimeoutError::Disconnected) => return true,
+            Err(RecvTimeoutError::Timeout) => return false,
+        }
+    }
+}
+
+/// The expiry the registrar granted: the Contact's `expires` param, else the Expires
+/// header, else what was requested.
+fn granted_expires(response: &Response, requested: u32) -> u32 {
+    response
+        .contact_header()
+        .ok()
+        .and_then(|c| c.expires().ok().flatten())
+        .and_then(|e| e.seconds().ok())
+        .or_else(|| response.expires_header().and_then(|e| e.seconds().ok()))
+        .unwrap_or(requested)
+}
+
+/// Refresh a little before expiry: 10% early, but at least 5s when there is room.
+fn refresh_interval(granted: u32) -> Duration {
+    let margin = (granted / 10).max(5.min(granted / 2));
+    Duration::from_secs(u64::from(granted.saturating_sub(margin).max(1)))
+}
+
+/// The local address the registrar should see in Via and Contact. An unspecified bind
+/// address is replaced with the interface the OS would route `dest` through.
+pub(crate) fn advertised_addr(listener: SocketAddr, dest: SocketAddr) -> SocketAddr {
+    if !listener.ip().is_unspecified() {
+        return listener;
+    }
+    let any: IpAddr = match dest {
+        SocketAddr::V4(_) => std::net::Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let ip = UdpSocket::bind(SocketAddr::new(any, 0))
+        .and_then(|probe| probe.connect(dest).and_then(|_| probe.local_addr()))
+        .map(|addr| addr.ip())
+        .unwrap_or(any);
+    SocketAddr::new(ip, listener.port())
+}
+
+impl RsipContext {
+    /// Starts registering `aor` at `registrar_ip:port` from the running listener.
+    /// `username` defaults to the AOR's user part; without a password challenges fail.
+    pub fn register(
+        self: &Arc<Self>,
+        registrar_ip: &str,
+        port: u16,
+        aor: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+        expires: u32,
+    ) -> Result<u64, RsipError> {
+        let listener = self.local_addr().ok_or(RsipError::NotRunning)?;
+        let dest = resolve(registrar_ip, port)?;
+        let aor_uri = Uri::try_from(aor).map_err(|_| RsipError::InvalidArgument)?;
+        let username = match username.or_else(|| aor_uri.user()) {
+            Some(username) => username.to_string(),
+            None => return Err(RsipError::InvalidArgument),
+        };
+        let request_uri = Uri {
+            scheme: aor_uri.scheme.clone(),
+            host_with_port: aor_uri.host_with_port.clone(),
+            ..Default::default()
+        };
+        let local = advertised_addr(listener, dest);
+        let local_host = match local.ip() {
+            IpAddr::V6(ip) => format!("[{}]", ip),
+            ip => ip.to_string(),
+        };
+
+        let (via_host, via_port) = self.via_sentby(local);
+
+        let id = self.next_registration_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let (wake, inbox) = mpsc::channel();
+        let state = Arc::new(Mutex::new(RegistrationState {
+            aor: aor.to_string(),
+            registrar: dest,
+            registered: false,
+            granted: None,
+            failure_status: None,
+            failed: false,
+            last_activity: Instant::now(),
+        }));
+        let registrar = Registrar {
+            ctx: self.clone(),
+            id,
+            dest,
+            aor: aor.to_string(),
+            request_uri: request_uri.to_string(),
+            contact: format!("<sip:{}@{}:{}>", username, local_host, local.port()),
+            username,
+            password: password.map(String::from),
+            call_id: random::generate_call_id(&local.ip().to_string()),
+            from: format!("<{}>;tag={}", aor, random::generate_tag()),
+            via_host,
+            via_port,
+            cseq: 0,
+            wake: wake.clone(),
+            inbox,
+            state: state.clone(),
+        };
+        let thread = thread::spawn(move || registrar.run(expires));
+        self.registrations.locked().insert(
+            id,
+            Registration {
+                wake,
+                thread,
+                state,
+            },
+        );
+        Ok(id)
+    }
+
+    /// The active registrations as a JSON array by id; see `rsip_list_registrations`.
+    pub fn registrations_json(&self) -> serde_json::Value {
+        let now = Instant::now();
+        let mut states: Vec<(u64, RegistrationState)> = self
+            .registrations
+            .locked()
+            .iter()
+            .map(|(id, registration)| (*id, registration.state.locked().clone()))
+            .collect();
+        states.sort_by_key(|(id, _)| *id);
+        states
+            .iter()
+            .map(|(id, state)| state.to_json(*id, now))
+            .collect()
+    }
+
+    /// Unregisters (Expires: 0) and stops refreshing. Blocks until the registrar
+    /// answered or a short timeout passed. False for an unknown id.
+    pub fn unregister(&self, id: u64) -> bool {
+        let registration = self.registrations.locked().remove(&id);
+        match registration {
+            Some(registration) => {
+                registration.stop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hands a response to the client transaction waiting for it (by
+    /// `transaction_key`), if any.
+    pub(crate) fn deliver_response(&self, response: &Response) {
+        let key = match transaction_key(response) {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(waiter) = self.waiters.locked().get(&key) {
+            let _ = waiter.send(Wakeup::Response(response.clone()));
+        }
+    }
+}
+
+fn register_args<'a>(
+    registrar_ip: *const c_char,
+    aor: *const c_char,
+) -> Result<(&'a str, &'a str), RsipError> {
+    match (str_arg(registrar_ip), str_arg(aor)) {
+        (Some(ip), Some(aor)) => Ok((ip, aor)),
+        _ => Err(RsipError::InvalidArgument),
+    }
+}
+
+/// Registers `aor` (e.g. "sip:alice@example.com") at the registrar through the running
+/// listener and keeps the binding refreshed. Returns a registration id, or 0 if the
+/// arguments are invalid or no listener runs.
+#[no_mangle]
+pub extern "C" fn rsip_register(
+    registrar_ip: *const c_char,
+    registrar_port: u16,
+    aor: *const c_char,
+    username: *const c_char,
+    password: *const c_char,
+    expires_secs: u32,
+) -> u64 {
+    register_args(registrar_ip, aor)
+        .and_then(|(ip, aor)| {
+            crate::default_context().register(
+                ip,
+                registrar_port,
+                aor,
+                str_arg(username),
+                str_arg(password),
+                expires_secs,
+            )
+        })
+        .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_unregister(id: u64) -> bool {
+    crate::default_context().unregister(id)
+}
+
+/// The active registrations as a caller-owned JSON array, by id: AOR, registrar, state
+/// (`registering`, `registered` or `failed`), granted and remaining expiry, the status
+/// of a failed attempt and the time since the last one.
+#[no_mangle]
+pub extern "C" fn rsip_list_registrations() -> *mut c_char {
+    into_c_string(crate::default_context().registrations_json().to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_register(
+    ctx: *mut RsipContext,
+    registrar_ip: *const c_char,
+    registrar_port: u16,
+    aor: *const c_char,
+    username: *const c_char,
+    password: *const c_char,
+    expires_secs: u32,
+) -> u64 {
+    register_args(registrar_ip, aor)
+        .and_then(|(ip, aor)| {
+            with_context(ctx, |ctx| {
+                ctx.register(
+                    ip,
+                    registrar_port,
+                    aor,
+                    str_arg(username),
+                    str_arg(password),
+                    expires_secs,
+                )
+            })
+            .unwrap_or(Err(RsipError::InvalidArgument))
+        })
+        .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_unregister(ctx: *mut RsipContext, id: u64) -> bool {
+    with_context(ctx, |ctx| ctx.unregister(id)).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_list_registrations(ctx: *mut RsipContext) -> *mut c_char {
+    with_context(ctx, |ctx| {
+        into_c_string(ctx.registrations_json().to_string())
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Algorithm;
+    use crate::events::Sink;
+    use rsip::SipMessage;
+    use std::ffi::CStr;
+    use std::sync::Mutex;
+
+    static EVENTS: Mutex<Vec<(String, serde_json::Value)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(event: *const c_char, payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+        EVENTS
+            .lock()
+            .unwrap()
+            .push((event, serde_json::from_str(payload).unwrap()));
+    }
+
+    fn wait_for(event: &str) -> serde_json::Value {
+        for _ in 0..300 {
+            if let Some((_, payload)) = EVENTS.lock().unwrap().iter().find(|(e, _)| e == event) {
+                return payload.clone();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no {} event", event);
+    }
+
+    fn reply(request: &Request, status: &str, extra: &str) -> String {
+        let copy = |name: &str| {
+            request
+                .headers()
+                .iter()
+                .map(|h| h.to_string())
+                .find(|h| h.starts_with(name))
+                .unwrap()
+        };
+        format!(
+            "SIP/2.0 {}\r\n{}\r\n{}\r\n{};tag=reg\r\n{}\r\n{}\r\n{}Content-Length: 0\r\n\r\n",
+            status,
+            copy("Via:"),
+            copy("From:"),
+            copy("To:"),
+            copy("Call-ID:"),
+            copy("CSeq:"),
+            extra
+        )
+    }
+
+    #[test]
+    fn registers_with_digest_refreshes_and_unregisters() {
+        let registrar = UdpSocket::bind("127.0.0.1:0").unwrap();
+        registrar
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let registrar_port = registrar.local_addr().unwrap().port();
+
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events.subscribe(
+            Some(vec!["registered".into(), "register_refresh".into()]),
+            Sink::Basic(record),
+        );
+        ctx.start_udp_listener_on("127.0.0.1", 0).unwrap();
+        let id = ctx
+            .register(
+                "127.0.0.1",
+                registrar_port,
+                "sip:alice@example.com",
+                None,
+                Some("secret"),
+                2,
+            )
+            .unwrap();
+
+        let recv = || {
+            let mut buf = [0u8; 4096];
+            let (n, src) = registrar.recv_from(&mut buf).unwrap();
+            match SipMessage::try_from(&buf[..n]).unwrap() {
+                SipMessage::Request(req) => (req, src),
+                _ => panic!("expected a request"),
+            }
+        };
+
+        let (first, src) = recv();
+        assert_eq!(first.method().to_string(), "REGISTER");
+        assert_eq!(first.uri().to_string(), "sip:example.com");
+        assert!(first.authorization_header().is_none());
+        let listed = ctx.registrations_json();
+        assert_eq!(listed[0]["id"], id);
+        assert_eq!(listed[0]["aor"], "sip:alice@example.com");
+        assert_eq!(
+            listed[0]["registrar"],
+            format!("127.0.0.1:{}", registrar_port)
+        );
+        assert_eq!(listed[0]["state"], "registering");
+        assert_eq!(listed[0]["expires"], serde_json::Value::Null);
+        let challenge =
+            "WWW-Authenticate: Digest realm=\"example.com\", nonce=\"abc\", qop=\"auth\"\r\n";
+        registrar
+            .send_to(reply(&first, "401 Unauthorized", challenge).as_bytes(), src)
+            .unwrap();
+
+        let (second, src) = recv();
+        let credentials = second.authorization_header().unwrap().value().to_string();
+        assert!(credentials.contains("username=\"alice\""));
+        assert!(credentials.contains("uri=\"sip:example.com\""));
+        let cnonce = credentials
+            .split("cnonce=\"")
+            .nth(1)
+            .unwrap()
+            .trim_end_matches('"');
+        let expected = auth::authorization(&DigestParams {
+            username: "alice",
+            password: "secret",
+            realm: "example.com",
+            nonce: "abc",
+            method: "REGISTER",
+            uri: "sip:example.com",
+            qop: Some("auth"),
+            nc: 1,
+            cnonce: Some(cnonce),
+            algorithm: Algorithm::Md5,
+            opaque: None,
+        });
+        assert_eq!(credentials, expected);
+        assert_eq!(
+            second.cseq_header().unwrap().seq().unwrap(),
+            first.cseq_header().unwrap().seq().unwrap() + 1
+        );
+        registrar
+            .send_to(reply(&second, "200 OK", "Expires: 2\r\n").as_bytes(), src)
+            .unwrap();
+        assert_eq!(wait_for("registered")["expires"], 2);
+        let listed = ctx.registrations_json();
+        assert_eq!(listed[0]["state"], "registered");
+        assert_eq!(listed[0]["expires"], 2);
+        assert!(listed[0]["expires_in"].as_u64().unwrap() <= 2);
+
+        // the refresh is due after 1s; answer it without a challenge
+        let (refresh, src) = recv();
+        assert_eq!(
+            refresh.call_id_header().unwrap(),
+            second.call_id_header().unwrap()
+        );
+        registrar
+            .send_to(reply(&refresh, "200 OK", "Expires: 60\r\n").as_bytes(), src)
+            .unwrap();
+        assert_eq!(wait_for("register_refresh")["id"], id);
+
+        let unregistering = thread::spawn({
+            let ctx = ctx.clone();
+            move || ctx.unregister(id)
+        });
+        let (bye, src) = recv();
+        assert_eq!(bye.expires_header().unwrap().seconds().unwrap(), 0);
+        registrar
+            .send_to(reply(&bye, "200 OK", "").as_bytes(), src)
+            .unwrap();
+        assert!(unregistering.join().unwrap());
+        assert!(!ctx.unregister(id), "already removed");
+        assert_eq!(ctx.registrations_json(), json!([]));
+
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn refresh_interval_leaves_a_margin() {
+        assert_eq!(refresh_interval(3600), Duration::from_secs(3240));
+        assert_eq!(refresh_interval(60), Duration::from_secs(54));
+        assert_eq!(refresh_interval(20), Duration::from_secs(15));
+        assert_eq!(refresh_interval(2), Duration::from_secs(1));
+        assert_eq!(refresh_interval(0), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,267 @@
+//! Per-method message handlers for building a UAS: each parsed request goes to the
+//! handler registered for its method, each response to the response handler, and
+//! anything without a handler to the catch-all.
+//!
+//! Handlers run on the thread handling the message, after its `sip_rx*` events.
+
+use crate::body::{covers, BodyHandler};
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::ffi::str_arg;
+use crate::lock::Lock;
+use rsip::SipMessage;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::net::SocketAddr;
+use std::os::raw::c_char;
+
+/// Receives the message text (lossily decoded as UTF-8), its `sip_rx_parsed` summary
+/// JSON and the peer address. The strings are only valid during the call.
+pub type MessageHandler = extern "C" fn(
+    message: *const c_char,
+    summary: *const c_char,
+    src_ip: *const c_char,
+    src_port: u16,
+);
+
+/// Method names are matched exactly: they are case-sensitive (RFC 3261 section 7.1).
+#[derive(Default)]
+pub(crate) struct Router {
+    methods: HashMap<String, MessageHandler>,
+    response: Option<MessageHandler>,
+    fallback: Option<MessageHandler>,
+    /// Body handlers by lowercase media type pattern; see `body.rs`.
+    bodies: HashMap<String, BodyHandler>,
+}
+
+impl Router {
+    /// `"*"` sets the catch-all.
+    pub fn set_method(&mut self, method: &str, cb: Option<MessageHandler>) {
+        match (method, cb) {
+            ("*", cb) => self.fallback = cb,
+            (method, Some(cb)) => {
+                self.methods.insert(method.to_string(), cb);
+            }
+            (method, None) => {
+                self.methods.remove(method);
+            }
+        }
+    }
+
+    pub fn set_response(&mut self, cb: Option<MessageHandler>) {
+        self.response = cb;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty()
+            && self.response.is_none()
+            && self.fallback.is_none()
+            && self.bodies.is_empty()
+    }
+
+    pub fn set_body(&mut self, pattern: &str, cb: Option<BodyHandler>) {
+        match cb {
+            Some(cb) => {
+                self.bodies.insert(pattern.to_string(), cb);
+            }
+            None => {
+                self.bodies.remove(pattern);
+            }
+        }
+    }
+
+    pub fn has_body_handlers(&self) -> bool {
+        !self.bodies.is_empty()
+    }
+
+    /// The handler for `media`: an exact match first, then `type/*`, then `*/*`.
+    pub fn body_handler_for(&self, media: &str) -> Option<BodyHandler> {
+        self.bodies
+            .iter()
+            .filter(|(pattern, _)| covers(pattern, media))
+            .max_by_key(|(pattern, _)| match pattern.as_str() {
+                "*/*" => 0,
+                p if p.ends_with("/*") => 1,
+                _ => 2,
+            })
+            .map(|(_, cb)| *cb)
+    }
+
+    pub fn handler_for(&self, msg: &SipMessage) -> Option<MessageHandler> {
+        let specific = match msg {
+            SipMessage::Request(request) => self.methods.get(&request.method.to_string()).copied(),
+            SipMessage::Response(_) => self.response,
+        };
+        specific.or(self.fallback)
+    }
+}
+
+impl RsipContext {
+    /// Sets (or with `None` removes) the handler for requests with `method`; `"*"` is
+    /// the catch-all for messages without a handler of their own.
+    pub fn on_method(&self, method: &str, cb: Option<MessageHandler>) -> Result<(), RsipError> {
+        let method = method.trim();
+        if method.is_empty() || method.contains(char::is_whitespace) {
+            return Err(RsipError::InvalidArgument);
+        }
+        self.events.router.locked().set_method(method, cb);
+        Ok(())
+    }
+
+    pub fn on_response(&self, cb: Option<MessageHandler>) {
+        self.events.router.locked().set_response(cb);
+    }
+
+    /// Hands a parsed message to its handler, if one is registered.
+    pub(crate) fn route(&self, msg: &SipMessage, raw: &str, summary: &str, src: SocketAddr) {
+        let cb = match self.events.router.locked().handler_for(msg) {
+            Some(cb) => cb,
+            None => return,
+        };
+        let raw = CString::new(raw).unwrap_or_default();
+        let summary = CString::new(summary).unwrap_or_default();
+        let src_ip = CString::new(src.ip().to_string()).unwrap_or_default();
+        self.events
+            .run_counted(|| cb(raw.as_ptr(), summary.as_ptr(), src_ip.as_ptr(), src.port()));
+    }
+}
+
+/// Routes received requests with `method` (e.g. "INVITE") to `cb`, replacing any
+/// previous handler; NULL removes it. `"*"` sets the catch-all. Returns false for a
+/// NULL or empty method.
+#[no_mangle]
+pub extern "C" fn rsip_on_method(method: *const c_char, cb: Option<MessageHandler>) -> bool {
+    match str_arg(method) {
+        Some(method) => crate::default_context().on_method(method, cb).is_ok(),
+        None => false,
+    }
+}
+
+/// Routes received responses to `cb`, replacing any previous handler; NULL removes it.
+#[no_mangle]
+pub extern "C" fn rsip_on_response(cb: Option<MessageHandler>) {
+    crate::default_context().on_response(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_on_method(
+    ctx: *mut RsipContext,
+    method: *const c_char,
+    cb: Option<MessageHandler>,
+) -> bool {
+    match str_arg(method) {
+        Some(method) => with_context(ctx, |ctx| ctx.on_method(method, cb).is_ok()).unwrap_or(false),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_on_response(ctx: *mut RsipContext, cb: Option<MessageHandler>) {
+    with_context(ctx, |ctx| ctx.on_response(cb));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::sync::Mutex;
+
+    static ROUTED: Mutex<Vec<(&str, String)>> = Mutex::new(Vec::new());
+
+    fn record(handler: &'static str, summary: *const c_char) {
+        let summary: serde_json::Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(summary) }.to_str().unwrap()).unwrap();
+        let what = summary["method"]
+            .as_str()
+            .map(String::from)
+            .unwrap_or_else(|| summary["status"].to_string());
+        ROUTED.lock().unwrap().push((handler, what));
+    }
+
+    extern "C" fn on_invite(
+        message: *const c_char,
+        summary: *const c_char,
+        _: *const c_char,
+        port: u16,
+    ) {
+        assert!(unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .starts_with("INVITE "));
+        assert_eq!(port, 5060);
+        record("invite", summary);
+    }
+
+    extern "C" fn on_bye(_: *const c_char, summary: *const c_char, _: *const c_char, _: u16) {
+        record("bye", summary);
+    }
+
+    extern "C" fn on_response(_: *const c_char, summary: *const c_char, _: *const c_char, _: u16) {
+        record("response", summary);
+    }
+
+    extern "C" fn on_other(_: *const c_char, summary: *const c_char, _: *const c_char, _: u16) {
+        record("other", summary);
+    }
+
+    fn request(method: &str) -> String {
+        format!(
+            "{} sip:bob@example.com SIP/2.0\r\n\
+             Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bK{}\r\n\
+             Max-Forwards: 70\r\n\
+             From: <sip:alice@example.com>;tag=1\r\n\
+             To: <sip:bob@example.com>\r\n\
+             Call-ID: router@192.0.2.1\r\n\
+             CSeq: 1 {}\r\n\
+             Content-Length: 0\r\n\r\n",
+            method, method, method
+        )
+    }
+
+    #[test]
+    fn dispatches_by_method_with_fallback() {
+        let ctx = RsipContext::new();
+        let src: SocketAddr = "192.0.2.1:5060".parse().unwrap();
+        ctx.on_method("INVITE", Some(on_invite)).unwrap();
+        ctx.on_method("BYE", Some(on_bye)).unwrap();
+        ctx.on_response(Some(on_response));
+        assert!(ctx.on_method(" ", Some(on_other)).is_err());
+        let response = "SIP/2.0 200 OK\r\n\
+            Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bK1\r\n\
+            From: <sip:alice@example.com>;tag=1\r\n\
+            To: <sip:bob@example.com>;tag=2\r\n\
+            Call-ID: router@192.0.2.1\r\n\
+            CSeq: 1 INVITE\r\n\
+            Content-Length: 0\r\n\r\n";
+
+        ctx.handle_datagram(request("INVITE").as_bytes(), src);
+        ctx.handle_datagram(request("BYE").as_bytes(), src);
+        ctx.handle_datagram(request("OPTIONS").as_bytes(), src);
+        ctx.handle_datagram(response.as_bytes(), src);
+        ctx.handle_datagram(b"garbage\r\n\r\n", src);
+        // no catch-all yet: OPTIONS went nowhere
+        ctx.on_method("*", Some(on_other)).unwrap();
+        ctx.on_method("BYE", None).unwrap();
+        ctx.handle_datagram(request("OPTIONS").as_bytes(), src);
+        ctx.handle_datagram(request("BYE").as_bytes(), src);
+        ctx.on_response(None);
+        ctx.handle_datagram(response.as_bytes(), src);
+
+        let routed = ROUTED.lock().unwrap();
+        let expected = [
+            ("invite", "INVITE"),
+            ("bye", "BYE"),
+            ("response", "200"),
+            ("other", "OPTIONS"),
+            ("other", "BYE"),
+            ("other", "200"),
+        ];
+        assert_eq!(
+            routed
+                .iter()
+                .map(|(h, w)| (*h, w.as_str()))
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! A lenient SDP (RFC 4566) reader producing the JSON handed to C hosts.
+//!
+//! Only the fields a SIP endpoint needs to set up media are extracted; unknown lines
+//! are ignored rather than rejected, since real-world SDP is full of extensions.
+
+use crate::ffi::{into_c_string, str_arg};
+use serde_json::{json, Map, Value};
+use std::os::raw::c_char;
+
+#[derive(Debug)]
+pub struct SdpError(String);
+
+impl std::fmt::Display for SdpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SdpError {}
+
+/// `c=IN IP4 203.0.113.1` -> `{net_type, addr_type, address}`.
+fn connection(value: &str) -> Value {
+    let mut parts = value.split_whitespace();
+    json!({
+        "net_type": parts.next(),
+        "addr_type": parts.next(),
+        "address": parts.next(),
+    })
+}
+
+/// `a=name:value` or the flag form `a=name`.
+fn attribute(value: &str) -> Value {
+    match value.split_once(':') {
+        Some((name, value)) => json!({ "name": name, "value": value }),
+        None => json!({ "name": value, "value": null }),
+    }
+}
+
+/// `a=rtpmap:<pt> <encoding>/<clock rate>[/<channels>]`.
+fn rtpmap(value: &str) -> Option<Value> {
+    let (pt, encoding) = value.split_once(' ')?;
+    let mut parts = encoding.trim().split('/');
+    Some(json!({
+        "payload_type": pt.trim().parse::<u8>().ok()?,
+        "encoding": parts.next()?,
+        "clock_rate": parts.next().and_then(|r| r.parse::<u32>().ok()),
+        "channels": parts.next().and_then(|c| c.parse::<u32>().ok()),
+    }))
+}
+
+/// Parses an SDP body into `{version, origin, session_name, connection, attributes,
+/// media:[{type, port, port_count, protocol, formats, payload_types, connection,
+/// rtpmap, attributes}]}`. A media section without its own `c=` line inherits the
+/// session-level one; `connection` is `null` when neither exists.
+pub fn parse(body: &str) -> Result<Value, SdpError> {
+    let mut session = Map::new();
+    let mut session_attrs = Vec::new();
+    let mut media: Vec<Map<String, Value>> = Vec::new();
+
+    for line in body.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, value) = match line.split_once('=') {
+            Some((kind, value)) if kind.len() == 1 => (kind, value.trim()),
+            _ => return Err(SdpError(format!("invalid line `{}`", line))),
+        };
+        if session.is_empty() && media.is_empty() && kind != "v" {
+            return Err(SdpError("SDP must start with v=".into()));
+        }
+
+        match (kind, media.last_mut()) {
+            ("v", None) => {
+                session.insert("version".into(), json!(value.parse::<u32>().ok()));
+            }
+            ("o", None) => {
+                let parts: Vec<&str> = value.split_whitespace().collect();
+                session.insert(
+                    "origin".into(),
+                    json!({
+                        "username": parts.first(),
+                        "session_id": parts.get(1),
+                        "session_version": parts.get(2),
+                        "net_type": parts.get(3),
+                        "addr_type": parts.get(4),
+                        "address": parts.get(5),
+                    }),
+                );
+            }
+            ("s", None) => {
+                session.insert("session_name".into(), json!(value));
+            }
+            ("c", None) => {
+                session.insert("connection".into(), connection(value));
+            }
+            ("a", None) => session_attrs.push(attribute(value)),
+            ("m", _) => {
+                let mut parts = value.split_whitespace();
+                let media_type = parts.next().unwrap_or_default();
+                let port_field = parts.next().unwrap_or_default();
+                let (port, count) = match port_field.split_once('/') {
+                    Some((port, count)) => (port, count.parse::<u32>().ok()),
+                    None => (port_field, None),
+                };
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| SdpError(format!("invalid media port in `{}`", line)))?;
+                let protocol = parts.next().unwrap_or_default();
+                let formats: Vec<&str> = parts.collect();
+                let payload_types: Vec<u8> = if protocol.contains("RTP") {
+                    formats.iter().filter_map(|f| f.parse().ok()).collect()
+                } else {
+                    Vec::new()
+                };
+
+                let mut section = Map::new();
+                section.insert("type".into(), json!(media_type));
+                section.insert("port".into(), json!(port));
+                section.insert("port_count".into(), json!(count));
+                section.insert("protocol".into(), json!(protocol));
+                section.insert("formats".into(), json!(formats));
+                section.insert("payload_types".into(), json!(payload_types));
+                section.insert("connection".into(), Value::Null);
+                section.insert("rtpmap".into(), json!([]));
+                section.insert("attributes".into(), json!([]));
+                media.push(section);
+            }
+            ("c", Some(section)) => {
+                section.insert("connection".into(), connection(value));
+            }
+            ("a", Some(section)) => {
+                if let Some(map) = value.strip_prefix("rtpmap:").and_then(rtpmap) {
+                    section["rtpmap"].as_array_mut().unwrap().push(map);
+                }
+                section["attributes"]
+                    .as_array_mut()
+                    .unwrap()
+                    .push(attribute(value));
+            }
+            // t=, b=, k=, i=, ... are not needed to set up media.
+            _ => {}
+        }
+    }
+
+    if session.is_empty() {
+        return Err(SdpError("empty SDP".into()));
+    }
+    let session_connection = session.get("connection").cloned().unwrap_or(Value::Null);
+    for section in media.iter_mut() {
+        if section["connection"].is_null() {
+            section.insert("connection".into(), session_connection.clone());
+        }
+    }
+
+    Ok(json!({
+        "version": session.get("version").cloned().unwrap_or(Value::Null),
+        "origin": session.get("origin").cloned().unwrap_or(Value::Null),
+        "session_name": session.get("session_name").cloned().unwrap_or(Value::Null),
+        "connection": session_connection,
+        "attributes": session_attrs,
+        "media": media,
+    }))
+}
+
+/// Parses an SDP body into caller-owned JSON (see `sdp::parse`), or NULL if `body` is
+/// NULL or not SDP.
+#[no_mangle]
+pub extern "C" fn rsip_parse_sdp(body: *const c_char) -> *mut c_char {
+    match str_arg(body).map(parse) {
+        Some(Ok(sdp)) => into_c_string(sdp.to_string()),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+        o=alice 2890844526 2890844526 IN IP4 atlanta.example.com\r\n\
+        s=-\r\n\
+        c=IN IP4 192.0.2.101\r\n\
+        t=0 0\r\n\
+        a=sendrecv\r\n\
+        m=audio 49172 RTP/AVP 0 8 101\r\n\
+        a=rtpmap:0 PCMU/8000\r\n\
+        a=rtpmap:8 PCMA/8000\r\n\
+        a=rtpmap:101 telephone-event/8000\r\n\
+        a=fmtp:101 0-16\r\n\
+        m=video 51372/2 RTP/AVP 99\r\n\
+        c=IN IP6 2001:db8::1\r\n\
+        a=rtpmap:99 h263-1998/90000\r\n\
+        m=application 0 UDP/BFCP *\r\n";
+
+    #[test]
+    fn parses_sessions_with_several_media() {
+        let sdp = parse(OFFER).unwrap();
+        assert_eq!(sdp["version"], 0);
+        assert_eq!(sdp["origin"]["username"], "alice");
+        assert_eq!(sdp["connection"]["address"], "192.0.2.101");
+        assert_eq!(sdp["attributes"][0]["name"], "sendrecv");
+
+        let audio = &sdp["media"][0];
+        assert_eq!(audio["type"], "audio");
+        assert_eq!(audio["port"], 49172);
+        assert_eq!(audio["payload_types"], json!([0, 8, 101]));
+        assert_eq!(audio["rtpmap"][1]["encoding"], "PCMA");
+        assert_eq!(audio["rtpmap"][2]["clock_rate"], 8000);
+        assert_eq!(audio["attributes"][3]["value"], "101 0-16");
+        // inherited from the session
+        assert_eq!(audio["connection"]["address"], "192.0.2.101");
+
+        let video = &sdp["media"][1];
+        assert_eq!(video["port_count"], 2);
+        assert_eq!(video["connection"]["address"], "2001:db8::1");
+
+        let app = &sdp["media"][2];
+        assert_eq!(app["formats"], json!(["*"]));
+        assert_eq!(app["payload_types"], json!([]));
+    }
+
+    #[test]
+    fn missing_connection_is_null() {
+        let sdp = parse("v=0\no=- 1 1 IN IP4 h\ns=-\nm=audio 4000 RTP/AVP 0\n").unwrap();
+        assert!(sdp["connection"].is_null());
+        assert!(sdp["media"][0]["connection"].is_null());
+    }
+
+    #[test]
+    fn rejects_non_sdp() {
+        assert!(parse("").is_err());
+        assert!(parse("hello world").is_err());
+        assert!(parse("o=- 1 1 IN IP4 h\nv=0\n").is_err());
+        assert!(parse("v=0\nm=audio notaport RTP/AVP 0\n").is_err());
+    }
+}
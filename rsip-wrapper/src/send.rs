@@ -0,0 +1,520 @@
+use crate::capture::CAPTURE_IN;
+use crate::context::RsipContext;
+use crate::error::RsipError;
+use crate::ffi::{into_c_string, str_arg};
+use crate::lock::Lock;
+use crate::log::LogLevel;
+use crate::transaction::transaction_key;
+use rsip::prelude::*;
+use rsip::SipMessage;
+use serde_json::json;
+use socket2::Socket;
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::raw::c_char;
+use std::time::{Duration, Instant};
+
+/// Validates the `(dest_ip, data)` pair every `rsip_send_udp*` function takes.
+pub(crate) fn send_args<'a>(
+    dest_ip: *const c_char,
+    data: *const c_char,
+) -> Result<(&'a str, &'a [u8]), RsipError> {
+    let ip = str_arg(dest_ip).ok_or(RsipError::InvalidArgument)?;
+    if data.is_null() {
+        return Err(RsipError::InvalidArgument);
+    }
+    Ok((ip, unsafe { CStr::from_ptr(data) }.to_bytes()))
+}
+
+/// Validates the `(dest_ip, data, len)` triple of the `*_bytes` senders. `data` may
+/// contain NULs; it is only NULL-checked when `len` is non-zero.
+pub(crate) fn bytes_args<'a>(
+    dest_ip: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> Result<(&'a str, &'a [u8]), RsipError> {
+    let ip = str_arg(dest_ip).ok_or(RsipError::InvalidArgument)?;
+    if len == 0 {
+        return Ok((ip, &[]));
+    }
+    if data.is_null() {
+        return Err(RsipError::InvalidArgument);
+    }
+    Ok((ip, unsafe { std::slice::from_raw_parts(data, len) }))
+}
+
+/// Joins a host and a port, bracketing IPv6 literals (`::1` -> `[::1]:5060`).
+pub(crate) fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Resolves the destination of a send; a name that does not resolve is a send failure.
+pub(crate) fn resolve(host: &str, port: u16) -> Result<SocketAddr, RsipError> {
+    host_port(host, port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or(RsipError::SendFailed)
+}
+
+/// Binds an ephemeral socket of the same address family as `dest`.
+pub(crate) fn ephemeral_socket(dest: SocketAddr, dscp: Option<u8>) -> Result<UdpSocket, RsipError> {
+    port_socket(dest, 0, dscp)
+}
+
+/// Binds local port `port` (0 for an ephemeral one) on a socket of the same address
+/// family as `dest`. A port taken by another socket fails with `AddrInUse`.
+pub(crate) fn port_socket(
+    dest: SocketAddr,
+    port: u16,
+    dscp: Option<u8>,
+) -> Result<UdpSocket, RsipError> {
+    let any: IpAddr = match dest {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket =
+        UdpSocket::bind(SocketAddr::new(any, port)).map_err(|e| RsipError::from_bind_error(&e))?;
+    if let Some(dscp) = dscp {
+        // Best effort: an unmarked send beats no send.
+        let _ = mark_dscp(&socket2::SockRef::from(&socket), dest.is_ipv6(), dscp);
+    }
+    Ok(socket)
+}
+
+/// Largest DSCP value: the upper six bits of the IPv4 TOS / IPv6 traffic class byte.
+pub const MAX_DSCP: u8 = 63;
+
+/// Whether packets can be marked with `IP_TOS` and `IPV6_TCLASS` on this platform.
+pub(crate) const DSCP_SUPPORTED: bool = cfg!(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+));
+
+/// Where socket2 lacks `set_tos` or `set_tclass_v6` (see `DSCP_SUPPORTED`), calls
+/// resolve to these instead of its inherent methods, so they compile on every platform.
+#[allow(dead_code)] // never called where the inherent methods exist
+trait NoDscp {
+    fn set_tos(&self, _: u32) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn set_tclass_v6(&self, _: u32) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl NoDscp for Socket {}
+
+/// Marks everything `socket` sends with `dscp`. An IPv6 socket gets both options, as a
+/// dual-stack one sends IPv4 packets too.
+pub(crate) fn mark_dscp(socket: &Socket, ipv6: bool, dscp: u8) -> io::Result<()> {
+    if !DSCP_SUPPORTED {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+    let tos = u32::from(dscp) << 2;
+    if ipv6 {
+        socket.set_tclass_v6(tos)?;
+        // Refused by v6-only sockets on some platforms; they send no IPv4 anyway.
+        let _ = socket.set_tos(tos);
+        Ok(())
+    } else {
+        socket.set_tos(tos)
+    }
+}
+
+/// Sends `payload` to `ip:port` from a fresh socket of the matching family bound to
+/// `src_port` (0: an ephemeral port), marked with the DSCP of `ctx` if set.
+pub(crate) fn send_udp_from_port(
+    ctx: &RsipContext,
+    src_port: u16,
+    ip: &str,
+    port: u16,
+    payload: &[u8],
+) -> Result<(), RsipError> {
+    let dest = resolve(ip, port)?;
+    let socket = port_socket(dest, src_port, ctx.dscp())?;
+    ctx.send_captured(&socket, payload, dest)
+        .map(|_| ())
+        .map_err(|_| RsipError::SendFailed)
+}
+
+/// What ties a response to its request: the transaction key (top Via branch and CSeq
+/// method) and, as a stricter check, the CSeq number.
+fn response_key(msg: &SipMessage) -> Option<(String, u32)> {
+    let seq = msg.cseq_header().ok()?.seq().ok()?;
+    Some((transaction_key(msg)?, seq))
+}
+
+/// Sends `request` from a one-shot socket and waits up to `timeout` for the final
+/// response with the same Via branch and CSeq. Provisional (1xx) responses and
+/// unrelated datagrams are skipped. Returns the raw response text, or `None` on
+/// timeout.
+///
+/// This is a convenience for simple clients, not a transaction layer: there are no
+/// retransmissions, and the peer must answer to the source address of the request.
+pub(crate) fn send_and_wait(
+    ctx: &RsipContext,
+    ip: &str,
+    port: u16,
+    request: &[u8],
+    timeout: Duration,
+) -> Result<Option<String>, RsipError> {
+    let key = match SipMessage::try_from(request) {
+        Ok(msg @ SipMessage::Request(_)) => response_key(&msg).ok_or(RsipError::InvalidArgument)?,
+        _ => return Err(RsipError::InvalidArgument),
+    };
+
+    let dest = resolve(ip, port)?;
+    let socket = ephemeral_socket(dest, ctx.dscp())?;
+    ctx.send_captured(&socket, request, dest)
+        .map_err(|_| RsipError::SendFailed)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0u8; crate::config::DEFAULT_RECV_BUFFER_SIZE];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(|_| RsipError::Io)?;
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let data = &buf[..n];
+        ctx.capture(CAPTURE_IN, &socket, from, data, None);
+        let msg = match SipMessage::try_from(data) {
+            Ok(msg) => msg,
+            Err(_) => continue,
+        };
+        if let SipMessage::Response(res) = &msg {
+            if res.status_code().code() >= 200 && response_key(&msg).as_ref() == Some(&key) {
+                return Ok(Some(String::from_utf8_lossy(data).into_owned()));
+            }
+        }
+    }
+}
+
+/// Sends `request` to `dest_ip:dest_port` and blocks until the matching final response
+/// arrives or `timeout_ms` elapses. Returns the raw response (caller-owned, free with
+/// `rsip_free_string`), or NULL on timeout, on a send error, or if `request` is not a
+/// parseable request with a Via branch and CSeq.
+#[no_mangle]
+pub extern "C" fn rsip_send_and_wait(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    request: *const c_char,
+    timeout_ms: u32,
+) -> *mut c_char {
+    let timeout = Duration::from_millis(timeout_ms.into());
+    match send_args(dest_ip, request).and_then(|(ip, payload)| {
+        send_and_wait(crate::default_context(), ip, dest_port, payload, timeout)
+    }) {
+        Ok(Some(response)) => into_c_string(response),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Whether a socket error is an ICMP error for an earlier datagram (port, host or
+/// network unreachable) rather than a problem with the current operation.
+pub(crate) fn is_unreachable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+/// Linux only reports ICMP errors on an unconnected UDP socket with IP_RECVERR set.
+/// They are then queued on the socket, each with the destination of the datagram that
+/// caused it, besides failing the next receive or send.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_unreachable_errors(socket: &Socket, ipv6: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mut options = vec![(libc::SOL_IP, libc::IP_RECVERR)];
+    if ipv6 {
+        options.push((libc::SOL_IPV6, libc::IPV6_RECVERR));
+    }
+    let on: libc::c_int = 1;
+    for (level, name) in options {
+        let rc = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &on as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Elsewhere ICMP errors surface (or not) without any setup, and without an address.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_unreachable_errors(_: &Socket, _: bool) -> io::Result<()> {
+    Ok(())
+}
+
+/// Drains the socket's error queue, returning the destinations that turned out to be
+/// unreachable.
+#[cfg(target_os = "linux")]
+fn unreachable_dests(socket: &UdpSocket) -> Vec<SocketAddr> {
+    let socket = socket2::SockRef::from(socket);
+    let mut buf = [std::mem::MaybeUninit::<u8>::uninit(); 1];
+    let mut dests = Vec::new();
+    // Reading the error queue never blocks: it fails with WouldBlock once empty.
+    while let Ok((_, addr)) = socket.recv_from_with_flags(&mut buf, libc::MSG_ERRQUEUE) {
+        if let Some(dest) = addr.as_socket() {
+            let dest = SocketAddr::new(dest.ip().to_canonical(), dest.port());
+            if !dests.contains(&dest) {
+                dests.push(dest);
+            }
+        }
+    }
+    dests
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unreachable_dests(_: &UdpSocket) -> Vec<SocketAddr> {
+    Vec::new()
+}
+
+impl RsipContext {
+    /// Reports the ICMP error `error` read from the listener socket as a
+    /// `dest_unreachable` event per affected destination. Without an address from the
+    /// platform, the destination the listener last sent to is blamed.
+    pub(crate) fn report_unreachable(&self, socket: &UdpSocket, error: &io::Error) {
+        let mut dests = unreachable_dests(socket);
+        if dests.is_empty() {
+            dests.extend(*self.last_listener_dest.locked());
+        }
+        for dest in dests {
+            self.log(
+                LogLevel::Debug,
+                format_args!("{} is unreachable: {}", dest, error),
+            );
+            let payload = json!({ "dest": dest.to_string(), "error": error.to_string() });
+            self.emit_from("dest_unreachable", &payload.to_string(), dest);
+        }
+    }
+
+    /// Sends from the listener's own socket so the source port matches the port we
+    /// listen on (needed for symmetric signaling / RFC 3581). With several listeners,
+    /// the first one added that is still running sends.
+    pub fn send_from_listener(&self, ip: &str, port: u16, payload: &[u8]) -> Result<(), RsipError> {
+        self.send_from_listener_on(None, ip, port, payload)
+    }
+
+    /// Like `send_from_listener`, from UDP listener `listener` while it is running. A
+    /// transport registered by the host takes precedence over any listener.
+    pub(crate) fn send_from_listener_on(
+        &self,
+        listener: Option<u64>,
+        ip: &str,
+        port: u16,
+        payload: &[u8],
+    ) -> Result<(), RsipError> {
+        if self.send_via_transport(ip, port, payload) {
+            self.stats.record_send(&Ok::<(), ()>(()));
+            self.dialog_sent(payload);
+            return Ok(());
+        }
+        let socket = listener
+            .and_then(|id| self.listener_socket(id))
+            .or_else(|| self.udp_socket())
+            .ok_or(RsipError::NotRunning)?;
+        let mut dest = resolve(ip, port)?;
+        // A dual-stack listener is an IPv6 socket; it reaches IPv4 peers through their
+        // v4-mapped address.
+        if let (SocketAddr::V4(v4), Ok(SocketAddr::V6(_))) = (dest, socket.local_addr()) {
+            dest = SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port());
+        }
+        let mut result = self.send_captured(&socket, payload, dest);
+        // The send may only have picked up the error of an earlier datagram.
+        if let Err(e) = &result {
+            if is_unreachable(e) {
+                self.report_unreachable(&socket, e);
+                result = self.send_captured(&socket, payload, dest);
+            }
+        }
+        self.stats.record_send(&result);
+        match result {
+            Ok(n) => {
+                *self.last_listener_dest.locked() =
+                    Some(SocketAddr::new(dest.ip().to_canonical(), dest.port()));
+                self.dialog_sent(payload);
+                self.log(
+                    LogLevel::Debug,
+                    format_args!("sent {} bytes to {}", n, dest),
+                );
+                Ok(())
+            }
+            Err(e) => {
+                self.log(
+                    LogLevel::Warn,
+                    format_args!("send to {} failed: {}", dest, e),
+                );
+                Err(RsipError::SendFailed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_hosts_are_bracketed() {
+        assert_eq!(host_port("::1", 5060), "[::1]:5060");
+        assert_eq!(host_port("[::1]", 5060), "[::1]:5060");
+        assert_eq!(host_port("127.0.0.1", 5060), "127.0.0.1:5060");
+        assert_eq!(host_port("example.com", 5061), "example.com:5061");
+    }
+
+    #[test]
+    fn send_to_ipv6_loopback() {
+        let peer = UdpSocket::bind("[::1]:15073").unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(2)))
+            .unwrap();
+        send_udp_from_port(
+            &RsipContext::new(),
+            0,
+            "::1",
+            15073,
+            b"OPTIONS sip:a@b SIP/2.0\r\n\r\n",
+        )
+        .unwrap();
+
+        let mut buf = [0u8; 64];
+        let (n, src) = peer.recv_from(&mut buf).unwrap();
+        assert!(buf[..n].starts_with(b"OPTIONS"));
+        assert!(src.is_ipv6());
+    }
+
+    const REQUEST: &str = "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+        Via: SIP/2.0/UDP 127.0.0.1;branch=z9hG4bKwait1\r\n\
+        CSeq: 7 OPTIONS\r\n\
+        Call-ID: wait@127.0.0.1\r\n\
+        Content-Length: 0\r\n\r\n";
+
+    fn response(status: &str, branch: &str, cseq: &str) -> String {
+        format!(
+            "SIP/2.0 {}\r\nVia: SIP/2.0/UDP 127.0.0.1;branch={}\r\nCSeq: {}\r\n\
+             Call-ID: wait@127.0.0.1\r\nContent-Length: 0\r\n\r\n",
+            status, branch, cseq
+        )
+    }
+
+    #[test]
+    fn send_and_wait_returns_matching_final_response() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let responder = std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (_, client) = server.recv_from(&mut buf).unwrap();
+            for reply in [
+                response("200 OK", "z9hG4bKother", "7 OPTIONS"),
+                response("200 OK", "z9hG4bKwait1", "8 OPTIONS"),
+                response("100 Trying", "z9hG4bKwait1", "7 OPTIONS"),
+                response("200 OK", "z9hG4bKwait1", "7 OPTIONS"),
+            ] {
+                server.send_to(reply.as_bytes(), client).unwrap();
+            }
+        });
+
+        let got = send_and_wait(
+            &RsipContext::new(),
+            "127.0.0.1",
+            port,
+            REQUEST.as_bytes(),
+            Duration::from_secs(2),
+        )
+        .unwrap()
+        .expect("response before timeout");
+        responder.join().unwrap();
+        assert_eq!(got, response("200 OK", "z9hG4bKwait1", "7 OPTIONS"));
+    }
+
+    #[test]
+    fn send_and_wait_times_out() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = silent.local_addr().unwrap().port();
+        let started = Instant::now();
+        let got = send_and_wait(
+            &RsipContext::new(),
+            "127.0.0.1",
+            port,
+            REQUEST.as_bytes(),
+            Duration::from_millis(100),
+        );
+        assert_eq!(got, Ok(None));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        assert_eq!(
+            send_and_wait(
+                &RsipContext::new(),
+                "127.0.0.1",
+                port,
+                b"not sip",
+                Duration::from_millis(10),
+            ),
+            Err(RsipError::InvalidArgument)
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn icmp_port_unreachable_is_reported() {
+        use crate::events::Sink;
+        use std::ffi::CStr;
+        use std::sync::{Arc, Mutex};
+
+        static UNREACHABLE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        extern "C" fn record(_: *const c_char, payload: *const c_char) {
+            let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+            UNREACHABLE.lock().unwrap().push(payload.to_string());
+        }
+
+        let closed = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events
+            .subscribe(Some(vec!["dest_unreachable".into()]), Sink::Basic(record));
+        ctx.start_udp_listener_on("127.0.0.1", 0).unwrap();
+        ctx.send_from_listener("127.0.0.1", port, b"OPTIONS sip:a@b SIP/2.0\r\n\r\n")
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while UNREACHABLE.lock().unwrap().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        ctx.shutdown();
+
+        let payloads = UNREACHABLE.lock().unwrap();
+        assert_eq!(payloads.len(), 1, "{:?}", payloads);
+        let payload: serde_json::Value = serde_json::from_str(&payloads[0]).unwrap();
+        assert_eq!(payload["dest"], format!("127.0.0.1:{}", port));
+    }
+}
@@ -0,0 +1,211 @@
+//! CSeq numbering for a UAC writing its own requests (RFC 3261 sections 8.1.1.5 and
+//! 12.2.1.1): every new request on a Call-ID takes the next number, while ACK and
+//! CANCEL reuse the number of the request they refer to.
+
+use crate::context::{with_context, RsipContext};
+use crate::ffi::str_arg;
+use crate::lock::Lock;
+use std::collections::HashMap;
+use std::os::raw::c_char;
+
+/// CSeq numbers must stay below 2^31.
+const MAX_CSEQ: u32 = (1 << 31) - 1;
+
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub call_id: String,
+    /// The last number handed out, 0 before the first request.
+    pub cseq: u32,
+    /// Method of the last request that took a new number.
+    pub last_method: Option<String>,
+    /// Number of the last INVITE, for its ACK.
+    invite_cseq: Option<u32>,
+}
+
+impl Session {
+    /// The CSeq number for the next `method` request, or `None` for an ACK without an
+    /// INVITE, a CANCEL without a request or once the numbers run out.
+    fn next(&mut self, method: &str) -> Option<u32> {
+        match method {
+            "ACK" => self.invite_cseq,
+            "CANCEL" => Some(self.cseq).filter(|n| *n > 0),
+            _ => {
+                if self.cseq >= MAX_CSEQ {
+                    return None;
+                }
+                self.cseq += 1;
+                self.last_method = Some(method.to_string());
+                if method == "INVITE" {
+                    self.invite_cseq = Some(self.cseq);
+                }
+                Some(self.cseq)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Sessions {
+    by_id: HashMap<u64, Session>,
+    next_id: u64,
+}
+
+impl RsipContext {
+    /// Starts CSeq numbering for `call_id` and returns the session id (never 0).
+    pub fn session_new(&self, call_id: &str) -> Option<u64> {
+        let call_id = call_id.trim();
+        if call_id.is_empty() {
+            return None;
+        }
+        let mut sessions = self.sessions.locked();
+        sessions.next_id += 1;
+        let id = sessions.next_id;
+        let session = Session {
+            call_id: call_id.to_string(),
+            ..Session::default()
+        };
+        sessions.by_id.insert(id, session);
+        Some(id)
+    }
+
+    /// The CSeq number for the next `method` request of session `id`; see
+    /// `Session::next`. Methods are case-sensitive (RFC 3261 section 7.1).
+    pub fn session_next_cseq(&self, id: u64, method: &str) -> Option<u32> {
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_graphic()) {
+            return None;
+        }
+        self.sessions.locked().by_id.get_mut(&id)?.next(method)
+    }
+
+    pub fn session(&self, id: u64) -> Option<Session> {
+        self.sessions.locked().by_id.get(&id).cloned()
+    }
+
+    pub fn session_free(&self, id: u64) -> bool {
+        self.sessions.locked().by_id.remove(&id).is_some()
+    }
+}
+
+/// Starts CSeq numbering for `call_id`. Returns the session id, or 0 if `call_id` is
+/// NULL or empty. Release it with `rsip_session_free`.
+#[no_mangle]
+pub extern "C" fn rsip_session_new(call_id: *const c_char) -> u64 {
+    str_arg(call_id)
+        .and_then(|call_id| crate::default_context().session_new(call_id))
+        .unwrap_or(0)
+}
+
+/// The CSeq number to use for the next `method` request of session `id`: one more than
+/// the last for a new request, the INVITE's number for an ACK and the last request's
+/// number for a CANCEL. 0 for an unknown session, a NULL or malformed method, an ACK
+/// before any INVITE, a CANCEL before any request, or once 2^31 - 1 has been used.
+#[no_mangle]
+pub extern "C" fn rsip_session_next_cseq(id: u64, method: *const c_char) -> u32 {
+    str_arg(method)
+        .and_then(|method| crate::default_context().session_next_cseq(id, method))
+        .unwrap_or(0)
+}
+
+/// Forgets session `id`. Returns false for an unknown id.
+#[no_mangle]
+pub extern "C" fn rsip_session_free(id: u64) -> bool {
+    crate::default_context().session_free(id)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_session_new(ctx: *mut RsipContext, call_id: *const c_char) -> u64 {
+    match str_arg(call_id) {
+        Some(call_id) => with_context(ctx, |ctx| ctx.session_new(call_id))
+            .flatten()
+            .unwrap_or(0),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_session_next_cseq(
+    ctx: *mut RsipContext,
+    id: u64,
+    method: *const c_char,
+) -> u32 {
+    match str_arg(method) {
+        Some(method) => with_context(ctx, |ctx| ctx.session_next_cseq(id, method))
+            .flatten()
+            .unwrap_or(0),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_session_free(ctx: *mut RsipContext, id: u64) -> bool {
+    with_context(ctx, |ctx| ctx.session_free(id)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn numbers_requests_in_order() {
+        let ctx = RsipContext::new();
+        let id = ctx.session_new("cseq@192.0.2.1").unwrap();
+        let other = ctx.session_new("other@192.0.2.1").unwrap();
+        assert_ne!(id, other);
+        assert_eq!(ctx.session_next_cseq(id, "ACK"), None, "no INVITE yet");
+        assert_eq!(
+            ctx.session_next_cseq(id, "CANCEL"),
+            None,
+            "nothing to cancel"
+        );
+
+        assert_eq!(ctx.session_next_cseq(id, "INVITE"), Some(1));
+        assert_eq!(ctx.session_next_cseq(id, "CANCEL"), Some(1));
+        assert_eq!(ctx.session_next_cseq(id, "ACK"), Some(1));
+        assert_eq!(ctx.session_next_cseq(id, "INFO"), Some(2));
+        assert_eq!(
+            ctx.session_next_cseq(id, "ACK"),
+            Some(1),
+            "ACK follows the INVITE"
+        );
+        assert_eq!(ctx.session_next_cseq(id, "INVITE"), Some(3));
+        assert_eq!(ctx.session_next_cseq(id, "ACK"), Some(3));
+        assert_eq!(ctx.session_next_cseq(id, "BYE"), Some(4));
+        assert_eq!(ctx.session_next_cseq(other, "OPTIONS"), Some(1));
+        assert_eq!(ctx.session_next_cseq(id, "bad method"), None);
+
+        let session = ctx.session(id).unwrap();
+        assert_eq!(session.call_id, "cseq@192.0.2.1");
+        assert_eq!(session.last_method.as_deref(), Some("BYE"));
+
+        ctx.sessions
+            .lock()
+            .unwrap()
+            .by_id
+            .get_mut(&id)
+            .unwrap()
+            .cseq = MAX_CSEQ;
+        assert_eq!(ctx.session_next_cseq(id, "BYE"), None);
+        assert!(ctx.session_free(id));
+        assert!(!ctx.session_free(id));
+        assert_eq!(ctx.session_next_cseq(id, "BYE"), None);
+    }
+
+    #[test]
+    fn ffi_reports_failures_as_zero() {
+        let call_id = CString::new("ffi@192.0.2.1").unwrap();
+        let method = CString::new("REGISTER").unwrap();
+        let id = rsip_session_new(call_id.as_ptr());
+        assert_ne!(id, 0);
+        assert_eq!(rsip_session_next_cseq(id, method.as_ptr()), 1);
+        assert_eq!(rsip_session_next_cseq(id, method.as_ptr()), 2);
+        assert_eq!(rsip_session_next_cseq(id, std::ptr::null()), 0);
+        assert!(rsip_session_free(id));
+        assert_eq!(rsip_session_next_cseq(id, method.as_ptr()), 0);
+        assert_eq!(rsip_session_new(std::ptr::null()), 0);
+        assert_eq!(
+            rsip_context_session_new(std::ptr::null_mut(), call_id.as_ptr()),
+            0
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Traffic counters. Updates are single relaxed atomic adds, cheap enough for the
+//! receive loop; a snapshot is therefore not a consistent cut across counters.
+
+use crate::context::{with_context, RsipContext};
+use crate::ffi::into_c_string;
+use serde_json::json;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Default)]
+pub(crate) struct Stats {
+    /// Messages handed to the receive pipeline: datagrams, or framed stream messages.
+    pub packets_received: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub parse_failures: AtomicU64,
+    /// Datagrams that filled the whole receive buffer (see `sip_rx_truncated`).
+    pub truncated: AtomicU64,
+    pub send_ok: AtomicU64,
+    pub send_failed: AtomicU64,
+    /// Messages discarded because their worker queue was full.
+    pub queue_dropped: AtomicU64,
+    /// Messages dropped by the size or per-source rate limit.
+    pub rate_limited: AtomicU64,
+    /// Messages rejected by the source IP filter.
+    pub filtered: AtomicU64,
+}
+
+impl Stats {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn record_send<T, E>(&self, result: &Result<T, E>) {
+        match result {
+            Ok(_) => Self::add(&self.send_ok, 1),
+            Err(_) => Self::add(&self.send_failed, 1),
+        }
+    }
+
+    fn counters(&self) -> [(&'static str, &AtomicU64); 9] {
+        [
+            ("packets_received", &self.packets_received),
+            ("bytes_received", &self.bytes_received),
+            ("parse_failures", &self.parse_failures),
+            ("truncated", &self.truncated),
+            ("send_ok", &self.send_ok),
+            ("send_failed", &self.send_failed),
+            ("queue_dropped", &self.queue_dropped),
+            ("rate_limited", &self.rate_limited),
+            ("filtered", &self.filtered),
+        ]
+    }
+
+    pub fn snapshot(&self) -> serde_json::Value {
+        let mut snapshot = json!({});
+        for (name, counter) in self.counters().iter() {
+            snapshot[*name] = json!(counter.load(Ordering::Relaxed));
+        }
+        snapshot
+    }
+
+    pub fn reset(&self) {
+        for (_, counter) in self.counters().iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// JSON snapshot of the counters: {packets_received, bytes_received, parse_failures,
+/// truncated, send_ok, send_failed, queue_dropped,
+/// rate_limited, filtered}. Caller-owned.
+#[no_mangle]
+pub extern "C" fn rsip_get_stats() -> *mut c_char {
+    into_c_string(crate::default_context().stats.snapshot().to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_reset_stats() {
+    crate::default_context().stats.reset();
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_get_stats(ctx: *mut RsipContext) -> *mut c_char {
+    with_context(ctx, |ctx| into_c_string(ctx.stats.snapshot().to_string()))
+        .unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_reset_stats(ctx: *mut RsipContext) {
+    with_context(ctx, |ctx| ctx.stats.reset());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::{rsip_context_free, rsip_context_new};
+    use crate::ffi::rsip_free_string;
+    use std::ffi::CStr;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    fn stats(ctx: *mut RsipContext) -> serde_json::Value {
+        let raw = rsip_context_get_stats(ctx);
+        let json = serde_json::from_str(unsafe { CStr::from_ptr(raw) }.to_str().unwrap()).unwrap();
+        rsip_free_string(raw);
+        json
+    }
+
+    #[test]
+    fn counts_received_datagrams_and_sends() {
+        const N: u64 = 20;
+        let ctx = rsip_context_new();
+        let addr = with_context(ctx, |ctx| {
+            ctx.start_udp_listener_on("127.0.0.1", 0).unwrap();
+            ctx.local_addr().unwrap()
+        })
+        .unwrap();
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let msg = b"OPTIONS sip:a@b SIP/2.0\r\n\r\n";
+        for _ in 0..N {
+            sender.send_to(msg, addr).unwrap();
+        }
+        sender.send_to(b"garbage", addr).unwrap();
+        for _ in 0..200 {
+            if stats(ctx)["packets_received"] == N + 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let snapshot = stats(ctx);
+        assert_eq!(snapshot["packets_received"], N + 1);
+        assert_eq!(snapshot["bytes_received"], N * msg.len() as u64 + 7);
+        assert_eq!(snapshot["parse_failures"], 1);
+
+        with_context(ctx, |ctx| {
+            let peer = sender.local_addr().unwrap();
+            ctx.send_from_listener("127.0.0.1", peer.port(), b"x")
+                .unwrap();
+        });
+        assert_eq!(stats(ctx)["send_ok"], 1);
+
+        rsip_context_reset_stats(ctx);
+        assert_eq!(stats(ctx)["packets_received"], 0);
+        assert_eq!(stats(ctx)["send_ok"], 0);
+        rsip_context_free(ctx);
+    }
+}
@@ -0,0 +1,572 @@
+//! Plumbing shared by the connection-oriented transports: splitting a byte stream
+//! into SIP messages (RFC 3261 §18.3) and keep-alive pings (RFC 5626 §3.5.1), and
+//! running an accept loop.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::{to_code, RsipError};
+use crate::lock::Lock;
+use crate::log::LogLevel;
+use serde_json::json;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often blocked accept/read calls re-check whether the listener was stopped.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// A header section larger than this is treated as garbage and the connection dropped.
+pub(crate) const MAX_HEADER_SIZE: usize = 64 * 1024;
+/// Upper bound for a declared Content-Length.
+pub(crate) const MAX_BODY_SIZE: usize = 1 << 20;
+/// Connections that have not finished their TLS or WebSocket handshake by then are dropped.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum FramingError {
+    HeaderTooLarge,
+    BadContentLength,
+}
+
+impl std::fmt::Display for FramingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HeaderTooLarge => write!(f, "header section too large"),
+            Self::BadContentLength => write!(f, "invalid Content-Length"),
+        }
+    }
+}
+
+/// The keep-alive ping a client sends between messages; answered with a single CRLF.
+const PING: &[u8] = b"\r\n\r\n";
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Frame {
+    Message(Vec<u8>),
+    /// A CRLFCRLF keep-alive ping.
+    Ping,
+}
+
+/// Accumulates bytes read from a stream and yields complete messages: the header
+/// section up to the blank line plus `Content-Length` bytes of body. With `lenient`
+/// set, a blank line may end in a bare LF, as sent by sloppy implementations.
+#[derive(Default)]
+pub(crate) struct StreamFramer {
+    buf: Vec<u8>,
+    /// How much of `buf` has been searched for the end of the header section, so
+    /// that a message arriving in pieces is not rescanned from the start each time.
+    scanned: usize,
+    lenient: bool,
+}
+
+impl StreamFramer {
+    pub fn new(lenient: bool) -> Self {
+        Self {
+            buf: Vec::new(),
+            scanned: 0,
+            lenient,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Pops the next complete message or ping, or `None` until more bytes arrive.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FramingError> {
+        // Other CRs and LFs between messages (e.g. a CRLF pong) are skipped, up to
+        // what is or may become a ping.
+        let skip = (0..self.buf.len())
+            .find(|&i| {
+                let rest = &self.buf[i..];
+                rest.starts_with(PING)
+                    || PING.starts_with(rest)
+                    || !matches!(rest[0], b'\r' | b'\n')
+            })
+            .unwrap_or(self.buf.len());
+        if skip > 0 {
+            self.buf.drain(..skip);
+            self.scanned = 0;
+        }
+        if self.buf.starts_with(PING) {
+            self.buf.drain(..PING.len());
+            return Ok(Some(Frame::Ping));
+        }
+        if !self.buf.is_empty() && PING.starts_with(&self.buf) {
+            return Ok(None);
+        }
+
+        // A blank line cut by the previous push starts at most 3 bytes before its end.
+        let from = self.scanned.saturating_sub(3);
+        let header_end = match header_end(&self.buf, from, self.lenient) {
+            Some(end) => end,
+            None if self.buf.len() > MAX_HEADER_SIZE => return Err(FramingError::HeaderTooLarge),
+            None => {
+                self.scanned = self.buf.len();
+                return Ok(None);
+            }
+        };
+        let body_len = content_length(&self.buf[..header_end], self.lenient)?;
+        if self.buf.len() < header_end + body_len {
+            return Ok(None);
+        }
+        self.scanned = 0;
+        Ok(Some(Frame::Message(
+            self.buf.drain(..header_end + body_len).collect(),
+        )))
+    }
+}
+
+/// The length of the header section, blank line included, once it is complete; the
+/// search starts at `from`.
+fn header_end(buf: &[u8], from: usize, lenient: bool) -> Option<usize> {
+    if !lenient {
+        return find(&buf[from..], b"\r\n\r\n").map(|pos| from + pos + 4);
+    }
+    // The first LF followed by an empty line, whether that ends in CRLF or LF.
+    buf.iter()
+        .enumerate()
+        .skip(from)
+        .find_map(|(i, b)| match (b, &buf[i + 1..]) {
+            (b'\n', [b'\n', ..]) => Some(i + 2),
+            (b'\n', [b'\r', b'\n', ..]) => Some(i + 3),
+            _ => None,
+        })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Content-Length (or its compact form `l`) from a header section; 0 when absent.
+fn content_length(headers: &[u8], lenient: bool) -> Result<usize, FramingError> {
+    let headers = String::from_utf8_lossy(headers);
+    let lines: Vec<&str> = if lenient {
+        headers
+            .split('\n')
+            .map(|l| l.trim_end_matches('\r'))
+            .collect()
+    } else {
+        headers.split("\r\n").collect()
+    };
+    for line in lines.into_iter().skip(1) {
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("l") {
+                return match value.trim().parse::<usize>() {
+                    Ok(len) if len <= MAX_BODY_SIZE => Ok(len),
+                    _ => Err(FramingError::BadContentLength),
+                };
+            }
+        }
+    }
+    Ok(0)
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+impl RsipContext {
+    /// Stream framing ends the header section at an empty line ending in a bare LF
+    /// too, for connections accepted from now on.
+    pub fn set_lenient_line_endings(&self, enabled: bool) {
+        self.config.locked().lenient_line_endings = enabled;
+    }
+
+    /// Whether keep-alive pings on stream connections are answered with a CRLF pong
+    /// (the default). Takes effect immediately.
+    pub fn set_keepalive_pong(&self, enabled: bool) {
+        self.config.locked().keepalive_pong = enabled;
+    }
+
+    /// Reads `stream` until EOF, an error, or `running` is cleared, handing every
+    /// complete message to the receive pipeline and reporting (and answering) keep-alive
+    /// pings. The underlying socket must have a read timeout so the flag is re-checked.
+    pub(crate) fn serve_stream(
+        &self,
+        stream: &mut (impl Read + Write),
+        src: SocketAddr,
+        running: &AtomicBool,
+    ) {
+        let mut framer = StreamFramer::new(self.config.locked().lenient_line_endings);
+        let mut buf = [0u8; 8192];
+        while running.load(Ordering::SeqCst) {
+            let n = match stream.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => n,
+                Err(e) if is_timeout(&e) => continue,
+                Err(_) => return,
+            };
+            framer.push(&buf[..n]);
+            loop {
+                match framer.next_frame() {
+                    Ok(Some(Frame::Message(msg))) => self.dispatch(&msg, src),
+                    Ok(Some(Frame::Ping)) => {
+                        let pong = self.config.locked().keepalive_pong;
+                        if pong
+                            && stream
+                                .write_all(b"\r\n")
+                                .and_then(|_| stream.flush())
+                                .is_err()
+                        {
+                            return;
+                        }
+                        let payload = json!({ "src": src.to_string(), "pong": pong });
+                        self.emit_from("keepalive_ping", &payload.to_string(), src);
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.log(LogLevel::Warn, format_args!("closing {}: {}", src, e));
+                        self.emit_from("error", &format!("framing_err:{}", e), src);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl RsipContext {
+    /// Accepts SIP over TCP on `port`, bound to the configured bind address.
+    pub fn start_tcp_listener(self: &Arc<Self>, port: u16) -> Result<(), RsipError> {
+        let mut slot = self.tcp_listener.locked();
+        if slot.is_some() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        let bind_ip = self.config.locked().bind_ip;
+        let listener = TcpListener::bind(SocketAddr::new(bind_ip, port)).map_err(|e| {
+            self.log(
+                LogLevel::Error,
+                format_args!("tcp bind port {} failed: {}", port, e),
+            );
+            self.emit("error", &format!("bind_err:{}", e));
+            RsipError::from_bind_error(&e)
+        })?;
+        self.start_workers();
+        let ctx = self.clone();
+        let listener = StreamListener::spawn(listener, move |mut tcp, peer, running| {
+            ctx.serve_stream(&mut tcp, peer, running)
+        })
+        .map_err(|_| RsipError::Io)?;
+        self.log(
+            LogLevel::Info,
+            format_args!("tcp listener on {}", listener.local_addr),
+        );
+        *slot = Some(listener);
+        Ok(())
+    }
+
+    /// The address the TCP listener is bound to, if one is running.
+    pub fn tcp_local_addr(&self) -> Option<SocketAddr> {
+        self.tcp_listener.locked().as_ref().map(|l| l.local_addr)
+    }
+}
+
+/// A running accept loop; stopping it also ends every connection it accepted.
+pub(crate) struct StreamListener {
+    pub local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl StreamListener {
+    /// Accepts connections on `listener`, running `serve` on a thread per connection.
+    /// `serve` gets the stream (with a read timeout set), the peer and the running flag.
+    pub fn spawn<F>(listener: TcpListener, serve: F) -> io::Result<Self>
+    where
+        F: Fn(TcpStream, SocketAddr, &AtomicBool) + Send + Sync + 'static,
+    {
+        let local_addr = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        let running = Arc::new(AtomicBool::new(true));
+        let serve = Arc::new(serve);
+
+        let flag = running.clone();
+        let thread = thread::spawn(move || {
+            let mut connections: Vec<JoinHandle<()>> = Vec::new();
+            while flag.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        if stream.set_nonblocking(false).is_err()
+                            || stream.set_read_timeout(Some(POLL_INTERVAL)).is_err()
+                        {
+                            continue;
+                        }
+                        let serve = serve.clone();
+                        let flag = flag.clone();
+                        connections.push(thread::spawn(move || serve(stream, peer, &flag)));
+                        connections.retain(|c| !c.is_finished());
+                    }
+                    Err(e) if is_timeout(&e) => thread::sleep(POLL_INTERVAL),
+                    Err(_) => thread::sleep(POLL_INTERVAL),
+                }
+            }
+            for connection in connections {
+                let _ = connection.join();
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            running,
+            thread,
+        })
+    }
+
+    /// Stops accepting and tells every connection to finish; does not wait for them.
+    pub fn halt(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn stop(self) {
+        self.halt();
+        let _ = self.thread.join();
+    }
+}
+
+/// Starts a SIP-over-TCP listener on `port`. Messages split across reads or sharing
+/// one read are reassembled before they reach the callback.
+#[no_mangle]
+pub extern "C" fn rsip_start_tcp_listener(port: u16) -> bool {
+    rsip_start_tcp_listener_ex(port) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_start_tcp_listener_ex(port: u16) -> i32 {
+    to_code(crate::default_context().start_tcp_listener(port))
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_tcp_listener(ctx: *mut RsipContext, port: u16) -> bool {
+    rsip_context_start_tcp_listener_ex(ctx, port) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_tcp_listener_ex(ctx: *mut RsipContext, port: u16) -> i32 {
+    to_code(
+        with_context(ctx, |ctx| ctx.start_tcp_listener(port))
+            .unwrap_or(Err(RsipError::InvalidArgument)),
+    )
+}
+
+/// Accepts header sections whose blank line ends in a bare LF on stream connections
+/// accepted from now on, for peers that do not send CRLF. Messages are parsed with
+/// bare-LF line endings either way.
+#[no_mangle]
+pub extern "C" fn rsip_set_lenient_line_endings(enabled: bool) {
+    crate::default_context().set_lenient_line_endings(enabled);
+}
+
+/// Whether CRLFCRLF keep-alive pings on TCP and TLS connections are answered with a
+/// CRLF pong (the default). They are reported as `keepalive_ping` either way.
+#[no_mangle]
+pub extern "C" fn rsip_set_keepalive_pong(enabled: bool) {
+    crate::default_context().set_keepalive_pong(enabled);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_lenient_line_endings(ctx: *mut RsipContext, enabled: bool) {
+    with_context(ctx, |ctx| ctx.set_lenient_line_endings(enabled));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_keepalive_pong(ctx: *mut RsipContext, enabled: bool) {
+    with_context(ctx, |ctx| ctx.set_keepalive_pong(enabled));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::ffi::CStr;
+    use std::io::Write;
+    use std::os::raw::c_char;
+    use std::sync::Mutex;
+
+    const MSG: &[u8] = b"MESSAGE sip:bob@example.com SIP/2.0\r\nContent-Length: 5\r\n\r\nhello";
+
+    #[test]
+    fn frames_split_and_coalesced_messages() {
+        let mut framer = StreamFramer::default();
+        framer.push(&MSG[..20]);
+        assert_eq!(framer.next_frame(), Ok(None));
+        framer.push(&MSG[20..]);
+        framer.push(b"\r\n");
+        framer.push(MSG);
+        assert_eq!(framer.next_frame(), Ok(Some(Frame::Message(MSG.to_vec()))));
+        assert_eq!(framer.next_frame(), Ok(Some(Frame::Message(MSG.to_vec()))));
+        assert_eq!(framer.next_frame(), Ok(None));
+    }
+
+    #[test]
+    fn frames_bytes_fed_one_at_a_time() {
+        let second = b"SIP/2.0 200 OK\r\nl: 3\r\n\r\nabc";
+        let stream: Vec<u8> = MSG.iter().chain(second.iter()).copied().collect();
+        let mut framer = StreamFramer::default();
+        let mut messages = Vec::new();
+        for byte in &stream {
+            framer.push(std::slice::from_ref(byte));
+            while let Some(frame) = framer.next_frame().unwrap() {
+                messages.push(frame);
+            }
+        }
+        assert_eq!(
+            messages,
+            vec![
+                Frame::Message(MSG.to_vec()),
+                Frame::Message(second.to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_content_length() {
+        let mut framer = StreamFramer::default();
+        framer.push(b"OPTIONS sip:a SIP/2.0\r\nl: nope\r\n\r\n");
+        assert_eq!(framer.next_frame(), Err(FramingError::BadContentLength));
+    }
+
+    #[test]
+    fn recognizes_keepalive_pings() {
+        let mut framer = StreamFramer::default();
+        framer.push(b"\r\n\r");
+        assert_eq!(framer.next_frame(), Ok(None), "half a ping");
+        framer.push(b"\n");
+        assert_eq!(framer.next_frame(), Ok(Some(Frame::Ping)));
+        // a pong (single CRLF) is skipped; a ping between messages is reported
+        framer.push(b"\r\n");
+        framer.push(MSG);
+        framer.push(b"\r\n\r\n");
+        framer.push(MSG);
+        assert_eq!(framer.next_frame(), Ok(Some(Frame::Message(MSG.to_vec()))));
+        assert_eq!(framer.next_frame(), Ok(Some(Frame::Ping)));
+        assert_eq!(framer.next_frame(), Ok(Some(Frame::Message(MSG.to_vec()))));
+        assert_eq!(framer.next_frame(), Ok(None));
+    }
+
+    #[test]
+    fn lenient_framing_accepts_bare_lf() {
+        let lf = b"MESSAGE sip:bob@example.com SIP/2.0\nl: 2\n\nhiOPTIONS sip:a SIP/2.0\r\n\n";
+        let mut strict = StreamFramer::default();
+        strict.push(lf);
+        assert_eq!(strict.next_frame(), Ok(None));
+
+        let mut framer = StreamFramer::new(true);
+        framer.push(lf);
+        assert_eq!(
+            framer.next_frame(),
+            Ok(Some(Frame::Message(
+                b"MESSAGE sip:bob@example.com SIP/2.0\nl: 2\n\nhi".to_vec()
+            )))
+        );
+        assert_eq!(
+            framer.next_frame(),
+            Ok(Some(Frame::Message(
+                b"OPTIONS sip:a SIP/2.0\r\n\n".to_vec()
+            )))
+        );
+        framer.push(MSG);
+        assert_eq!(framer.next_frame(), Ok(Some(Frame::Message(MSG.to_vec()))));
+    }
+
+    #[test]
+    fn lenient_framing_resumes_across_pushes() {
+        let stream = b"OPTIONS sip:a SIP/2.0\nl: 0\n\r\nOPTIONS sip:b SIP/2.0\r\nl: 0\r\n\n";
+        let mut framer = StreamFramer::new(true);
+        let mut messages = Vec::new();
+        for chunk in stream.chunks(2) {
+            framer.push(chunk);
+            while let Some(frame) = framer.next_frame().unwrap() {
+                messages.push(frame);
+            }
+        }
+        assert_eq!(
+            messages,
+            vec![
+                Frame::Message(stream[..29].to_vec()),
+                Frame::Message(stream[29..].to_vec())
+            ]
+        );
+    }
+
+    static RECEIVED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(_event: *const c_char, payload: *const c_char) {
+        let payload = unsafe { CStr::from_ptr(payload) }.to_string_lossy();
+        RECEIVED.lock().unwrap().push(payload.into_owned());
+    }
+
+    #[test]
+    fn tcp_listener_reassembles_fragmented_messages() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events
+            .subscribe(Some(vec!["sip_rx".into()]), Sink::Basic(record));
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        ctx.start_tcp_listener(0).unwrap();
+        assert_eq!(ctx.start_tcp_listener(0), Err(RsipError::AlreadyRunning));
+        let addr = ctx.tcp_local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_nodelay(true).unwrap();
+        for byte in MSG {
+            client.write_all(std::slice::from_ref(byte)).unwrap();
+        }
+        // two messages in a single write
+        client.write_all(&[MSG, MSG].concat()).unwrap();
+
+        let expected = String::from_utf8(MSG.to_vec()).unwrap();
+        for _ in 0..200 {
+            if RECEIVED.lock().unwrap().len() >= 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*RECEIVED.lock().unwrap(), vec![expected; 3]);
+
+        ctx.shutdown();
+        assert!(ctx.tcp_local_addr().is_none());
+    }
+
+    static PINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    extern "C" fn record_ping(_event: *const c_char, payload: *const c_char) {
+        let payload = unsafe { CStr::from_ptr(payload) }.to_string_lossy();
+        PINGS.lock().unwrap().push(payload.into_owned());
+    }
+
+    #[test]
+    fn tcp_keepalive_ping_gets_a_pong() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events.subscribe(
+            Some(vec!["keepalive_ping".into()]),
+            Sink::Basic(record_ping),
+        );
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        ctx.start_tcp_listener(0).unwrap();
+        let mut client = TcpStream::connect(ctx.tcp_local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(b"\r\n\r\n").unwrap();
+        let mut pong = [0u8; 2];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(&pong, b"\r\n");
+        for _ in 0..200 {
+            if !PINGS.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let src = client.local_addr().unwrap().to_string();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&PINGS.lock().unwrap()[0]).unwrap(),
+            json!({ "src": src, "pong": true })
+        );
+        ctx.shutdown();
+    }
+}
@@ -0,0 +1,231 @@
+//! One-shot timers for hosts driving SIP state machines (RFC 3261 Timers A to K): a
+//! per-context thread, started on first use, sleeps until the earliest deadline in a
+//! binary heap and calls the timer callback with the id and the host's pointer.
+
+use crate::context::{with_context, RsipContext};
+use crate::lock::{wait, wait_timeout, Lock};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::os::raw::c_void;
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Where delays too long to represent end up: never, for any practical purpose.
+const FOREVER: Duration = Duration::from_secs(100 * 365 * 24 * 3600);
+
+pub type TimerCallback = extern "C" fn(id: u64, user_data: *mut c_void);
+
+#[derive(Default)]
+struct State {
+    /// Deadlines, earliest first. Cancelled timers stay until they come up or
+    /// `compact` drops them.
+    heap: BinaryHeap<Reverse<(Instant, u64)>>,
+    /// The host's pointer for each armed timer, as an address so it can cross threads.
+    pending: HashMap<u64, usize>,
+    next_id: u64,
+    /// Bumped by `stop_timers`; a timer thread exits once it no longer matches the
+    /// value it was started with.
+    generation: u64,
+}
+
+impl State {
+    /// Drops cancelled deadlines once they outnumber the armed ones, so set/cancel
+    /// churn of long timers cannot grow the heap without bound.
+    fn compact(&mut self) {
+        if self.heap.len() > 2 * self.pending.len() + 64 {
+            let pending = &self.pending;
+            self.heap
+                .retain(|Reverse((_, id))| pending.contains_key(id));
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Timers {
+    state: Mutex<State>,
+    wake: Condvar,
+    callback: Mutex<Option<TimerCallback>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Timers {
+    fn run(&self, generation: u64) {
+        let mut state = self.state.locked();
+        loop {
+            if state.generation != generation {
+                return;
+            }
+            let (due, id) = match state.heap.peek() {
+                Some(Reverse(next)) => *next,
+                None => {
+                    state = wait(&self.wake, state);
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            if due > now {
+                state = wait_timeout(&self.wake, state, due - now);
+                continue;
+            }
+            state.heap.pop();
+            if let Some(user_data) = state.pending.remove(&id) {
+                drop(state);
+                let callback = *self.callback.locked();
+                if let Some(cb) = callback {
+                    cb(id, user_data as *mut c_void);
+                }
+                state = self.state.locked();
+            }
+        }
+    }
+}
+
+impl RsipContext {
+    /// Sets the callback expired timers are reported to, replacing any previous one;
+    /// `None` lets timers expire silently.
+    pub fn set_timer_callback(&self, cb: Option<TimerCallback>) {
+        *self.timers.callback.locked() = cb;
+    }
+
+    /// Arms a one-shot timer firing after `delay` and returns its id (never 0).
+    pub fn set_timer(&self, delay: Duration, user_data: *mut c_void) -> u64 {
+        let now = Instant::now();
+        let due = now.checked_add(delay).unwrap_or_else(|| now + FOREVER);
+        let mut thread = self.timers.thread.locked();
+        let mut state = self.timers.state.locked();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.heap.push(Reverse((due, id)));
+        state.pending.insert(id, user_data as usize);
+        if thread.is_none() {
+            let timers = self.timers.clone();
+            let generation = state.generation;
+            *thread = Some(thread::spawn(move || timers.run(generation)));
+        }
+        drop(state);
+        self.timers.wake.notify_one();
+        id
+    }
+
+    /// Disarms timer `id`. Returns false if it is unknown or has already fired.
+    pub fn cancel_timer(&self, id: u64) -> bool {
+        let mut state = self.timers.state.locked();
+        let cancelled = state.pending.remove(&id).is_some();
+        state.compact();
+        cancelled
+    }
+
+    /// Disarms every timer and stops the timer thread, unless called from it (from
+    /// a timer callback), in which case it ends once the callback returns.
+    pub(crate) fn stop_timers(&self) {
+        {
+            let mut state = self.timers.state.locked();
+            state.heap.clear();
+            state.pending.clear();
+            state.generation += 1;
+        }
+        self.timers.wake.notify_one();
+        let thread = self.timers.thread.locked().take();
+        if let Some(thread) = thread {
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Sets the callback that receives each expired timer's id and user_data, replacing
+/// any previous one; NULL lets timers expire silently.
+#[no_mangle]
+pub extern "C" fn rsip_set_timer_callback(cb: Option<TimerCallback>) {
+    crate::default_context().set_timer_callback(cb);
+}
+
+/// Arms a one-shot timer firing `delay_ms` from now on the timer thread and returns
+/// its id (never 0). `user_data` is passed back untouched.
+#[no_mangle]
+pub extern "C" fn rsip_set_timer(delay_ms: u64, user_data: *mut c_void) -> u64 {
+    crate::default_context().set_timer(Duration::from_millis(delay_ms), user_data)
+}
+
+/// Disarms timer `id`. Returns false if it is unknown or has already fired.
+#[no_mangle]
+pub extern "C" fn rsip_cancel_timer(id: u64) -> bool {
+    crate::default_context().cancel_timer(id)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_timer_callback(
+    ctx: *mut RsipContext,
+    cb: Option<TimerCallback>,
+) {
+    with_context(ctx, |ctx| ctx.set_timer_callback(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_timer(
+    ctx: *mut RsipContext,
+    delay_ms: u64,
+    user_data: *mut c_void,
+) -> u64 {
+    with_context(ctx, |ctx| {
+        ctx.set_timer(Duration::from_millis(delay_ms), user_data)
+    })
+    .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_cancel_timer(ctx: *mut RsipContext, id: u64) -> bool {
+    with_context(ctx, |ctx| ctx.cancel_timer(id)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static FIRED: Mutex<Vec<(u64, usize)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(id: u64, user_data: *mut c_void) {
+        FIRED.lock().unwrap().push((id, user_data as usize));
+    }
+
+    #[test]
+    fn fires_in_deadline_order_and_cancels() {
+        let ctx = RsipContext::new();
+        ctx.set_timer_callback(Some(record));
+        let late = ctx.set_timer(Duration::from_millis(60), 0x300 as *mut c_void);
+        let early = ctx.set_timer(Duration::from_millis(20), 0x100 as *mut c_void);
+        let cancelled = ctx.set_timer(Duration::from_millis(40), 0x200 as *mut c_void);
+        assert!(ctx.cancel_timer(cancelled));
+        assert!(!ctx.cancel_timer(cancelled));
+
+        for _ in 0..200 {
+            if FIRED.lock().unwrap().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*FIRED.lock().unwrap(), [(early, 0x100), (late, 0x300)]);
+        assert!(!ctx.cancel_timer(early), "already fired");
+
+        let pending = ctx.set_timer(Duration::from_secs(60), std::ptr::null_mut());
+        let never = ctx.set_timer(Duration::from_millis(u64::MAX), std::ptr::null_mut());
+        ctx.shutdown();
+        assert!(!ctx.cancel_timer(pending));
+        assert!(!ctx.cancel_timer(never));
+        assert!(ctx.timers.thread.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn cancelled_deadlines_are_compacted() {
+        let mut state = State::default();
+        let now = Instant::now();
+        for id in 0..1000 {
+            state.heap.push(Reverse((now, id)));
+        }
+        state.pending.insert(7, 0);
+        state.compact();
+        assert_eq!(state.heap.len(), 1);
+    }
+}
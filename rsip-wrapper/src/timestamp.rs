@@ -0,0 +1,184 @@
+//! Kernel receive timestamps: with `SO_TIMESTAMPNS` set on a UDP listener socket, the
+//! kernel records when each datagram arrived, which is more accurate than reading the
+//! clock once the listener thread gets to it. Linux only; elsewhere datagrams carry no
+//! timestamp.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::lock::Lock;
+use socket2::{SockRef, Socket};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+pub(crate) const RX_TIMESTAMPS_SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Asks the kernel to timestamp every datagram `socket` receives.
+#[cfg(target_os = "linux")]
+pub(crate) fn enable_rx_timestamps(socket: &Socket, enabled: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let on = libc::c_int::from(enabled);
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enable_rx_timestamps(_: &Socket, _: bool) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// `recv_from` that also returns the kernel's receive timestamp, in nanoseconds since
+/// the Unix epoch, if the socket has them enabled.
+#[cfg(target_os = "linux")]
+pub(crate) fn recv_timestamped(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u64>)> {
+    use std::os::unix::io::AsRawFd;
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // Room for one timespec control message, u64-aligned as cmsghdr requires.
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    let (n, src) = unsafe {
+        socket2::SockAddr::try_init(|addr, len| {
+            msg.msg_name = addr as *mut libc::c_void;
+            msg.msg_namelen = *len;
+            let n = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+            *len = msg.msg_namelen;
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as usize)
+            }
+        })
+    }?;
+    let src = src
+        .as_socket()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+    let mut timestamp = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while let Some(header) = unsafe { cmsg.as_ref() } {
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_TIMESTAMPNS {
+            let ts =
+                unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec) };
+            timestamp = Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok((n, src, timestamp))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn recv_timestamped(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<u64>)> {
+    socket.recv_from(buf).map(|(n, src)| (n, src, None))
+}
+
+impl RsipContext {
+    /// Turns kernel receive timestamps on or off for the UDP listeners, running ones
+    /// included and those added later. Messages they receive then carry `rx_ts_ns` in
+    /// their JSON payloads. `InvalidArgument` where the platform has no such option.
+    pub fn set_rx_timestamps(&self, enabled: bool) -> Result<(), RsipError> {
+        if !RX_TIMESTAMPS_SUPPORTED {
+            return Err(RsipError::InvalidArgument);
+        }
+        self.config.locked().rx_timestamps = enabled;
+        for listener in self.udp_listeners.locked().values() {
+            enable_rx_timestamps(&SockRef::from(&*listener.socket), enabled)
+                .map_err(|_| RsipError::Io)?;
+        }
+        Ok(())
+    }
+}
+
+/// Kernel receive timestamps (SO_TIMESTAMPNS) for the UDP listeners, running and future
+/// ones: the JSON payloads of messages they receive carry "rx_ts_ns", the arrival time
+/// in nanoseconds since the Unix epoch. False where the platform has no such option
+/// (only Linux does); messages then carry no timestamp.
+#[no_mangle]
+pub extern "C" fn rsip_set_rx_timestamps(enabled: bool) -> bool {
+    crate::default_context().set_rx_timestamps(enabled).is_ok()
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_rx_timestamps(ctx: *mut RsipContext, enabled: bool) -> bool {
+    with_context(ctx, |ctx| ctx.set_rx_timestamps(enabled).is_ok()).unwrap_or(false)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    static PARSED: Mutex<Vec<serde_json::Value>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(_: *const c_char, data: *const c_char) {
+        let data = unsafe { CStr::from_ptr(data) }.to_str().unwrap();
+        PARSED
+            .lock()
+            .unwrap()
+            .push(serde_json::from_str(data).unwrap());
+    }
+
+    #[test]
+    fn stamps_datagrams_from_running_listeners() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events
+            .subscribe(Some(vec!["sip_rx_parsed".into()]), Sink::Basic(record));
+        let id = ctx.add_udp_listener_on("127.0.0.1", 0).unwrap();
+        let addr = ctx.udp_listeners.lock().unwrap()[&id]
+            .socket
+            .local_addr()
+            .unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let wait_for = |count: usize| {
+            for _ in 0..400 {
+                if PARSED.lock().unwrap().len() >= count {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            panic!("no sip_rx_parsed");
+        };
+
+        peer.send_to(b"OPTIONS sip:a@b SIP/2.0\r\n\r\n", addr)
+            .unwrap();
+        wait_for(1);
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        ctx.set_rx_timestamps(true).unwrap();
+        peer.send_to(b"OPTIONS sip:a@b SIP/2.0\r\n\r\n", addr)
+            .unwrap();
+        wait_for(2);
+        let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        ctx.shutdown();
+
+        let parsed = PARSED.lock().unwrap();
+        assert!(parsed[0].get("rx_ts_ns").is_none());
+        assert_eq!(parsed[1]["listener"], id);
+        let ts = parsed[1]["rx_ts_ns"].as_u64().unwrap();
+        assert!(ts >= before.as_nanos() as u64 && ts <= after.as_nanos() as u64);
+    }
+}
@@ -0,0 +1,524 @@
+//! SIP over TLS (SIPS, RFC 3261 §26.2) listener backed by rustls.
+//!
+//! Each accepted connection completes its handshake on its own thread, then reads
+//! messages with the same stream framing as the other stream transports.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::{to_code, RsipError};
+use crate::ffi::str_arg;
+use crate::lock::Lock;
+use crate::log::LogLevel;
+use crate::stream::{is_timeout, StreamListener, HANDSHAKE_TIMEOUT};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{InconsistentKeys, ServerConfig, ServerConnection, StreamOwned};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// TLS versions as they appear on the wire; also the values the C API takes.
+pub const TLS_VERSION_1_2: u16 = 0x0303;
+pub const TLS_VERSION_1_3: u16 = 0x0304;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Loads a PEM certificate chain and private key from disk.
+pub(crate) fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificate found", cert_path));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| format!("{}: {}", key_path, e))?;
+    certified_key(certs, key)
+}
+
+/// Loads a PEM certificate chain and private key held in memory.
+pub(crate) fn load_certified_key_pem(
+    cert_pem: &str,
+    key_pem: &str,
+) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_slice_iter(cert_pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("certificate: {}", e))?;
+    if certs.is_empty() {
+        return Err("certificate: no certificate found".to_string());
+    }
+    let key = PrivateKeyDer::from_pem_slice(key_pem.as_bytes())
+        .map_err(|e| format!("private key: {}", e))?;
+    certified_key(certs, key)
+}
+
+/// Pairs the chain with its key, rejecting a key that does not belong to the leaf.
+fn certified_key(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<CertifiedKey, String> {
+    CertifiedKey::from_der(certs, key, &provider()).map_err(|e| match e {
+        rustls::Error::InconsistentKeys(InconsistentKeys::KeyMismatch) => {
+            "private key does not match the leaf certificate".to_string()
+        }
+        e => e.to_string(),
+    })
+}
+
+/// Picks the certificate for the SNI name the client asked for, falling back to the
+/// listener's default certificate for unknown names and clients that send no SNI.
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn lookup(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        server_name
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.lookup(hello.server_name()))
+    }
+}
+
+fn server_config(
+    default: CertifiedKey,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    min_version: u16,
+) -> Result<ServerConfig, rustls::Error> {
+    let versions: &[&rustls::SupportedProtocolVersion] = if min_version >= TLS_VERSION_1_3 {
+        &[&rustls::version::TLS13]
+    } else {
+        rustls::ALL_VERSIONS
+    };
+    Ok(ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(versions)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver {
+            default: Arc::new(default),
+            by_name,
+        })))
+}
+
+/// Drives the handshake to completion, tolerating the socket's poll timeouts.
+fn handshake(
+    conn: &mut ServerConnection,
+    tcp: &mut TcpStream,
+    running: &AtomicBool,
+) -> Result<(), String> {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    while conn.is_handshaking() {
+        if !running.load(Ordering::SeqCst) {
+            return Err("listener stopped".into());
+        }
+        if Instant::now() >= deadline {
+            return Err("handshake timeout".into());
+        }
+        match conn.complete_io(tcp) {
+            Ok((0, 0)) if conn.wants_read() => return Err("connection closed".into()),
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(())
+}
+
+impl RsipContext {
+    /// Sets the lowest TLS version accepted by the next TLS listener.
+    pub fn set_tls_min_version(&self, version: u16) -> Result<(), RsipError> {
+        if version != TLS_VERSION_1_2 && version != TLS_VERSION_1_3 {
+            return Err(RsipError::InvalidArgument);
+        }
+        self.config.locked().tls_min_version = version;
+        Ok(())
+    }
+
+    /// Logs and reports a certificate or key that cannot be used, as `tls_cert:<why>`.
+    fn cert_error(&self, e: String) -> RsipError {
+        self.log(LogLevel::Error, format_args!("tls certificate: {}", e));
+        self.emit("error", &format!("tls_cert:{}", e));
+        RsipError::InvalidArgument
+    }
+
+    /// Adds a certificate served to clients that ask for `server_name` via SNI.
+    pub fn add_tls_sni_cert(
+        &self,
+        server_name: &str,
+        cert_path: &str,
+        key_path: &str,
+    ) -> Result<(), RsipError> {
+        let key = load_certified_key(cert_path, key_path).map_err(|e| self.cert_error(e))?;
+        self.config
+            .locked()
+            .tls_sni_certs
+            .insert(server_name.to_ascii_lowercase(), Arc::new(key));
+        Ok(())
+    }
+
+    pub fn start_tls_listener(
+        self: &Arc<Self>,
+        port: u16,
+        cert_path: &str,
+        key_path: &str,
+    ) -> Result<(), RsipError> {
+        let key = load_certified_key(cert_path, key_path).map_err(|e| self.cert_error(e))?;
+        self.start_tls_listener_with(port, key)
+    }
+
+    /// Like `start_tls_listener`, with the chain and key given as PEM text.
+    pub fn start_tls_listener_mem(
+        self: &Arc<Self>,
+        port: u16,
+        cert_pem: &str,
+        key_pem: &str,
+    ) -> Result<(), RsipError> {
+        let key = load_certified_key_pem(cert_pem, key_pem).map_err(|e| self.cert_error(e))?;
+        self.start_tls_listener_with(port, key)
+    }
+
+    pub(crate) fn start_tls_listener_with(
+        self: &Arc<Self>,
+        port: u16,
+        key: CertifiedKey,
+    ) -> Result<(), RsipError> {
+        let mut slot = self.tls_listener.locked();
+        if slot.is_some() {
+            return Err(RsipError::AlreadyRunning);
+        }
+
+        let (bind_ip, by_name, min_version) = {
+            let config = self.config.locked();
+            (
+                config.bind_ip,
+                config.tls_sni_certs.clone(),
+                config.tls_min_version,
+            )
+        };
+        let config = Arc::new(server_config(key, by_name, min_version).map_err(|e| {
+            self.log(LogLevel::Error, format_args!("tls config: {}", e));
+            self.emit("error", &format!("tls_config:{}", e));
+            RsipError::InvalidArgument
+        })?);
+        let listener = TcpListener::bind(SocketAddr::new(bind_ip, port)).map_err(|e| {
+            self.log(
+                LogLevel::Error,
+                format_args!("tls bind port {} failed: {}", port, e),
+            );
+            self.emit("error", &format!("bind_err:{}", e));
+            RsipError::from_bind_error(&e)
+        })?;
+
+        self.start_workers();
+        let ctx = self.clone();
+        let listener = StreamListener::spawn(listener, move |mut tcp, peer, running| {
+            let mut conn = match ServerConnection::new(config.clone()) {
+                Ok(conn) => conn,
+                Err(e) => return ctx.tls_error(peer, &e.to_string()),
+            };
+            if let Err(reason) = handshake(&mut conn, &mut tcp, running) {
+                return ctx.tls_error(peer, &reason);
+            }
+            ctx.serve_stream(&mut StreamOwned::new(conn, tcp), peer, running);
+        })
+        .map_err(|_| RsipError::Io)?;
+        self.log(
+            LogLevel::Info,
+            format_args!("tls listener on {}", listener.local_addr),
+        );
+        *slot = Some(listener);
+        Ok(())
+    }
+
+    fn tls_error(&self, peer: SocketAddr, reason: &str) {
+        self.log(
+            LogLevel::Debug,
+            format_args!("tls handshake with {} failed: {}", peer, reason),
+        );
+        let payload = json!({ "src": peer.to_string(), "reason": reason });
+        self.emit_from("tls_error", &payload.to_string(), peer);
+    }
+
+    /// The address the TLS listener is bound to, if one is running.
+    pub fn tls_local_addr(&self) -> Option<SocketAddr> {
+        self.tls_listener.locked().as_ref().map(|l| l.local_addr)
+    }
+}
+
+fn two_str_args<'a>(a: *const c_char, b: *const c_char) -> Result<(&'a str, &'a str), RsipError> {
+    match (str_arg(a), str_arg(b)) {
+        (Some(a), Some(b)) => Ok((a, b)),
+        _ => Err(RsipError::InvalidArgument),
+    }
+}
+
+/// Starts a TLS listener on `port` (bound to the configured bind address) with a PEM
+/// certificate chain and private key. Returns an `RsipError` code.
+#[no_mangle]
+pub extern "C" fn rsip_start_tls_listener(
+    port: u16,
+    cert_path: *const c_char,
+    key_path: *const c_char,
+) -> i32 {
+    let result = two_str_args(cert_path, key_path)
+        .and_then(|(cert, key)| crate::default_context().start_tls_listener(port, cert, key));
+    to_code(result)
+}
+
+/// Starts a TLS listener on `port` with the certificate chain and private key passed as
+/// PEM text rather than file paths. A key that does not match the leaf certificate is
+/// rejected with an `error` event. Returns an `RsipError` code.
+#[no_mangle]
+pub extern "C" fn rsip_start_tls_listener_mem(
+    port: u16,
+    cert_pem: *const c_char,
+    key_pem: *const c_char,
+) -> i32 {
+    let result = two_str_args(cert_pem, key_pem)
+        .and_then(|(cert, key)| crate::default_context().start_tls_listener_mem(port, cert, key));
+    to_code(result)
+}
+
+/// Lowest accepted TLS version: 0x0303 (TLS 1.2, the default) or 0x0304 (TLS 1.3).
+#[no_mangle]
+pub extern "C" fn rsip_set_tls_min_version(version: u16) -> bool {
+    crate::default_context()
+        .set_tls_min_version(version)
+        .is_ok()
+}
+
+/// Serves `cert_path`/`key_path` to clients requesting `server_name` via SNI.
+#[no_mangle]
+pub extern "C" fn rsip_add_tls_sni_cert(
+    server_name: *const c_char,
+    cert_path: *const c_char,
+    key_path: *const c_char,
+) -> i32 {
+    let result = match str_arg(server_name) {
+        Some(name) => two_str_args(cert_path, key_path)
+            .and_then(|(cert, key)| crate::default_context().add_tls_sni_cert(name, cert, key)),
+        None => Err(RsipError::InvalidArgument),
+    };
+    to_code(result)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_tls_listener(
+    ctx: *mut RsipContext,
+    port: u16,
+    cert_path: *const c_char,
+    key_path: *const c_char,
+) -> i32 {
+    let result = two_str_args(cert_path, key_path).and_then(|(cert, key)| {
+        with_context(ctx, |ctx| ctx.start_tls_listener(port, cert, key))
+            .unwrap_or(Err(RsipError::InvalidArgument))
+    });
+    to_code(result)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_tls_listener_mem(
+    ctx: *mut RsipContext,
+    port: u16,
+    cert_pem: *const c_char,
+    key_pem: *const c_char,
+) -> i32 {
+    let result = two_str_args(cert_pem, key_pem).and_then(|(cert, key)| {
+        with_context(ctx, |ctx| ctx.start_tls_listener_mem(port, cert, key))
+            .unwrap_or(Err(RsipError::InvalidArgument))
+    });
+    to_code(result)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_tls_min_version(ctx: *mut RsipContext, version: u16) -> bool {
+    with_context(ctx, |ctx| ctx.set_tls_min_version(version).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_add_tls_sni_cert(
+    ctx: *mut RsipContext,
+    server_name: *const c_char,
+    cert_path: *const c_char,
+    key_path: *const c_char,
+) -> i32 {
+    let result = match str_arg(server_name) {
+        Some(name) => two_str_args(cert_path, key_path).and_then(|(cert, key)| {
+            with_context(ctx, |ctx| ctx.add_tls_sni_cert(name, cert, key))
+                .unwrap_or(Err(RsipError::InvalidArgument))
+        }),
+        None => Err(RsipError::InvalidArgument),
+    };
+    to_code(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::convert::TryFrom;
+    use std::ffi::CStr;
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls-cert.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls-key.pem");
+    const OTHER_KEY: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/tls-other-key.pem"
+    );
+
+    static EVENTS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(event: *const c_char, payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }
+            .to_string_lossy()
+            .into_owned();
+        let payload = unsafe { CStr::from_ptr(payload) }
+            .to_string_lossy()
+            .into_owned();
+        EVENTS.lock().unwrap().push((event, payload));
+    }
+
+    fn wait_for(event: &str) -> Option<String> {
+        for _ in 0..200 {
+            if let Some((_, p)) = EVENTS.lock().unwrap().iter().find(|(e, _)| e == event) {
+                return Some(p.clone());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        None
+    }
+
+    fn client_config() -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(CERT).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        Arc::new(
+            ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }
+
+    #[test]
+    fn delivers_messages_and_reports_handshake_failures() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events.subscribe(
+            Some(vec!["sip_rx".into(), "tls_error".into()]),
+            Sink::Basic(record),
+        );
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        ctx.start_tls_listener(0, CERT, KEY).unwrap();
+        let addr = ctx.tls_local_addr().unwrap();
+        assert_eq!(
+            ctx.start_tls_listener(0, CERT, KEY),
+            Err(RsipError::AlreadyRunning)
+        );
+
+        // a plain-text client fails the handshake
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain.write_all(b"OPTIONS sip:a SIP/2.0\r\n\r\n").unwrap();
+        let error: serde_json::Value =
+            serde_json::from_str(&wait_for("tls_error").unwrap()).unwrap();
+        assert_eq!(error["src"], plain.local_addr().unwrap().to_string());
+        assert!(!error["reason"].as_str().unwrap().is_empty());
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let conn = ClientConnection::new(client_config(), name).unwrap();
+        let mut tls = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+        let msg = "MESSAGE sip:bob@example.com SIP/2.0\r\nContent-Length: 2\r\n\r\nhi";
+        // split across writes to exercise the framing
+        tls.write_all(&msg.as_bytes()[..10]).unwrap();
+        tls.flush().unwrap();
+        tls.write_all(&msg.as_bytes()[10..]).unwrap();
+        tls.flush().unwrap();
+        assert_eq!(wait_for("sip_rx").as_deref(), Some(msg));
+
+        ctx.shutdown();
+        assert!(ctx.tls_local_addr().is_none());
+    }
+
+    #[test]
+    fn sni_picks_the_matching_certificate() {
+        let default = Arc::new(load_certified_key(CERT, KEY).unwrap());
+        let other = Arc::new(load_certified_key(CERT, KEY).unwrap());
+        let mut by_name = HashMap::new();
+        by_name.insert("sip.example.com".to_string(), other.clone());
+        let resolver = SniResolver {
+            default: default.clone(),
+            by_name,
+        };
+        assert!(Arc::ptr_eq(
+            &resolver.lookup(Some("SIP.example.com")),
+            &other
+        ));
+        assert!(Arc::ptr_eq(
+            &resolver.lookup(Some("unknown.example.com")),
+            &default
+        ));
+        assert!(Arc::ptr_eq(&resolver.lookup(None), &default));
+    }
+
+    #[test]
+    fn loads_pem_from_memory() {
+        let cert = std::fs::read_to_string(CERT).unwrap();
+        let key = std::fs::read_to_string(KEY).unwrap();
+        let other_key = std::fs::read_to_string(OTHER_KEY).unwrap();
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events
+            .subscribe(Some(vec!["error".into()]), Sink::Basic(record));
+
+        assert_eq!(
+            ctx.start_tls_listener_mem(0, &cert, &other_key),
+            Err(RsipError::InvalidArgument)
+        );
+        assert_eq!(
+            wait_for("error").as_deref(),
+            Some("tls_cert:private key does not match the leaf certificate")
+        );
+        assert_eq!(
+            ctx.start_tls_listener_mem(0, "not pem", &key),
+            Err(RsipError::InvalidArgument)
+        );
+        assert!(ctx.tls_local_addr().is_none());
+
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        ctx.start_tls_listener_mem(0, &cert, &key).unwrap();
+        assert!(ctx.tls_local_addr().is_some());
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn config_validation() {
+        let ctx = RsipContext::new();
+        assert!(ctx.set_tls_min_version(TLS_VERSION_1_3).is_ok());
+        assert_eq!(
+            ctx.set_tls_min_version(0x0301),
+            Err(RsipError::InvalidArgument)
+        );
+        assert_eq!(
+            ctx.add_tls_sni_cert("sip.example.com", "/nonexistent.pem", KEY),
+            Err(RsipError::InvalidArgument)
+        );
+    }
+}
@@ -0,0 +1,472 @@
+//! INVITE client transactions over UDP (RFC 3261 §17.1.1).
+//!
+//! Each transaction runs on its own thread: it retransmits the INVITE from the listener
+//! socket on timer A until a response arrives or timer B fires, ACKs non-2xx finals
+//! itself and absorbs their retransmissions until timer D. ACKing a 2xx is left to the
+//! host, as it belongs to the dialog rather than the transaction.
+
+use crate::builder::{ack_for_failure, serialize};
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::ffi::{into_c_string, str_arg};
+use crate::lock::Lock;
+use crate::register::Wakeup;
+use crate::send::{resolve, send_args};
+use rsip::prelude::*;
+use rsip::{Method, Request, Response, SipMessage};
+use serde_json::json;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::os::raw::c_char;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// RTT estimate; the first retransmission interval.
+pub(crate) const T1: Duration = Duration::from_millis(500);
+/// Cap on the retransmission interval of non-INVITE requests.
+pub(crate) const T2: Duration = Duration::from_secs(4);
+/// Non-INVITE transaction timeout (64*T1).
+pub(crate) const TIMER_F: Duration = Duration::from_secs(32);
+
+/// The client INVITE timers, overridable so tests need not wait 32 seconds.
+#[derive(Clone, Copy)]
+pub(crate) struct InviteTimers {
+    /// Initial timer A interval, doubled on every retransmission.
+    pub t1: Duration,
+    /// Timer B: give up if nothing was heard back by then.
+    pub timer_b: Duration,
+    /// Timer D: how long to keep ACKing retransmitted non-2xx finals.
+    pub timer_d: Duration,
+}
+
+impl Default for InviteTimers {
+    fn default() -> Self {
+        Self {
+            t1: T1,
+            timer_b: T1 * 64,
+            timer_d: Duration::from_secs(32),
+        }
+    }
+}
+
+/// The key matching a response to its client transaction (RFC 3261 §17.1.3): the top
+/// Via branch and the CSeq method, as `<branch>:<METHOD>`. An ACK gets the key of its
+/// INVITE, as the ACK for a non-2xx final is part of the INVITE transaction. `None`
+/// without a Via branch or a valid CSeq.
+pub fn transaction_key(msg: &impl HeadersExt) -> Option<String> {
+    let branch = msg.via_header().ok()?.branch().ok()?.to_string();
+    let method = match msg.cseq_header().ok()?.method().ok()? {
+        Method::Ack => Method::Invite,
+        method => method,
+    };
+    if branch.is_empty() {
+        return None;
+    }
+    Some(format!("{}:{}", branch, method))
+}
+
+pub(crate) struct Transaction {
+    wake: Sender<Wakeup>,
+    thread: JoinHandle<()>,
+}
+
+impl Transaction {
+    /// Abandons the transaction (no further events) and waits for its thread.
+    pub(crate) fn stop(self) {
+        let _ = self.wake.send(Wakeup::Stop);
+        let _ = self.thread.join();
+    }
+}
+
+struct InviteClient {
+    ctx: Arc<RsipContext>,
+    id: u64,
+    dest: SocketAddr,
+    invite: Request,
+    timers: InviteTimers,
+    inbox: Receiver<Wakeup>,
+}
+
+impl InviteClient {
+    fn send(&self, raw: &[u8]) -> Result<(), RsipError> {
+        self.ctx
+            .send_from_listener(&self.dest.ip().to_string(), self.dest.port(), raw)
+    }
+
+    fn emit(&self, event: &str, response: Option<&Response>, reason: Option<String>) {
+        let payload = match response {
+            Some(response) => json!({
+                "id": self.id,
+                "status": response.status_code().code(),
+                "reason": response.status_code().to_string(),
+                "response": response.to_string(),
+            }),
+            None => json!({ "id": self.id, "reason": reason }),
+        };
+        self.ctx.emit(event, &payload.to_string());
+    }
+
+    fn run(self) {
+        let raw = self.invite.to_string();
+        let deadline = Instant::now() + self.timers.timer_b;
+        let mut interval = self.timers.t1;
+        let mut proceeding = false;
+        let mut next_send = Instant::now();
+        let final_response = loop {
+            // Calling: retransmit on timer A. Proceeding: only wait.
+            if !proceeding && Instant::now() >= next_send {
+                if let Err(e) = self.send(raw.as_bytes()) {
+                    return self.emit("txn_timeout", None, Some(format!("transport: {}", e)));
+                }
+                next_send = Instant::now() + interval;
+                interval *= 2;
+            }
+            let wait = if proceeding {
+                self.timers.timer_b
+            } else {
+                next_send
+                    .min(deadline)
+                    .saturating_duration_since(Instant::now())
+            };
+            match self.inbox.recv_timeout(wait) {
+                Ok(Wakeup::Response(res)) if res.status_code().code() >= 200 => break res,
+                Ok(Wakeup::Response(res)) => {
+                    proceeding = true;
+                    self.emit("txn_provisional", Some(&res), None);
+                }
+                Ok(Wakeup::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                Err(RecvTimeoutError::Timeout) if !proceeding && Instant::now() >= deadline => {
+                    return self.emit("txn_timeout", None, Some("timer B".into()));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        };
+
+        self.emit("txn_final", Some(&final_response), None);
+        if final_response.status_code().code() < 300 {
+            return;
+        }
+
+        // Completed: ACK, and ACK again for every retransmitted final until timer D.
+        let ack = serialize(
+            ack_for_failure(&self.invite, &final_response),
+            self.ctx.message_defaults().compact,
+        );
+        let _ = self.send(ack.as_bytes());
+        let timer_d = Instant::now() + self.timers.timer_d;
+        loop {
+            let remaining = timer_d.saturating_duration_since(Instant::now());
+            match self.inbox.recv_timeout(remaining) {
+                Ok(Wakeup::Response(res)) if res.status_code().code() >= 300 => {
+                    let _ = self.send(ack.as_bytes());
+                }
+                Ok(Wakeup::Response(_)) => {}
+                Ok(Wakeup::Stop) | Err(_) => return,
+            }
+        }
+    }
+}
+
+impl RsipContext {
+    /// Starts a client INVITE transaction to `ip:port` from the running listener and
+    /// returns its id. `request` must be an INVITE with a Via branch.
+    pub fn txn_send_invite(
+        self: &Arc<Self>,
+        ip: &str,
+        port: u16,
+        request: &[u8],
+    ) -> Result<u64, RsipError> {
+        self.txn_send_invite_with(ip, port, request, InviteTimers::default())
+    }
+
+    pub(crate) fn txn_send_invite_with(
+        self: &Arc<Self>,
+        ip: &str,
+        port: u16,
+        request: &[u8],
+        timers: InviteTimers,
+    ) -> Result<u64, RsipError> {
+        if self.local_addr().is_none() {
+            return Err(RsipError::NotRunning);
+        }
+        let dest = resolve(ip, port)?;
+        let invite = match Request::try_from(request) {
+            Ok(invite) if invite.method == Method::Invite => invite,
+            _ => return Err(RsipError::InvalidArgument),
+        };
+        let key = transaction_key(&invite).ok_or(RsipError::InvalidArgument)?;
+
+        let id = self.next_transaction_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let (wake, inbox) = mpsc::channel();
+        self.waiters.locked().insert(key.clone(), wake.clone());
+        let client = InviteClient {
+            ctx: self.clone(),
+            id,
+            dest,
+            invite,
+            timers,
+            inbox,
+        };
+        // Hold the lock so the thread cannot finish and deregister before it is added.
+        let mut transactions = self.transactions.locked();
+        let thread = thread::spawn(move || {
+            let ctx = client.ctx.clone();
+            client.run();
+            ctx.waiters.locked().remove(&key);
+            ctx.transactions.locked().remove(&id);
+        });
+        transactions.insert(id, Transaction { wake, thread });
+        Ok(id)
+    }
+}
+
+/// The transaction key of a raw request or response (see `transaction_key`),
+/// caller-owned; NULL if `raw` does not parse or lacks a Via branch or CSeq.
+#[no_mangle]
+pub extern "C" fn rsip_transaction_key(raw: *const c_char) -> *mut c_char {
+    str_arg(raw)
+        .and_then(|raw| SipMessage::try_from(raw).ok())
+        .and_then(|msg| transaction_key(&msg))
+        .map_or(std::ptr::null_mut(), into_c_string)
+}
+
+/// Sends `request` (an INVITE) to `dest_ip:dest_port` from the running listener as a
+/// client transaction. Returns its id, or 0 if the arguments are invalid or no listener
+/// runs. Progress is reported as `txn_provisional`, `txn_final` and `txn_timeout`.
+#[no_mangle]
+pub extern "C" fn rsip_txn_send_invite(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    request: *const c_char,
+) -> u64 {
+    send_args(dest_ip, request)
+        .and_then(|(ip, request)| crate::default_context().txn_send_invite(ip, dest_port, request))
+        .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_txn_send_invite(
+    ctx: *mut RsipContext,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    request: *const c_char,
+) -> u64 {
+    send_args(dest_ip, request)
+        .and_then(|(ip, request)| {
+            with_context(ctx, |ctx| ctx.txn_send_invite(ip, dest_port, request))
+                .unwrap_or(Err(RsipError::InvalidArgument))
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::ffi::{CStr, CString};
+    use std::net::UdpSocket;
+    use std::sync::Mutex;
+
+    static EVENTS: Mutex<Vec<(String, serde_json::Value)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(event: *const c_char, payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        let payload = unsafe { CStr::from_ptr(payload) }.to_str().unwrap();
+        EVENTS
+            .lock()
+            .unwrap()
+            .push((event, serde_json::from_str(payload).unwrap()));
+    }
+
+    fn wait_for(event: &str, id: u64) -> serde_json::Value {
+        for _ in 0..300 {
+            let events = EVENTS.lock().unwrap();
+            if let Some((_, payload)) = events.iter().find(|(e, p)| e == event && p["id"] == id) {
+                return payload.clone();
+            }
+            drop(events);
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no {} event for transaction {}", event, id);
+    }
+
+    const TIMERS: InviteTimers = InviteTimers {
+        t1: Duration::from_millis(50),
+        timer_b: Duration::from_millis(400),
+        timer_d: Duration::from_millis(500),
+    };
+
+    fn invite(branch: &str) -> String {
+        format!(
+            "INVITE sip:bob@127.0.0.1 SIP/2.0\r\n\
+             Via: SIP/2.0/UDP 127.0.0.1;branch={}\r\n\
+             Max-Forwards: 70\r\n\
+             From: <sip:alice@127.0.0.1>;tag=a1\r\n\
+             To: <sip:bob@127.0.0.1>\r\n\
+             Call-ID: {}@127.0.0.1\r\n\
+             CSeq: 7 INVITE\r\n\
+             Content-Length: 0\r\n\r\n",
+            branch, branch
+        )
+    }
+
+    fn listening_context() -> Arc<RsipContext> {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events.subscribe(
+            Some(vec![
+                "txn_provisional".into(),
+                "txn_final".into(),
+                "txn_timeout".into(),
+            ]),
+            Sink::Basic(record),
+        );
+        ctx.start_udp_listener_on("127.0.0.1", 0).unwrap();
+        ctx
+    }
+
+    fn recv(uas: &UdpSocket) -> (SipMessage, SocketAddr) {
+        let mut buf = [0u8; 4096];
+        let (n, from) = uas.recv_from(&mut buf).unwrap();
+        (SipMessage::try_from(&buf[..n]).unwrap(), from)
+    }
+
+    fn respond(uas: &UdpSocket, to: SocketAddr, branch: &str, status: &str) {
+        let response = format!(
+            "SIP/2.0 {}\r\n\
+             Via: SIP/2.0/UDP 127.0.0.1;branch={}\r\n\
+             From: <sip:alice@127.0.0.1>;tag=a1\r\n\
+             To: <sip:bob@127.0.0.1>;tag=b1\r\n\
+             Call-ID: {}@127.0.0.1\r\n\
+             CSeq: 7 INVITE\r\n\
+             Content-Length: 0\r\n\r\n",
+            status, branch, branch
+        );
+        uas.send_to(response.as_bytes(), to).unwrap();
+    }
+
+    #[test]
+    fn retransmits_then_acks_a_failure_response() {
+        let ctx = listening_context();
+        let uas = UdpSocket::bind("127.0.0.1:0").unwrap();
+        uas.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let port = uas.local_addr().unwrap().port();
+
+        let id = ctx
+            .txn_send_invite_with("127.0.0.1", port, invite("z9hG4bKtxn1").as_bytes(), TIMERS)
+            .unwrap();
+        let (first, client) = recv(&uas);
+        // timer A fires before the UAS answers
+        let (second, _) = recv(&uas);
+        assert_eq!(first.to_string(), second.to_string());
+
+        respond(&uas, client, "z9hG4bKtxn1", "180 Ringing");
+        assert_eq!(wait_for("txn_provisional", id)["status"], 180);
+        respond(&uas, client, "z9hG4bKtxn1", "486 Busy Here");
+        let fin = wait_for("txn_final", id);
+        assert_eq!(fin["status"], 486);
+
+        let (ack, _) = recv(&uas);
+        let ack = match ack {
+            SipMessage::Request(ack) => ack,
+            other => panic!("expected ACK, got {}", other),
+        };
+        assert_eq!(ack.method, Method::Ack);
+        assert_eq!(ack.cseq_header().unwrap().value(), "7 ACK");
+        assert_eq!(
+            ack.to_header().unwrap().tag().unwrap().unwrap().to_string(),
+            "b1"
+        );
+        assert_eq!(
+            ack.via_header().unwrap().branch().unwrap().to_string(),
+            "z9hG4bKtxn1"
+        );
+
+        // a retransmitted final is ACKed again, not reported again
+        respond(&uas, client, "z9hG4bKtxn1", "486 Busy Here");
+        let (again, _) = recv(&uas);
+        assert!(again.to_string().starts_with("ACK "));
+        thread::sleep(Duration::from_millis(100));
+        let finals = EVENTS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(e, p)| e == "txn_final" && p["id"] == id)
+            .count();
+        assert_eq!(finals, 1);
+
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn timer_b_times_out() {
+        let ctx = listening_context();
+        let uas = UdpSocket::bind("127.0.0.1:0").unwrap();
+        uas.set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let port = uas.local_addr().unwrap().port();
+
+        let id = ctx
+            .txn_send_invite_with("127.0.0.1", port, invite("z9hG4bKtxn2").as_bytes(), TIMERS)
+            .unwrap();
+        assert_eq!(wait_for("txn_timeout", id)["reason"], "timer B");
+        // sent at 0, 50, 150 and 350ms
+        let mut copies = 0;
+        let mut buf = [0u8; 4096];
+        while uas.recv_from(&mut buf).is_ok() {
+            copies += 1;
+        }
+        assert_eq!(copies, 4);
+        assert!(ctx.transactions.lock().unwrap().is_empty());
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn rejects_non_invites_and_missing_listener() {
+        let ctx = Arc::new(RsipContext::new());
+        let request = invite("z9hG4bKtxn3");
+        assert_eq!(
+            ctx.txn_send_invite("127.0.0.1", 5060, request.as_bytes()),
+            Err(RsipError::NotRunning)
+        );
+        ctx.start_udp_listener_on("127.0.0.1", 0).unwrap();
+        let options = request.replace("INVITE", "OPTIONS");
+        assert_eq!(
+            ctx.txn_send_invite("127.0.0.1", 5060, options.as_bytes()),
+            Err(RsipError::InvalidArgument)
+        );
+        ctx.shutdown();
+    }
+
+    #[test]
+    fn keys_match_responses_acks_but_not_cancels() {
+        let key = |raw: &str| {
+            let key = rsip_transaction_key(CString::new(raw).unwrap().as_ptr());
+            if key.is_null() {
+                return None;
+            }
+            let owned = unsafe { CStr::from_ptr(key) }.to_str().unwrap().to_string();
+            crate::rsip_free_string(key);
+            Some(owned)
+        };
+        let request = invite("z9hG4bKkey");
+        let response = request
+            .replace("INVITE sip:bob@127.0.0.1 SIP/2.0", "SIP/2.0 486 Busy Here")
+            .replace("To: <sip:bob@127.0.0.1>", "To: <sip:bob@127.0.0.1>;tag=b1");
+        assert_eq!(key(&request).as_deref(), Some("z9hG4bKkey:INVITE"));
+        assert_eq!(key(&response), key(&request));
+        assert_eq!(key(&request.replace("INVITE", "ACK")), key(&request));
+        assert_eq!(
+            key(&request.replace("INVITE", "CANCEL")).as_deref(),
+            Some("z9hG4bKkey:CANCEL")
+        );
+        assert_eq!(key(&request.replace(";branch=z9hG4bKkey", "")), None);
+        assert_eq!(key("not SIP"), None);
+        assert!(rsip_transaction_key(std::ptr::null()).is_null());
+    }
+}
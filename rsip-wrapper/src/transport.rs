@@ -0,0 +1,114 @@
+//! A host-provided transport (shared memory, QUIC, ...) in place of the UDP listener:
+//! what the stack would send from the listener goes to the host's callback, and the
+//! host hands received messages to `feed_bytes`.
+
+use crate::context::{with_context, RsipContext};
+use crate::lock::Lock;
+use crate::send::host_port;
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+/// `dest` is `"host:port"` (IPv6 bracketed) as the stack addressed the message; `data`
+/// is only valid during the call.
+pub type TransportSendCallback = extern "C" fn(dest: *const c_char, data: *const u8, len: usize);
+
+impl RsipContext {
+    /// Routes everything sent from the listener to `cb` instead; `None` goes back to
+    /// the UDP listener.
+    pub fn register_transport(&self, cb: Option<TransportSendCallback>) {
+        *self.transport.locked() = cb;
+    }
+
+    /// Hands `payload` for `ip:port` to the registered transport. Returns false,
+    /// without sending, if there is none.
+    pub(crate) fn send_via_transport(&self, ip: &str, port: u16, payload: &[u8]) -> bool {
+        let cb = match *self.transport.locked() {
+            Some(cb) => cb,
+            None => return false,
+        };
+        // A host name with a NUL in it cannot be passed on; it arrives empty.
+        let dest = CString::new(host_port(ip, port)).unwrap_or_default();
+        cb(dest.as_ptr(), payload.as_ptr(), payload.len());
+        true
+    }
+}
+
+/// Sends everything the stack would send from the UDP listener
+/// (`rsip_send_udp_from_listener`, registrations, client transactions, 513 responses)
+/// through `send_cb` instead, whether or not a listener runs. NULL goes back to the
+/// listener. Received messages enter through `rsip_feed_bytes`.
+#[no_mangle]
+pub extern "C" fn rsip_register_transport(send_cb: Option<TransportSendCallback>) {
+    crate::default_context().register_transport(send_cb);
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_register_transport(
+    ctx: *mut RsipContext,
+    send_cb: Option<TransportSendCallback>,
+) {
+    with_context(ctx, |ctx| ctx.register_transport(send_cb));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RsipError;
+    use crate::events::Sink;
+    use std::ffi::CStr;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    static SENT: Mutex<Vec<(String, Vec<u8>)>> = Mutex::new(Vec::new());
+    static EVENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    extern "C" fn send(dest: *const c_char, data: *const u8, len: usize) {
+        let dest = unsafe { CStr::from_ptr(dest) }
+            .to_str()
+            .unwrap()
+            .to_string();
+        let data = unsafe { std::slice::from_raw_parts(data, len) }.to_vec();
+        SENT.lock().unwrap().push((dest, data));
+    }
+
+    extern "C" fn record(event: *const c_char, _: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }.to_str().unwrap();
+        EVENTS.lock().unwrap().push(event.to_string());
+    }
+
+    #[test]
+    fn outbound_goes_to_the_host_and_inbound_is_fed() {
+        let ctx = RsipContext::new();
+        ctx.events
+            .subscribe(Some(vec!["sip_rx_parsed".into()]), Sink::Basic(record));
+        assert_eq!(
+            ctx.send_from_listener("192.0.2.1", 5060, b"OPTIONS"),
+            Err(RsipError::NotRunning)
+        );
+
+        ctx.register_transport(Some(send));
+        ctx.send_from_listener("192.0.2.1", 5060, b"OPTIONS sip:a@b SIP/2.0\r\n\r\n")
+            .unwrap();
+        ctx.send_from_listener("2001:db8::1", 5080, b"").unwrap();
+        let src: SocketAddr = "192.0.2.1:5060".parse().unwrap();
+        ctx.feed_bytes(b"OPTIONS sip:a@b SIP/2.0\r\n\r\n", src);
+
+        assert_eq!(
+            *SENT.lock().unwrap(),
+            [
+                (
+                    "192.0.2.1:5060".to_string(),
+                    b"OPTIONS sip:a@b SIP/2.0\r\n\r\n".to_vec()
+                ),
+                ("[2001:db8::1]:5080".to_string(), Vec::new()),
+            ]
+        );
+        assert_eq!(*EVENTS.lock().unwrap(), ["sip_rx_parsed"]);
+
+        ctx.register_transport(None);
+        assert_eq!(
+            ctx.send_from_listener("192.0.2.1", 5060, b"OPTIONS"),
+            Err(RsipError::NotRunning)
+        );
+    }
+}
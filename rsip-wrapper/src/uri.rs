@@ -0,0 +1,560 @@
+//! SIP, SIPS and tel URI helpers built on `rsip::Uri`, covering what a C host needs
+//! that the parser leaves open: bracketed IPv6 hosts, `?` headers, and rejecting
+//! inputs without a supported scheme (rsip accepts any text as a bare host).
+
+use crate::ffi::{into_c_string, str_arg};
+use rsip::common::uri::{Param, Scheme};
+use serde_json::{json, Map, Value};
+use std::convert::TryFrom;
+use std::net::Ipv6Addr;
+use std::os::raw::c_char;
+
+/// Stands in for a bracketed IPv6 host while rsip parses the rest of the URI.
+const IPV6_PLACEHOLDER: &str = "ipv6-literal.invalid";
+
+/// Characters RFC 3966 treats as visual separators in telephone numbers.
+const VISUAL_SEPARATORS: &[char] = &['-', '.', '(', ')'];
+
+pub struct ParsedUri {
+    pub scheme: Scheme,
+    /// For tel URIs, the telephone number.
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// IPv6 hosts are bracketed. `None` for tel URIs.
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// `(name, value)` in order of appearance; names are lowercased.
+    pub params: Vec<(String, Option<String>)>,
+    /// The raw `?` part, without the `?`.
+    pub headers: Option<String>,
+}
+
+impl ParsedUri {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    /// 5061 for sips and transport=tls, 5060 otherwise.
+    pub fn default_port(&self) -> u16 {
+        let tls = self
+            .param("transport")
+            .is_some_and(|t| t.eq_ignore_ascii_case("tls"));
+        if self.scheme == Scheme::Sips || tls {
+            5061
+        } else {
+            5060
+        }
+    }
+}
+
+fn param_pair(param: &Param) -> (String, Option<String>) {
+    let text = param.to_string();
+    let text = text.trim_start_matches(';');
+    match text.split_once('=') {
+        Some((name, value)) => (name.to_ascii_lowercase(), Some(value.to_string())),
+        None => (text.to_ascii_lowercase(), None),
+    }
+}
+
+/// Parses `raw` (optionally in angle brackets), with an error message fit for a host.
+pub fn parse(raw: &str) -> Result<ParsedUri, String> {
+    let mut raw = raw.trim();
+    if raw.starts_with('<') && raw.ends_with('>') {
+        raw = raw[1..raw.len() - 1].trim();
+    }
+    if raw.is_empty() {
+        return Err("empty URI".into());
+    }
+    // rsip only recognises other schemes in the `scheme://` form, so check it here.
+    let scheme = raw
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        });
+    match scheme {
+        None => return Err("missing scheme (expected sip:, sips: or tel:)".into()),
+        Some(scheme)
+            if !["sip", "sips", "tel"]
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(scheme)) =>
+        {
+            return Err(format!("unsupported scheme: {}", scheme))
+        }
+        Some(_) => {}
+    }
+    let (raw, headers) = match raw.split_once('?') {
+        Some((uri, headers)) => (uri, Some(headers.to_string())),
+        None => (raw, None),
+    };
+
+    let mut text = raw.to_string();
+    let mut ipv6 = None;
+    if let Some(open) = raw.find('[') {
+        let close = raw[open..]
+            .find(']')
+            .map(|i| open + i)
+            .ok_or("unterminated IPv6 host")?;
+        let ip: Ipv6Addr = raw[open + 1..close]
+            .parse()
+            .map_err(|_| format!("invalid IPv6 host: {}", &raw[open + 1..close]))?;
+        text.replace_range(open..=close, IPV6_PLACEHOLDER);
+        ipv6 = Some(ip);
+    }
+
+    let uri = rsip::Uri::try_from(text.as_str()).map_err(|e| format!("malformed URI: {}", e))?;
+    let scheme = match uri.scheme.clone() {
+        Some(Scheme::Other(other)) => return Err(format!("unsupported scheme: {}", other)),
+        Some(scheme) => scheme,
+        None => return Err("malformed URI: no scheme".into()),
+    };
+    let host = match ipv6 {
+        Some(ip) => format!("[{}]", ip),
+        None => uri.host().to_string(),
+    };
+    if host.is_empty() {
+        return Err("missing host".into());
+    }
+    let params = uri.params.iter().map(param_pair).collect();
+    let port = uri.port().map(|port| u16::from(*port));
+
+    if scheme == Scheme::Tel {
+        if uri.auth.is_some() || port.is_some() {
+            return Err("malformed tel URI".into());
+        }
+        return Ok(ParsedUri {
+            scheme,
+            user: Some(host),
+            password: None,
+            host: None,
+            port: None,
+            params,
+            headers,
+        });
+    }
+    Ok(ParsedUri {
+        scheme,
+        user: uri.auth.as_ref().map(|auth| auth.user.clone()),
+        password: uri.auth.and_then(|auth| auth.password),
+        host: Some(host),
+        port,
+        params,
+        headers,
+    })
+}
+
+/// `{scheme, user, host, port, transport, params, headers}` as documented in the header.
+pub fn to_json(uri: &ParsedUri) -> Value {
+    let mut params = Map::new();
+    for (name, value) in &uri.params {
+        params.insert(name.clone(), json!(value));
+    }
+    let mut headers = Map::new();
+    for header in uri.headers.iter().flat_map(|h| h.split('&')) {
+        if let Some((name, value)) = header.split_once('=') {
+            headers.insert(name.to_string(), json!(value));
+        }
+    }
+    json!({
+        "scheme": uri.scheme.to_string(),
+        "user": uri.user,
+        "host": uri.host,
+        "port": uri.port,
+        "transport": uri.param("transport").map(str::to_ascii_lowercase),
+        "params": params,
+        "headers": headers,
+    })
+}
+
+/// Canonical text form: lowercase scheme, host and parameter names; lowercase values
+/// of `transport`, `user` and `maddr`, uppercase `method`; no port when it is the
+/// default for the scheme and transport; no visual separators in telephone numbers.
+/// The user part and other values keep their case, as RFC 3261 compares them exactly.
+pub fn normalize(uri: &ParsedUri) -> String {
+    let mut out = format!("{}:", uri.scheme);
+    if let Some(user) = &uri.user {
+        if uri.scheme == Scheme::Tel {
+            out.extend(user.chars().filter(|c| !VISUAL_SEPARATORS.contains(c)));
+        } else {
+            out.push_str(user);
+            if let Some(password) = &uri.password {
+                out.push(':');
+                out.push_str(password);
+            }
+            out.push('@');
+        }
+    }
+    if let Some(host) = &uri.host {
+        out.push_str(&host.to_ascii_lowercase());
+    }
+    if let Some(port) = uri.port.filter(|port| *port != uri.default_port()) {
+        out.push_str(&format!(":{}", port));
+    }
+    for (name, value) in &uri.params {
+        out.push(';');
+        out.push_str(name);
+        if let Some(value) = value {
+            let value = match name.as_str() {
+                "transport" | "user" | "maddr" => value.to_ascii_lowercase(),
+                "method" => value.to_ascii_uppercase(),
+                _ => value.clone(),
+            };
+            out.push('=');
+            out.push_str(&value);
+        }
+    }
+    if let Some(headers) = &uri.headers {
+        out.push('?');
+        out.push_str(headers);
+    }
+    out
+}
+
+/// Decodes `%XX` escapes; malformed ones are kept as they are.
+fn unescape(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parameters that must be in both SIP URIs, or neither, for them to match.
+const SIGNIFICANT_PARAMS: &[&str] = &["user", "ttl", "method", "maddr", "transport"];
+
+/// `name=value` pairs of the `?` part, unescaped, names lowercased, sorted.
+fn header_set(uri: &ParsedUri) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = uri
+        .headers
+        .iter()
+        .flat_map(|h| h.split('&'))
+        .filter(|h| !h.is_empty())
+        .map(|h| {
+            let (name, value) = h.split_once('=').unwrap_or((h, ""));
+            (unescape(name).to_ascii_lowercase(), unescape(value))
+        })
+        .collect();
+    headers.sort();
+    headers
+}
+
+fn param_value(uri: &ParsedUri, name: &str) -> Option<Option<String>> {
+    uri.params
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_deref().map(unescape))
+}
+
+fn same_value(a: &Option<String>, b: &Option<String>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// URI equality as RFC 3261 §19.1.4 defines it for sip and sips URIs: same scheme;
+/// user and password equal case-sensitively, host and everything else
+/// case-insensitively, all after unescaping. A port, or one of the `user`, `ttl`,
+/// `method`, `maddr` and `transport` parameters, present in only one URI makes them
+/// differ even when it holds the default (`sip:a@b` is not `sip:a@b:5060`). Other
+/// parameters only count when both URIs have them. `?` headers must all be present in
+/// both, in any order. tel URIs (RFC 3966 §4) match on the number without visual
+/// separators and on all their parameters.
+pub fn equals(a: &ParsedUri, b: &ParsedUri) -> bool {
+    if a.scheme != b.scheme {
+        return false;
+    }
+    if a.scheme == Scheme::Tel {
+        let number = |uri: &ParsedUri| {
+            uri.user
+                .as_deref()
+                .unwrap_or_default()
+                .chars()
+                .filter(|c| !VISUAL_SEPARATORS.contains(c))
+                .collect::<String>()
+                .to_ascii_lowercase()
+        };
+        let params = |uri: &ParsedUri| {
+            let mut params: Vec<(String, Option<String>)> = uri
+                .params
+                .iter()
+                .map(|(n, v)| {
+                    (
+                        n.clone(),
+                        v.as_deref().map(|v| unescape(v).to_ascii_lowercase()),
+                    )
+                })
+                .collect();
+            params.sort();
+            params
+        };
+        return number(a) == number(b) && params(a) == params(b);
+    }
+
+    let userinfo = |uri: &ParsedUri| {
+        (
+            uri.user.as_deref().map(unescape),
+            uri.password.as_deref().map(unescape),
+        )
+    };
+    let host = |uri: &ParsedUri| {
+        uri.host
+            .as_deref()
+            .map(|h| unescape(h).to_ascii_lowercase())
+    };
+    if userinfo(a) != userinfo(b) || host(a) != host(b) || a.port != b.port {
+        return false;
+    }
+    for (name, value) in &a.params {
+        let value = value.as_deref().map(unescape);
+        match param_value(b, name) {
+            Some(other) if !same_value(&value, &other) => return false,
+            None if SIGNIFICANT_PARAMS.contains(&name.as_str()) => return false,
+            _ => {}
+        }
+    }
+    let only_in_b = b.params.iter().any(|(name, _)| {
+        SIGNIFICANT_PARAMS.contains(&name.as_str()) && param_value(a, name).is_none()
+    });
+    if only_in_b {
+        return false;
+    }
+    let (headers_a, headers_b) = (header_set(a), header_set(b));
+    headers_a.len() == headers_b.len()
+        && headers_a
+            .iter()
+            .zip(&headers_b)
+            .all(|((name_a, a), (name_b, b))| name_a == name_b && a.eq_ignore_ascii_case(b))
+}
+
+/// Parses a SIP, SIPS or tel URI into caller-owned JSON (see `to_json`). Malformed input
+/// yields `{"error": "..."}`; NULL is returned only for a NULL argument.
+#[no_mangle]
+pub extern "C" fn rsip_parse_uri(uri: *const c_char) -> *mut c_char {
+    match str_arg(uri).map(parse) {
+        Some(Ok(uri)) => into_c_string(to_json(&uri).to_string()),
+        Some(Err(error)) => into_c_string(json!({ "error": error }).to_string()),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// The canonical form of a URI (see `normalize`), caller-owned, or NULL if it does not
+/// parse.
+#[no_mangle]
+pub extern "C" fn rsip_uri_normalize(uri: *const c_char) -> *mut c_char {
+    match str_arg(uri).map(parse) {
+        Some(Ok(uri)) => into_c_string(normalize(&uri)),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Compares two URIs by the rules of RFC 3261 §19.1.4 (see `equals`): 1 if they are
+/// equal, 0 if not, -1 if either is NULL or does not parse.
+#[no_mangle]
+pub extern "C" fn rsip_uri_equals(a: *const c_char, b: *const c_char) -> i32 {
+    match (str_arg(a).map(parse), str_arg(b).map(parse)) {
+        (Some(Ok(a)), Some(Ok(b))) => i32::from(equals(&a, &b)),
+        _ => -1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::rsip_free_string;
+    use std::ffi::{CStr, CString};
+
+    fn parsed(raw: &str) -> Value {
+        to_json(&parse(raw).unwrap())
+    }
+
+    fn normalized(raw: &str) -> String {
+        normalize(&parse(raw).unwrap())
+    }
+
+    #[test]
+    fn parses_sip_uris() {
+        assert_eq!(
+            parsed("sip:alice@Example.COM:5070;transport=TCP;lr"),
+            json!({
+                "scheme": "sip",
+                "user": "alice",
+                "host": "Example.COM",
+                "port": 5070,
+                "transport": "tcp",
+                "params": { "transport": "TCP", "lr": null },
+                "headers": {},
+            })
+        );
+        let uri = parsed("<sips:bob@[2001:DB8::1]:5061?subject=hi&priority=urgent>");
+        assert_eq!(uri["scheme"], "sips");
+        assert_eq!(uri["host"], "[2001:db8::1]");
+        assert_eq!(uri["port"], 5061);
+        assert_eq!(
+            uri["headers"],
+            json!({ "subject": "hi", "priority": "urgent" })
+        );
+        let uri = parsed("sip:10.0.0.1");
+        assert_eq!(
+            (uri["user"].clone(), uri["port"].clone()),
+            (Value::Null, Value::Null)
+        );
+    }
+
+    #[test]
+    fn parses_tel_uris() {
+        let uri = parsed("tel:+1-201-555-0123;phone-context=example.com");
+        assert_eq!(uri["scheme"], "tel");
+        assert_eq!(uri["user"], "+1-201-555-0123");
+        assert_eq!(uri["host"], Value::Null);
+        assert_eq!(uri["params"]["phone-context"], "example.com");
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for (raw, error) in [
+            ("", "empty URI"),
+            ("alice@example.com", "missing scheme"),
+            ("http://example.com", "unsupported scheme: http"),
+            ("sip:", "malformed URI"),
+            ("sip:alice@example.com:port", "malformed URI"),
+            ("sip:bob@[2001:db8::zz]", "invalid IPv6 host"),
+            ("sip:bob@[2001:db8::1", "unterminated IPv6 host"),
+        ]
+        .iter()
+        {
+            let err = parse(raw).err().unwrap_or_else(|| panic!("{} parsed", raw));
+            assert!(err.starts_with(error), "{}: {}", raw, err);
+        }
+    }
+
+    #[test]
+    fn normalizes() {
+        assert_eq!(
+            normalized("SIP:Alice@Example.COM:5060;Transport=UDP"),
+            "sip:Alice@example.com;transport=udp"
+        );
+        assert_eq!(normalized("sips:bob@host:5061"), "sips:bob@host");
+        assert_eq!(
+            normalized("sip:bob@host:5061;transport=TLS"),
+            "sip:bob@host;transport=tls"
+        );
+        assert_eq!(normalized("sip:bob@host:5061"), "sip:bob@host:5061");
+        assert_eq!(
+            normalized("sip:[2001:0DB8:0::1]:5080;method=invite?subject=x"),
+            "sip:[2001:db8::1]:5080;method=INVITE?subject=x"
+        );
+        assert_eq!(normalized("tel:+1-(201)-555.0123"), "tel:+12015550123");
+    }
+
+    #[test]
+    fn compares_uris_like_rfc_3261() {
+        let equal = |a: &str, b: &str| {
+            let result = equals(&parse(a).unwrap(), &parse(b).unwrap());
+            assert_eq!(result, equals(&parse(b).unwrap(), &parse(a).unwrap()));
+            result
+        };
+        // RFC 3261 §19.1.4, equivalent
+        assert!(equal(
+            "sip:%61lice@atlanta.com;transport=TCP",
+            "sip:alice@AtLanTa.CoM;Transport=tcp"
+        ));
+        assert!(equal(
+            "sip:carol@chicago.com",
+            "sip:carol@chicago.com;newparam=5"
+        ));
+        assert!(equal(
+            "sip:carol@chicago.com;newparam=5",
+            "sip:carol@chicago.com;security=on"
+        ));
+        assert!(equal(
+            "sip:biloxi.com;transport=tcp;method=REGISTER?to=sip:bob%40biloxi.com",
+            "sip:biloxi.com;method=REGISTER;transport=tcp?to=sip:bob%40biloxi.com"
+        ));
+        assert!(equal(
+            "sip:alice@atlanta.com?subject=project%20x&priority=urgent",
+            "sip:alice@atlanta.com?priority=urgent&subject=project%20x"
+        ));
+        // RFC 3261 §19.1.4, not equivalent
+        assert!(!equal(
+            "SIP:ALICE@AtLanTa.CoM;Transport=udp",
+            "sip:alice@AtLanTa.CoM;Transport=UDP"
+        ));
+        assert!(!equal("sip:bob@biloxi.com", "sips:bob@biloxi.com"));
+        assert!(!equal("sip:bob@biloxi.com", "sip:bob@biloxi.com:5060"));
+        assert!(!equal(
+            "sip:bob@biloxi.com",
+            "sip:bob@biloxi.com;transport=udp"
+        ));
+        assert!(!equal(
+            "sip:carol@chicago.com",
+            "sip:carol@chicago.com?Subject=next%20meeting"
+        ));
+        assert!(!equal(
+            "sip:bob@phone21.boxesbybob.com",
+            "sip:bob@192.0.2.4"
+        ));
+
+        assert!(equal("sip:bob@[2001:DB8::1]", "<sip:bob@[2001:db8:0::1]>"));
+        assert!(!equal("sip:bob:secret@host", "sip:bob:SECRET@host"));
+        assert!(!equal("sip:bob@host;lr;foo=1", "sip:bob@host;foo=2"));
+        assert!(equal("tel:+1-201-555-0123", "tel:+12015550123"));
+        assert!(!equal("tel:+12015550123", "tel:+12015550123;ext=1"));
+        assert!(!equal("tel:+12015550123", "sip:+12015550123@host"));
+    }
+
+    #[test]
+    fn ffi_compares_uris() {
+        let cmp = |a: &str, b: &str| {
+            let (a, b) = (CString::new(a).unwrap(), CString::new(b).unwrap());
+            rsip_uri_equals(a.as_ptr(), b.as_ptr())
+        };
+        assert_eq!(cmp("sip:a@EXAMPLE.com", "sip:a@example.com"), 1);
+        assert_eq!(cmp("sip:A@example.com", "sip:a@example.com"), 0);
+        assert_eq!(cmp("sip:a@example.com", "mailto:a@example.com"), -1);
+        let a = CString::new("sip:a@example.com").unwrap();
+        assert_eq!(rsip_uri_equals(a.as_ptr(), std::ptr::null()), -1);
+    }
+
+    #[test]
+    fn ffi_reports_errors_as_json() {
+        let raw = CString::new("mailto:a@b").unwrap();
+        let out = rsip_parse_uri(raw.as_ptr());
+        let json: Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(out) }.to_str().unwrap()).unwrap();
+        assert_eq!(json["error"], "unsupported scheme: mailto");
+        rsip_free_string(out);
+
+        assert!(rsip_uri_normalize(raw.as_ptr()).is_null());
+        assert!(rsip_parse_uri(std::ptr::null()).is_null());
+        let raw = CString::new("sip:A@B.example").unwrap();
+        let out = rsip_uri_normalize(raw.as_ptr());
+        assert_eq!(
+            unsafe { CStr::from_ptr(out) }.to_str().unwrap(),
+            "sip:A@b.example"
+        );
+        rsip_free_string(out);
+    }
+}
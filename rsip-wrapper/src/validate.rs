@@ -0,0 +1,189 @@
+//! Semantic checks (RFC 3261 §8.1.1, §20) for messages that parse but may still be
+//! unusable: missing mandatory headers, a CSeq that disagrees with the request line, a
+//! Content-Length that disagrees with the body.
+
+use crate::ffi::{into_c_string, str_arg};
+use crate::message::header_values;
+use rsip::SipMessage;
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::os::raw::c_char;
+
+/// Present in every request and response.
+const MANDATORY: &[&str] = &["Via", "From", "To", "Call-ID", "CSeq"];
+/// Must appear at most once (RFC 3261 §7.3.1 only allows repeating list headers).
+const SINGLE: &[&str] = &[
+    "From",
+    "To",
+    "Call-ID",
+    "CSeq",
+    "Max-Forwards",
+    "Content-Length",
+];
+
+fn violation(code: &str, header: Option<&str>, detail: String) -> Value {
+    json!({ "code": code, "header": header, "detail": detail })
+}
+
+/// Lists every violation found in `msg` as `{code, header, detail}`; empty if the
+/// message is valid. `header` is null for violations not tied to one header.
+pub fn validate(msg: &SipMessage) -> Vec<Value> {
+    let mut violations = Vec::new();
+    let request_method = match msg {
+        SipMessage::Request(req) => Some(req.method.to_string()),
+        SipMessage::Response(_) => None,
+    };
+
+    let mut mandatory = MANDATORY.to_vec();
+    if request_method.is_some() {
+        mandatory.push("Max-Forwards");
+    }
+    for name in mandatory {
+        if header_values(msg, name).is_empty() {
+            violations.push(violation(
+                "missing_header",
+                Some(name),
+                format!("{} header is required", name),
+            ));
+        }
+    }
+    for name in SINGLE.iter().copied() {
+        let count = header_values(msg, name).len();
+        if count > 1 {
+            violations.push(violation(
+                "duplicate_header",
+                Some(name),
+                format!("{} appears {} times", name, count),
+            ));
+        }
+    }
+
+    if let Some(cseq) = header_values(msg, "CSeq").first() {
+        let mut parts = cseq.split_whitespace();
+        let seq = parts.next().and_then(|seq| seq.parse::<u32>().ok());
+        match (seq, parts.next(), parts.next()) {
+            (Some(_), Some(method), None) => {
+                if let Some(request_method) = request_method.as_deref() {
+                    if method != request_method {
+                        violations.push(violation(
+                            "cseq_method_mismatch",
+                            Some("CSeq"),
+                            format!("CSeq method {} does not match {}", method, request_method),
+                        ));
+                    }
+                }
+            }
+            _ => violations.push(violation(
+                "invalid_header",
+                Some("CSeq"),
+                format!("expected `<sequence> <method>`, got `{}`", cseq),
+            )),
+        }
+    }
+
+    if let Some(max_forwards) = header_values(msg, "Max-Forwards").first() {
+        if max_forwards.parse::<u8>().is_err() {
+            violations.push(violation(
+                "invalid_header",
+                Some("Max-Forwards"),
+                format!("expected 0-255, got `{}`", max_forwards),
+            ));
+        }
+    }
+
+    if let Some(length) = header_values(msg, "Content-Length").first() {
+        let actual = msg.body().len();
+        match length.parse::<usize>() {
+            Ok(declared) if declared != actual => violations.push(violation(
+                "content_length_mismatch",
+                Some("Content-Length"),
+                format!("declared {} bytes, body has {}", declared, actual),
+            )),
+            Ok(_) => {}
+            Err(_) => violations.push(violation(
+                "invalid_header",
+                Some("Content-Length"),
+                format!("expected a byte count, got `{}`", length),
+            )),
+        }
+    }
+
+    violations
+}
+
+/// Validates `raw` and returns a caller-owned JSON array of violations (see
+/// `validate::validate`), `[]` if there are none. NULL if `raw` is NULL or does not
+/// parse as SIP at all.
+#[no_mangle]
+pub extern "C" fn rsip_validate_message(raw: *const c_char) -> *mut c_char {
+    match str_arg(raw).map(SipMessage::try_from) {
+        Some(Ok(msg)) => into_c_string(Value::from(validate(&msg)).to_string()),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(raw: &str) -> Vec<(String, Option<String>)> {
+        validate(&SipMessage::try_from(raw).unwrap())
+            .into_iter()
+            .map(|v| {
+                (
+                    v["code"].as_str().unwrap().to_string(),
+                    v["header"].as_str().map(String::from),
+                )
+            })
+            .collect()
+    }
+
+    fn code(code: &str, header: &str) -> (String, Option<String>) {
+        (code.to_string(), Some(header.to_string()))
+    }
+
+    #[test]
+    fn accepts_a_compliant_request() {
+        let invite = "INVITE sip:bob@example.com SIP/2.0\r\n\
+            v: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKv\r\n\
+            Max-Forwards: 70\r\n\
+            f: <sip:alice@example.com>;tag=1\r\n\
+            t: <sip:bob@example.com>\r\n\
+            i: ok@192.0.2.1\r\n\
+            CSeq: 1 INVITE\r\n\
+            l: 4\r\n\r\nbody";
+        assert!(codes(invite).is_empty());
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        let request = "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+            Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKv\r\n\
+            To: <sip:bob@example.com>\r\n\
+            To: <sip:carol@example.com>\r\n\
+            Call-ID: bad@192.0.2.1\r\n\
+            CSeq: 7 INVITE\r\n\
+            Content-Length: 10\r\n\r\nshort";
+        assert_eq!(
+            codes(request),
+            vec![
+                code("missing_header", "From"),
+                code("missing_header", "Max-Forwards"),
+                code("duplicate_header", "To"),
+                code("cseq_method_mismatch", "CSeq"),
+                code("content_length_mismatch", "Content-Length"),
+            ]
+        );
+    }
+
+    #[test]
+    fn responses_need_no_max_forwards() {
+        let response = "SIP/2.0 200 OK\r\n\
+            Via: SIP/2.0/UDP 192.0.2.1;branch=z9hG4bKv\r\n\
+            From: <sip:alice@example.com>;tag=1\r\n\
+            To: <sip:bob@example.com>;tag=2\r\n\
+            Call-ID: ok@192.0.2.1\r\n\
+            CSeq: one REGISTER\r\n\r\n";
+        assert_eq!(codes(response), vec![code("invalid_header", "CSeq")]);
+    }
+}
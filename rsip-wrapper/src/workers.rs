@@ -0,0 +1,422 @@
+//! Optional worker pool running the receive pipeline (and so every callback) off the
+//! listener threads, which then only read and enqueue. Without it, messages are handled
+//! inline on the thread that read them.
+//!
+//! Each worker owns a bounded queue and messages are assigned by source address, so
+//! messages from one peer are still handled in arrival order.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::RsipError;
+use crate::lock::Lock;
+use crate::log::LogLevel;
+use crate::stats::Stats;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+pub const MAX_WORKER_THREADS: usize = 64;
+/// Messages each worker may have waiting before backpressure applies.
+pub const WORKER_QUEUE_CAPACITY: usize = 1024;
+
+/// What a listener does when the worker queue for a message is full. The numeric values
+/// are part of the C ABI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Discard the message and count it in `queue_dropped`.
+    Drop = 0,
+    /// Wait for room, so the listener stops reading (the kernel buffers, then drops).
+    Block = 1,
+}
+
+impl Backpressure {
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(Self::Drop),
+            1 => Some(Self::Block),
+            _ => None,
+        }
+    }
+}
+
+/// Where received messages are handled. The numeric values are part of the C ABI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatchMode {
+    /// On the listener thread that read them: lowest latency, but a slow callback
+    /// holds up reception.
+    Inline = 0,
+    /// Queued to worker threads (one unless `set_worker_threads` asks for more).
+    Queued = 1,
+}
+
+impl DispatchMode {
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(Self::Inline),
+            1 => Some(Self::Queued),
+            _ => None,
+        }
+    }
+}
+
+struct Job {
+    data: Vec<u8>,
+    src: SocketAddr,
+    listener: Option<u64>,
+    rx_ts_ns: Option<u64>,
+}
+
+pub(crate) struct WorkerPool {
+    queues: Vec<SyncSender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+    backpressure: Backpressure,
+}
+
+impl WorkerPool {
+    fn spawn(ctx: &Arc<RsipContext>, threads: usize, backpressure: Backpressure) -> Self {
+        let (queues, threads) = (0..threads)
+            .map(|_| {
+                let (tx, rx) = mpsc::sync_channel::<Job>(WORKER_QUEUE_CAPACITY);
+                let ctx = ctx.clone();
+                let handle = thread::spawn(move || {
+                    // Ends once the pool drops its sender and the queue is empty.
+                    for job in rx {
+                        ctx.queued.fetch_sub(1, Ordering::SeqCst);
+                        ctx.handle_datagram_on(&job.data, job.src, job.listener, job.rx_ts_ns);
+                    }
+                });
+                (tx, handle)
+            })
+            .unzip();
+        Self {
+            queues,
+            threads,
+            backpressure,
+        }
+    }
+
+    /// The queue of the worker handling messages from `src`, cloned so that a send
+    /// blocking on it does not hold the pool lock.
+    fn queue_for(&self, src: SocketAddr) -> (SyncSender<Job>, Backpressure) {
+        let mut hasher = DefaultHasher::new();
+        src.hash(&mut hasher);
+        let queue = &self.queues[hasher.finish() as usize % self.queues.len()];
+        (queue.clone(), self.backpressure)
+    }
+
+    fn submit(ctx: &RsipContext, queue: &SyncSender<Job>, backpressure: Backpressure, job: Job) {
+        let src = job.src;
+        ctx.queued.fetch_add(1, Ordering::SeqCst);
+        let sent = match backpressure {
+            Backpressure::Block => queue.send(job).is_ok(),
+            Backpressure::Drop => match queue.try_send(job) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    Stats::add(&ctx.stats.queue_dropped, 1);
+                    ctx.events.skip_seq();
+                    ctx.log(
+                        LogLevel::Debug,
+                        format_args!("worker queue full, dropped message from {}", src),
+                    );
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+        };
+        if !sent {
+            ctx.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Closes the queues and waits for the workers to finish what is already queued.
+    pub fn stop(self) {
+        drop(self.queues);
+        for handle in self.threads {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl RsipContext {
+    /// Number of worker threads started with the next listener; 0 (the default) handles
+    /// messages on the listener threads.
+    pub fn set_worker_threads(&self, threads: usize) -> Result<(), RsipError> {
+        if self.is_running() || self.workers.locked().is_some() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        if threads > MAX_WORKER_THREADS {
+            return Err(RsipError::InvalidArgument);
+        }
+        self.config.locked().worker_threads = threads;
+        Ok(())
+    }
+
+    /// Shorthand for the worker count: `Inline` is 0 workers, `Queued` keeps a count
+    /// already configured or else uses a single worker.
+    pub fn set_dispatch_mode(&self, mode: DispatchMode) -> Result<(), RsipError> {
+        let threads = match mode {
+            DispatchMode::Inline => 0,
+            DispatchMode::Queued => self.config.locked().worker_threads.max(1),
+        };
+        self.set_worker_threads(threads)
+    }
+
+    pub fn set_backpressure(&self, mode: Backpressure) -> Result<(), RsipError> {
+        if self.is_running() || self.workers.locked().is_some() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        self.config.locked().backpressure = mode;
+        Ok(())
+    }
+
+    /// Starts the configured worker pool unless it is already running; called by every
+    /// listener before it starts reading.
+    pub(crate) fn start_workers(self: &Arc<Self>) {
+        let mut workers = self.workers.locked();
+        let (threads, backpressure) = {
+            let config = self.config.locked();
+            (config.worker_threads, config.backpressure)
+        };
+        if workers.is_none() && threads > 0 {
+            *workers = Some(WorkerPool::spawn(self, threads, backpressure));
+        }
+    }
+
+    /// Hands a received message that passes the source filter and rate limits to the
+    /// worker pool, or handles it right away if there is none.
+    pub(crate) fn dispatch(&self, data: &[u8], src: SocketAddr) {
+        self.dispatch_on(data, src, None, None);
+    }
+
+    /// `dispatch` for a message that arrived on the UDP listener `listener`, at the
+    /// kernel receive timestamp `rx_ts_ns` if there is one.
+    pub(crate) fn dispatch_on(
+        &self,
+        data: &[u8],
+        src: SocketAddr,
+        listener: Option<u64>,
+        rx_ts_ns: Option<u64>,
+    ) {
+        if !self.filter_source(data.len(), src) || !self.admit(data.len(), src) {
+            return;
+        }
+        let queue = self
+            .workers
+            .locked()
+            .as_ref()
+            .map(|pool| pool.queue_for(src));
+        match queue {
+            Some((queue, backpressure)) => {
+                let job = Job {
+                    data: data.to_vec(),
+                    src,
+                    listener,
+                    rx_ts_ns,
+                };
+                WorkerPool::submit(self, &queue, backpressure, job);
+            }
+            None => self.handle_datagram_on(data, src, listener, rx_ts_ns),
+        }
+    }
+
+    /// Messages waiting in worker queues, counted without taking the pool lock.
+    pub(crate) fn queued_messages(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Closes the worker queues after the messages already queued are handled.
+    pub(crate) fn stop_workers(&self) {
+        let workers = self.workers.locked().take();
+        if let Some(workers) = workers {
+            workers.stop();
+        }
+    }
+}
+
+/// Sets the number of worker threads (0..=64, default 0) that run callbacks for the
+/// listeners started afterwards. Fails while a listener is running.
+#[no_mangle]
+pub extern "C" fn rsip_set_worker_threads(threads: u32) -> bool {
+    crate::default_context()
+        .set_worker_threads(threads as usize)
+        .is_ok()
+}
+
+/// Sets where callbacks run: 0 inline on the listener threads, 1 on worker threads fed
+/// by a queue. Returns false for an unknown mode or while a listener is running.
+#[no_mangle]
+pub extern "C" fn rsip_set_dispatch_mode(mode: i32) -> bool {
+    match DispatchMode::from_code(mode) {
+        Some(mode) => crate::default_context().set_dispatch_mode(mode).is_ok(),
+        None => false,
+    }
+}
+
+/// Sets what happens when a worker queue is full: 0 drops the message (counted in the
+/// `queue_dropped` statistic), 1 blocks the listener. Returns false for an unknown mode
+/// or while a listener is running.
+#[no_mangle]
+pub extern "C" fn rsip_set_backpressure_mode(mode: i32) -> bool {
+    match Backpressure::from_code(mode) {
+        Some(mode) => crate::default_context().set_backpressure(mode).is_ok(),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_worker_threads(ctx: *mut RsipContext, threads: u32) -> bool {
+    with_context(ctx, |ctx| ctx.set_worker_threads(threads as usize).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_dispatch_mode(ctx: *mut RsipContext, mode: i32) -> bool {
+    match DispatchMode::from_code(mode) {
+        Some(mode) => with_context(ctx, |ctx| ctx.set_dispatch_mode(mode).is_ok()).unwrap_or(false),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_set_backpressure_mode(ctx: *mut RsipContext, mode: i32) -> bool {
+    match Backpressure::from_code(mode) {
+        Some(mode) => with_context(ctx, |ctx| ctx.set_backpressure(mode).is_ok()).unwrap_or(false),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::net::UdpSocket;
+    use std::os::raw::c_char;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    static RELEASE: AtomicBool = AtomicBool::new(false);
+    static RELEASE_FULL: AtomicBool = AtomicBool::new(false);
+    static RECEIVED: Mutex<Vec<u16>> = Mutex::new(Vec::new());
+
+    fn wait(release: &AtomicBool) {
+        while !release.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // Stand-ins for a slow host callback, blocking until released.
+    extern "C" fn slow(_: *const c_char, _: *const c_char) {
+        wait(&RELEASE);
+    }
+
+    extern "C" fn stuck(_: *const c_char, _: *const c_char) {
+        wait(&RELEASE_FULL);
+    }
+
+    extern "C" fn record_src(_: *const c_char, _: *const c_char, _: *const c_char, port: u16) {
+        RECEIVED.lock().unwrap().push(port);
+    }
+
+    fn wait_for(what: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !what() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    #[test]
+    fn slow_callback_does_not_stall_the_listener() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.set_worker_threads(2).unwrap();
+        ctx.events
+            .subscribe(Some(vec!["sip_rx".into()]), Sink::Basic(slow));
+        ctx.events
+            .subscribe(Some(vec!["sip_rx".into()]), Sink::WithSource(record_src));
+        ctx.start_udp_listener_on("127.0.0.1", 0).unwrap();
+        let addr = ctx.local_addr().unwrap();
+        assert!(ctx.set_worker_threads(4).is_err());
+
+        let senders: Vec<UdpSocket> = (0..8)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        for sender in &senders {
+            sender
+                .send_to(b"OPTIONS sip:a@b SIP/2.0\r\n\r\n", addr)
+                .unwrap();
+        }
+        // Every message is read while the workers sit in the slow callback: each is
+        // either being handled (and counted) or queued.
+        assert!(wait_for(|| {
+            ctx.stats.packets_received.load(Ordering::Relaxed) as usize + ctx.queued_messages() == 8
+        }));
+        assert!(RECEIVED.lock().unwrap().is_empty());
+
+        RELEASE.store(true, Ordering::SeqCst);
+        assert!(wait_for(|| RECEIVED.lock().unwrap().len() == 8));
+        ctx.shutdown();
+        assert_eq!(ctx.queued_messages(), 0);
+    }
+
+    #[test]
+    fn full_queue_drops_and_counts() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.set_worker_threads(1).unwrap();
+        ctx.events.subscribe(None, Sink::Basic(stuck));
+        ctx.start_workers();
+        let src: SocketAddr = "192.0.2.1:5060".parse().unwrap();
+        // The first message occupies the only worker; the queue holds the next
+        // WORKER_QUEUE_CAPACITY, and the rest are dropped.
+        for _ in 0..WORKER_QUEUE_CAPACITY + 10 {
+            ctx.dispatch(b"x", src);
+        }
+        let dropped = ctx.stats.queue_dropped.load(Ordering::Relaxed);
+        assert!(dropped >= 9);
+        // each dropped message used up an event number
+        assert!(ctx.events.last_seq() >= dropped);
+        RELEASE_FULL.store(true, Ordering::SeqCst);
+        ctx.shutdown();
+        assert!(ctx.workers.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let ctx = RsipContext::new();
+        assert_eq!(
+            ctx.set_worker_threads(MAX_WORKER_THREADS + 1),
+            Err(RsipError::InvalidArgument)
+        );
+        assert_eq!(Backpressure::from_code(2), None);
+        assert!(!rsip_context_set_backpressure_mode(std::ptr::null_mut(), 1));
+        assert_eq!(DispatchMode::from_code(2), None);
+        assert!(!rsip_context_set_dispatch_mode(std::ptr::null_mut(), 1));
+    }
+
+    #[test]
+    fn dispatch_mode_sets_the_worker_count() {
+        let ctx = Arc::new(RsipContext::new());
+        let threads = || ctx.config.lock().unwrap().worker_threads;
+        ctx.set_dispatch_mode(DispatchMode::Queued).unwrap();
+        assert_eq!(threads(), 1);
+        ctx.set_worker_threads(3).unwrap();
+        ctx.set_dispatch_mode(DispatchMode::Queued).unwrap();
+        assert_eq!(threads(), 3, "an explicit pool size is kept");
+        ctx.set_dispatch_mode(DispatchMode::Inline).unwrap();
+        assert_eq!(threads(), 0);
+
+        ctx.set_dispatch_mode(DispatchMode::Queued).unwrap();
+        ctx.start_udp_listener_on("127.0.0.1", 0).unwrap();
+        assert!(ctx.workers.lock().unwrap().is_some());
+        assert_eq!(
+            ctx.set_dispatch_mode(DispatchMode::Inline),
+            Err(RsipError::AlreadyRunning)
+        );
+        ctx.shutdown();
+    }
+}
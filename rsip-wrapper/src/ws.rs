@@ -0,0 +1,615 @@
+//! SIP over WebSocket (RFC 7118) listener, for browser and WebRTC clients.
+//!
+//! The upgrade and framing (RFC 6455) are small enough to do by hand: each complete
+//! text or binary message carries exactly one SIP message, so no stream framing is
+//! needed on top.
+
+use crate::context::{with_context, RsipContext};
+use crate::error::{to_code, RsipError};
+use crate::ffi::str_arg;
+use crate::lock::Lock;
+use crate::log::LogLevel;
+use crate::send::{resolve, send_args};
+use crate::stream::{
+    is_timeout, StreamListener, HANDSHAKE_TIMEOUT, MAX_BODY_SIZE, MAX_HEADER_SIZE,
+};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// RFC 6455 §1.3: appended to the client's key to compute `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// RFC 7118 §4: the subprotocol both sides must agree on.
+const SUBPROTOCOL: &str = "sip";
+/// A message (possibly fragmented) larger than this closes the connection.
+const MAX_MESSAGE_SIZE: usize = MAX_HEADER_SIZE + MAX_BODY_SIZE;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Write half of an upgraded connection, shared between its reader and `send_ws`.
+pub(crate) type WsWriter = Arc<Mutex<TcpStream>>;
+
+fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.trim().as_bytes());
+    sha.update(ACCEPT_GUID.as_bytes());
+    base64::encode(sha.finalize())
+}
+
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.split("\r\n").skip(1).find_map(|line| {
+        let (n, v) = line.split_once(':')?;
+        if n.trim().eq_ignore_ascii_case(name) {
+            Some(v.trim())
+        } else {
+            None
+        }
+    })
+}
+
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Checks an upgrade request for `path` and returns the `101` response, or the status
+/// line to reject it with and the reason reported in `ws_error`.
+fn upgrade_response(request: &str, path: &str) -> Result<String, (&'static str, String)> {
+    const BAD_REQUEST: &str = "400 Bad Request";
+    let mut request_line = request.split("\r\n").next().unwrap_or_default().split(' ');
+    if request_line.next() != Some("GET") {
+        return Err(("405 Method Not Allowed", "not a GET request".into()));
+    }
+    let target = request_line.next().unwrap_or_default();
+    if target.split('?').next() != Some(path) {
+        return Err(("404 Not Found", format!("unknown path {}", target)));
+    }
+    if !has_token(header(request, "Upgrade"), "websocket")
+        || !has_token(header(request, "Connection"), "upgrade")
+    {
+        return Err((BAD_REQUEST, "not a websocket upgrade".into()));
+    }
+    if header(request, "Sec-WebSocket-Version") != Some("13") {
+        return Err((
+            "426 Upgrade Required",
+            "unsupported websocket version".into(),
+        ));
+    }
+    if !has_token(header(request, "Sec-WebSocket-Protocol"), SUBPROTOCOL) {
+        return Err((BAD_REQUEST, "sip subprotocol not offered".into()));
+    }
+    let key = header(request, "Sec-WebSocket-Key")
+        .ok_or_else(|| (BAD_REQUEST, "missing Sec-WebSocket-Key".to_string()))?;
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\r\n",
+        accept_key(key),
+        SUBPROTOCOL
+    ))
+}
+
+/// Reads the HTTP upgrade request. Returns it together with any bytes the client sent
+/// after it, which already belong to the first frames.
+fn read_request(stream: &mut TcpStream, running: &AtomicBool) -> Result<(String, Vec<u8>), String> {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(pos + 4);
+            return Ok((String::from_utf8_lossy(&buf).into_owned(), rest));
+        }
+        if buf.len() > MAX_HEADER_SIZE {
+            return Err("upgrade request too large".into());
+        }
+        if !running.load(Ordering::SeqCst) {
+            return Err("listener stopped".into());
+        }
+        if Instant::now() >= deadline {
+            return Err("handshake timeout".into());
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err("connection closed".into()),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// A single frame off the wire, already unmasked.
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Accumulates bytes from a client and yields complete frames.
+#[derive(Default)]
+struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Pops the next complete frame, or `None` until more bytes arrive.
+    fn next_frame(&mut self) -> Result<Option<Frame>, String> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let (b0, b1) = (self.buf[0], self.buf[1]);
+        if b0 & 0x70 != 0 {
+            return Err("reserved bits set".into());
+        }
+        // RFC 6455 §5.1: every frame from a client is masked.
+        if b1 & 0x80 == 0 {
+            return Err("unmasked client frame".into());
+        }
+        let (len, mut pos) = match b1 & 0x7F {
+            126 if self.buf.len() >= 4 => {
+                (u16::from_be_bytes([self.buf[2], self.buf[3]]) as u64, 4)
+            }
+            127 if self.buf.len() >= 10 => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&self.buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        if len > MAX_MESSAGE_SIZE as u64 {
+            return Err("frame too large".into());
+        }
+        let len = len as usize;
+        if self.buf.len() < pos + 4 + len {
+            return Ok(None);
+        }
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&self.buf[pos..pos + 4]);
+        pos += 4;
+        let payload = self.buf[pos..pos + len]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        self.buf.drain(..pos + len);
+        Ok(Some(Frame {
+            fin: b0 & 0x80 != 0,
+            opcode: b0 & 0x0F,
+            payload,
+        }))
+    }
+}
+
+/// Encodes an unmasked (server-to-client) frame.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+impl RsipContext {
+    /// Accepts SIP-over-WebSocket connections on `port` for requests to `path`.
+    pub fn start_ws_listener(self: &Arc<Self>, port: u16, path: &str) -> Result<(), RsipError> {
+        if !path.starts_with('/') {
+            return Err(RsipError::InvalidArgument);
+        }
+        let mut slot = self.ws_listener.locked();
+        if slot.is_some() {
+            return Err(RsipError::AlreadyRunning);
+        }
+        let bind_ip = self.config.locked().bind_ip;
+        let listener = TcpListener::bind(SocketAddr::new(bind_ip, port)).map_err(|e| {
+            self.log(
+                LogLevel::Error,
+                format_args!("ws bind port {} failed: {}", port, e),
+            );
+            self.emit("error", &format!("bind_err:{}", e));
+            RsipError::from_bind_error(&e)
+        })?;
+
+        self.start_workers();
+        let ctx = self.clone();
+        let path = path.to_string();
+        let listener = StreamListener::spawn(listener, move |tcp, peer, running| {
+            ctx.serve_ws(
+                tcp,
+                SocketAddr::new(peer.ip().to_canonical(), peer.port()),
+                &path,
+                running,
+            )
+        })
+        .map_err(|_| RsipError::Io)?;
+        self.log(
+            LogLevel::Info,
+            format_args!("ws listener on {}", listener.local_addr),
+        );
+        *slot = Some(listener);
+        Ok(())
+    }
+
+    fn serve_ws(&self, mut tcp: TcpStream, peer: SocketAddr, path: &str, running: &AtomicBool) {
+        let (request, rest) = match read_request(&mut tcp, running) {
+            Ok(request) => request,
+            Err(reason) => return self.ws_error(peer, &reason),
+        };
+        let response = match upgrade_response(&request, path) {
+            Ok(response) => response,
+            Err((status, reason)) => {
+                let _ = tcp.write_all(
+                    format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes(),
+                );
+                return self.ws_error(peer, &reason);
+            }
+        };
+        let writer: WsWriter = match tcp.try_clone() {
+            Ok(writer) => Arc::new(Mutex::new(writer)),
+            Err(e) => return self.ws_error(peer, &e.to_string()),
+        };
+        if writer.locked().write_all(response.as_bytes()).is_err() {
+            return;
+        }
+        self.ws_connections.locked().insert(peer, writer.clone());
+
+        if let Err(reason) = self.read_frames(&mut tcp, rest, peer, &writer, running) {
+            let _ = writer
+                .locked()
+                .write_all(&encode_frame(OP_CLOSE, &1002u16.to_be_bytes()));
+            self.ws_error(peer, &reason);
+        }
+        self.ws_connections.locked().remove(&peer);
+    }
+
+    /// Delivers every complete message until the client closes the connection.
+    fn read_frames(
+        &self,
+        tcp: &mut TcpStream,
+        initial: Vec<u8>,
+        peer: SocketAddr,
+        writer: &WsWriter,
+        running: &AtomicBool,
+    ) -> Result<(), String> {
+        let mut frames = FrameReader::default();
+        frames.push(&initial);
+        let mut message: Option<Vec<u8>> = None;
+        let mut buf = [0u8; 8192];
+        loop {
+            while let Some(frame) = frames.next_frame()? {
+                match frame.opcode {
+                    OP_TEXT | OP_BINARY if message.is_none() => message = Some(frame.payload),
+                    OP_CONTINUATION if message.is_some() => {
+                        let partial = message.as_mut().unwrap();
+                        if partial.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                            return Err("message too large".into());
+                        }
+                        partial.extend_from_slice(&frame.payload);
+                    }
+                    OP_CLOSE => {
+                        let _ = writer
+                            .locked()
+                            .write_all(&encode_frame(OP_CLOSE, &frame.payload));
+                        return Ok(());
+                    }
+                    OP_PING => {
+                        let _ = writer
+                            .locked()
+                            .write_all(&encode_frame(OP_PONG, &frame.payload));
+                        continue;
+                    }
+                    OP_PONG => continue,
+                    _ => return Err(format!("unexpected opcode {}", frame.opcode)),
+                }
+                if frame.fin {
+                    self.dispatch(&message.take().unwrap(), peer);
+                }
+            }
+
+            if !running.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            match tcp.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => frames.push(&buf[..n]),
+                Err(e) if is_timeout(&e) => {}
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+
+    fn ws_error(&self, peer: SocketAddr, reason: &str) {
+        self.log(
+            LogLevel::Debug,
+            format_args!("websocket {}: {}", peer, reason),
+        );
+        let payload = json!({ "src": peer.to_string(), "reason": reason });
+        self.emit_from("ws_error", &payload.to_string(), peer);
+    }
+
+    /// Sends `payload` as one text frame to the WebSocket client connected from
+    /// `ip:port`. Fails with `SendFailed` if no such client is connected.
+    pub fn send_ws(&self, ip: &str, port: u16, payload: &[u8]) -> Result<(), RsipError> {
+        let dest = resolve(ip, port)?;
+        let dest = SocketAddr::new(dest.ip().to_canonical(), dest.port());
+        let writer = self
+            .ws_connections
+            .locked()
+            .get(&dest)
+            .cloned()
+            .ok_or(RsipError::SendFailed)?;
+        let result = writer.locked().write_all(&encode_frame(OP_TEXT, payload));
+        self.stats.record_send(&result);
+        result.map_err(|_| RsipError::SendFailed)?;
+        self.dialog_sent(payload);
+        Ok(())
+    }
+
+    /// The address the WebSocket listener is bound to, if one is running.
+    pub fn ws_local_addr(&self) -> Option<SocketAddr> {
+        self.ws_listener.locked().as_ref().map(|l| l.local_addr)
+    }
+}
+
+/// Starts a SIP-over-WebSocket listener on `port` accepting upgrades to `path`
+/// (e.g. "/sip").
+#[no_mangle]
+pub extern "C" fn rsip_start_ws_listener(port: u16, path: *const c_char) -> bool {
+    rsip_start_ws_listener_ex(port, path) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_start_ws_listener_ex(port: u16, path: *const c_char) -> i32 {
+    let result = str_arg(path)
+        .ok_or(RsipError::InvalidArgument)
+        .and_then(|path| crate::default_context().start_ws_listener(port, path));
+    to_code(result)
+}
+
+/// Sends `data` to the WebSocket client connected from `dest_ip:dest_port`.
+#[no_mangle]
+pub extern "C" fn rsip_send_ws(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> bool {
+    rsip_send_ws_ex(dest_ip, dest_port, data) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_send_ws_ex(
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> i32 {
+    let result = send_args(dest_ip, data)
+        .and_then(|(ip, payload)| crate::default_context().send_ws(ip, dest_port, payload));
+    to_code(result)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_ws_listener(
+    ctx: *mut RsipContext,
+    port: u16,
+    path: *const c_char,
+) -> bool {
+    rsip_context_start_ws_listener_ex(ctx, port, path) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_start_ws_listener_ex(
+    ctx: *mut RsipContext,
+    port: u16,
+    path: *const c_char,
+) -> i32 {
+    let result = str_arg(path)
+        .ok_or(RsipError::InvalidArgument)
+        .and_then(|path| {
+            with_context(ctx, |ctx| ctx.start_ws_listener(port, path))
+                .unwrap_or(Err(RsipError::InvalidArgument))
+        });
+    to_code(result)
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_send_ws(
+    ctx: *mut RsipContext,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> bool {
+    rsip_context_send_ws_ex(ctx, dest_ip, dest_port, data) == 0
+}
+
+#[no_mangle]
+pub extern "C" fn rsip_context_send_ws_ex(
+    ctx: *mut RsipContext,
+    dest_ip: *const c_char,
+    dest_port: u16,
+    data: *const c_char,
+) -> i32 {
+    let result = send_args(dest_ip, data).and_then(|(ip, payload)| {
+        with_context(ctx, |ctx| ctx.send_ws(ip, dest_port, payload))
+            .unwrap_or(Err(RsipError::InvalidArgument))
+    });
+    to_code(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Sink;
+    use std::ffi::CStr;
+    use std::time::Duration;
+
+    static EVENTS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(event: *const c_char, payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }
+            .to_string_lossy()
+            .into_owned();
+        let payload = unsafe { CStr::from_ptr(payload) }
+            .to_string_lossy()
+            .into_owned();
+        EVENTS.lock().unwrap().push((event, payload));
+    }
+
+    fn wait_for(event: &str, payload: impl Fn(&str) -> bool) -> Option<String> {
+        for _ in 0..200 {
+            let events = EVENTS.lock().unwrap();
+            if let Some((_, p)) = events.iter().find(|(e, p)| e == event && payload(p)) {
+                return Some(p.clone());
+            }
+            drop(events);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    fn masked(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![(if fin { 0x80 } else { 0 }) | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn upgrade(addr: SocketAddr, path: &str, protocol: &str) -> (TcpStream, String) {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        write!(
+            client,
+            "GET {} HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+             Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: {}\r\n\r\n",
+            path, protocol
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") && client.read(&mut byte).unwrap_or(0) == 1 {
+            response.push(byte[0]);
+        }
+        (client, String::from_utf8(response).unwrap())
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut reader = FrameReader::default();
+        let long = vec![b'x'; 300];
+        let mut bytes = masked(OP_TEXT, false, b"he");
+        bytes.extend(masked(OP_CONTINUATION, true, &long));
+        reader.push(&bytes[..3]);
+        assert_eq!(reader.next_frame(), Ok(None));
+        reader.push(&bytes[3..]);
+        assert_eq!(reader.next_frame().unwrap().unwrap().payload, b"he");
+        let last = reader.next_frame().unwrap().unwrap();
+        assert!(last.fin);
+        assert_eq!(last.payload, long);
+
+        reader.push(&encode_frame(OP_TEXT, b"unmasked"));
+        assert!(reader.next_frame().is_err());
+        assert_eq!(encode_frame(OP_TEXT, &long)[1..4], [126, 1, 44]);
+    }
+
+    #[test]
+    fn upgrades_delivers_and_sends() {
+        let ctx = Arc::new(RsipContext::new());
+        ctx.events.subscribe(
+            Some(vec!["sip_rx".into(), "ws_error".into()]),
+            Sink::Basic(record),
+        );
+        ctx.set_bind_address("127.0.0.1").unwrap();
+        assert_eq!(
+            ctx.start_ws_listener(0, "sip"),
+            Err(RsipError::InvalidArgument)
+        );
+        ctx.start_ws_listener(0, "/sip").unwrap();
+        let addr = ctx.ws_local_addr().unwrap();
+
+        // no sip subprotocol: rejected before the upgrade
+        let (refused, response) = upgrade(addr, "/sip", "chat");
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        let src = refused.local_addr().unwrap().to_string();
+        assert!(wait_for("ws_error", |p| p.contains(&src)).is_some());
+
+        let (mut client, response) = upgrade(addr, "/sip?token=1", "chat, sip");
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.contains("Sec-WebSocket-Protocol: sip\r\n"));
+
+        let msg = "OPTIONS sip:bob@example.com SIP/2.0\r\nVia: SIP/2.0/WS df7jal23ls0d.invalid;branch=z9hG4bKws\r\nContent-Length: 0\r\n\r\n";
+        let mut bytes = masked(OP_PING, true, b"p");
+        bytes.extend(masked(OP_TEXT, false, &msg.as_bytes()[..7]));
+        bytes.extend(masked(OP_CONTINUATION, true, &msg.as_bytes()[7..]));
+        client.write_all(&bytes).unwrap();
+        assert!(wait_for("sip_rx", |p| p == msg).is_some());
+
+        let mut pong = [0u8; 3];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x80 | OP_PONG, 1, b'p']);
+
+        let local = client.local_addr().unwrap();
+        ctx.send_ws("127.0.0.1", local.port(), b"SIP/2.0 200 OK\r\n\r\n")
+            .unwrap();
+        let mut reply = [0u8; 20];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[..2], &[0x80 | OP_TEXT, 18]);
+        assert_eq!(&reply[2..], b"SIP/2.0 200 OK\r\n\r\n");
+        assert_eq!(
+            ctx.send_ws("127.0.0.1", 1, b"x"),
+            Err(RsipError::SendFailed)
+        );
+
+        client
+            .write_all(&masked(OP_CLOSE, true, &1000u16.to_be_bytes()))
+            .unwrap();
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).unwrap();
+        assert_eq!(close[0], 0x80 | OP_CLOSE);
+
+        ctx.shutdown();
+        assert!(ctx.ws_local_addr().is_none());
+    }
+}
@@ -9,6 +9,14 @@ use std::thread;
 use std::time::Duration;
 use std::net::UdpSocket;
 
+// Pull in the library so its exported symbols are linked into this test binary
+use rsip_wrapper as _;
+
+#[repr(C)]
+struct RsipContext {
+    _private: [u8; 0],
+}
+
 // FFI declarations (would normally be in a generated header)
 extern "C" {
     fn rsip_init() -> bool;
@@ -18,6 +26,15 @@ extern "C" {
     fn rsip_send_udp(dest_ip: *const c_char, dest_port: u16, data: *const c_char) -> bool;
     fn rsip_shutdown();
     fn rsip_version() -> *const c_char;
+    fn rsip_context_new() -> *mut RsipContext;
+    fn rsip_context_free(ctx: *mut RsipContext);
+    fn rsip_context_set_event_callback(
+        ctx: *mut RsipContext,
+        cb: extern "C" fn(event: *const c_char, payload: *const c_char),
+    );
+    fn rsip_context_start_udp_listener(ctx: *mut RsipContext, port: u16) -> bool;
+    fn rsip_context_shutdown(ctx: *mut RsipContext);
+    fn rsip_context_set_recv_buffer_size(ctx: *mut RsipContext, bytes: usize) -> bool;
 }
 
 #[test]
@@ -81,13 +98,18 @@ fn test_ffi_listener_lifecycle() {
 
         // Register a callback to count events
         let event_count = Arc::new(AtomicBool::new(false));
-        let event_count_clone = event_count.clone();
+        let _event_count_clone = event_count.clone();
+
+        static STARTED: AtomicBool = AtomicBool::new(false);
 
         extern "C" fn capture_callback(event: *const c_char, payload: *const c_char) {
             unsafe {
                 let ev = CStr::from_ptr(event).to_str().unwrap_or("");
                 let pl = CStr::from_ptr(payload).to_str().unwrap_or("");
                 println!("capture_callback: event={}, payload_len={}", ev, pl.len());
+                if ev == "listener_started" {
+                    STARTED.store(true, Ordering::SeqCst);
+                }
             }
         }
 
@@ -98,8 +120,14 @@ fn test_ffi_listener_lifecycle() {
         assert!(listener_result, "rsip_start_udp_listener should succeed");
         println!("Listener started on port 15060");
 
-        // Give listener time to start
-        thread::sleep(Duration::from_millis(100));
+        // Wait for the receive thread to report that it is running. Other tests share
+        // the default context and may replace the callback, so this is bounded.
+        for _ in 0..200 {
+            if STARTED.load(Ordering::SeqCst) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
 
         // Send a test SIP message to ourselves
         let test_message = "INVITE sip:test@localhost SIP/2.0\r\nVia: SIP/2.0/UDP 127.0.0.1\r\n\r\n";
@@ -132,3 +160,66 @@ fn test_ffi_multiple_lifecycle() {
         }
     }
 }
+
+#[test]
+fn test_ffi_two_contexts_on_different_ports() {
+    extern "C" fn noop_callback(_event: *const c_char, _payload: *const c_char) {}
+
+    unsafe {
+        let a = rsip_context_new();
+        let b = rsip_context_new();
+        assert!(!a.is_null() && !b.is_null(), "contexts should be allocated");
+
+        rsip_context_set_event_callback(a, noop_callback);
+        rsip_context_set_event_callback(b, noop_callback);
+        assert!(
+            rsip_context_start_udp_listener(a, 15070),
+            "first context should bind 15070"
+        );
+        assert!(
+            rsip_context_start_udp_listener(b, 15071),
+            "second context should bind 15071"
+        );
+
+        rsip_context_shutdown(a);
+        rsip_context_shutdown(b);
+        rsip_context_free(a);
+        rsip_context_free(b);
+    }
+}
+
+#[test]
+fn test_ffi_oversized_datagram_emits_truncated() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    static TRUNCATED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn watch_truncation(event: *const c_char, _payload: *const c_char) {
+        let event = unsafe { CStr::from_ptr(event) }.to_str().unwrap_or("");
+        if event == "sip_rx_truncated" {
+            TRUNCATED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    unsafe {
+        let ctx = rsip_context_new();
+        rsip_context_set_event_callback(ctx, watch_truncation);
+        assert!(rsip_context_set_recv_buffer_size(ctx, 576));
+        assert!(rsip_context_start_udp_listener(ctx, 15072));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&[b'x'; 1200], "127.0.0.1:15072").unwrap();
+
+        for _ in 0..50 {
+            if TRUNCATED.load(Ordering::SeqCst) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(
+            TRUNCATED.load(Ordering::SeqCst),
+            "oversized datagram should be flagged"
+        );
+
+        rsip_context_free(ctx);
+    }
+}